@@ -4,24 +4,72 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use std::any::Any;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
 use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use failure::Error;
 use sgxs::loader::{Load, MappingInfo};
 
 use crate::loader::{EnclaveBuilder, ErasedTcs};
-use crate::usercalls::EnclaveState;
-use crate::usercalls::UsercallExtension;
+use crate::usercalls::{EnclaveConfig, EnclaveState, MetricsMonitor, ResourceMonitor};
+use std::fmt;
 use std::os::raw::c_void;
 
+/// The enclave was stopped with [`CommandHandle::abort`] before it exited.
+///
+/// This is returned (as a [`failure::Error`] that can be downcast to this
+/// type) as the exit status of an aborted enclave, unless a more specific
+/// reason such as an [`EnclavePanic`] was recorded first.
+///
+/// [`CommandHandle::abort`]: struct.CommandHandle.html#method.abort
+/// [`failure::Error`]: https://docs.rs/failure/0.1/failure/struct.Error.html
+/// [`EnclavePanic`]: enum.EnclavePanic.html
+#[derive(Debug, Fail)]
+#[fail(display = "The enclave was aborted")]
+pub struct CommandAborted;
+
+/// The runner panicked while running an enclave started with
+/// [`Command::spawn`], for example because the enclave panicked and
+/// [`EnclaveBuilder::forward_panics`] was set.
+///
+/// This is returned (as a [`failure::Error`] that can be downcast to this
+/// type) as the exit status of the enclave. It contains the panic message.
+///
+/// [`Command::spawn`]: struct.Command.html#method.spawn
+/// [`EnclaveBuilder::forward_panics`]: struct.EnclaveBuilder.html#method.forward_panics
+/// [`failure::Error`]: https://docs.rs/failure/0.1/failure/struct.Error.html
+#[derive(Debug, Fail)]
+#[fail(display = "The enclave runner panicked: {}", _0)]
+pub struct CommandPanicked(pub String);
+
+impl CommandPanicked {
+    pub(crate) fn from_payload(payload: Box<dyn Any + Send>) -> Self {
+        let message = match payload.downcast::<String>() {
+            Ok(message) => *message,
+            Err(payload) => match payload.downcast::<&'static str>() {
+                Ok(message) => (*message).to_owned(),
+                Err(_) => "Box<dyn Any>".to_owned(),
+            },
+        };
+        CommandPanicked(message)
+    }
+}
+
 #[derive(Debug)]
 pub struct Command {
     main: ErasedTcs,
     threads: Vec<ErasedTcs>,
     address: usize,
     size: usize,
-    usercall_ext: Option<Box<dyn UsercallExtension>>,
-    forward_panics: bool,
+    config: EnclaveConfig,
+    cmd_args: Vec<Vec<u8>>,
 }
 
 impl MappingInfo for Command {
@@ -41,8 +89,8 @@ impl Command {
         mut tcss: Vec<ErasedTcs>,
         address: *mut c_void,
         size: usize,
-        usercall_ext: Option<Box<dyn UsercallExtension>>,
-        forward_panics: bool,
+        config: EnclaveConfig,
+        cmd_args: Vec<Vec<u8>>,
     ) -> Command {
         let main = tcss.remove(0);
         Command {
@@ -50,8 +98,8 @@ impl Command {
             threads: tcss,
             address: address as _,
             size,
-            usercall_ext,
-            forward_panics,
+            config,
+            cmd_args,
         }
     }
 
@@ -59,7 +107,255 @@ impl Command {
         EnclaveBuilder::new(enclave_path.as_ref()).build(loader)
     }
 
+    /// Returns a handle to observe the host resources used by the enclave,
+    /// which can be used from another thread while the enclave runs.
+    pub fn resource_monitor(&self) -> ResourceMonitor {
+        ResourceMonitor(self.config.resources.clone())
+    }
+
+    /// Returns a handle to read the usercall, I/O and thread metrics of the
+    /// enclave, which can be used from another thread while the enclave
+    /// runs.
+    pub fn metrics(&self) -> MetricsMonitor {
+        MetricsMonitor(self.config.metrics.clone())
+    }
+
+    /// Run the enclave on the current thread until it exits.
+    ///
+    /// If the enclave exits abnormally, for example because it panicked,
+    /// the error can be downcast to an [`EnclavePanicReport`]. If it was
+    /// aborted, the error is a [`CommandAborted`].
+    ///
+    /// [`EnclavePanicReport`]: usercalls/struct.EnclavePanicReport.html
+    /// [`CommandAborted`]: struct.CommandAborted.html
     pub fn run(self) -> Result<(), Error> {
-        EnclaveState::main_entry(self.main, self.threads, self.usercall_ext, self.forward_panics)
+        let enclave_range = self.address..self.address + self.size;
+        let (_, run) =
+            EnclaveState::main_entry(self.main, self.threads, enclave_range, self.config, self.cmd_args);
+        run()
+    }
+
+    /// Run the enclave on a new thread, returning a handle that can be used
+    /// to wait for or abort the enclave.
+    ///
+    /// If the runner panics while running the enclave, the exit status is a
+    /// [`CommandPanicked`] error.
+    ///
+    /// [`CommandPanicked`]: struct.CommandPanicked.html
+    pub fn spawn(self) -> CommandHandle {
+        let enclave_range = self.address..self.address + self.size;
+        let (enclave, run) =
+            EnclaveState::main_entry(self.main, self.threads, enclave_range, self.config, self.cmd_args);
+        let exit = Arc::new(ExitState::default());
+        let exit_clone = exit.clone();
+        thread::spawn(move || {
+            // Report panics as the exit status, so that waiting for the
+            // enclave doesn't block forever
+            let result = panic::catch_unwind(AssertUnwindSafe(run))
+                .unwrap_or_else(|payload| Err(CommandPanicked::from_payload(payload).into()));
+            exit_clone.exit(result);
+        });
+        CommandHandle { enclave, exit }
+    }
+}
+
+#[derive(Default)]
+struct ExitStatus {
+    result: Option<Result<(), Error>>,
+    /// Whether `result` has been returned by the handle
+    collected: bool,
+    waker: Option<Waker>,
+}
+
+impl ExitStatus {
+    fn take(&mut self) -> Option<Result<(), Error>> {
+        let result = self.result.take();
+        if result.is_some() {
+            self.collected = true;
+        } else if self.collected {
+            panic!("enclave exit status already collected");
+        }
+        result
+    }
+}
+
+#[derive(Default)]
+struct ExitState {
+    status: Mutex<ExitStatus>,
+    exited: Condvar,
+}
+
+impl ExitState {
+    /// Store the exit status, waking up any task or thread waiting for it.
+    fn exit(&self, result: Result<(), Error>) {
+        let mut status = self.status.lock().unwrap();
+        status.result = Some(result);
+        if let Some(waker) = status.waker.take() {
+            waker.wake();
+        }
+        self.exited.notify_all();
+    }
+
+    fn poll(&self, cx: &mut Context) -> Poll<Result<(), Error>> {
+        let mut status = self.status.lock().unwrap();
+        match status.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                status.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// A handle to an enclave started with [`Command::spawn`].
+///
+/// The handle is also a future that resolves to the exit status of the
+/// enclave, as would be returned by [`Command::run`]. The exit status can
+/// be obtained only once, by waiting or polling.
+///
+/// [`Command::spawn`]: struct.Command.html#method.spawn
+/// [`Command::run`]: struct.Command.html#method.run
+pub struct CommandHandle {
+    enclave: Arc<EnclaveState>,
+    exit: Arc<ExitState>,
+}
+
+impl fmt::Debug for CommandHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CommandHandle")
+            .field("exited", &self.has_exited())
+            .finish()
+    }
+}
+
+impl CommandHandle {
+    /// Stop the enclave. Enclave threads waiting for events are woken up,
+    /// their next usercall makes them exit the enclave, and no further
+    /// usercalls are handled.
+    ///
+    /// Unless the enclave had already exited for a different reason, its
+    /// exit status will be a [`CommandAborted`] error. Enclave threads that
+    /// don't perform any usercalls can't be interrupted, so the enclave
+    /// might not exit after being aborted; use [`wait_timeout`] to avoid
+    /// blocking indefinitely.
+    ///
+    /// [`CommandAborted`]: struct.CommandAborted.html
+    /// [`wait_timeout`]: #method.wait_timeout
+    pub fn abort(&self) {
+        self.enclave.abort()
+    }
+
+    /// Whether the enclave has exited.
+    pub fn has_exited(&self) -> bool {
+        let status = self.exit.status.lock().unwrap();
+        status.result.is_some() || status.collected
+    }
+
+    /// Block until the enclave exits and return its exit status.
+    ///
+    /// # Panics
+    /// Panics if the exit status was already collected.
+    pub fn wait(self) -> Result<(), Error> {
+        let mut status = self.exit.status.lock().unwrap();
+        loop {
+            if let Some(result) = status.take() {
+                return result;
+            }
+            status = self.exit.exited.wait(status).unwrap();
+        }
+    }
+
+    /// Block until the enclave exits or `timeout` has passed. Returns the
+    /// exit status if the enclave exited.
+    ///
+    /// # Panics
+    /// Panics if the exit status was already collected.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> Option<Result<(), Error>> {
+        let deadline = Instant::now() + timeout;
+        let mut status = self.exit.status.lock().unwrap();
+        loop {
+            if let Some(result) = status.take() {
+                return Some(result);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            status = self.exit.exited.wait_timeout(status, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Returns a handle to observe the host resources used by the enclave.
+    pub fn resource_monitor(&self) -> ResourceMonitor {
+        self.enclave.resource_monitor()
+    }
+
+    /// Returns a handle to read the usercall, I/O and thread metrics of the
+    /// enclave.
+    pub fn metrics(&self) -> MetricsMonitor {
+        self.enclave.metrics_monitor()
+    }
+}
+
+impl Future for CommandHandle {
+    type Output = Result<(), Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.exit.poll(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use futures::task::{self, ArcWake};
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Woken(AtomicBool);
+
+    impl ArcWake for Woken {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn take() {
+        let mut status = ExitStatus::default();
+        assert!(status.take().is_none());
+        status.result = Some(Ok(()));
+        assert!(status.take().unwrap().is_ok());
+        assert!(status.collected);
+    }
+
+    #[test]
+    #[should_panic(expected = "enclave exit status already collected")]
+    fn take_twice() {
+        let mut status = ExitStatus::default();
+        status.result = Some(Ok(()));
+        status.take();
+        status.take();
+    }
+
+    #[test]
+    fn wake() {
+        let exit = Arc::new(ExitState::default());
+        let woken = Arc::new(Woken::default());
+        let waker = task::waker(woken.clone());
+        let mut cx = Context::from_waker(&waker);
+        assert!(exit.poll(&mut cx).is_pending());
+        assert!(!woken.0.load(Ordering::SeqCst));
+
+        let exit_clone = exit.clone();
+        thread::spawn(move || exit_clone.exit(Err(CommandAborted.into()))).join().unwrap();
+        assert!(woken.0.load(Ordering::SeqCst));
+        match exit.poll(&mut cx) {
+            Poll::Ready(Err(e)) => assert!(e.downcast_ref::<CommandAborted>().is_some()),
+            _ => panic!("exit status not ready"),
+        }
     }
 }
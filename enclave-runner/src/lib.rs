@@ -25,13 +25,21 @@ extern crate fortanix_sgx_abi;
 #[macro_use]
 extern crate lazy_static;
 extern crate futures;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate addr2line;
+extern crate object;
 
 mod command;
 mod library;
 mod loader;
+mod symbolize;
 mod tcs;
 pub mod usercalls;
 
-pub use crate::command::Command;
-pub use crate::library::Library;
+pub use crate::command::{Command, CommandAborted, CommandHandle, CommandPanicked};
+pub use crate::library::{Library, TcsUnavailable};
 pub use crate::loader::{EnclaveBuilder, EnclavePanic};
+pub use crate::usercalls::{EnclavePanicReport, EnclaveRuntime, EnclaveRuntimeBuilder};
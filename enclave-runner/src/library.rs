@@ -4,18 +4,40 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use std::future::Future;
 use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 
 use failure::Error;
 use sgxs::loader::{Load, MappingInfo};
 
 use crate::loader::{EnclaveBuilder, ErasedTcs};
-use crate::usercalls::EnclaveState;
-use crate::usercalls::UsercallExtension;
+use crate::usercalls::{EnclaveConfig, EnclaveState, MetricsMonitor, ResourceMonitor, TcsWait};
 use std::fmt;
 use std::os::raw::c_void;
 
+/// The reason a library call couldn't be started.
+///
+/// This is returned (as a [`failure::Error`] that can be downcast to this
+/// type) by the [`Library`] call functions when no TCS could be obtained.
+///
+/// [`failure::Error`]: https://docs.rs/failure/0.1/failure/struct.Error.html
+/// [`Library`]: struct.Library.html
+#[derive(Debug, Fail)]
+pub enum TcsUnavailable {
+    /// All TCSs are servicing other calls, or other callers are already
+    /// waiting for a TCS.
+    #[fail(display = "All library TCSs are busy")]
+    Busy,
+    /// No TCS became available within the specified timeout.
+    #[fail(display = "Timed out waiting for a library TCS")]
+    TimedOut,
+    /// The enclave has exited and can no longer be called.
+    #[fail(display = "The library enclave has exited")]
+    Exited,
+}
+
 pub struct Library {
     enclave: Arc<EnclaveState>,
     address: *mut c_void,
@@ -46,11 +68,10 @@ impl Library {
         tcss: Vec<ErasedTcs>,
         address: *mut c_void,
         size: usize,
-        usercall_ext: Option<Box<dyn UsercallExtension>>,
-        forward_panics: bool,
+        config: EnclaveConfig,
     ) -> Library {
         Library {
-            enclave: EnclaveState::library(tcss, usercall_ext, forward_panics),
+            enclave: EnclaveState::library(tcss, address as usize..address as usize + size, config),
             address,
             size,
         }
@@ -60,8 +81,20 @@ impl Library {
         EnclaveBuilder::new(enclave_path.as_ref()).build_library(loader)
     }
 
+    /// Returns a handle to observe the host resources used by the enclave.
+    pub fn resource_monitor(&self) -> ResourceMonitor {
+        self.enclave.resource_monitor()
+    }
+
+    /// Returns a handle to read the usercall, I/O and thread metrics of the
+    /// enclave.
+    pub fn metrics(&self) -> MetricsMonitor {
+        self.enclave.metrics_monitor()
+    }
+
     /// If this library's TCSs are all currently servicing other calls, this
-    /// function will block until a TCS becomes available.
+    /// function will block until a TCS becomes available. Callers are
+    /// served in the order in which they started waiting.
     ///
     /// # Safety
     ///
@@ -75,6 +108,83 @@ impl Library {
         p4: u64,
         p5: u64,
     ) -> Result<(u64, u64), Error> {
-        EnclaveState::library_entry(&self.enclave, p1, p2, p3, p4, p5)
+        EnclaveState::library_entry(&self.enclave, p1, p2, p3, p4, p5, TcsWait::Indefinite)
+    }
+
+    /// Asynchronous version of [`call`](#method.call).
+    ///
+    /// Usercalls made by the enclave during this call are handled on a
+    /// thread of the blocking thread pool of the current tokio runtime, and
+    /// the enclave is entered on another thread of that pool. This function
+    /// must therefore be called from within a tokio runtime.
+    ///
+    /// If this library's TCSs are all currently servicing other calls, the
+    /// returned future will wait until a TCS becomes available, without
+    /// blocking. Waiting callers are served in order, together with those
+    /// of [`call`](#method.call).
+    ///
+    /// Dropping the future while it's waiting for a TCS cancels the call.
+    /// Once the enclave has been entered, the call runs to completion in the
+    /// background, after which the TCS is available again.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the parameters passed-in match what the
+    /// enclave is expecting.
+    pub unsafe fn call_async(
+        &self,
+        p1: u64,
+        p2: u64,
+        p3: u64,
+        p4: u64,
+        p5: u64,
+    ) -> impl Future<Output = Result<(u64, u64), Error>> + Send {
+        EnclaveState::library_entry_async(self.enclave.clone(), p1, p2, p3, p4, p5)
+    }
+
+    /// Like [`call`](#method.call), but waits at most `timeout` for a TCS to
+    /// become available. If none does, [`TcsUnavailable::TimedOut`] is
+    /// returned.
+    ///
+    /// The timeout only applies to waiting for a TCS, not to the execution
+    /// of the call itself.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the parameters passed-in match what the
+    /// enclave is expecting.
+    ///
+    /// [`TcsUnavailable::TimedOut`]: enum.TcsUnavailable.html#variant.TimedOut
+    pub unsafe fn call_timeout(
+        &self,
+        p1: u64,
+        p2: u64,
+        p3: u64,
+        p4: u64,
+        p5: u64,
+        timeout: Duration,
+    ) -> Result<(u64, u64), Error> {
+        EnclaveState::library_entry(&self.enclave, p1, p2, p3, p4, p5, TcsWait::Timeout(timeout))
+    }
+
+    /// Like [`call`](#method.call), but doesn't block if no TCS is
+    /// immediately available. In that case, [`TcsUnavailable::Busy`] is
+    /// returned.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the parameters passed-in match what the
+    /// enclave is expecting.
+    ///
+    /// [`TcsUnavailable::Busy`]: enum.TcsUnavailable.html#variant.Busy
+    pub unsafe fn try_call(
+        &self,
+        p1: u64,
+        p2: u64,
+        p3: u64,
+        p4: u64,
+        p5: u64,
+    ) -> Result<(u64, u64), Error> {
+        EnclaveState::library_entry(&self.enclave, p1, p2, p3, p4, p5, TcsWait::No)
     }
 }
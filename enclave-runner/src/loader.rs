@@ -7,7 +7,9 @@
 use std::fs::File;
 use std::io::{Error as IoError, ErrorKind, Read, Result as IoResult};
 use std::os::raw::c_void;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 use std::{arch, str};
 
 use failure::{Error, ResultExt};
@@ -21,10 +23,15 @@ use openssl::{
 use sgx_isa::{Attributes, AttributesFlags, Miscselect, Sigstruct};
 use sgxs::crypto::{SgxHashOps, SgxRsaOps};
 use sgxs::loader::{Load, MappingInfo, Tcs};
+use sgxs::sgxs::{Meas, SgxsRead};
 use sgxs::sigstruct::{self, EnclaveHash, Signer};
 
+use crate::symbolize::Symbolizer;
 use crate::tcs::DebugBuffer;
-use crate::usercalls::UsercallExtension;
+use crate::usercalls::{
+    Clock, CoreDumper, EnclaveConfig, EnclaveRuntime, GdbServer, LaunchThreadPolicy, NetworkPolicy, MetricsCounters, ResourceCounters, ResourceLimits, Stdio, UsercallExtension,
+    UsercallRecorder, UsercallReplayer, UsercallSession, UsercallTraceSink,
+};
 use crate::{Command, Library};
 
 enum EnclaveSource<'a> {
@@ -41,6 +48,35 @@ impl<'a> EnclaveSource<'a> {
             EnclaveSource::File(_) => None,
         }
     }
+
+    /// The SSA frame size in pages, from the ECREATE record of the enclave.
+    /// Defaults to 1 if the enclave can't be read again.
+    fn ssa_frame_size(&self) -> Result<u32, Error> {
+        let mut enclave = match self.try_clone() {
+            Some(enclave) => enclave,
+            None => return Ok(1),
+        };
+        match enclave.read_meas()? {
+            Some(Meas::ECreate(ecreate)) | Some(Meas::Unsized(ecreate)) => Ok(ecreate.ssaframesize),
+            _ => bail!("Invalid SGXS file: missing ECREATE"),
+        }
+    }
+
+    /// The offsets of the pages added to the enclave, from the EADD records
+    /// of the enclave. Returns `None` if the enclave can't be read again.
+    fn added_pages(&self) -> Result<Option<Vec<usize>>, Error> {
+        let mut enclave = match self.try_clone() {
+            Some(enclave) => enclave,
+            None => return Ok(None),
+        };
+        let mut pages = vec![];
+        while let Some(meas) = enclave.read_meas()? {
+            if let Meas::EAdd(eadd) = meas {
+                pages.push(eadd.offset as usize);
+            }
+        }
+        Ok(Some(pages))
+    }
 }
 
 impl<'a> Read for EnclaveSource<'a> {
@@ -64,12 +100,28 @@ pub struct EnclaveBuilder<'a> {
     attributes: Option<Attributes>,
     miscselect: Option<Miscselect>,
     usercall_ext: Option<Box<dyn UsercallExtension>>,
+    clock: Option<Box<dyn Clock>>,
     load_and_sign: Option<Box<dyn FnOnce(Signer) -> Result<Sigstruct, Error>>>,
     hash_enclave: Option<Box<dyn FnOnce(&mut EnclaveSource<'_>) -> Result<EnclaveHash, Error>>>,
     forward_panics: bool,
+    signal_grace_period: Option<Duration>,
+    usercall_trace: Option<Box<dyn UsercallTraceSink>>,
+    usercall_session: Option<UsercallSession>,
+    stdin: Stdio,
+    stdout: Stdio,
+    stderr: Stdio,
+    network_policy: NetworkPolicy,
+    resource_limits: ResourceLimits,
+    enclave_elf: Option<PathBuf>,
+    core_dump: Option<PathBuf>,
+    gdbserver: Option<String>,
+    runtime: Option<EnclaveRuntime>,
+    launch_thread_policy: LaunchThreadPolicy,
+    cmd_args: Option<Vec<Vec<u8>>>,
 }
 
-#[derive(Debug, Fail)]
+#[derive(Clone, Debug, Fail, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum EnclavePanic {
     /// The first byte of the debug buffer was 0
     #[fail(display = "Enclave panicked.")]
@@ -137,9 +189,24 @@ impl<'a> EnclaveBuilder<'a> {
             miscselect: None,
             signature: None,
             usercall_ext: None,
+            clock: None,
             load_and_sign: None,
             hash_enclave: None,
             forward_panics: false,
+            signal_grace_period: None,
+            usercall_trace: None,
+            usercall_session: None,
+            stdin: Stdio::inherit(),
+            stdout: Stdio::inherit(),
+            stderr: Stdio::inherit(),
+            network_policy: NetworkPolicy::default(),
+            resource_limits: ResourceLimits::default(),
+            enclave_elf: None,
+            core_dump: None,
+            gdbserver: None,
+            runtime: None,
+            launch_thread_policy: LaunchThreadPolicy::default(),
+            cmd_args: None,
         };
 
         let _ = ret.coresident_signature();
@@ -250,6 +317,45 @@ impl<'a> EnclaveBuilder<'a> {
         self.usercall_ext = Some(extension.into());
     }
 
+    /// Sets the source of time of the enclave, which is used for the
+    /// `insecure_time` usercall and for timeouts of the `wait` usercall.
+    /// Defaults to the [`SystemClock`].
+    ///
+    /// Timeouts of the `wait` usercall are supported regardless of the
+    /// clock, and advertised to the enclave with
+    /// [`CAPABILITY_WAIT_TIMEOUT`]. An event that is queued when the timeout
+    /// expires is still returned.
+    ///
+    /// [`SystemClock`]: usercalls/struct.SystemClock.html
+    /// [`CAPABILITY_WAIT_TIMEOUT`]: ../fortanix_sgx_abi/constant.CAPABILITY_WAIT_TIMEOUT.html
+    pub fn clock<T: Into<Box<dyn Clock>>>(&mut self, clock: T) -> &mut Self {
+        self.clock = Some(clock.into());
+        self
+    }
+
+    /// Run the enclave on the threads of `runtime`, which can be shared with
+    /// other enclaves, instead of starting threads whenever the enclave is
+    /// entered. See [`EnclaveRuntime`] for details.
+    ///
+    /// [`EnclaveRuntime`]: usercalls/struct.EnclaveRuntime.html
+    pub fn runtime(&mut self, runtime: &EnclaveRuntime) -> &mut Self {
+        self.runtime = Some(runtime.clone());
+        self
+    }
+
+    /// What to do when the enclave launches a thread while none of its TCSs
+    /// are available. Defaults to [`LaunchThreadPolicy::FailFast`].
+    ///
+    /// Threads launched by library enclaves are entered through the library
+    /// entry point with all parameters set to zero, and may keep running
+    /// after the library call that launched them has returned.
+    ///
+    /// [`LaunchThreadPolicy::FailFast`]: usercalls/enum.LaunchThreadPolicy.html#variant.FailFast
+    pub fn launch_thread_policy(&mut self, policy: LaunchThreadPolicy) -> &mut Self {
+        self.launch_thread_policy = policy;
+        self
+    }
+
     /// Whether to panic the runner if any enclave thread panics.
     /// Defaults to `false`.
     /// Note: If multiple enclaves are loaded, and an enclave with this set to
@@ -260,10 +366,218 @@ impl<'a> EnclaveBuilder<'a> {
         self
     }
 
+    /// Forward termination signals received by the runner (`SIGINT` or
+    /// `SIGTERM`, or Ctrl-C on Windows) to the enclave as [`EV_SIGNAL`]
+    /// events, instead of terminating the runner. If the enclave hasn't
+    /// exited `grace_period` after the signal, or if another signal is
+    /// received, the enclave is aborted. The enclave can check whether this
+    /// is enabled with the `capabilities` usercall, which then reports
+    /// [`CAPABILITY_SIGNAL_EVENT`].
+    ///
+    /// This only applies to enclaves run as a [`Command`]. Once enabled, the
+    /// signals won't terminate the runner process anymore. Aborts after the
+    /// grace period, and failures to listen for the signals, are counted in
+    /// the [`EnclaveMetrics`].
+    ///
+    /// [`EnclaveMetrics`]: usercalls/struct.EnclaveMetrics.html
+    /// [`EV_SIGNAL`]: ../fortanix_sgx_abi/constant.EV_SIGNAL.html
+    /// [`CAPABILITY_SIGNAL_EVENT`]: ../fortanix_sgx_abi/constant.CAPABILITY_SIGNAL_EVENT.html
+    /// [`Command`]: struct.Command.html
+    pub fn forward_signals(&mut self, grace_period: Duration) -> &mut Self {
+        self.signal_grace_period = Some(grace_period);
+        self
+    }
+
+    /// Report every usercall made by the enclave to `sink`, similar to
+    /// `strace`. See [`UsercallTraceSink`](usercalls/trait.UsercallTraceSink.html).
+    ///
+    /// Tracing is disabled by default and has no overhead when disabled.
+    pub fn usercall_trace<T: Into<Box<dyn UsercallTraceSink>>>(&mut self, sink: T) -> &mut Self {
+        self.usercall_trace = Some(sink.into());
+        self
+    }
+
+    /// Sets the standard input stream of the enclave. Defaults to the
+    /// standard input of the runner.
+    ///
+    /// When running several enclaves in the same process, the standard
+    /// streams of the runner are shared by all of them.
+    pub fn stdin<T: Into<Stdio>>(&mut self, stdin: T) -> &mut Self {
+        self.stdin = stdin.into();
+        self
+    }
+
+    /// Sets the standard output stream of the enclave. Defaults to the
+    /// standard output of the runner.
+    pub fn stdout<T: Into<Stdio>>(&mut self, stdout: T) -> &mut Self {
+        self.stdout = stdout.into();
+        self
+    }
+
+    /// Sets the standard error stream of the enclave. Defaults to the
+    /// standard error of the runner.
+    pub fn stderr<T: Into<Stdio>>(&mut self, stderr: T) -> &mut Self {
+        self.stderr = stderr.into();
+        self
+    }
+
+    /// Restrict the addresses the enclave can connect to or bind to. By
+    /// default, all addresses are allowed.
+    ///
+    /// The policy applies to `connect_stream` and `bind_stream` alike, and
+    /// is checked before an address is passed to a
+    /// [`UsercallExtension`](usercalls/trait.UsercallExtension.html). The
+    /// exception is `file:`, `file+write:` and `file+append:` addresses, as
+    /// used by [`HostDirectory`](usercalls/struct.HostDirectory.html), which
+    /// are only checked if no extension handles them.
+    pub fn network_policy(&mut self, policy: NetworkPolicy) -> &mut Self {
+        self.network_policy = policy;
+        self
+    }
+
+    /// The ELF file the enclave was converted from. If set, backtraces of
+    /// enclave panics are symbolized using its debug information and printed
+    /// to the standard error of the runner.
+    ///
+    /// The backtrace printed by the enclave (after `stack backtrace:`) is
+    /// used. If the enclave didn't print one, the stack of the panicking
+    /// thread is used instead, which only works for debug enclaves.
+    pub fn enclave_elf<P: Into<PathBuf>>(&mut self, path: P) -> &mut Self {
+        self.enclave_elf = Some(path.into());
+        self
+    }
+
+    /// Write an ELF core file to `path` when the enclave panics, containing
+    /// the enclave memory and the registers of each TCS. The core file can
+    /// be loaded in gdb together with the enclave ELF file.
+    ///
+    /// Only the pages added to the enclave when it was loaded are included.
+    /// The other enclave threads are aborted before the core file is
+    /// written, but threads that don't perform usercalls keep running.
+    ///
+    /// This requires a debug enclave; building the enclave will return an
+    /// error otherwise.
+    pub fn core_dump<P: Into<PathBuf>>(&mut self, path: P) -> &mut Self {
+        self.core_dump = Some(path.into());
+        self
+    }
+
+    /// Serve the gdb remote serial protocol on the TCP address `addr`, to
+    /// debug the enclave with gdb. Enclave threads are shown as threads
+    /// identified by their TCS address. Memory and registers can be
+    /// inspected and breakpoints can be set in enclave code.
+    ///
+    /// If `addr` is just a port (e.g. `"1234"`), the server only listens on
+    /// localhost. Connections aren't authenticated and gdb can write to
+    /// enclave memory, so only listen on other addresses on trusted
+    /// networks.
+    ///
+    /// Running enclave threads can't be interrupted from gdb, and killing
+    /// the program from gdb terminates the runner process.
+    ///
+    /// This requires a debug enclave; building the enclave will return an
+    /// error otherwise. Only one enclave per process can be debugged this
+    /// way.
+    pub fn gdbserver<A: Into<String>>(&mut self, addr: A) -> &mut Self {
+        self.gdbserver = Some(addr.into());
+        self
+    }
+
+    /// Limit the host resources the enclave can use through usercalls. By
+    /// default, there are no limits.
+    ///
+    /// The current usage can be observed with [`Command::resource_monitor`]
+    /// or [`Library::resource_monitor`].
+    ///
+    /// [`Command::resource_monitor`]: struct.Command.html#method.resource_monitor
+    /// [`Library::resource_monitor`]: struct.Library.html#method.resource_monitor
+    pub fn resource_limits(&mut self, limits: ResourceLimits) -> &mut Self {
+        self.resource_limits = limits;
+        self
+    }
+
+    /// Record the results of non-deterministic usercalls to the file at
+    /// `path`, so that the enclave run can later be reproduced using
+    /// [`replay_usercalls`].
+    ///
+    /// This covers data read from streams, the outcome of stream I/O and
+    /// network operations, `insecure_time` values and `wait` results. If
+    /// writing the recording fails, the error is printed and the enclave
+    /// keeps running without recording.
+    ///
+    /// **NOTE:** The recording contains all data received by the enclave,
+    /// including data that may be sensitive.
+    ///
+    /// [`replay_usercalls`]: #method.replay_usercalls
+    pub fn record_usercalls<P: AsRef<Path>>(&mut self, path: P) -> IoResult<&mut Self> {
+        let recorder = UsercallRecorder::create(path)?;
+        self.usercall_session = Some(UsercallSession::Record(recorder));
+        Ok(self)
+    }
+
+    /// Replay the usercall results recorded using [`record_usercalls`] to
+    /// the enclave, instead of accessing any streams, sockets or clocks.
+    ///
+    /// Data the enclave writes to its standard output and error streams is
+    /// still written to the streams set with [`stdout`] and [`stderr`]. If
+    /// the enclave makes a usercall for which no result was recorded, the
+    /// enclave is aborted with [`AbortReason::ReplayDiverged`].
+    ///
+    /// [`record_usercalls`]: #method.record_usercalls
+    /// [`stdout`]: #method.stdout
+    /// [`stderr`]: #method.stderr
+    /// [`AbortReason::ReplayDiverged`]: usercalls/enum.AbortReason.html#variant.ReplayDiverged
+    pub fn replay_usercalls<P: AsRef<Path>>(&mut self, path: P) -> IoResult<&mut Self> {
+        let replayer = UsercallReplayer::open(path)?;
+        self.usercall_session = Some(UsercallSession::Replay(replayer));
+        Ok(self)
+    }
+
+    /// Adds an argument to pass to the enclave's `main` function.
+    ///
+    /// The first argument seen by the enclave is always the path of the
+    /// enclave file (or `enclave` when loading from memory), followed by the
+    /// arguments added using this function and [`args`].
+    ///
+    /// **NOTE:** This is not an appropriate channel for passing secrets or
+    /// security configurations to the enclave.
+    ///
+    /// **NOTE:** This is only applicable to [`Command`] enclaves. Calling
+    /// [`build_library`] after adding arguments will return an error.
+    ///
+    /// [`args`]: #method.args
+    /// [`Command`]: struct.Command.html
+    /// [`build_library`]: #method.build_library
+    pub fn arg<T: AsRef<[u8]>>(&mut self, arg: T) -> &mut Self {
+        let arg = arg.as_ref().to_owned();
+        self.cmd_args.get_or_insert_with(Vec::new).push(arg);
+        self
+    }
+
+    /// Adds multiple arguments to pass to the enclave's `main` function.
+    ///
+    /// See [`arg`](#method.arg) for details.
+    pub fn args<I, S>(&mut self, args: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<[u8]>,
+    {
+        let args = args.into_iter().map(|a| a.as_ref().to_owned());
+        self.cmd_args.get_or_insert_with(Vec::new).extend(args);
+        self
+    }
+
+    fn enclave_name(&self) -> Vec<u8> {
+        match self.enclave {
+            EnclaveSource::Path(path) => path.to_string_lossy().into_owned().into_bytes(),
+            _ => b"enclave".to_vec(),
+        }
+    }
+
     fn load<T: Load>(
         mut self,
         loader: &mut T,
-    ) -> Result<(Vec<ErasedTcs>, *mut c_void, usize, bool), Error> {
+    ) -> Result<(Vec<ErasedTcs>, *mut c_void, usize, EnclaveConfig), Error> {
         let signature = match self.signature {
             Some(sig) => sig,
             None => self
@@ -272,28 +586,87 @@ impl<'a> EnclaveBuilder<'a> {
         };
         let attributes = self.attributes.unwrap_or(signature.attributes);
         let miscselect = self.miscselect.unwrap_or(signature.miscselect);
+        let debug = attributes.flags.contains(AttributesFlags::DEBUG);
+        if !debug && self.core_dump.is_some() {
+            bail!("Core dumps require a debug enclave");
+        }
+        if !debug && self.gdbserver.is_some() {
+            bail!("The gdb server requires a debug enclave");
+        }
+        let ssa_frame_size = match self.core_dump.is_some() || self.gdbserver.is_some() {
+            true => self.enclave.ssa_frame_size().context("While reading SSA frame size")?,
+            false => 1,
+        };
+        let gdbserver = match self.gdbserver.take() {
+            Some(addr) => Some(GdbServer::bind(&addr, ssa_frame_size).context("While starting gdb server")?),
+            None => None,
+        };
+        let core_dump = match self.core_dump.take() {
+            Some(path) => {
+                let name = match self.enclave {
+                    EnclaveSource::Path(path) => path.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+                    _ => "enclave".to_owned(),
+                };
+                let pages = self.enclave.added_pages().context("While reading enclave layout")?;
+                Some(CoreDumper::new(path, name, ssa_frame_size, pages))
+            }
+            None => None,
+        };
         let mapping = loader.load(&mut self.enclave, &signature, attributes, miscselect)?;
-        let forward_panics = self.forward_panics;
+        let config = EnclaveConfig {
+            usercall_ext: self.usercall_ext.take(),
+            clock: self.clock.take(),
+            forward_panics: self.forward_panics,
+            signal_grace_period: self.signal_grace_period,
+            usercall_trace: self.usercall_trace.take(),
+            usercall_session: self.usercall_session.take(),
+            stdin: self.stdin,
+            stdout: self.stdout,
+            stderr: self.stderr,
+            network_policy: self.network_policy,
+            symbolizer: self.enclave_elf.map(Symbolizer::new),
+            core_dump,
+            gdbserver,
+            resources: Arc::new(ResourceCounters::new(self.resource_limits)),
+            metrics: Arc::new(MetricsCounters::default()),
+            runtime: self.runtime,
+            launch_thread_policy: self.launch_thread_policy,
+        };
         if mapping.tcss.is_empty() {
-            unimplemented!()
+            bail!("The enclave has no TCSs");
         }
         Ok((
             mapping.tcss.into_iter().map(ErasedTcs::new).collect(),
             mapping.info.address(),
             mapping.info.size(),
-            forward_panics,
+            config,
         ))
     }
 
     pub fn build<T: Load>(mut self, loader: &mut T) -> Result<Command, Error> {
-        let c = self.usercall_ext.take();
+        let mut args = vec![self.enclave_name()];
+        args.extend(self.cmd_args.take().unwrap_or_default());
+        if let LaunchThreadPolicy::ReserveForLibrary(_) = self.launch_thread_policy {
+            bail!("TCSs can only be reserved for library calls in library enclaves");
+        }
         self.load(loader)
-            .map(|(t, a, s, fp)| Command::internal_new(t, a, s, c, fp))
+            .map(|(t, a, s, c)| Command::internal_new(t, a, s, c, args))
     }
 
-    pub fn build_library<T: Load>(mut self, loader: &mut T) -> Result<Library, Error> {
-        let c = self.usercall_ext.take();
-        self.load(loader)
-            .map(|(t, a, s, fp)| Library::internal_new(t, a, s, c, fp))
+    pub fn build_library<T: Load>(self, loader: &mut T) -> Result<Library, Error> {
+        if self.cmd_args.is_some() {
+            bail!("Command arguments can't be passed to library enclaves");
+        }
+        let (tcss, address, size, config) = self.load(loader)?;
+        if let LaunchThreadPolicy::ReserveForLibrary(reserved) = config.launch_thread_policy {
+            if reserved >= tcss.len() {
+                bail!(
+                    "Can't reserve {} TCSs for library calls, the enclave only has {}, leaving none for launching threads",
+                    reserved,
+                    tcss.len()
+                );
+            }
+        }
+        Ok(Library::internal_new(tcss, address, size, config))
     }
 }
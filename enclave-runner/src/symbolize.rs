@@ -0,0 +1,203 @@
+/* Copyright (c) Fortanix, Inc.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Symbolization of enclave backtraces using the DWARF debug information of
+//! the enclave ELF file.
+
+use std::fmt::Write;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use addr2line::gimli::{self, EndianSlice, RunTimeEndian};
+use addr2line::Context;
+use failure::{Error, ResultExt};
+use object::{Object, ObjectSection};
+
+const STACK_BACKTRACE: &str = "stack backtrace:";
+
+/// Parse a frame of a backtrace printed by the enclave, such as
+/// `  0:     0x4a3f` or `  1: <unknown> (0x4a3f)`, returning the offset.
+fn parse_frame(line: &str) -> Option<u64> {
+    let mut parts = line.trim().splitn(2, ':');
+    parts.next()?.parse::<u32>().ok()?;
+    let last = parts.next()?.split_whitespace().last()?;
+    let last = if last.starts_with('(') && last.ends_with(')') {
+        &last[1..last.len() - 1]
+    } else {
+        last
+    };
+    if !last.starts_with("0x") {
+        return None;
+    }
+    u64::from_str_radix(&last[2..], 16).ok()
+}
+
+/// The offset to look up the source location of frame `i` at. Frames other
+/// than the first are return addresses, which point to the instruction after
+/// the call. That instruction might belong to the next line or even the next
+/// function, for example after a call that doesn't return, so the lookup
+/// uses the address before it, which is part of the call instruction.
+fn lookup_offset(i: usize, offset: u64) -> u64 {
+    if i == 0 {
+        offset
+    } else {
+        offset.saturating_sub(1)
+    }
+}
+
+/// Collects the last backtrace printed by the enclave.
+#[derive(Debug, Default)]
+struct BacktraceParser {
+    /// The incomplete last line of output
+    line: Vec<u8>,
+    collecting: bool,
+    offsets: Vec<u64>,
+}
+
+impl BacktraceParser {
+    fn push(&mut self, data: &[u8]) {
+        for &byte in data {
+            if byte != b'\n' {
+                self.line.push(byte);
+                continue;
+            }
+            let line = String::from_utf8_lossy(&self.line).into_owned();
+            self.line.clear();
+            if line.trim() == STACK_BACKTRACE {
+                self.collecting = true;
+                self.offsets.clear();
+            } else if self.collecting {
+                match parse_frame(&line) {
+                    Some(offset) => self.offsets.push(offset),
+                    None => self.collecting = false,
+                }
+            }
+        }
+    }
+}
+
+/// Resolves enclave backtraces to functions and source locations.
+///
+/// The ELF file is only read once a backtrace needs to be symbolized.
+#[derive(Debug)]
+pub(crate) struct Symbolizer {
+    elf_path: PathBuf,
+    parser: Mutex<BacktraceParser>,
+}
+
+impl Symbolizer {
+    pub(crate) fn new(elf_path: PathBuf) -> Self {
+        Symbolizer {
+            elf_path,
+            parser: Default::default(),
+        }
+    }
+
+    /// Scan output of the enclave for backtraces.
+    pub(crate) fn enclave_output(&self, data: &[u8]) {
+        self.parser.lock().unwrap().push(data)
+    }
+
+    /// Print the last backtrace printed by the enclave, symbolized. If the
+    /// enclave didn't print a backtrace, `fallback` is used, which contains
+    /// offsets relative to the enclave base.
+    pub(crate) fn print_backtrace(&self, fallback: Option<&[u64]>) {
+        let offsets = {
+            let mut parser = self.parser.lock().unwrap();
+            parser.collecting = false;
+            std::mem::replace(&mut parser.offsets, vec![])
+        };
+        let offsets = match (offsets.is_empty(), fallback) {
+            (false, _) => &offsets[..],
+            (true, Some(fallback)) => fallback,
+            (true, None) => return,
+        };
+        match self.symbolize(offsets) {
+            Ok(backtrace) => eprint!("Symbolized enclave backtrace:\n{}", backtrace),
+            Err(e) => eprintln!("Unable to symbolize enclave backtrace: {}", e),
+        }
+    }
+
+    fn symbolize(&self, offsets: &[u64]) -> Result<String, Error> {
+        let data = fs::read(&self.elf_path).context("While reading enclave ELF file")?;
+        let file = object::File::parse(&*data).context("While parsing enclave ELF file")?;
+        let endian = if file.is_little_endian() {
+            RunTimeEndian::Little
+        } else {
+            RunTimeEndian::Big
+        };
+        let dwarf = gimli::Dwarf::load(
+            |id| -> Result<_, gimli::Error> {
+                let data = file
+                    .section_by_name(id.name())
+                    .and_then(|section| section.data().ok())
+                    .unwrap_or(&[]);
+                Ok(EndianSlice::new(data, endian))
+            },
+            // There is no supplementary object file
+            |_| Ok(EndianSlice::new(&[], endian)),
+        )?;
+        let context = Context::from_dwarf(dwarf)?;
+
+        let mut out = String::new();
+        for (i, &offset) in offsets.iter().enumerate() {
+            let mut frames = context.find_frames(lookup_offset(i, offset))?;
+            let mut first = true;
+            while let Some(frame) = frames.next()? {
+                let name = match frame.function {
+                    Some(ref function) => function.demangle()?.into_owned(),
+                    None => "<unknown>".to_owned(),
+                };
+                if first {
+                    writeln!(out, "{:4}: {:#10x} - {}", i, offset, name)?;
+                    first = false;
+                } else {
+                    writeln!(out, "{:19}{}", "", name)?;
+                }
+                if let Some(location) = frame.location {
+                    write!(out, "{:23}at {}", "", location.file.unwrap_or("<unknown>"))?;
+                    if let Some(line) = location.line {
+                        write!(out, ":{}", line)?;
+                        if let Some(column) = location.column {
+                            write!(out, ":{}", column)?;
+                        }
+                    }
+                    writeln!(out)?;
+                }
+            }
+            if first {
+                writeln!(out, "{:4}: {:#10x} - <unknown>", i, offset)?;
+            }
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_backtrace() {
+        assert_eq!(parse_frame("   0:     0x4a3f"), Some(0x4a3f));
+        assert_eq!(parse_frame("  12: <unknown> (0x10)"), Some(0x10));
+        assert_eq!(parse_frame("note: run with `RUST_BACKTRACE=full`"), None);
+
+        let mut parser = BacktraceParser::default();
+        parser.push(b"thread panicked\nstack back");
+        parser.push(b"trace:\n   0:     0x1\n   1:   ");
+        parser.push(b"  0x2\nnote: done\n   2:     0x3\n");
+        assert_eq!(parser.offsets, [1, 2]);
+        assert!(!parser.collecting);
+    }
+
+    #[test]
+    fn return_addresses() {
+        assert_eq!(lookup_offset(0, 0x4a3f), 0x4a3f);
+        assert_eq!(lookup_offset(1, 0x4a3f), 0x4a3e);
+        assert_eq!(lookup_offset(1, 0), 0);
+    }
+}
@@ -29,6 +29,20 @@ type EnclaveAbort = super::EnclaveAbort<bool>;
 pub(crate) type UsercallResult<T> = ::std::result::Result<T, EnclaveAbort>;
 pub(crate) type DispatchResult = UsercallResult<(Register, Register)>;
 
+/// Description of a usercall, as specified in the ABI.
+#[derive(Copy, Clone, Debug)]
+pub(super) struct UsercallInfo {
+    pub name: &'static str,
+    /// The name and type of each parameter
+    pub params: &'static [(&'static str, &'static str)],
+    /// The return type, or the empty string if the usercall returns nothing
+    pub returns: &'static str,
+    /// Whether the first return value is a [`Result`], i.e. an error code
+    ///
+    /// [`Result`]: ../../fortanix_sgx_abi/type.Result.html
+    pub returns_result: bool,
+}
+
 trait ReturnValue {
     fn into_registers(self) -> DispatchResult;
 }
@@ -45,13 +59,26 @@ macro_rules! define_usercalls {
 
         pub(super) trait Usercalls <'future>: Sized {
             $(fn $f (self, $($n: $t),*) -> dispatch_return_type!($(-> $r )* 'future);)*
-            fn other(self, n: u64, a1: u64, a2: u64, a3: u64, a4: u64) -> (Self, DispatchResult) {
-                (self, Err($crate::usercalls::EnclaveAbort::InvalidUsercall(n)))
-            }
+            fn other(self, n: u64, a1: u64, a2: u64, a3: u64, a4: u64) -> std::pin::Pin<Box<dyn Future<Output = (Self, DispatchResult)> + 'future>>;
 
             fn is_exiting(&self) -> bool;
         }
 
+        /// Look up the name, parameters and return type of usercall `n`.
+        pub(super) fn usercall_info(n: u64) -> Option<UsercallInfo> {
+            $(
+                if n == UsercallList::$f as Register {
+                    return Some(UsercallInfo {
+                        name: stringify!($f),
+                        params: &[$((stringify!($n), stringify!($t))),*],
+                        returns: stringify!($($r)*),
+                        returns_result: returns_result!($($r)*),
+                    });
+                }
+            )*
+            None
+        }
+
         #[allow(unused_variables)]
         pub(super) async fn dispatch<'future,  H: Usercalls<'future>> (mut handler: H, n: u64, a1: u64, a2: u64, a3: u64, a4: u64) -> (H, DispatchResult) {
             // using if/else because you can't match an integer against enum variants
@@ -65,7 +92,7 @@ macro_rules! define_usercalls {
                 } else
             )*
             {
-                handler.other(n, a1, a2, a3, a4)
+                handler.other(n, a1, a2, a3, a4).await
             };
             if ret.is_ok() && handler.is_exiting() {
                 (handler, Err(super::EnclaveAbort::Secondary))
@@ -171,6 +198,12 @@ impl<T: RegisterArgument, U: RegisterArgument> ReturnValue for UsercallResult<(T
     }
 }
 
+macro_rules! returns_result {
+    (Result) => (true);
+    ((Result, $t:ty)) => (true);
+    ($($r:tt)*) => (false);
+}
+
 macro_rules! dispatch_return_type {
     (-> ! $l:lifetime) => { std::pin::Pin<Box<dyn Future<Output = (Self, EnclaveAbort)> + $l>> };
     (-> $r:tt $l:lifetime) => {
@@ -309,3 +342,22 @@ macro_rules! enclave_usercalls_internal_define_usercalls {
 }
 
 invoke_with_usercalls!(define_usercalls);
+
+#[cfg(test)]
+mod tests {
+    use fortanix_sgx_abi::description::USERCALLS;
+
+    use super::*;
+
+    #[test]
+    fn matches_description() {
+        for usercall in USERCALLS {
+            let info = usercall_info(usercall.number).unwrap();
+            assert_eq!(info.name, usercall.name);
+            let params = usercall.params.iter().map(|p| (p.name, p.ty)).collect::<Vec<_>>();
+            assert_eq!(info.params, &params[..], "parameters of {}", usercall.name);
+            assert_eq!(info.returns_result, usercall.fallible, "return type of {}", usercall.name);
+        }
+        assert!(usercall_info(USERCALLS.len() as u64 + 1).is_none());
+    }
+}
@@ -0,0 +1,207 @@
+/* Copyright (c) Fortanix, Inc.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Time sources for the `insecure_time` usercall and `wait` timeouts.
+
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use futures::future;
+
+/// The source of time seen by an enclave. It is used for the
+/// [`insecure_time`] usercall, and to measure timeouts of the [`wait`]
+/// usercall.
+///
+/// A clock can be registered while [building] the enclave. This can be used
+/// to test time-dependent enclave code, such as certificate expiry, timeouts
+/// and timers, deterministically.
+///
+/// [`insecure_time`]: ../../fortanix_sgx_abi/struct.Usercalls.html#method.insecure_time
+/// [`wait`]: ../../fortanix_sgx_abi/struct.Usercalls.html#method.wait
+/// [building]: ../struct.EnclaveBuilder.html#method.clock
+pub trait Clock: 'static + Send + Sync + Debug {
+    /// The current time.
+    fn now(&self) -> SystemTime;
+
+    /// Returns a future that resolves once `duration` has passed according
+    /// to this clock.
+    fn delay(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()>>>;
+}
+
+impl<C: Clock> From<C> for Box<dyn Clock> {
+    fn from(clock: C) -> Box<dyn Clock> {
+        Box::new(clock)
+    }
+}
+
+/// The system clock. This is the default.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn delay(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()>>> {
+        Box::pin(tokio::time::delay_for(duration))
+    }
+}
+
+/// A clock that only advances when told to, or when the enclave waits for a
+/// timeout. Timeouts expire immediately, advancing the clock by the length
+/// of the timeout.
+///
+/// Clones of the clock share the same time, so a clone can be used to change
+/// the time while the enclave runs.
+#[derive(Clone, Debug)]
+pub struct FixedClock(Arc<Mutex<SystemTime>>);
+
+impl FixedClock {
+    pub fn new(time: SystemTime) -> Self {
+        FixedClock(Arc::new(Mutex::new(time)))
+    }
+
+    pub fn set(&self, time: SystemTime) {
+        *self.0.lock().unwrap() = time;
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.0.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for FixedClock {
+    fn now(&self) -> SystemTime {
+        *self.0.lock().unwrap()
+    }
+
+    fn delay(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()>>> {
+        self.advance(duration);
+        Box::pin(future::ready(()))
+    }
+}
+
+/// A clock that runs at a multiple of the speed of the system clock,
+/// starting at a given time.
+#[derive(Clone, Debug)]
+pub struct AcceleratedClock {
+    start: SystemTime,
+    started: Instant,
+    factor: f64,
+}
+
+impl AcceleratedClock {
+    /// A clock starting at `start` and running `factor` times as fast as
+    /// the system clock. Timeouts are shortened accordingly.
+    ///
+    /// # Panics
+    /// Panics if `factor` isn't positive.
+    pub fn new(start: SystemTime, factor: f64) -> Self {
+        assert!(factor > 0.0, "clock speed must be positive");
+        AcceleratedClock {
+            start,
+            started: Instant::now(),
+            factor,
+        }
+    }
+
+    fn at(&self, now: Instant) -> SystemTime {
+        self.start + (now - self.started).mul_f64(self.factor)
+    }
+}
+
+impl Clock for AcceleratedClock {
+    fn now(&self) -> SystemTime {
+        self.at(Instant::now())
+    }
+
+    fn delay(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()>>> {
+        Box::pin(tokio::time::delay_for(duration.div_f64(self.factor)))
+    }
+}
+
+/// A clock that runs at the speed of the system clock, offset to start at a
+/// given time.
+#[derive(Clone, Debug)]
+pub struct OffsetClock(AcceleratedClock);
+
+impl OffsetClock {
+    pub fn new(start: SystemTime) -> Self {
+        OffsetClock(AcceleratedClock::new(start, 1.0))
+    }
+}
+
+impl Clock for OffsetClock {
+    fn now(&self) -> SystemTime {
+        self.0.now()
+    }
+
+    fn delay(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()>>> {
+        self.0.delay(duration)
+    }
+}
+
+/// A clock that returns a recorded sequence of times, such as the times
+/// seen by a previous run of the enclave. Once all times have been
+/// returned, the last one is repeated. Timeouts expire immediately.
+#[derive(Clone, Debug)]
+pub struct RecordedClock(Arc<Mutex<VecDeque<SystemTime>>>);
+
+impl RecordedClock {
+    /// # Panics
+    /// Panics if `times` is empty.
+    pub fn new<I: IntoIterator<Item = SystemTime>>(times: I) -> Self {
+        let times = times.into_iter().collect::<VecDeque<_>>();
+        assert!(!times.is_empty(), "a recorded clock needs at least one time");
+        RecordedClock(Arc::new(Mutex::new(times)))
+    }
+}
+
+impl Clock for RecordedClock {
+    fn now(&self) -> SystemTime {
+        let mut times = self.0.lock().unwrap();
+        if times.len() > 1 {
+            times.pop_front().unwrap()
+        } else {
+            times[0]
+        }
+    }
+
+    fn delay(&self, _duration: Duration) -> Pin<Box<dyn Future<Output = ()>>> {
+        Box::pin(future::ready(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn clocks() {
+        let start = UNIX_EPOCH + Duration::from_secs(1_000_000);
+
+        let fixed = FixedClock::new(start);
+        let clone = fixed.clone();
+        clone.advance(Duration::from_secs(5));
+        assert_eq!(fixed.now(), start + Duration::from_secs(5));
+        futures::executor::block_on(fixed.delay(Duration::from_secs(10)));
+        assert_eq!(clone.now(), start + Duration::from_secs(15));
+
+        let accelerated = AcceleratedClock::new(start, 60.0);
+        let now = accelerated.started + Duration::from_secs(2);
+        assert_eq!(accelerated.at(now), start + Duration::from_secs(120));
+
+        let recorded = RecordedClock::new(vec![start, start + Duration::from_secs(1)]);
+        assert_eq!(recorded.now(), start);
+        assert_eq!(recorded.now(), start + Duration::from_secs(1));
+        assert_eq!(recorded.now(), start + Duration::from_secs(1));
+    }
+}
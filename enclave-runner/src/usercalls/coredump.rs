@@ -0,0 +1,418 @@
+/* Copyright (c) Fortanix, Inc.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! ELF core dumps of debug enclaves.
+//!
+//! The core file contains the enclave memory that could be read through the
+//! debug interface, and a thread for each TCS. Load it in gdb together with
+//! the enclave ELF file, relocated to the enclave base:
+//!
+//! ```text
+//! (gdb) core-file enclave.core
+//! (gdb) add-symbol-file enclave.elf -o <base>
+//! ```
+//!
+//! The TCS address of each thread (numbered from 1 as an LWP) is recorded in
+//! an `SGX` note, which can be shown using `readelf -n`.
+
+use std::fs::File;
+use std::io::{BufWriter, Result as IoResult, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use super::debug::{DebugMemory, Registers, ThreadState};
+
+const PAGE_SIZE: usize = 0x1000;
+
+const ELF_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const ET_CORE: u16 = 4;
+const EM_X86_64: u16 = 62;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const NT_PRSTATUS: u32 = 1;
+const NT_PRPSINFO: u32 = 3;
+const NT_AUXV: u32 = 6;
+/// The type of the `SGX` note containing the TCS of a thread.
+const NT_SGX_TCS: u32 = 1;
+const PRSTATUS_SIZE: usize = 336;
+const PRSTATUS_PID: usize = 32;
+const PRSTATUS_REG: usize = 112;
+const PRPSINFO_SIZE: usize = 136;
+const PRPSINFO_FNAME: usize = 40;
+
+const AT_NULL: u64 = 0;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+const SIGABRT: u16 = 6;
+
+impl Registers {
+    /// The registers in the order of `struct user_regs_struct`.
+    fn user_regs(&self) -> [u64; 27] {
+        const CS: u64 = 0x33;
+        const SS: u64 = 0x2b;
+        [
+            self.r15, self.r14, self.r13, self.r12, self.rbp, self.rbx, self.r11, self.r10,
+            self.r9, self.r8, self.rax, self.rcx, self.rdx, self.rsi, self.rdi, !0, self.rip, CS,
+            self.rflags, self.rsp, SS, self.fs_base, self.gs_base, 0, 0, 0, 0,
+        ]
+    }
+}
+
+#[derive(Clone, Debug)]
+struct CoreThread {
+    tcs: u64,
+    registers: Registers,
+}
+
+/// The contents of a core file, except for the memory itself.
+#[derive(Debug)]
+struct CoreLayout {
+    name: String,
+    entry: u64,
+    threads: Vec<CoreThread>,
+    /// The address ranges of memory that is included
+    segments: Vec<Range<usize>>,
+}
+
+fn write_note(out: &mut Vec<u8>, name: &[u8], ty: u32, desc: &[u8]) {
+    fn pad(out: &mut Vec<u8>) {
+        while out.len() % 4 != 0 {
+            out.push(0);
+        }
+    }
+
+    out.extend_from_slice(&(name.len() as u32 + 1).to_le_bytes());
+    out.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+    out.extend_from_slice(&ty.to_le_bytes());
+    out.extend_from_slice(name);
+    out.push(0);
+    pad(out);
+    out.extend_from_slice(desc);
+    pad(out);
+}
+
+impl CoreLayout {
+    fn notes(&self) -> Vec<u8> {
+        let mut notes = vec![];
+
+        let mut prpsinfo = [0u8; PRPSINFO_SIZE];
+        let fname = self.name.as_bytes();
+        let len = fname.len().min(15);
+        prpsinfo[PRPSINFO_FNAME..][..len].copy_from_slice(&fname[..len]);
+        write_note(&mut notes, b"CORE", NT_PRPSINFO, &prpsinfo);
+
+        for (i, thread) in self.threads.iter().enumerate() {
+            let mut prstatus = [0u8; PRSTATUS_SIZE];
+            if i == 0 {
+                // `si_signo` and `pr_cursig`
+                prstatus[0..4].copy_from_slice(&(SIGABRT as u32).to_le_bytes());
+                prstatus[12..14].copy_from_slice(&SIGABRT.to_le_bytes());
+            }
+            prstatus[PRSTATUS_PID..][..4].copy_from_slice(&(i as u32 + 1).to_le_bytes());
+            for (j, reg) in thread.registers.user_regs().iter().enumerate() {
+                prstatus[PRSTATUS_REG + j * 8..][..8].copy_from_slice(&reg.to_le_bytes());
+            }
+            write_note(&mut notes, b"CORE", NT_PRSTATUS, &prstatus);
+
+            let mut tcs = thread.tcs.to_le_bytes().to_vec();
+            tcs.extend_from_slice(format!("TCS {:#x}", thread.tcs).as_bytes());
+            write_note(&mut notes, b"SGX", NT_SGX_TCS, &tcs);
+        }
+
+        let mut auxv = vec![];
+        for &(key, value) in &[(AT_PAGESZ, PAGE_SIZE as u64), (AT_ENTRY, self.entry), (AT_NULL, 0)] {
+            auxv.extend_from_slice(&key.to_le_bytes());
+            auxv.extend_from_slice(&value.to_le_bytes());
+        }
+        write_note(&mut notes, b"CORE", NT_AUXV, &auxv);
+
+        notes
+    }
+
+    /// Write the core file, reading memory using `read`. Pages that can't be
+    /// read anymore are filled with zeros.
+    fn write<W, R>(&self, mut out: W, mut read: R) -> IoResult<()>
+    where
+        W: Write,
+        R: FnMut(usize, &mut [u8]) -> IoResult<()>,
+    {
+        let notes = self.notes();
+        let phnum = 1 + self.segments.len();
+        let headers_end = ELF_HEADER_SIZE + phnum * PROGRAM_HEADER_SIZE;
+        // Segments are page-aligned in the file
+        let data_start = (headers_end + notes.len() + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+
+        let mut header = Vec::with_capacity(data_start);
+        header.extend_from_slice(b"\x7fELF");
+        // 64-bit, little endian, current version, System V ABI
+        header.extend_from_slice(&[2, 1, 1, 0]);
+        header.extend_from_slice(&[0; 8]);
+        header.extend_from_slice(&ET_CORE.to_le_bytes());
+        header.extend_from_slice(&EM_X86_64.to_le_bytes());
+        header.extend_from_slice(&1u32.to_le_bytes());
+        header.extend_from_slice(&0u64.to_le_bytes()); // e_entry
+        header.extend_from_slice(&(ELF_HEADER_SIZE as u64).to_le_bytes()); // e_phoff
+        header.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+        header.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+        header.extend_from_slice(&(ELF_HEADER_SIZE as u16).to_le_bytes());
+        header.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+        header.extend_from_slice(&(phnum as u16).to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes()); // e_shentsize
+        header.extend_from_slice(&0u16.to_le_bytes()); // e_shnum
+        header.extend_from_slice(&0u16.to_le_bytes()); // e_shstrndx
+
+        let mut program_header = |ty: u32, flags: u32, offset: usize, vaddr: usize, size: usize, align: usize| {
+            header.extend_from_slice(&ty.to_le_bytes());
+            header.extend_from_slice(&flags.to_le_bytes());
+            header.extend_from_slice(&(offset as u64).to_le_bytes());
+            header.extend_from_slice(&(vaddr as u64).to_le_bytes());
+            header.extend_from_slice(&0u64.to_le_bytes()); // p_paddr
+            header.extend_from_slice(&(size as u64).to_le_bytes()); // p_filesz
+            header.extend_from_slice(&(size as u64).to_le_bytes()); // p_memsz
+            header.extend_from_slice(&(align as u64).to_le_bytes());
+        };
+        program_header(PT_NOTE, 0, headers_end, 0, notes.len(), 4);
+        let mut offset = data_start;
+        for segment in &self.segments {
+            let size = segment.end - segment.start;
+            program_header(PT_LOAD, PF_R | PF_W | PF_X, offset, segment.start, size, PAGE_SIZE);
+            offset += size;
+        }
+        header.extend_from_slice(&notes);
+        header.resize(data_start, 0);
+        out.write_all(&header)?;
+
+        let mut page = [0u8; PAGE_SIZE];
+        for segment in &self.segments {
+            for address in segment.clone().step_by(PAGE_SIZE) {
+                if read(address, &mut page).is_err() {
+                    page = [0; PAGE_SIZE];
+                }
+                out.write_all(&page)?;
+            }
+        }
+        out.flush()
+    }
+}
+
+/// Group the pages starting at the addresses in `pages`, in ascending order,
+/// into contiguous ranges.
+fn page_ranges<I: IntoIterator<Item = usize>>(pages: I) -> Vec<Range<usize>> {
+    let mut ranges: Vec<Range<usize>> = vec![];
+    for page in pages {
+        match ranges.last_mut() {
+            Some(last) if last.end == page => last.end += PAGE_SIZE,
+            _ => ranges.push(page..page + PAGE_SIZE),
+        }
+    }
+    ranges
+}
+
+/// Group the pages in `range` for which `readable` returns true into
+/// contiguous ranges.
+fn readable_ranges<F: FnMut(usize) -> bool>(range: &Range<usize>, mut readable: F) -> Vec<Range<usize>> {
+    page_ranges(range.clone().step_by(PAGE_SIZE).filter(|&page| readable(page)))
+}
+
+/// Writes a core file of the enclave when it panics, at most once.
+#[derive(Debug)]
+pub(crate) struct CoreDumper {
+    path: PathBuf,
+    name: String,
+    ssa_frame_size: u32,
+    /// The pages added to the enclave when it was loaded, as offsets from
+    /// the enclave base. If unknown, every page of the enclave that can be
+    /// read is included.
+    regions: Option<Vec<Range<usize>>>,
+    written: AtomicBool,
+}
+
+impl CoreDumper {
+    /// `pages` are the offsets of the pages that were added to the enclave,
+    /// if known.
+    pub(crate) fn new(path: PathBuf, name: String, ssa_frame_size: u32, pages: Option<Vec<usize>>) -> Self {
+        let regions = pages.map(|mut pages| {
+            pages.sort();
+            page_ranges(pages)
+        });
+        CoreDumper {
+            path,
+            name,
+            ssa_frame_size,
+            regions,
+            written: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Write the core file, unless it was written already. `current` is the
+    /// TCS of the thread that caused the dump, if known, which will be the
+    /// first thread in the dump.
+    ///
+    /// Returns `Ok(false)` if the core file was written already.
+    pub(crate) fn write(
+        &self,
+        enclave_range: &Range<usize>,
+        current: Option<usize>,
+        tcss: &[usize],
+    ) -> IoResult<bool> {
+        if self.written.swap(true, Ordering::SeqCst) {
+            return Ok(false);
+        }
+        let mem = DebugMemory::open()?;
+        let state = ThreadState {
+            enclave_range: enclave_range.clone(),
+            ssa_frame_size: self.ssa_frame_size,
+        };
+
+        let mut tcss = tcss.to_vec();
+        tcss.sort();
+        if let Some(current) = current {
+            tcss.retain(|&tcs| tcs != current);
+            tcss.insert(0, current);
+        }
+        let threads = tcss
+            .into_iter()
+            .map(|tcs| {
+                Ok(CoreThread {
+                    tcs: tcs as u64,
+                    registers: state.registers(&mem, tcs)?,
+                })
+            })
+            .collect::<IoResult<Vec<_>>>()?;
+        let entry = match threads.first() {
+            Some(thread) => state.entry(&mem, thread.tcs as usize)?,
+            None => 0,
+        };
+
+        let segments = match self.regions {
+            Some(ref regions) => regions
+                .iter()
+                .map(|region| enclave_range.start + region.start..enclave_range.start + region.end)
+                .filter(|region| region.end <= enclave_range.end)
+                .collect(),
+            None => readable_ranges(enclave_range, |page| mem.read_u64(page).is_ok()),
+        };
+        let layout = CoreLayout {
+            name: self.name.clone(),
+            entry,
+            threads,
+            segments,
+        };
+        let file = BufWriter::new(File::create(&self.path)?);
+        layout.write(file, |address, buf| mem.read(address, buf))?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object::elf::{FileHeader64, PT_LOAD as OBJ_PT_LOAD, PT_NOTE as OBJ_PT_NOTE};
+    use object::read::elf::{FileHeader, ProgramHeader};
+    use object::{Bytes, LittleEndian};
+
+    #[test]
+    fn ranges() {
+        let range = 0x10000..0x18000;
+        let ranges = readable_ranges(&range, |page| page != 0x12000 && page < 0x16000);
+        assert_eq!(ranges, [0x10000..0x12000, 0x13000..0x16000]);
+
+        let ranges = page_ranges(vec![0x0, 0x1000, 0x3000, 0x5000, 0x6000]);
+        assert_eq!(ranges, [0x0..0x2000, 0x3000..0x4000, 0x5000..0x7000]);
+    }
+
+    #[test]
+    fn core_file() {
+        let layout = CoreLayout {
+            name: "enclave".to_owned(),
+            entry: 0x10100,
+            threads: vec![
+                CoreThread {
+                    tcs: 0x11000,
+                    registers: Registers {
+                        rip: 0x10123,
+                        rsp: 0x14ff0,
+                        ..Default::default()
+                    },
+                },
+                CoreThread {
+                    tcs: 0x12000,
+                    registers: Registers::default(),
+                },
+            ],
+            segments: vec![0x10000..0x12000, 0x13000..0x14000],
+        };
+        let mut core = vec![];
+        layout
+            .write(&mut core, |address, buf| {
+                for b in buf.iter_mut() {
+                    *b = (address >> 12) as u8;
+                }
+                Ok(())
+            })
+            .unwrap();
+
+        let endian = LittleEndian;
+        let header = FileHeader64::<LittleEndian>::parse(Bytes(&core)).unwrap();
+        assert_eq!(header.e_type.get(endian), ET_CORE);
+        let segments = header.program_headers(endian, Bytes(&core)).unwrap();
+        assert_eq!(segments.len(), 3);
+        assert_eq!(segments[0].p_type(endian), OBJ_PT_NOTE);
+        let loads: Vec<_> = segments[1..]
+            .iter()
+            .map(|segment| {
+                assert_eq!(segment.p_type(endian), OBJ_PT_LOAD);
+                (segment.p_vaddr(endian), segment.data(endian, Bytes(&core)).unwrap().0)
+            })
+            .collect();
+        assert_eq!(loads[0].0, 0x10000);
+        assert_eq!(loads[0].1.len(), 0x2000);
+        assert_eq!(loads[0].1[0x1000], 0x11);
+        assert_eq!(loads[1].0, 0x13000);
+        assert!(loads[1].1.iter().all(|&b| b == 0x13));
+
+        let mut notes = segments[0].data(endian, Bytes(&core)).unwrap().0;
+        let mut prstatus = vec![];
+        let mut tcss = vec![];
+        let word = |data: &[u8], i: usize| {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(&data[i * 4..][..4]);
+            u32::from_le_bytes(bytes) as usize
+        };
+        while !notes.is_empty() {
+            let (namesz, descsz, n_type) = (word(notes, 0), word(notes, 1), word(notes, 2) as u32);
+            let name = &notes[12..][..namesz];
+            let desc_start = 12 + ((namesz + 3) & !3);
+            let desc = &notes[desc_start..][..descsz];
+            match (name, n_type) {
+                (b"CORE\0", NT_PRSTATUS) => prstatus.push(desc.to_vec()),
+                (b"SGX\0", NT_SGX_TCS) => tcss.push(String::from_utf8_lossy(&desc[8..]).into_owned()),
+                _ => {}
+            }
+            notes = &notes[desc_start + ((descsz + 3) & !3)..];
+        }
+        assert_eq!(prstatus.len(), 2);
+        let reg = |desc: &[u8], n: usize| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&desc[PRSTATUS_REG + n * 8..][..8]);
+            u64::from_le_bytes(bytes)
+        };
+        assert_eq!(reg(&prstatus[0], 16), 0x10123);
+        assert_eq!(reg(&prstatus[0], 19), 0x14ff0);
+        assert_eq!(prstatus[1][PRSTATUS_PID], 2);
+        assert_eq!(tcss, ["TCS 0x11000", "TCS 0x12000"]);
+    }
+}
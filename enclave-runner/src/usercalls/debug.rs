@@ -0,0 +1,268 @@
+/* Copyright (c) Fortanix, Inc.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Access to the memory and thread state of debug enclaves.
+
+use std::io::{ErrorKind as IoErrorKind, Result as IoResult};
+use std::ops::Range;
+
+/// Reads enclave memory through the debug interface of the SGX driver. This
+/// only works for debug enclaves.
+#[cfg(unix)]
+pub(crate) struct DebugMemory(std::fs::File);
+
+#[cfg(unix)]
+impl DebugMemory {
+    pub(crate) fn open() -> IoResult<Self> {
+        std::fs::File::open("/proc/self/mem").map(DebugMemory)
+    }
+
+    /// Like `open`, but also allows writing enclave memory.
+    pub(crate) fn open_writable() -> IoResult<Self> {
+        std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/proc/self/mem")
+            .map(DebugMemory)
+    }
+
+    pub(crate) fn read(&self, address: usize, buf: &mut [u8]) -> IoResult<()> {
+        use std::os::unix::fs::FileExt;
+
+        self.0.read_exact_at(buf, address as u64)
+    }
+
+    pub(crate) fn write(&self, address: usize, data: &[u8]) -> IoResult<()> {
+        use std::os::unix::fs::FileExt;
+
+        self.0.write_all_at(data, address as u64)
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) struct DebugMemory(());
+
+#[cfg(not(unix))]
+impl DebugMemory {
+    pub(crate) fn open() -> IoResult<Self> {
+        Err(IoErrorKind::Other.into())
+    }
+
+    pub(crate) fn open_writable() -> IoResult<Self> {
+        Err(IoErrorKind::Other.into())
+    }
+
+    pub(crate) fn read(&self, _address: usize, _buf: &mut [u8]) -> IoResult<()> {
+        Err(IoErrorKind::Other.into())
+    }
+
+    pub(crate) fn write(&self, _address: usize, _data: &[u8]) -> IoResult<()> {
+        Err(IoErrorKind::Other.into())
+    }
+}
+
+impl DebugMemory {
+    pub(crate) fn read_u64(&self, address: usize) -> IoResult<u64> {
+        let mut buf = [0; 8];
+        self.read(address, &mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+}
+
+/// Offsets of fields in the TCS.
+pub(super) const TCS_OSSA: usize = 16;
+pub(super) const TCS_CSSA: usize = 24;
+pub(super) const TCS_OENTRY: usize = 32;
+pub(super) const TCS_OFSBASGX: usize = 48;
+pub(super) const TCS_OGSBASGX: usize = 56;
+/// Offset of the enclave stack pointer saved by a usercall in the TCS-local
+/// storage of the Rust standard library.
+const TCSLS_LAST_RSP: usize = 0x10;
+
+const PAGE_SIZE: usize = 0x1000;
+/// Size of the GPRSGX region at the end of an SSA frame.
+pub(super) const GPRSGX_SIZE: usize = 184;
+
+/// The registers saved by the Rust standard library when an enclave thread
+/// performs a usercall.
+#[derive(Clone, Debug, Default)]
+pub(super) struct UsercallFrame {
+    /// The stack pointer after returning from the usercall
+    pub rsp: u64,
+    pub rbx: u64,
+    pub rbp: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    /// The return address of the usercall
+    pub rip: u64,
+}
+
+impl UsercallFrame {
+    /// Read the registers saved by the last usercall of `tcs`.
+    pub(super) fn read(mem: &DebugMemory, enclave_range: &Range<usize>, tcs: usize) -> IoResult<Self> {
+        let tls = enclave_range.start + mem.read_u64(tcs + TCS_OGSBASGX)? as usize;
+        let rsp = mem.read_u64(tls + TCSLS_LAST_RSP)? as usize;
+        if !(enclave_range.start <= rsp && rsp < enclave_range.end) {
+            return Err(IoErrorKind::InvalidData.into());
+        }
+        // The MXCSR and FPU control word come first, then the pushed
+        // registers and the return address
+        let mut saved = [0u64; 8];
+        for (i, value) in saved.iter_mut().enumerate() {
+            *value = mem.read_u64(rsp + i * 8)?;
+        }
+        Ok(UsercallFrame {
+            rsp: (rsp + saved.len() * 8) as u64,
+            rbx: saved[1],
+            rbp: saved[2],
+            r12: saved[3],
+            r13: saved[4],
+            r14: saved[5],
+            r15: saved[6],
+            rip: saved[7],
+        })
+    }
+}
+
+/// The general-purpose registers of an enclave thread.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(super) struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub rsp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub fs_base: u64,
+    pub gs_base: u64,
+}
+
+impl Registers {
+    /// Registers from the GPRSGX region of an SSA frame.
+    fn from_gprsgx(gpr: &[u64; GPRSGX_SIZE / 8]) -> Self {
+        Registers {
+            rax: gpr[0],
+            rcx: gpr[1],
+            rdx: gpr[2],
+            rbx: gpr[3],
+            rsp: gpr[4],
+            rbp: gpr[5],
+            rsi: gpr[6],
+            rdi: gpr[7],
+            r8: gpr[8],
+            r9: gpr[9],
+            r10: gpr[10],
+            r11: gpr[11],
+            r12: gpr[12],
+            r13: gpr[13],
+            r14: gpr[14],
+            r15: gpr[15],
+            rflags: gpr[16],
+            rip: gpr[17],
+            // URSP, URBP and EXITINFO come next
+            fs_base: gpr[21],
+            gs_base: gpr[22],
+        }
+    }
+
+    /// The registers up to RIP in the order of the GPRSGX region.
+    fn to_gprsgx(&self) -> [u64; 18] {
+        [
+            self.rax, self.rcx, self.rdx, self.rbx, self.rsp, self.rbp, self.rsi, self.rdi,
+            self.r8, self.r9, self.r10, self.r11, self.r12, self.r13, self.r14, self.r15,
+            self.rflags, self.rip,
+        ]
+    }
+
+    /// Registers preserved across a usercall. The other registers are
+    /// clobbered by the usercall and aren't available.
+    fn from_usercall_frame(frame: &UsercallFrame) -> Self {
+        Registers {
+            rbx: frame.rbx,
+            rbp: frame.rbp,
+            rsp: frame.rsp,
+            r12: frame.r12,
+            r13: frame.r13,
+            r14: frame.r14,
+            r15: frame.r15,
+            rip: frame.rip,
+            ..Default::default()
+        }
+    }
+}
+
+/// The state of the threads of a debug enclave.
+#[derive(Clone, Debug)]
+pub(super) struct ThreadState {
+    pub enclave_range: Range<usize>,
+    /// The SSA frame size in pages
+    pub ssa_frame_size: u32,
+}
+
+impl ThreadState {
+    /// The address of the GPRSGX region of the SSA frame in use by `tcs`,
+    /// or `None` if the thread wasn't interrupted.
+    fn gprsgx(&self, mem: &DebugMemory, tcs: usize) -> IoResult<Option<usize>> {
+        let cssa = mem.read_u64(tcs + TCS_CSSA)? as u32 as usize;
+        if cssa == 0 {
+            return Ok(None);
+        }
+        let ssa = self.enclave_range.start + mem.read_u64(tcs + TCS_OSSA)? as usize;
+        let frame_end = ssa + cssa * self.ssa_frame_size as usize * PAGE_SIZE;
+        Ok(Some(frame_end - GPRSGX_SIZE))
+    }
+
+    /// The registers of the thread of `tcs`. If the thread was interrupted,
+    /// they are read from the last SSA frame in use. Otherwise, the thread
+    /// is outside the enclave, so it performed a usercall or hasn't entered
+    /// yet, and only the registers preserved across usercalls are available.
+    pub(super) fn registers(&self, mem: &DebugMemory, tcs: usize) -> IoResult<Registers> {
+        if let Some(gprsgx) = self.gprsgx(mem, tcs)? {
+            let mut gpr = [0u64; GPRSGX_SIZE / 8];
+            for (i, value) in gpr.iter_mut().enumerate() {
+                *value = mem.read_u64(gprsgx + i * 8)?;
+            }
+            return Ok(Registers::from_gprsgx(&gpr));
+        }
+        let base = self.enclave_range.start as u64;
+        let frame = UsercallFrame::read(mem, &self.enclave_range, tcs).unwrap_or_default();
+        Ok(Registers {
+            fs_base: base.wrapping_add(mem.read_u64(tcs + TCS_OFSBASGX)?),
+            gs_base: base.wrapping_add(mem.read_u64(tcs + TCS_OGSBASGX)?),
+            ..Registers::from_usercall_frame(&frame)
+        })
+    }
+
+    /// Change the registers of the thread of `tcs`, which must have been
+    /// interrupted. The new values take effect when the thread resumes.
+    pub(super) fn set_registers(&self, mem: &DebugMemory, tcs: usize, registers: &Registers) -> IoResult<()> {
+        let gprsgx = self.gprsgx(mem, tcs)?.ok_or(IoErrorKind::PermissionDenied)?;
+        let data = registers
+            .to_gprsgx()
+            .iter()
+            .flat_map(|value| value.to_le_bytes().to_vec())
+            .collect::<Vec<u8>>();
+        mem.write(gprsgx, &data)
+    }
+
+    /// The address of the enclave entry point of `tcs`.
+    pub(super) fn entry(&self, mem: &DebugMemory, tcs: usize) -> IoResult<u64> {
+        Ok(self.enclave_range.start as u64 + mem.read_u64(tcs + TCS_OENTRY)?)
+    }
+}
@@ -0,0 +1,239 @@
+/* Copyright (c) Fortanix, Inc.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Userspace side of the FIFO queues used for asynchronous usercalls.
+//!
+//! See the [`FifoDescriptor`] documentation for the queue protocol.
+//!
+//! [`FifoDescriptor`]: ../../../fortanix_sgx_abi/struct.FifoDescriptor.html
+
+use std::cell::UnsafeCell;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{spin_loop_hint, AtomicU64, AtomicUsize, Ordering};
+
+use fortanix_sgx_abi::{FifoDescriptor, Return, Usercall};
+
+/// An element of a FIFO queue.
+///
+/// # Safety
+///
+/// Implementors must be `#[repr(C)]` with a `u64` `id` as the first field,
+/// and the all-zero bit pattern must be a valid (empty) value.
+pub(super) unsafe trait WithId: Copy {
+    fn id(&self) -> u64;
+}
+
+unsafe impl WithId for Usercall {
+    fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+unsafe impl WithId for Return {
+    fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Offsets {
+    read: u32,
+    write: u32,
+    len: u32,
+}
+
+impl Offsets {
+    fn new(offsets: usize, len: usize) -> Self {
+        Offsets {
+            read: offsets as u32,
+            write: (offsets >> 32) as u32,
+            len: len as u32,
+        }
+    }
+
+    fn as_usize(&self) -> usize {
+        ((self.write as usize) << 32) | (self.read as usize)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.read == self.write
+    }
+
+    fn is_full(&self) -> bool {
+        self.read != self.write && self.slot(self.read) == self.slot(self.write)
+    }
+
+    /// Increment an offset, wrapping around after going through the buffer
+    /// twice so the extra bit keeps track of the wraparound.
+    fn increment(&self, offset: u32) -> u32 {
+        ((offset as u64 + 1) % (self.len as u64 * 2)) as u32
+    }
+
+    fn slot(&self, offset: u32) -> usize {
+        (offset & (self.len - 1)) as usize
+    }
+}
+
+/// A FIFO queue in user memory, shared with the enclave.
+pub(super) struct Fifo<T> {
+    data: Box<[UnsafeCell<T>]>,
+    offsets: Box<AtomicUsize>,
+}
+
+// The queue contents are only accessed following the synchronization protocol
+unsafe impl<T: Send> Send for Fifo<T> {}
+unsafe impl<T: Send> Sync for Fifo<T> {}
+
+impl<T: WithId> Fifo<T> {
+    /// # Panics
+    /// Panics if `len` is not a power of two or larger than 2³¹.
+    pub(super) fn new(len: usize) -> Self {
+        assert!(len.is_power_of_two() && len <= 1 << 31, "invalid FIFO length {}", len);
+        assert_eq!(mem::size_of::<usize>(), 8);
+        let data = (0..len)
+            .map(|_| UnsafeCell::new(unsafe { mem::zeroed() }))
+            .collect();
+        Fifo {
+            data,
+            offsets: Box::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Describes this queue in the format expected by the enclave.
+    pub(super) fn descriptor(&self) -> FifoDescriptor<T> {
+        FifoDescriptor {
+            data: self.data.as_ptr() as *mut T,
+            len: self.data.len(),
+            offsets: &*self.offsets,
+        }
+    }
+
+    fn offsets(&self) -> Offsets {
+        Offsets::new(self.offsets.load(Ordering::SeqCst), self.data.len())
+    }
+
+    fn id(&self, slot: usize) -> &AtomicU64 {
+        // `WithId` guarantees the `id` is at the start of the element
+        unsafe { &*(self.data[slot].get() as *const AtomicU64) }
+    }
+
+    /// Push `value` onto the queue.
+    ///
+    /// Returns whether the queue was empty before pushing, in which case the
+    /// reader should be woken up. If the queue is full, `value` is returned.
+    pub(super) fn try_push(&self, value: T) -> Result<bool, T> {
+        debug_assert_ne!(value.id(), 0);
+        let (current, new) = loop {
+            let current = self.offsets();
+            if current.is_full() {
+                return Err(value);
+            }
+            let new = Offsets {
+                write: current.increment(current.write),
+                ..current
+            };
+            if self
+                .offsets
+                .compare_exchange(current.as_usize(), new.as_usize(), Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+            {
+                break (current, new);
+            }
+        };
+
+        let slot = new.slot(new.write);
+        unsafe {
+            // Write everything but the `id`, which signals to the reader that
+            // the data is ready.
+            let src = &value as *const T as *const u8;
+            let dst = self.data[slot].get() as *mut u8;
+            let id_len = mem::size_of::<u64>();
+            ptr::copy_nonoverlapping(src.add(id_len), dst.add(id_len), mem::size_of::<T>() - id_len);
+        }
+        self.id(slot).store(value.id(), Ordering::SeqCst);
+
+        Ok(current.is_empty())
+    }
+
+    /// Pop a value off the queue.
+    ///
+    /// Returns the value and whether the queue was full before popping, in
+    /// which case the writer should be woken up. Returns `None` if the queue
+    /// is empty.
+    ///
+    /// There must be only a single reader of the queue.
+    pub(super) fn try_pop(&self) -> Option<(T, bool)> {
+        let current = self.offsets();
+        if current.is_empty() {
+            return None;
+        }
+        let read = current.increment(current.read);
+        let slot = current.slot(read);
+
+        // The writer has claimed the slot but might not have finished writing
+        while self.id(slot).load(Ordering::SeqCst) == 0 {
+            spin_loop_hint();
+        }
+        let value = unsafe { ptr::read(self.data[slot].get()) };
+        self.id(slot).store(0, Ordering::SeqCst);
+
+        // Writers may have moved the write offset in the meantime
+        let mut offsets = self.offsets.load(Ordering::SeqCst);
+        loop {
+            let new = Offsets {
+                read,
+                ..Offsets::new(offsets, self.data.len())
+            };
+            match self.offsets.compare_exchange(offsets, new.as_usize(), Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => break,
+                Err(prev) => offsets = prev,
+            }
+        }
+
+        Some((value, current.is_full()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ret(id: u64) -> Return {
+        Return { id, value: (id * 2, id * 3) }
+    }
+
+    #[test]
+    fn push_pop_wraparound() {
+        let fifo = Fifo::<Return>::new(4);
+        assert!(fifo.try_pop().is_none());
+        for round in 0..3 {
+            for i in 1..=4 {
+                let was_empty = fifo.try_push(ret(round * 10 + i)).ok().unwrap();
+                assert_eq!(was_empty, i == 1);
+            }
+            assert!(fifo.try_push(ret(99)).is_err());
+            for i in 1..=4 {
+                let (value, was_full) = fifo.try_pop().unwrap();
+                assert_eq!(value.id, round * 10 + i);
+                assert_eq!(value.value, ((round * 10 + i) * 2, (round * 10 + i) * 3));
+                assert_eq!(was_full, i == 1);
+            }
+            assert!(fifo.try_pop().is_none());
+        }
+    }
+
+    #[test]
+    fn descriptor() {
+        let fifo = Fifo::<Usercall>::new(8);
+        let desc = fifo.descriptor();
+        assert_eq!(desc.len, 8);
+        unsafe {
+            (*desc.offsets).store(0x0000_0003_0000_0001, Ordering::SeqCst);
+        }
+        let offsets = fifo.offsets();
+        assert_eq!((offsets.read, offsets.write), (1, 3));
+    }
+}
@@ -0,0 +1,1006 @@
+/* Copyright (c) Fortanix, Inc.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! A gdb remote serial protocol server for debug enclaves.
+//!
+//! Each TCS is a thread, identified by its address. Memory and registers
+//! are accessed through the SGX debug interface. Software breakpoints are
+//! supported: when an enclave thread hits one, it waits until gdb continues.
+//! Other enclave threads keep running. Enclave threads can't be stopped
+//! from the outside, so interrupting (Ctrl-C) is refused with a message
+//! instead of reporting a stop. Registers can only be changed for a thread
+//! that was stopped at a breakpoint or after a single step. Killing the
+//! program (`kill` in gdb) terminates the runner process.
+//!
+//! The server doesn't authenticate gdb, and a connected gdb can read and
+//! write enclave memory, so it only listens on localhost unless an address
+//! is given explicitly.
+//!
+//! Connect with gdb using the enclave ELF file, which is relocated to the
+//! enclave base automatically:
+//!
+//! ```text
+//! (gdb) file enclave.elf
+//! (gdb) target remote localhost:1234
+//! ```
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
+use std::net::{Ipv4Addr, TcpListener};
+use std::ops::Range;
+use std::str;
+use std::sync::Arc;
+
+use super::debug::Registers;
+use super::MetricsCounters;
+
+const SIGTRAP: u8 = 5;
+const INT3: u8 = 0xcc;
+const RFLAGS_TF: u64 = 1 << 8;
+/// The largest memory read or write handled at once, in bytes.
+const MAX_MEMORY_ACCESS: usize = 0x1000;
+/// The size of the registers in a `g` packet, of which only the
+/// general-purpose registers and the segment selectors are available.
+const REGISTERS_SIZE: usize = 536;
+const GENERAL_REGISTERS_SIZE: usize = 17 * 8 + 7 * 4;
+
+/// The enclave being debugged.
+pub(super) trait DebugTarget {
+    /// The base address of the enclave.
+    fn base(&self) -> u64;
+    /// The addresses of the TCSs of the enclave.
+    fn threads(&self) -> Vec<u64>;
+    fn read_memory(&self, address: u64, buf: &mut [u8]) -> IoResult<()>;
+    fn write_memory(&self, address: u64, data: &[u8]) -> IoResult<()>;
+    fn registers(&self, tcs: u64) -> IoResult<Registers>;
+    fn set_registers(&self, tcs: u64, registers: &Registers) -> IoResult<()>;
+    /// Let a thread that stopped continue.
+    fn resume(&self, tcs: u64);
+    /// Terminate the program.
+    fn kill(&self);
+}
+
+/// An enclave thread that stopped because of a signal.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(super) struct Stop {
+    pub tcs: u64,
+    pub signal: u8,
+}
+
+#[derive(Debug)]
+pub(super) enum Event {
+    /// Data received from gdb
+    Input(Vec<u8>),
+    /// gdb disconnected
+    Closed,
+    Stop(Stop),
+}
+
+fn parse_hex(s: &str) -> IoResult<u64> {
+    u64::from_str_radix(s, 16).map_err(|_| IoErrorKind::InvalidInput.into())
+}
+
+fn decode_hex(s: &str) -> IoResult<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return Err(IoErrorKind::InvalidInput.into());
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| s.get(i..i + 2).ok_or(IoErrorKind::InvalidInput.into()).and_then(parse_hex).map(|b| b as u8))
+        .collect()
+}
+
+fn encode_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Parse `addr,len`.
+fn parse_range(s: &str) -> IoResult<(u64, usize)> {
+    let mut parts = s.splitn(2, ',');
+    let address = parse_hex(parts.next().unwrap_or(""))?;
+    let len = parse_hex(parts.next().ok_or(IoErrorKind::InvalidInput)?)? as usize;
+    if len > MAX_MEMORY_ACCESS {
+        return Err(IoErrorKind::InvalidInput.into());
+    }
+    Ok((address, len))
+}
+
+/// The registers in the order of gdb's amd64 register numbers. `rflags`
+/// is number 17, the segment selectors follow.
+fn register_mut(registers: &mut Registers, n: usize) -> Option<&mut u64> {
+    Some(match n {
+        0 => &mut registers.rax,
+        1 => &mut registers.rbx,
+        2 => &mut registers.rcx,
+        3 => &mut registers.rdx,
+        4 => &mut registers.rsi,
+        5 => &mut registers.rdi,
+        6 => &mut registers.rbp,
+        7 => &mut registers.rsp,
+        8 => &mut registers.r8,
+        9 => &mut registers.r9,
+        10 => &mut registers.r10,
+        11 => &mut registers.r11,
+        12 => &mut registers.r12,
+        13 => &mut registers.r13,
+        14 => &mut registers.r14,
+        15 => &mut registers.r15,
+        16 => &mut registers.rip,
+        17 => &mut registers.rflags,
+        _ => return None,
+    })
+}
+
+/// The value of register `n` as sent to gdb.
+fn encode_register(registers: &Registers, n: usize) -> Option<String> {
+    const SEGMENTS: [u32; 6] = [0x33, 0x2b, 0, 0, 0, 0];
+    match n {
+        0..=16 => register_mut(&mut registers.clone(), n).map(|value| encode_hex(&value.to_le_bytes())),
+        17 => Some(encode_hex(&(registers.rflags as u32).to_le_bytes())),
+        18..=23 => Some(encode_hex(&SEGMENTS[n - 18].to_le_bytes())),
+        _ => None,
+    }
+}
+
+/// The state of a connection with gdb.
+pub(super) struct GdbStub<T: DebugTarget, W: Write> {
+    target: T,
+    out: W,
+    input: Vec<u8>,
+    ack: bool,
+    /// Inserted breakpoints, with the original byte
+    breakpoints: BTreeMap<u64, u8>,
+    /// The thread selected for register and memory accesses
+    thread: Option<u64>,
+    /// Threads that stopped and were reported to gdb
+    stopped: Vec<u64>,
+    /// Threads that stopped but weren't reported yet, and whether they hit a
+    /// breakpoint
+    pending: VecDeque<(Stop, bool)>,
+    last_stop: Option<(Stop, bool)>,
+    /// The thread being single-stepped
+    stepping: Option<u64>,
+    running: bool,
+    done: bool,
+}
+
+impl<T: DebugTarget, W: Write> GdbStub<T, W> {
+    pub(super) fn new(target: T, out: W) -> Self {
+        GdbStub {
+            target,
+            out,
+            input: vec![],
+            ack: true,
+            breakpoints: BTreeMap::new(),
+            thread: None,
+            stopped: vec![],
+            pending: VecDeque::new(),
+            last_stop: None,
+            stepping: None,
+            running: false,
+            done: false,
+        }
+    }
+
+    /// Handle an event. Returns `false` once the session is over, after
+    /// which all breakpoints are removed and all stopped threads resumed.
+    pub(super) fn event(&mut self, event: Event) -> IoResult<bool> {
+        let result = match event {
+            Event::Input(data) => {
+                self.input.extend_from_slice(&data);
+                self.process_input()
+            }
+            Event::Closed => {
+                self.done = true;
+                Ok(())
+            }
+            Event::Stop(stop) => self.stop(stop),
+        };
+        if self.done || result.is_err() {
+            self.detach();
+        }
+        result.map(|()| !self.done)
+    }
+
+    fn send(&mut self, data: &str) -> IoResult<()> {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        write!(self.out, "${}#{:02x}", data, checksum)?;
+        self.out.flush()
+    }
+
+    fn process_input(&mut self) -> IoResult<()> {
+        while !self.input.is_empty() && !self.done {
+            match self.input[0] {
+                // Interrupt. Running enclave threads can't be stopped, so
+                // keep waiting for a thread to stop by itself.
+                0x03 => {
+                    self.input.remove(0);
+                    if self.running {
+                        let message = "Enclave threads can't be interrupted, set a breakpoint instead\n";
+                        self.send(&format!("O{}", encode_hex(message.as_bytes())))?;
+                    }
+                }
+                b'$' => {
+                    let end = match self.input.iter().position(|&b| b == b'#') {
+                        Some(end) if self.input.len() >= end + 3 => end,
+                        _ => return Ok(()),
+                    };
+                    let packet = self.input[1..end].to_vec();
+                    let checksum = str::from_utf8(&self.input[end + 1..end + 3]).ok().and_then(|s| parse_hex(s).ok());
+                    self.input.drain(..end + 3);
+                    let valid = checksum == Some(packet.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) as u64);
+                    if self.ack {
+                        self.out.write_all(if valid { b"+" } else { b"-" })?;
+                    }
+                    if valid {
+                        // Errors are reported to gdb
+                        let reply = match str::from_utf8(&packet) {
+                            Ok(packet) => self.packet(packet).unwrap_or_else(|_| Some("E01".to_owned())),
+                            Err(_) => Some("E01".to_owned()),
+                        };
+                        if let Some(reply) = reply {
+                            self.send(&reply)?;
+                        }
+                    }
+                    self.out.flush()?;
+                }
+                // Acknowledgements and anything else
+                _ => {
+                    self.input.remove(0);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The thread selected by gdb, or the thread that stopped last.
+    fn thread(&self) -> u64 {
+        self.thread
+            .or(self.last_stop.map(|(stop, _)| stop.tcs))
+            .or_else(|| self.target.threads().first().cloned())
+            .unwrap_or(0)
+    }
+
+    fn is_thread(&self, tcs: u64) -> bool {
+        self.target.threads().contains(&tcs)
+    }
+
+    /// Handle a packet, returning the reply, or `None` if the reply is sent
+    /// later.
+    fn packet(&mut self, packet: &str) -> IoResult<Option<String>> {
+        let (command, args) = packet.split_at(1.min(packet.len()));
+        let reply = match command {
+            "?" => {
+                if let Some((stop, breakpoint)) = self.pending.pop_front() {
+                    self.stopped.push(stop.tcs);
+                    self.last_stop = Some((stop, breakpoint));
+                }
+                match self.last_stop {
+                    Some((stop, breakpoint)) => self.stop_reply(stop, breakpoint),
+                    None => format!("T00thread:{:x};", self.thread()),
+                }
+            }
+            "H" => {
+                let tcs = args.get(1..).unwrap_or("");
+                if args.starts_with('g') {
+                    self.thread = match tcs {
+                        "0" | "-1" => None,
+                        tcs => Some(parse_hex(tcs)?),
+                    };
+                }
+                "OK".to_owned()
+            }
+            "T" => match self.is_thread(parse_hex(args)?) {
+                true => "OK".to_owned(),
+                false => "E01".to_owned(),
+            },
+            "g" => {
+                let registers = self.target.registers(self.thread())?;
+                let mut reply = (0..24).filter_map(|n| encode_register(&registers, n)).collect::<String>();
+                reply.extend((GENERAL_REGISTERS_SIZE..REGISTERS_SIZE).map(|_| "xx"));
+                reply
+            }
+            "G" => {
+                let data = decode_hex(args.get(..GENERAL_REGISTERS_SIZE * 2).ok_or(IoErrorKind::InvalidInput)?)?;
+                let tcs = self.thread();
+                let mut registers = self.target.registers(tcs)?;
+                for n in 0..17 {
+                    let mut value = [0; 8];
+                    value.copy_from_slice(&data[n * 8..][..8]);
+                    *register_mut(&mut registers, n).unwrap() = u64::from_le_bytes(value);
+                }
+                let mut rflags = [0; 4];
+                rflags.copy_from_slice(&data[17 * 8..][..4]);
+                registers.rflags = u32::from_le_bytes(rflags) as u64;
+                self.target.set_registers(tcs, &registers)?;
+                "OK".to_owned()
+            }
+            "p" => {
+                let registers = self.target.registers(self.thread())?;
+                encode_register(&registers, parse_hex(args)? as usize).ok_or(IoErrorKind::InvalidInput)?
+            }
+            "P" => {
+                let mut parts = args.splitn(2, '=');
+                let n = parse_hex(parts.next().unwrap_or(""))? as usize;
+                let data = decode_hex(parts.next().ok_or(IoErrorKind::InvalidInput)?)?;
+                let tcs = self.thread();
+                let mut registers = self.target.registers(tcs)?;
+                let register = register_mut(&mut registers, n).ok_or(IoErrorKind::InvalidInput)?;
+                let mut value = [0; 8];
+                value[..data.len().min(8)].copy_from_slice(&data[..data.len().min(8)]);
+                *register = u64::from_le_bytes(value);
+                self.target.set_registers(tcs, &registers)?;
+                "OK".to_owned()
+            }
+            "m" => {
+                let (address, len) = parse_range(args)?;
+                let mut buf = vec![0; len];
+                self.target.read_memory(address, &mut buf)?;
+                // Hide the inserted breakpoints
+                let end = address.saturating_add(len as u64);
+                for (&breakpoint, &original) in self.breakpoints.range(address..end) {
+                    buf[(breakpoint - address) as usize] = original;
+                }
+                encode_hex(&buf)
+            }
+            "M" => {
+                let mut parts = args.splitn(2, ':');
+                let (address, len) = parse_range(parts.next().unwrap_or(""))?;
+                let mut data = decode_hex(parts.next().ok_or(IoErrorKind::InvalidInput)?)?;
+                if data.len() != len {
+                    return Err(IoErrorKind::InvalidInput.into());
+                }
+                let end = address.saturating_add(len as u64);
+                for (&breakpoint, original) in self.breakpoints.range_mut(address..end) {
+                    let i = (breakpoint - address) as usize;
+                    *original = data[i];
+                    data[i] = INT3;
+                }
+                self.target.write_memory(address, &data)?;
+                "OK".to_owned()
+            }
+            "Z" | "z" if args.starts_with("0,") => {
+                let address = parse_hex(args[2..].split(',').next().unwrap_or(""))?;
+                if command == "Z" {
+                    if !self.breakpoints.contains_key(&address) {
+                        let mut original = [0];
+                        self.target.read_memory(address, &mut original)?;
+                        self.target.write_memory(address, &[INT3])?;
+                        self.breakpoints.insert(address, original[0]);
+                    }
+                } else if let Some(original) = self.breakpoints.remove(&address) {
+                    self.target.write_memory(address, &[original])?;
+                }
+                "OK".to_owned()
+            }
+            "c" | "s" => {
+                if !args.is_empty() {
+                    let tcs = self.thread();
+                    let mut registers = self.target.registers(tcs)?;
+                    registers.rip = parse_hex(args)?;
+                    self.target.set_registers(tcs, &registers)?;
+                }
+                return self.resume(command == "s");
+            }
+            "D" => {
+                self.done = true;
+                "OK".to_owned()
+            }
+            "k" => {
+                self.target.kill();
+                self.done = true;
+                return Ok(None);
+            }
+            "q" | "Q" => return self.query(packet).map(Some),
+            _ => String::new(),
+        };
+        Ok(Some(reply))
+    }
+
+    fn query(&mut self, packet: &str) -> IoResult<String> {
+        let mut parts = packet.splitn(2, |c| c == ':' || c == ',');
+        let name = parts.next().unwrap_or("");
+        let args = parts.next().unwrap_or("");
+        Ok(match name {
+            "qSupported" => format!("PacketSize={:x};QStartNoAckMode+;swbreak+", 2 * MAX_MEMORY_ACCESS + 16),
+            "QStartNoAckMode" => {
+                // This packet itself was still acknowledged
+                self.ack = false;
+                "OK".to_owned()
+            }
+            "qAttached" => "1".to_owned(),
+            "qC" => format!("QC{:x}", self.thread()),
+            "qfThreadInfo" => {
+                let threads = self.target.threads().iter().map(|tcs| format!("{:x}", tcs)).collect::<Vec<_>>();
+                format!("m{}", threads.join(","))
+            }
+            "qsThreadInfo" => "l".to_owned(),
+            "qThreadExtraInfo" => {
+                let tcs = parse_hex(args)?;
+                let state = if self.stopped.contains(&tcs) { "stopped" } else { "running" };
+                encode_hex(format!("TCS {:#x} ({})", tcs, state).as_bytes())
+            }
+            "qOffsets" => {
+                let base = self.target.base();
+                format!("Text={:x};Data={:x};Bss={:x}", base, base, base)
+            }
+            "qSymbol" => "OK".to_owned(),
+            _ => String::new(),
+        })
+    }
+
+    fn stop_reply(&self, stop: Stop, breakpoint: bool) -> String {
+        let reason = if breakpoint { "swbreak:;" } else { "" };
+        format!("T{:02x}{}thread:{:x};", stop.signal, reason, stop.tcs)
+    }
+
+    fn report(&mut self, stop: Stop, breakpoint: bool) -> IoResult<()> {
+        self.last_stop = Some((stop, breakpoint));
+        self.thread = None;
+        let reply = self.stop_reply(stop, breakpoint);
+        self.send(&reply)
+    }
+
+    fn resume(&mut self, step: bool) -> IoResult<Option<String>> {
+        if let Some((stop, breakpoint)) = self.pending.pop_front() {
+            self.stopped.push(stop.tcs);
+            self.last_stop = Some((stop, breakpoint));
+            self.thread = None;
+            return Ok(Some(self.stop_reply(stop, breakpoint)));
+        }
+        let tcs = self.thread();
+        if step && self.stopped.contains(&tcs) {
+            let mut registers = self.target.registers(tcs)?;
+            registers.rflags |= RFLAGS_TF;
+            self.target.set_registers(tcs, &registers)?;
+            self.stepping = Some(tcs);
+            self.stopped.retain(|&stopped| stopped != tcs);
+            self.target.resume(tcs);
+        } else {
+            for tcs in self.stopped.drain(..) {
+                self.target.resume(tcs);
+            }
+        }
+        self.running = true;
+        Ok(None)
+    }
+
+    fn stop(&mut self, stop: Stop) -> IoResult<()> {
+        let mut breakpoint = false;
+        if self.stepping == Some(stop.tcs) {
+            self.stepping = None;
+            let mut registers = self.target.registers(stop.tcs)?;
+            registers.rflags &= !RFLAGS_TF;
+            self.target.set_registers(stop.tcs, &registers)?;
+        } else if stop.signal == SIGTRAP {
+            // After a breakpoint, the thread resumes after the `int3`
+            let mut registers = self.target.registers(stop.tcs)?;
+            if self.breakpoints.contains_key(&registers.rip.wrapping_sub(1)) {
+                registers.rip -= 1;
+                self.target.set_registers(stop.tcs, &registers)?;
+                breakpoint = true;
+            }
+        }
+        if self.running {
+            self.running = false;
+            self.stopped.push(stop.tcs);
+            self.report(stop, breakpoint)
+        } else {
+            self.pending.push_back((stop, breakpoint));
+            Ok(())
+        }
+    }
+
+    /// Remove all breakpoints and resume all stopped threads.
+    fn detach(&mut self) {
+        for (address, original) in std::mem::replace(&mut self.breakpoints, BTreeMap::new()) {
+            let _ = self.target.write_memory(address, &[original]);
+        }
+        if let Some(tcs) = self.stepping.take() {
+            if let Ok(mut registers) = self.target.registers(tcs) {
+                registers.rflags &= !RFLAGS_TF;
+                let _ = self.target.set_registers(tcs, &registers);
+            }
+        }
+        let pending = self.pending.drain(..).map(|(stop, _)| stop.tcs);
+        for tcs in self.stopped.drain(..).chain(pending).collect::<Vec<_>>() {
+            self.target.resume(tcs);
+        }
+        self.done = true;
+    }
+}
+
+/// A listening socket for gdb to connect to, bound when building the
+/// enclave.
+#[derive(Debug)]
+pub(crate) struct GdbServer {
+    listener: TcpListener,
+    ssa_frame_size: u32,
+}
+
+impl GdbServer {
+    /// Bind to `addr`, which is either a TCP address or just a port, in
+    /// which case only connections from localhost are accepted.
+    pub(crate) fn bind(addr: &str, ssa_frame_size: u32) -> IoResult<Self> {
+        let listener = match addr.trim_start_matches(':').parse::<u16>() {
+            Ok(port) => TcpListener::bind((Ipv4Addr::LOCALHOST, port))?,
+            Err(_) => TcpListener::bind(addr)?,
+        };
+        if !listener.local_addr()?.ip().is_loopback() {
+            eprintln!(
+                "Warning: the gdb server accepts connections on {}, which allows anyone who can connect to it to \
+                 read and write enclave memory",
+                listener.local_addr()?
+            );
+        }
+        Ok(GdbServer {
+            listener,
+            ssa_frame_size,
+        })
+    }
+
+    /// Start serving gdb connections for the enclave in the background.
+    /// There can only be one server per process.
+    #[cfg(all(unix, not(target_abi = "musl")))]
+    pub(super) fn start(self, enclave_range: Range<usize>, tcss: Vec<u64>, metrics: Arc<MetricsCounters>) -> IoResult<()> {
+        use std::sync::mpsc;
+        use std::sync::Mutex;
+        use std::thread;
+
+        use super::debug::{DebugMemory, ThreadState};
+
+        let mem = DebugMemory::open_writable()?;
+        let mut stops = trap::install(&tcss)?;
+        let target = EnclaveTarget {
+            mem,
+            state: ThreadState {
+                enclave_range,
+                ssa_frame_size: self.ssa_frame_size,
+            },
+            tcss,
+        };
+        eprintln!("Listening for gdb connections on {}", self.listener.local_addr()?);
+
+        let connection = Arc::new(Mutex::new(None::<mpsc::Sender<Event>>));
+        let connection_clone = connection.clone();
+        thread::spawn(move || loop {
+            let mut buf = [0u8; 16];
+            if stops.read_exact(&mut buf).is_err() {
+                return;
+            }
+            let mut tcs = [0; 8];
+            tcs.copy_from_slice(&buf[..8]);
+            let stop = Stop {
+                tcs: u64::from_le_bytes(tcs),
+                signal: buf[8],
+            };
+            metrics.trap();
+            let connection = connection_clone.lock().unwrap();
+            match *connection {
+                Some(ref sender) if sender.send(Event::Stop(stop)).is_ok() => {}
+                // Nobody is debugging, so just continue
+                _ => trap::resume(stop.tcs),
+            }
+        });
+
+        let listener = self.listener;
+        thread::spawn(move || {
+            let mut target = target;
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let (sender, receiver) = mpsc::channel();
+                let mut reader = match stream.try_clone() {
+                    Ok(reader) => reader,
+                    Err(_) => continue,
+                };
+                let input = sender.clone();
+                *connection.lock().unwrap() = Some(sender);
+                thread::spawn(move || {
+                    let mut buf = [0u8; 4096];
+                    loop {
+                        match reader.read(&mut buf) {
+                            Ok(0) | Err(_) => {
+                                let _ = input.send(Event::Closed);
+                                return;
+                            }
+                            Ok(n) => {
+                                if input.send(Event::Input(buf[..n].to_vec())).is_err() {
+                                    return;
+                                }
+                            }
+                        }
+                    }
+                });
+
+                let mut stub = GdbStub::new(target, &stream);
+                for event in receiver.iter() {
+                    match stub.event(event) {
+                        Ok(true) => {}
+                        Ok(false) | Err(_) => break,
+                    }
+                }
+                let _ = stream.shutdown(std::net::Shutdown::Both);
+                target = stub.target;
+                *connection.lock().unwrap() = None;
+                for event in receiver.try_iter() {
+                    if let Event::Stop(stop) = event {
+                        trap::resume(stop.tcs);
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    #[cfg(not(all(unix, not(target_abi = "musl"))))]
+    pub(super) fn start(self, _enclave_range: Range<usize>, _tcss: Vec<u64>, _metrics: Arc<MetricsCounters>) -> IoResult<()> {
+        Err(IoErrorKind::Other.into())
+    }
+}
+
+#[cfg(all(unix, not(target_abi = "musl")))]
+struct EnclaveTarget {
+    mem: super::debug::DebugMemory,
+    state: super::debug::ThreadState,
+    tcss: Vec<u64>,
+}
+
+#[cfg(all(unix, not(target_abi = "musl")))]
+impl EnclaveTarget {
+    /// Only allow accessing enclave memory.
+    fn check_range(&self, address: u64, len: usize) -> IoResult<usize> {
+        let range = &self.state.enclave_range;
+        let address = address as usize;
+        match address.checked_add(len) {
+            Some(end) if range.start <= address && end <= range.end => Ok(address),
+            _ => Err(IoErrorKind::PermissionDenied.into()),
+        }
+    }
+}
+
+#[cfg(all(unix, not(target_abi = "musl")))]
+impl DebugTarget for EnclaveTarget {
+    fn base(&self) -> u64 {
+        self.state.enclave_range.start as u64
+    }
+
+    fn threads(&self) -> Vec<u64> {
+        self.tcss.clone()
+    }
+
+    fn read_memory(&self, address: u64, buf: &mut [u8]) -> IoResult<()> {
+        let address = self.check_range(address, buf.len())?;
+        self.mem.read(address, buf)
+    }
+
+    fn write_memory(&self, address: u64, data: &[u8]) -> IoResult<()> {
+        let address = self.check_range(address, data.len())?;
+        self.mem.write(address, data)
+    }
+
+    fn registers(&self, tcs: u64) -> IoResult<Registers> {
+        self.state.registers(&self.mem, tcs as usize)
+    }
+
+    fn set_registers(&self, tcs: u64, registers: &Registers) -> IoResult<()> {
+        self.state.set_registers(&self.mem, tcs as usize, registers)
+    }
+
+    fn resume(&self, tcs: u64) {
+        trap::resume(tcs)
+    }
+
+    fn kill(&self) {
+        eprintln!("Killed by gdb");
+        unsafe {
+            libc::raise(libc::SIGKILL);
+        }
+    }
+}
+
+/// Stopping enclave threads on SIGTRAP.
+///
+/// An exception in a debug enclave, such as hitting a breakpoint or after a
+/// single step, causes an asynchronous exit. The signal is then delivered to
+/// the thread at the ENCLU instruction that resumes the enclave, with the
+/// TCS address in RBX. The signal handler reports the stop through a pipe
+/// and waits until the thread is resumed. Only async-signal-safe operations
+/// are used in the handler.
+#[cfg(all(unix, not(target_abi = "musl")))]
+mod trap {
+    use std::fs::File;
+    use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult};
+    use std::os::unix::io::FromRawFd;
+    use std::ptr;
+    use std::slice;
+    use std::sync::atomic::{AtomicBool, AtomicI32, AtomicPtr, AtomicUsize, Ordering};
+
+    use libc::{c_int, c_void, siginfo_t, ucontext_t};
+    use nix::sys::signal;
+
+    use super::super::{handle_trap, Greg};
+
+    const ENCLU: [u8; 3] = [0x0f, 0x01, 0xd7];
+
+    /// Whether the signal handler was installed.
+    static INSTALLED: AtomicBool = AtomicBool::new(false);
+    /// The write end of the pipe stops are reported to.
+    static STOP_PIPE: AtomicI32 = AtomicI32::new(-1);
+    /// A slot for each TCS, which are never freed once installed.
+    static SLOTS: AtomicPtr<Slot> = AtomicPtr::new(ptr::null_mut());
+    static SLOT_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    struct Slot {
+        tcs: u64,
+        /// Whether the stopped thread of `tcs` may continue
+        resume: AtomicBool,
+    }
+
+    fn slot(tcs: u64) -> Option<&'static Slot> {
+        let slots = SLOTS.load(Ordering::SeqCst);
+        if slots.is_null() {
+            return None;
+        }
+        let slots = unsafe { slice::from_raw_parts(slots, SLOT_COUNT.load(Ordering::SeqCst)) };
+        slots.iter().find(|slot| slot.tcs == tcs)
+    }
+
+    /// Install the signal handler for the threads of `tcss`, returning the
+    /// read end of the pipe stops are reported to. Each stop is the TCS
+    /// address and the signal number, as 64-bit little-endian integers.
+    pub(super) fn install(tcss: &[u64]) -> IoResult<File> {
+        if INSTALLED.swap(true, Ordering::SeqCst) {
+            return Err(IoError::new(IoErrorKind::AlreadyExists, "a gdb server is already running"));
+        }
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            INSTALLED.store(false, Ordering::SeqCst);
+            return Err(IoError::last_os_error());
+        }
+        let slots = tcss
+            .iter()
+            .map(|&tcs| Slot {
+                tcs,
+                resume: AtomicBool::new(false),
+            })
+            .collect::<Vec<_>>()
+            .into_boxed_slice();
+        SLOT_COUNT.store(slots.len(), Ordering::SeqCst);
+        SLOTS.store(Box::leak(slots).as_mut_ptr(), Ordering::SeqCst);
+        STOP_PIPE.store(fds[1], Ordering::SeqCst);
+        let hdl = signal::SigHandler::SigAction(handle_enclave_trap);
+        let sig_action = signal::SigAction::new(hdl, signal::SaFlags::empty(), signal::SigSet::empty());
+        unsafe { signal::sigaction(signal::SIGTRAP, &sig_action) }
+            .map_err(|e| IoError::new(IoErrorKind::Other, e.to_string()))?;
+        Ok(unsafe { File::from_raw_fd(fds[0]) })
+    }
+
+    /// Let the thread of `tcs` continue, if it stopped.
+    pub(super) fn resume(tcs: u64) {
+        if let Some(slot) = slot(tcs) {
+            slot.resume.store(true, Ordering::SeqCst);
+        }
+    }
+
+    extern "C" fn handle_enclave_trap(signo: c_int, info: *mut siginfo_t, context: *mut c_void) {
+        unsafe {
+            let ucontext = &*(context as *const ucontext_t);
+            let rip = ucontext.uc_mcontext.gregs[Greg::RIP as usize] as *const [u8; 3];
+            let fd = STOP_PIPE.load(Ordering::SeqCst);
+            if fd < 0 || *rip != ENCLU {
+                return handle_trap(signo, info, context);
+            }
+            let tcs = ucontext.uc_mcontext.gregs[Greg::RBX as usize] as u64;
+            let slot = match slot(tcs) {
+                Some(slot) => slot,
+                None => return,
+            };
+            slot.resume.store(false, Ordering::SeqCst);
+            let mut stop = [0u8; 16];
+            stop[..8].copy_from_slice(&tcs.to_le_bytes());
+            stop[8..].copy_from_slice(&(signo as u64).to_le_bytes());
+            if libc::write(fd, stop.as_ptr() as *const c_void, stop.len()) != stop.len() as isize {
+                return;
+            }
+            let delay = libc::timespec {
+                tv_sec: 0,
+                tv_nsec: 1_000_000,
+            };
+            while !slot.resume.swap(false, Ordering::SeqCst) {
+                libc::nanosleep(&delay, ptr::null_mut());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+
+    #[derive(Default)]
+    struct MockTarget {
+        memory: RefCell<Vec<u8>>,
+        registers: RefCell<HashMap<u64, Registers>>,
+        resumed: RefCell<Vec<u64>>,
+        killed: RefCell<bool>,
+    }
+
+    const BASE: u64 = 0x10000;
+
+    impl DebugTarget for &MockTarget {
+        fn base(&self) -> u64 {
+            BASE
+        }
+
+        fn threads(&self) -> Vec<u64> {
+            let mut threads = self.registers.borrow().keys().cloned().collect::<Vec<_>>();
+            threads.sort();
+            threads
+        }
+
+        fn read_memory(&self, address: u64, buf: &mut [u8]) -> IoResult<()> {
+            let memory = self.memory.borrow();
+            let start = address.checked_sub(BASE).ok_or(IoErrorKind::InvalidInput)? as usize;
+            let data = memory.get(start..start + buf.len()).ok_or(IoErrorKind::InvalidInput)?;
+            buf.copy_from_slice(data);
+            Ok(())
+        }
+
+        fn write_memory(&self, address: u64, data: &[u8]) -> IoResult<()> {
+            let mut memory = self.memory.borrow_mut();
+            let start = address.checked_sub(BASE).ok_or(IoErrorKind::InvalidInput)? as usize;
+            memory
+                .get_mut(start..start + data.len())
+                .ok_or(IoErrorKind::InvalidInput)?
+                .copy_from_slice(data);
+            Ok(())
+        }
+
+        fn registers(&self, tcs: u64) -> IoResult<Registers> {
+            self.registers.borrow().get(&tcs).cloned().ok_or(IoErrorKind::NotFound.into())
+        }
+
+        fn set_registers(&self, tcs: u64, registers: &Registers) -> IoResult<()> {
+            self.registers.borrow_mut().insert(tcs, registers.clone());
+            Ok(())
+        }
+
+        fn resume(&self, tcs: u64) {
+            self.resumed.borrow_mut().push(tcs)
+        }
+
+        fn kill(&self) {
+            *self.killed.borrow_mut() = true;
+        }
+    }
+
+    fn packet(data: &str) -> Event {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        Event::Input(format!("${}#{:02x}", data, checksum).into_bytes())
+    }
+
+    /// Send a packet and return the reply, without acknowledgements.
+    fn request(stub: &mut GdbStub<&MockTarget, Vec<u8>>, data: &str) -> String {
+        stub.out.clear();
+        assert!(stub.event(packet(data)).unwrap());
+        let reply = String::from_utf8(stub.out.clone()).unwrap();
+        let reply = reply.trim_start_matches('+');
+        if reply.is_empty() {
+            return reply.to_owned();
+        }
+        assert!(reply.starts_with('$'));
+        reply[1..reply.len() - 3].to_owned()
+    }
+
+    #[test]
+    fn session() {
+        let target = MockTarget {
+            memory: RefCell::new((0..0x100).map(|i| i as u8).collect()),
+            ..Default::default()
+        };
+        target.registers.borrow_mut().insert(0x11000, Registers {
+            rax: 1,
+            rip: 0x10010,
+            rflags: 0x202,
+            ..Default::default()
+        });
+        target.registers.borrow_mut().insert(0x12000, Registers::default());
+        let mut stub = GdbStub::new(&target, vec![]);
+
+        assert_eq!(request(&mut stub, "?"), "T00thread:11000;");
+        assert_eq!(request(&mut stub, "qfThreadInfo"), "m11000,12000");
+        assert_eq!(request(&mut stub, "qsThreadInfo"), "l");
+        assert_eq!(request(&mut stub, "qOffsets"), "Text=10000;Data=10000;Bss=10000");
+        assert_eq!(request(&mut stub, "qThreadExtraInfo,12000"), encode_hex(b"TCS 0x12000 (running)"));
+        assert_eq!(request(&mut stub, "vMustReplyEmpty"), "");
+
+        let registers = request(&mut stub, "g");
+        assert_eq!(registers.len(), REGISTERS_SIZE * 2);
+        assert!(registers.starts_with("0100000000000000"));
+        assert_eq!(&registers[16 * 16..17 * 16], "1000010000000000");
+        assert_eq!(request(&mut stub, "p11"), "02020000");
+        assert_eq!(request(&mut stub, "P0=2a00000000000000"), "OK");
+        assert_eq!(target.registers.borrow()[&0x11000].rax, 0x2a);
+        assert_eq!(request(&mut stub, "Hg12000"), "OK");
+        assert_eq!(request(&mut stub, "p0"), "0000000000000000");
+        assert_eq!(request(&mut stub, "Hg0"), "OK");
+
+        assert_eq!(request(&mut stub, "m10004,4"), "04050607");
+        assert_eq!(request(&mut stub, "M10004,2:aabb"), "OK");
+        assert_eq!(request(&mut stub, "m10004,4"), "aabb0607");
+        assert_eq!(request(&mut stub, "m20000,4"), "E01");
+
+        // Breakpoints are hidden from memory reads
+        assert_eq!(request(&mut stub, "Z0,10020,1"), "OK");
+        assert_eq!(target.memory.borrow()[0x20], INT3);
+        assert_eq!(request(&mut stub, "m1001f,3"), "1f2021");
+        assert_eq!(request(&mut stub, "M10020,1:99"), "OK");
+        assert_eq!(target.memory.borrow()[0x20], INT3);
+
+        // Hit the breakpoint
+        assert_eq!(request(&mut stub, "c"), "");
+        target.registers.borrow_mut().get_mut(&0x12000).unwrap().rip = 0x10021;
+        stub.out.clear();
+        assert!(stub.event(Event::Stop(Stop { tcs: 0x12000, signal: SIGTRAP })).unwrap());
+        assert!(String::from_utf8_lossy(&stub.out).contains("T05swbreak:;thread:12000;"));
+        assert_eq!(target.registers.borrow()[&0x12000].rip, 0x10020);
+        assert_eq!(request(&mut stub, "qC"), "QC12000");
+
+        // Single step with the trap flag
+        assert_eq!(request(&mut stub, "z0,10020,1"), "OK");
+        assert_eq!(target.memory.borrow()[0x20], 0x99);
+        assert_eq!(request(&mut stub, "s"), "");
+        assert_eq!(*target.resumed.borrow(), [0x12000]);
+        assert_ne!(target.registers.borrow()[&0x12000].rflags & RFLAGS_TF, 0);
+        stub.out.clear();
+        assert!(stub.event(Event::Stop(Stop { tcs: 0x12000, signal: SIGTRAP })).unwrap());
+        assert!(String::from_utf8_lossy(&stub.out).contains("T05thread:12000;"));
+        assert_eq!(target.registers.borrow()[&0x12000].rflags & RFLAGS_TF, 0);
+
+        // A stop while not running is reported on the next continue
+        assert!(stub.event(Event::Stop(Stop { tcs: 0x11000, signal: SIGTRAP })).unwrap());
+        assert_eq!(request(&mut stub, "c"), "T05thread:11000;");
+
+        assert_eq!(request(&mut stub, "QStartNoAckMode"), "OK");
+        assert_eq!(request(&mut stub, "Z0,10030,1"), "OK");
+        assert_eq!(request(&mut stub, "c"), "");
+        assert_eq!(*target.resumed.borrow(), [0x12000, 0x12000, 0x11000]);
+        // Interrupting is refused, the threads keep running
+        stub.out.clear();
+        assert!(stub.event(Event::Input(vec![0x03])).unwrap());
+        assert!(String::from_utf8_lossy(&stub.out).starts_with("$O"));
+        stub.out.clear();
+        assert!(stub.event(Event::Stop(Stop { tcs: 0x11000, signal: SIGTRAP })).unwrap());
+        assert!(String::from_utf8_lossy(&stub.out).contains("T05thread:11000;"));
+
+        assert!(!stub.event(packet("D")).unwrap());
+        assert_eq!(target.memory.borrow()[0x30], 0x30);
+        assert!(!*target.killed.borrow());
+    }
+
+    #[test]
+    fn kill() {
+        let target = MockTarget::default();
+        let mut stub = GdbStub::new(&target, vec![]);
+        assert!(!stub.event(packet("k")).unwrap());
+        assert!(*target.killed.borrow());
+    }
+
+    #[test]
+    fn framing() {
+        let target = MockTarget::default();
+        let mut stub = GdbStub::new(&target, vec![]);
+        assert!(stub.event(Event::Input(b"+$qAttach".to_vec())).unwrap());
+        assert!(stub.out.is_empty());
+        assert!(stub.event(Event::Input(b"ed#8f$qAttached#00".to_vec())).unwrap());
+        assert_eq!(String::from_utf8(stub.out.clone()).unwrap(), "+$1#31-");
+        assert!(!stub.event(Event::Closed).unwrap());
+    }
+}
@@ -0,0 +1,225 @@
+/* Copyright (c) Fortanix, Inc.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Access to files in a host directory through `file:` addresses.
+
+use std::fs;
+use std::future::Future;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult};
+use std::path::{Component, Path, PathBuf};
+use std::pin::Pin;
+
+use fortanix_sgx_abi::CAPABILITY_EXTENSION_ADDRESSES;
+use futures::FutureExt;
+
+use super::{AsyncStream, UsercallExtension};
+
+/// How a file is opened, determined by the prefix of the address.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(super) enum FileMode {
+    /// `file:PATH`
+    Read,
+    /// `file+write:PATH`, creating or truncating the file
+    Write,
+    /// `file+append:PATH`, creating the file if needed
+    Append,
+}
+
+/// Split a `file:` address into the mode and the path.
+pub(super) fn parse_file_addr(addr: &str) -> Option<(FileMode, &str)> {
+    const PREFIXES: [(&str, FileMode); 3] = [
+        ("file:", FileMode::Read),
+        ("file+write:", FileMode::Write),
+        ("file+append:", FileMode::Append),
+    ];
+    PREFIXES
+        .iter()
+        .find(|(prefix, _)| addr.starts_with(prefix))
+        .map(|&(prefix, mode)| (mode, &addr[prefix.len()..]))
+}
+
+/// Whether the enclave may change files in a [`HostDirectory`].
+///
+/// [`HostDirectory`]: struct.HostDirectory.html
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DirectoryAccess {
+    ReadOnly,
+    ReadWrite,
+}
+
+/// A usercall extension that gives the enclave access to the files in a
+/// host directory and its subdirectories.
+///
+/// The enclave opens a file as a stream by connecting to one of the
+/// following addresses, with a path relative to the directory:
+/// * `file:PATH` to read the file,
+/// * `file+write:PATH` to create the file, or truncate it if it exists,
+/// * `file+append:PATH` to append to the file, creating it if needed.
+///
+/// For example, using the standard library in the enclave:
+///
+/// ```ignore
+/// let config = TcpStream::connect("file:config/app.toml")?;
+/// let log = TcpStream::connect("file+append:app.log")?;
+/// ```
+///
+/// Paths can't be absolute and can't contain `..`, and paths that resolve to
+/// a location outside the directory through symbolic links are rejected.
+/// Writing requires [`DirectoryAccess::ReadWrite`]. The enclave gets a
+/// `PermissionDenied` error otherwise. Other addresses are not handled by
+/// this extension. `file:` addresses are not subject to the
+/// [network policy].
+///
+/// **NOTE:** The host controls the contents of the directory, so the enclave
+/// must not trust the data it reads, and data written is not protected.
+///
+/// [`DirectoryAccess::ReadWrite`]: enum.DirectoryAccess.html#variant.ReadWrite
+/// [network policy]: ../struct.EnclaveBuilder.html#method.network_policy
+#[derive(Clone, Debug)]
+pub struct HostDirectory {
+    root: PathBuf,
+    access: DirectoryAccess,
+}
+
+impl HostDirectory {
+    /// Serve the files in the directory `root`, which must exist.
+    pub fn new<P: AsRef<Path>>(root: P, access: DirectoryAccess) -> IoResult<Self> {
+        let root = fs::canonicalize(root)?;
+        if !root.is_dir() {
+            return Err(IoError::new(IoErrorKind::InvalidInput, "not a directory"));
+        }
+        Ok(HostDirectory { root, access })
+    }
+
+    /// The location of `path` in the host file system, if it is inside the
+    /// directory.
+    fn resolve(&self, path: &str) -> IoResult<PathBuf> {
+        let path = Path::new(path);
+        let mut relative = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => relative.push(name),
+                Component::CurDir => {}
+                _ => return Err(IoErrorKind::PermissionDenied.into()),
+            }
+        }
+        let file_name = match relative.file_name() {
+            Some(name) => name.to_owned(),
+            None => return Err(IoErrorKind::InvalidInput.into()),
+        };
+
+        let full = self.root.join(&relative);
+        let resolved = match fs::canonicalize(&full) {
+            Ok(resolved) => resolved,
+            // A file that doesn't exist yet, in a directory that does
+            Err(ref e) if e.kind() == IoErrorKind::NotFound && fs::symlink_metadata(&full).is_err() => {
+                let parent = full.parent().ok_or(IoErrorKind::InvalidInput)?;
+                fs::canonicalize(parent)?.join(file_name)
+            }
+            Err(e) => return Err(e),
+        };
+        if !resolved.starts_with(&self.root) {
+            return Err(IoErrorKind::PermissionDenied.into());
+        }
+        Ok(resolved)
+    }
+
+    async fn open(&self, mode: FileMode, path: &str) -> IoResult<tokio::fs::File> {
+        if mode != FileMode::Read && self.access != DirectoryAccess::ReadWrite {
+            return Err(IoErrorKind::PermissionDenied.into());
+        }
+        let path = self.resolve(path)?;
+        let mut options = fs::OpenOptions::new();
+        match mode {
+            FileMode::Read => options.read(true),
+            FileMode::Write => options.write(true).create(true).truncate(true),
+            FileMode::Append => options.append(true).create(true),
+        };
+        // Don't follow a symbolic link created after the path was resolved
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.custom_flags(libc::O_NOFOLLOW);
+        }
+        tokio::fs::OpenOptions::from(options).open(path).await
+    }
+}
+
+impl UsercallExtension for HostDirectory {
+    fn connect_stream<'future>(
+        &'future self,
+        addr: &'future str,
+        local_addr: Option<&'future mut String>,
+        peer_addr: Option<&'future mut String>,
+    ) -> Pin<Box<dyn Future<Output = IoResult<Option<Box<dyn AsyncStream>>>> + 'future>> {
+        async move {
+            let (mode, path) = match parse_file_addr(addr) {
+                Some(file) => file,
+                None => return Ok(None),
+            };
+            let file = self.open(mode, path).await?;
+            if let Some(local_addr) = local_addr {
+                local_addr.push_str(addr);
+            }
+            if let Some(peer_addr) = peer_addr {
+                peer_addr.push_str(addr);
+            }
+            Ok(Some(Box::new(file) as Box<dyn AsyncStream>))
+        }
+        .boxed_local()
+    }
+
+    fn capabilities(&self) -> u64 {
+        CAPABILITY_EXTENSION_ADDRESSES
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses() {
+        assert_eq!(parse_file_addr("file:a/b"), Some((FileMode::Read, "a/b")));
+        assert_eq!(parse_file_addr("file+write:out"), Some((FileMode::Write, "out")));
+        assert_eq!(parse_file_addr("file+append:log"), Some((FileMode::Append, "log")));
+        assert_eq!(parse_file_addr("files:x"), None);
+        assert_eq!(parse_file_addr("example.com:80"), None);
+    }
+
+    #[test]
+    fn sandbox() {
+        let dir = std::env::temp_dir().join(format!("enclave-runner-host-directory-{}", std::process::id()));
+        let root = dir.join("root");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("sub/file"), b"data").unwrap();
+        fs::write(dir.join("secret"), b"secret").unwrap();
+
+        let read_only = HostDirectory::new(&root, DirectoryAccess::ReadOnly).unwrap();
+        let root = read_only.root.clone();
+        assert_eq!(read_only.resolve("sub/file").unwrap(), root.join("sub/file"));
+        assert_eq!(read_only.resolve("./sub/new").unwrap(), root.join("sub/new"));
+        let denied = |path| read_only.resolve(path).unwrap_err().kind() == IoErrorKind::PermissionDenied;
+        assert!(denied("../secret"));
+        assert!(denied("sub/../../secret"));
+        assert!(denied("/etc/passwd"));
+        assert!(read_only.resolve("missing/file").is_err());
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(dir.join("secret"), root.join("link")).unwrap();
+            assert!(denied("link"));
+        }
+
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let write = read_only.open(FileMode::Write, "new");
+        assert_eq!(rt.block_on(write).unwrap_err().kind(), IoErrorKind::PermissionDenied);
+        assert!(rt.block_on(read_only.open(FileMode::Read, "sub/file")).is_ok());
+        let read_write = HostDirectory::new(&root, DirectoryAccess::ReadWrite).unwrap();
+        assert!(rt.block_on(read_write.open(FileMode::Append, "sub/new")).is_ok());
+        assert!(root.join("sub/new").exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -6,13 +6,15 @@
 //! Adaptors between the usercall ABI types and functions and (mostly) safe
 //! Rust types.
 
+use std::alloc::Layout;
 use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult};
 use std::slice;
+use std::sync::Arc;
 
 use fortanix_sgx_abi::*;
 
-use super::abi::{UsercallResult, Usercalls};
-use super::{EnclaveAbort, IOHandlerInput};
+use super::abi::{DispatchResult, UsercallResult, Usercalls};
+use super::{EnclaveAbort, IOHandlerInput, ResourceCounters};
 use futures::FutureExt;
 use futures::future::Future;
 
@@ -23,6 +25,21 @@ impl<'future, 'ioinput: 'future, 'tcs: 'ioinput> Usercalls<'future> for Handler<
         self.0.is_exiting()
     }
 
+    fn other(
+        self,
+        n: u64,
+        a1: u64,
+        a2: u64,
+        a3: u64,
+        a4: u64,
+    ) -> std::pin::Pin<Box<dyn Future<Output = (Self, DispatchResult)> + 'future>> {
+        async move {
+            let ret = self.0.user_defined_usercall(n, a1, a2, a3, a4).await;
+            return (self, ret);
+        }
+            .boxed_local()
+    }
+
     fn read(
         self,
         fd: Fd,
@@ -53,7 +70,7 @@ impl<'future, 'ioinput: 'future, 'tcs: 'ioinput> Usercalls<'future> for Handler<
                 match buf.as_mut().ok_or(IoErrorKind::InvalidInput) {
                     Err(e) => ret = Err(e.into()),
                     Ok(k) => {
-                        let mut out = OutputBuffer::new(k);
+                        let mut out = OutputBuffer::new(k, self.0.enclave.resources.clone());
                         if !out.buf.data.is_null() {
                             ret = Err(IoErrorKind::InvalidInput.into());
                         } else {
@@ -117,7 +134,7 @@ impl<'future, 'ioinput: 'future, 'tcs: 'ioinput> Usercalls<'future> for Handler<
     {
         async move {
             unsafe {
-                let mut local_addr = local_addr.as_mut().map(OutputBuffer::new);
+                let mut local_addr = local_addr.as_mut().map(|buf| OutputBuffer::new(buf, self.0.enclave.resources.clone()));
                 let ret = match from_raw_parts_nonnull(addr, len) {
                     Ok(addr) => self.0.bind_stream(addr, local_addr.as_mut()).await,
                     Err(e) => Err(e),
@@ -137,8 +154,8 @@ impl<'future, 'ioinput: 'future, 'tcs: 'ioinput> Usercalls<'future> for Handler<
     {
         async move {
             unsafe {
-                let mut local_addr = local_addr.as_mut().map(OutputBuffer::new);
-                let mut peer_addr = peer_addr.as_mut().map(OutputBuffer::new);
+                let mut local_addr = local_addr.as_mut().map(|buf| OutputBuffer::new(buf, self.0.enclave.resources.clone()));
+                let mut peer_addr = peer_addr.as_mut().map(|buf| OutputBuffer::new(buf, self.0.enclave.resources.clone()));
                 let ret = Ok(self
                     .0
                     .accept_stream(fd, local_addr.as_mut(), peer_addr.as_mut())
@@ -160,8 +177,8 @@ impl<'future, 'ioinput: 'future, 'tcs: 'ioinput> Usercalls<'future> for Handler<
     {
         async move {
             unsafe {
-                let mut local_addr = local_addr.as_mut().map(OutputBuffer::new);
-                let mut peer_addr = peer_addr.as_mut().map(OutputBuffer::new);
+                let mut local_addr = local_addr.as_mut().map(|buf| OutputBuffer::new(buf, self.0.enclave.resources.clone()));
+                let mut peer_addr = peer_addr.as_mut().map(|buf| OutputBuffer::new(buf, self.0.enclave.resources.clone()));
 
                 let ret = match from_raw_parts_nonnull(addr, len) {
                     Ok(addr) => {
@@ -270,51 +287,78 @@ impl<'future, 'ioinput: 'future, 'tcs: 'ioinput> Usercalls<'future> for Handler<
     ) -> std::pin::Pin<Box<dyn Future<Output = (Self, UsercallResult<Result>)> + 'future>> {
         async move {
             unsafe {
-                let ret = Ok((|| {
-                    let usercall_queue = usercall_queue
-                        .as_mut()
-                        .ok_or(IoError::from(IoErrorKind::InvalidInput))?;
-                    let return_queue = return_queue
-                        .as_mut()
-                        .ok_or(IoError::from(IoErrorKind::InvalidInput))?;
-                    self.0.async_queues(usercall_queue, return_queue)
-                })()
-                .to_sgx_result());
+                let ret = match (usercall_queue.as_mut(), return_queue.as_mut()) {
+                    (Some(usercall_queue), Some(return_queue)) => self
+                        .0
+                        .async_queues(usercall_queue, return_queue)
+                        .map(|ret| ret.to_sgx_result()),
+                    _ => Ok(Err::<(), _>(IoError::from(IoErrorKind::InvalidInput)).to_sgx_result()),
+                };
                 return (self, ret);
             }
         }
             .boxed_local()
     }
+
+    fn capabilities(
+        self,
+    ) -> std::pin::Pin<Box<dyn Future<Output = (Self, UsercallResult<(u64, u64)>)> + 'future>> {
+        async move {
+            let ret = Ok((ABI_VERSION, self.0.capabilities()));
+            return (self, ret);
+        }
+            .boxed_local()
+    }
 }
 
+/// A `ByteBuffer` returned to the enclave, which the enclave deallocates
+/// with the `free` usercall. The data is copied to user memory when it's
+/// set, counting against the enclave's memory limit, and the `ByteBuffer`
+/// is filled in when this is dropped.
 pub(super) struct OutputBuffer<'a> {
     buf: &'a mut ByteBuffer,
-    data: Option<Box<[u8]>>,
+    resources: Arc<ResourceCounters>,
+    data: Option<(*mut u8, usize)>,
 }
 
 impl<'a> OutputBuffer<'a> {
-    fn new(buf: &'a mut ByteBuffer) -> Self {
-        OutputBuffer { buf, data: None }
+    pub(super) fn new(buf: &'a mut ByteBuffer, resources: Arc<ResourceCounters>) -> Self {
+        OutputBuffer { buf, resources, data: None }
+    }
+
+    pub(super) fn set<T: AsRef<[u8]>>(&mut self, value: T) -> IoResult<()> {
+        let value = value.as_ref();
+        let ptr = self.resources.alloc_user_bytes(value)?;
+        self.free();
+        self.data = Some((ptr, value.len()));
+        Ok(())
+    }
+
+    pub(super) fn get(&self) -> Option<&[u8]> {
+        self.data.map(|(ptr, len)| unsafe { slice::from_raw_parts(ptr, len) })
     }
 
-    pub(super) fn set<T: Into<Box<[u8]>>>(&mut self, value: T) {
-        // NB. this should use the same allocator as usercall alloc/free
-        self.data = Some(value.into());
+    fn free(&mut self) {
+        if let Some((ptr, len)) = self.data.take() {
+            if len > 0 {
+                unsafe { self.resources.free_user(ptr, Layout::from_size_align(len, 1).unwrap()) };
+            }
+        }
     }
 }
 
 impl<'a> Drop for OutputBuffer<'a> {
     fn drop(&mut self) {
-        if let Some(buf) = self.data.take() {
-            self.buf.len = buf.len();
-            self.buf.data = Box::into_raw(buf) as _;
+        if let Some((ptr, len)) = self.data.take() {
+            self.buf.len = len;
+            self.buf.data = ptr as _;
         } else {
             self.buf.len = 0;
         }
     }
 }
 
-fn result_from_io_error(err: IoError) -> Result {
+pub(super) fn result_from_io_error(err: IoError) -> Result {
     let ret = match err.kind() {
         IoErrorKind::NotFound => Error::NotFound,
         IoErrorKind::PermissionDenied => Error::PermissionDenied,
@@ -0,0 +1,616 @@
+/* Copyright (c) Fortanix, Inc.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Counters of the usercalls, I/O and threads of an enclave.
+
+use std::fmt::Write as FmtWrite;
+use std::io::{Read, Result as IoResult, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use fnv::FnvHashMap;
+use fortanix_sgx_abi::Fd;
+
+use super::abi::{usercall_info, UsercallResult};
+
+/// Upper bounds of the buckets of the usercall latency histograms.
+const LATENCY_BUCKETS: [Duration; 8] = [
+    Duration::from_micros(1),
+    Duration::from_micros(10),
+    Duration::from_micros(100),
+    Duration::from_millis(1),
+    Duration::from_millis(10),
+    Duration::from_millis(100),
+    Duration::from_secs(1),
+    Duration::from_secs(10),
+];
+
+/// Usercall numbers are small, so counters are indexed by number. Number 0
+/// collects usercalls with invalid numbers.
+const USERCALL_SLOTS: usize = 64;
+
+#[derive(Debug, Default)]
+struct UsercallCounters {
+    count: AtomicU64,
+    errors: AtomicU64,
+    /// The count of each bucket, the last one being unbounded
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_nanos: AtomicU64,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct FdBytes {
+    read: u64,
+    written: u64,
+}
+
+/// Metrics counters of an enclave, updated while it runs.
+#[derive(Debug)]
+pub(crate) struct MetricsCounters {
+    usercalls: Vec<UsercallCounters>,
+    /// Bytes transferred through open file descriptors
+    fds: Mutex<FnvHashMap<Fd, FdBytes>>,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    tcs_total: AtomicU64,
+    tcs_stopped: AtomicU64,
+    tcs_waiting: AtomicU64,
+    launches_queued: AtomicU64,
+    launches_failed: AtomicU64,
+    threads_failed: AtomicU64,
+    traps: AtomicU64,
+    network_denials: AtomicU64,
+    signal_forwarding_errors: AtomicU64,
+    signal_aborts: AtomicU64,
+}
+
+impl Default for MetricsCounters {
+    fn default() -> Self {
+        MetricsCounters {
+            usercalls: (0..USERCALL_SLOTS).map(|_| UsercallCounters::default()).collect(),
+            fds: Mutex::default(),
+            bytes_read: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            tcs_total: AtomicU64::new(0),
+            tcs_stopped: AtomicU64::new(0),
+            tcs_waiting: AtomicU64::new(0),
+            launches_queued: AtomicU64::new(0),
+            launches_failed: AtomicU64::new(0),
+            threads_failed: AtomicU64::new(0),
+            traps: AtomicU64::new(0),
+            network_denials: AtomicU64::new(0),
+            signal_forwarding_errors: AtomicU64::new(0),
+            signal_aborts: AtomicU64::new(0),
+        }
+    }
+}
+
+impl MetricsCounters {
+    /// Account for a completed usercall.
+    pub(super) fn usercall(&self, n: u64, duration: Duration, result: &UsercallResult<(u64, u64)>) {
+        let info = usercall_info(n);
+        let slot = match info {
+            Some(_) if (n as usize) < USERCALL_SLOTS => n as usize,
+            _ => 0,
+        };
+        let failed = match *result {
+            Ok((value, _)) => info.map_or(false, |info| info.returns_result && value != 0),
+            Err(_) => true,
+        };
+
+        let counters = &self.usercalls[slot];
+        counters.count.fetch_add(1, Ordering::Relaxed);
+        if failed {
+            counters.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&bound| duration <= bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        counters.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        counters.sum_nanos.fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub(super) fn read(&self, fd: Fd, bytes: usize) {
+        self.bytes_read.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.fds.lock().unwrap().entry(fd).or_default().read += bytes as u64;
+    }
+
+    pub(super) fn written(&self, fd: Fd, bytes: usize) {
+        self.bytes_written.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.fds.lock().unwrap().entry(fd).or_default().written += bytes as u64;
+    }
+
+    pub(super) fn closed(&self, fd: Fd) {
+        self.fds.lock().unwrap().remove(&fd);
+    }
+
+    pub(super) fn set_tcs_total(&self, total: usize) {
+        self.tcs_total.store(total as u64, Ordering::Relaxed);
+    }
+
+    pub(super) fn tcs_stopped(&self) {
+        self.tcs_stopped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn tcs_started(&self) {
+        self.tcs_stopped.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Count a thread as waiting until the returned guard is dropped.
+    pub(super) fn tcs_waiting(&self) -> WaitingGuard<'_> {
+        self.tcs_waiting.fetch_add(1, Ordering::Relaxed);
+        WaitingGuard(self)
+    }
+
+    pub(super) fn set_launches_queued(&self, queued: usize) {
+        self.launches_queued.store(queued as u64, Ordering::Relaxed);
+    }
+
+    pub(super) fn launch_failed(&self) {
+        self.launches_failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn thread_failed(&self) {
+        self.threads_failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn trap(&self) {
+        self.traps.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn network_denied(&self) {
+        self.network_denials.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn signal_forwarding_failed(&self) {
+        self.signal_forwarding_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn signal_abort(&self) {
+        self.signal_aborts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> EnclaveMetrics {
+        let usercalls = self
+            .usercalls
+            .iter()
+            .enumerate()
+            .filter(|(_, counters)| counters.count.load(Ordering::Relaxed) != 0)
+            .map(|(n, counters)| {
+                let mut cumulative = 0;
+                let mut buckets = vec![];
+                for (bound, count) in LATENCY_BUCKETS.iter().zip(&counters.buckets) {
+                    cumulative += count.load(Ordering::Relaxed);
+                    buckets.push((*bound, cumulative));
+                }
+                cumulative += counters.buckets[LATENCY_BUCKETS.len()].load(Ordering::Relaxed);
+                UsercallMetrics {
+                    name: usercall_info(n as u64).filter(|_| n != 0).map(|info| info.name),
+                    count: counters.count.load(Ordering::Relaxed),
+                    errors: counters.errors.load(Ordering::Relaxed),
+                    latency: LatencyHistogram {
+                        buckets,
+                        count: cumulative,
+                        sum: Duration::from_nanos(counters.sum_nanos.load(Ordering::Relaxed)),
+                    },
+                }
+            })
+            .collect();
+
+        let mut fds = self
+            .fds
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&fd, bytes)| FdMetrics {
+                fd,
+                bytes_read: bytes.read,
+                bytes_written: bytes.written,
+            })
+            .collect::<Vec<_>>();
+        fds.sort_by_key(|fd| fd.fd);
+
+        let total = self.tcs_total.load(Ordering::Relaxed);
+        let stopped = self.tcs_stopped.load(Ordering::Relaxed);
+        let waiting = self.tcs_waiting.load(Ordering::Relaxed);
+        EnclaveMetrics {
+            usercalls,
+            fds,
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            tcs: TcsMetrics {
+                total,
+                busy: total.saturating_sub(stopped + waiting),
+                stopped,
+                waiting,
+                launches_queued: self.launches_queued.load(Ordering::Relaxed),
+                launches_failed: self.launches_failed.load(Ordering::Relaxed),
+                threads_failed: self.threads_failed.load(Ordering::Relaxed),
+            },
+            traps: self.traps.load(Ordering::Relaxed),
+            network_denials: self.network_denials.load(Ordering::Relaxed),
+            signal_forwarding_errors: self.signal_forwarding_errors.load(Ordering::Relaxed),
+            signal_aborts: self.signal_aborts.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub(super) struct WaitingGuard<'a>(&'a MetricsCounters);
+
+impl Drop for WaitingGuard<'_> {
+    fn drop(&mut self) {
+        self.0.tcs_waiting.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A snapshot of the metrics of an enclave.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EnclaveMetrics {
+    /// The usercalls the enclave has performed, by type.
+    pub usercalls: Vec<UsercallMetrics>,
+    /// The bytes transferred through each open file descriptor.
+    pub fds: Vec<FdMetrics>,
+    /// The bytes read from all file descriptors, including closed ones.
+    pub bytes_read: u64,
+    /// The bytes written to all file descriptors, including closed ones.
+    pub bytes_written: u64,
+    pub tcs: TcsMetrics,
+    /// The number of times an enclave thread stopped on a debug trap, i.e.
+    /// on a breakpoint or single step while being debugged with the gdb
+    /// server. Panics aren't counted, even though a debugger can attach
+    /// when the enclave panics.
+    ///
+    /// Other asynchronous exits, such as those caused by interrupts, are
+    /// resumed by the processor without involving the runner, so they
+    /// aren't counted.
+    pub traps: u64,
+    /// The number of `connect_stream` and `bind_stream` usercalls denied by
+    /// the [`NetworkPolicy`](struct.NetworkPolicy.html).
+    pub network_denials: u64,
+    /// The number of times the runner couldn't listen for termination
+    /// signals to forward to the enclave, see
+    /// [`EnclaveBuilder::forward_signals`].
+    ///
+    /// [`EnclaveBuilder::forward_signals`]: ../struct.EnclaveBuilder.html#method.forward_signals
+    pub signal_forwarding_errors: u64,
+    /// The number of times the enclave was aborted because it didn't exit
+    /// within the grace period after a termination signal was forwarded.
+    pub signal_aborts: u64,
+}
+
+/// Usercalls of one type.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UsercallMetrics {
+    /// The usercall name, or `None` for usercalls not defined by the ABI
+    pub name: Option<&'static str>,
+    pub count: u64,
+    /// The number of usercalls that returned an error or aborted the
+    /// enclave.
+    pub errors: u64,
+    pub latency: LatencyHistogram,
+}
+
+/// A histogram of durations.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LatencyHistogram {
+    /// The upper bound of each bucket, with the number of durations up to
+    /// that bound. Durations above the last bound are only included in
+    /// `count`.
+    pub buckets: Vec<(Duration, u64)>,
+    pub count: u64,
+    pub sum: Duration,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FdMetrics {
+    pub fd: Fd,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+/// The states of the TCSs of an enclave.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TcsMetrics {
+    pub total: u64,
+    /// TCSs running enclave code or performing usercalls other than `wait`
+    pub busy: u64,
+    /// TCSs that aren't in use, and can be used to launch a thread or to
+    /// call into a library
+    pub stopped: u64,
+    /// TCSs blocked in the `wait` usercall
+    pub waiting: u64,
+    /// Threads launched by the enclave that are waiting for a TCS to
+    /// become available, see [`LaunchThreadPolicy::Queue`]
+    ///
+    /// [`LaunchThreadPolicy::Queue`]: enum.LaunchThreadPolicy.html#variant.Queue
+    pub launches_queued: u64,
+    /// Thread launches that failed because no TCS was available, or because
+    /// the host thread to run a library's thread on couldn't be created
+    pub launches_failed: u64,
+    /// Threads launched by a library that returned an error or panicked
+    pub threads_failed: u64,
+}
+
+/// A handle to read the metrics of an enclave.
+///
+/// Obtained from [`Command::metrics`] or [`Library::metrics`]. The handle
+/// remains valid after the enclave exits.
+///
+/// [`Command::metrics`]: ../struct.Command.html#method.metrics
+/// [`Library::metrics`]: ../struct.Library.html#method.metrics
+#[derive(Clone, Debug)]
+pub struct MetricsMonitor(pub(crate) Arc<MetricsCounters>);
+
+impl MetricsMonitor {
+    /// The current metrics of the enclave.
+    pub fn metrics(&self) -> EnclaveMetrics {
+        self.0.snapshot()
+    }
+}
+
+/// Exports the metrics of enclaves in the Prometheus text format.
+///
+/// Each enclave is registered with a name, which is used as the `enclave`
+/// label of its metrics. Enclaves that have exited can be unregistered to
+/// stop exporting their metrics. The metrics can be served over HTTP, or
+/// rendered to be served by other means.
+#[derive(Clone, Debug, Default)]
+pub struct PrometheusExporter(Arc<Mutex<Vec<(String, MetricsMonitor)>>>);
+
+impl PrometheusExporter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Export the metrics of an enclave, replacing any enclave that was
+    /// registered with the same name.
+    pub fn register<S: Into<String>>(&self, enclave: S, monitor: MetricsMonitor) {
+        let enclave = enclave.into();
+        let mut enclaves = self.0.lock().unwrap();
+        enclaves.retain(|(name, _)| *name != enclave);
+        enclaves.push((enclave, monitor));
+    }
+
+    /// Stop exporting the metrics of the enclave registered with the name
+    /// `enclave`. Returns its monitor, if it was registered.
+    pub fn unregister(&self, enclave: &str) -> Option<MetricsMonitor> {
+        let mut enclaves = self.0.lock().unwrap();
+        let i = enclaves.iter().position(|(name, _)| name == enclave)?;
+        Some(enclaves.remove(i).1)
+    }
+
+    /// The current metrics of all registered enclaves.
+    pub fn render(&self) -> String {
+        let enclaves = self
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, monitor)| (format!("enclave=\"{}\"", escape_label(name)), monitor.metrics()))
+            .collect::<Vec<_>>();
+
+        let mut out = String::new();
+        let mut family = |name: &str, ty: &str, help: &str, samples: &mut dyn FnMut(&mut String)| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, ty);
+            samples(&mut out);
+        };
+
+        family("enclave_usercalls_total", "counter", "Usercalls performed by the enclave.", &mut |out| {
+            for (enclave, metrics) in &enclaves {
+                for usercall in &metrics.usercalls {
+                    let _ = writeln!(out, "enclave_usercalls_total{{{},usercall=\"{}\"}} {}", enclave, usercall.name.unwrap_or("unknown"), usercall.count);
+                }
+            }
+        });
+        family("enclave_usercall_errors_total", "counter", "Usercalls that returned an error or aborted the enclave.", &mut |out| {
+            for (enclave, metrics) in &enclaves {
+                for usercall in &metrics.usercalls {
+                    let _ = writeln!(out, "enclave_usercall_errors_total{{{},usercall=\"{}\"}} {}", enclave, usercall.name.unwrap_or("unknown"), usercall.errors);
+                }
+            }
+        });
+        family("enclave_usercall_duration_seconds", "histogram", "Time taken to handle usercalls.", &mut |out| {
+            const NAME: &str = "enclave_usercall_duration_seconds";
+            for (enclave, metrics) in &enclaves {
+                for usercall in &metrics.usercalls {
+                    let labels = format!("{},usercall=\"{}\"", enclave, usercall.name.unwrap_or("unknown"));
+                    let latency = &usercall.latency;
+                    for (bound, count) in &latency.buckets {
+                        let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", NAME, labels, bound.as_secs_f64(), count);
+                    }
+                    let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", NAME, labels, latency.count);
+                    let _ = writeln!(out, "{}_sum{{{}}} {}", NAME, labels, latency.sum.as_secs_f64());
+                    let _ = writeln!(out, "{}_count{{{}}} {}", NAME, labels, latency.count);
+                }
+            }
+        });
+        family("enclave_fd_read_bytes_total", "counter", "Bytes read from open file descriptors.", &mut |out| {
+            for (enclave, metrics) in &enclaves {
+                for fd in &metrics.fds {
+                    let _ = writeln!(out, "enclave_fd_read_bytes_total{{{},fd=\"{}\"}} {}", enclave, fd.fd, fd.bytes_read);
+                }
+            }
+        });
+        family("enclave_fd_written_bytes_total", "counter", "Bytes written to open file descriptors.", &mut |out| {
+            for (enclave, metrics) in &enclaves {
+                for fd in &metrics.fds {
+                    let _ = writeln!(out, "enclave_fd_written_bytes_total{{{},fd=\"{}\"}} {}", enclave, fd.fd, fd.bytes_written);
+                }
+            }
+        });
+        family("enclave_read_bytes_total", "counter", "Bytes read from all file descriptors.", &mut |out| {
+            for (enclave, metrics) in &enclaves {
+                let _ = writeln!(out, "enclave_read_bytes_total{{{}}} {}", enclave, metrics.bytes_read);
+            }
+        });
+        family("enclave_written_bytes_total", "counter", "Bytes written to all file descriptors.", &mut |out| {
+            for (enclave, metrics) in &enclaves {
+                let _ = writeln!(out, "enclave_written_bytes_total{{{}}} {}", enclave, metrics.bytes_written);
+            }
+        });
+        family("enclave_tcs", "gauge", "TCSs of the enclave by state.", &mut |out| {
+            for (enclave, metrics) in &enclaves {
+                let tcs = &metrics.tcs;
+                for &(state, value) in &[("busy", tcs.busy), ("stopped", tcs.stopped), ("waiting", tcs.waiting)] {
+                    let _ = writeln!(out, "enclave_tcs{{{},state=\"{}\"}} {}", enclave, state, value);
+                }
+            }
+        });
+        family("enclave_thread_launches_queued", "gauge", "Thread launches waiting for a TCS.", &mut |out| {
+            for (enclave, metrics) in &enclaves {
+                let _ = writeln!(out, "enclave_thread_launches_queued{{{}}} {}", enclave, metrics.tcs.launches_queued);
+            }
+        });
+        family("enclave_thread_launches_failed_total", "counter", "Thread launches that failed for lack of a TCS or host thread.", &mut |out| {
+            for (enclave, metrics) in &enclaves {
+                let _ = writeln!(out, "enclave_thread_launches_failed_total{{{}}} {}", enclave, metrics.tcs.launches_failed);
+            }
+        });
+        family("enclave_library_threads_failed_total", "counter", "Threads launched by a library that failed.", &mut |out| {
+            for (enclave, metrics) in &enclaves {
+                let _ = writeln!(out, "enclave_library_threads_failed_total{{{}}} {}", enclave, metrics.tcs.threads_failed);
+            }
+        });
+        family("enclave_traps_total", "counter", "Debug traps of enclave threads.", &mut |out| {
+            for (enclave, metrics) in &enclaves {
+                let _ = writeln!(out, "enclave_traps_total{{{}}} {}", enclave, metrics.traps);
+            }
+        });
+        family("enclave_network_denials_total", "counter", "Connections and binds denied by the network policy.", &mut |out| {
+            for (enclave, metrics) in &enclaves {
+                let _ = writeln!(out, "enclave_network_denials_total{{{}}} {}", enclave, metrics.network_denials);
+            }
+        });
+        family("enclave_signal_forwarding_errors_total", "counter", "Failures to listen for termination signals to forward.", &mut |out| {
+            for (enclave, metrics) in &enclaves {
+                let _ = writeln!(out, "enclave_signal_forwarding_errors_total{{{}}} {}", enclave, metrics.signal_forwarding_errors);
+            }
+        });
+        family("enclave_signal_aborts_total", "counter", "Aborts after the signal grace period expired.", &mut |out| {
+            for (enclave, metrics) in &enclaves {
+                let _ = writeln!(out, "enclave_signal_aborts_total{{{}}} {}", enclave, metrics.signal_aborts);
+            }
+        });
+        out
+    }
+
+    /// Serve the metrics over HTTP in the background, and return the
+    /// address the server is listening on. Every request is answered with
+    /// the metrics, regardless of the path. Each connection is handled on
+    /// its own thread, so a slow client doesn't delay the others.
+    pub fn serve<A: ToSocketAddrs>(&self, addr: A) -> IoResult<SocketAddr> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let exporter = self.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if let Ok(stream) = stream {
+                    let exporter = exporter.clone();
+                    thread::spawn(move || exporter.respond(stream));
+                }
+            }
+        });
+        Ok(local_addr)
+    }
+
+    fn respond(&self, mut stream: TcpStream) -> IoResult<()> {
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        // Read the request headers
+        let mut request = vec![];
+        let mut buf = [0u8; 1024];
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 16 * 1024 {
+            match stream.read(&mut buf)? {
+                0 => break,
+                n => request.extend_from_slice(&buf[..n]),
+            }
+        }
+        let body = self.render();
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics() {
+        let counters = Arc::new(MetricsCounters::default());
+        let monitor = MetricsMonitor(counters.clone());
+        let write = (1..64).find(|&n| usercall_info(n).map(|info| info.name) == Some("write")).unwrap();
+        counters.usercall(write, Duration::from_micros(5), &Ok((0, 0)));
+        counters.usercall(write, Duration::from_secs(20), &Ok((1, 0)));
+        counters.usercall(1000, Duration::from_micros(1), &Ok((0, 0)));
+        // Only usercalls returning a `Result` can fail by returning a value
+        let time = (1..64).find(|&n| usercall_info(n).map(|info| info.name) == Some("insecure_time")).unwrap();
+        counters.usercall(time, Duration::from_micros(1), &Ok((1, 0)));
+        counters.written(1, 10);
+        counters.written(5, 3);
+        counters.read(5, 7);
+        counters.closed(5);
+        counters.set_tcs_total(4);
+        counters.tcs_stopped();
+        counters.tcs_stopped();
+        counters.tcs_started();
+        let waiting = counters.tcs_waiting();
+        counters.set_launches_queued(2);
+        counters.launch_failed();
+        counters.thread_failed();
+        counters.network_denied();
+        counters.signal_abort();
+
+        let metrics = monitor.metrics();
+        assert_eq!(metrics.usercalls.len(), 3);
+        assert_eq!(metrics.usercalls[0].name, None);
+        let time_usercall = metrics.usercalls.iter().find(|usercall| usercall.name == Some("insecure_time")).unwrap();
+        assert_eq!((time_usercall.count, time_usercall.errors), (1, 0));
+        let usercall = metrics.usercalls.iter().find(|usercall| usercall.name == Some("write")).unwrap();
+        assert_eq!(usercall.name, Some("write"));
+        assert_eq!((usercall.count, usercall.errors), (2, 1));
+        assert_eq!(usercall.latency.buckets[1], (Duration::from_micros(10), 1));
+        assert_eq!(usercall.latency.buckets[7], (Duration::from_secs(10), 1));
+        assert_eq!(usercall.latency.count, 2);
+        assert_eq!(metrics.fds, [FdMetrics { fd: 1, bytes_read: 0, bytes_written: 10 }]);
+        assert_eq!((metrics.bytes_read, metrics.bytes_written), (7, 13));
+        assert_eq!(
+            metrics.tcs,
+            TcsMetrics { total: 4, busy: 2, stopped: 1, waiting: 1, launches_queued: 2, launches_failed: 1, threads_failed: 1 }
+        );
+        assert_eq!(metrics.network_denials, 1);
+        assert_eq!((metrics.signal_forwarding_errors, metrics.signal_aborts), (0, 1));
+        drop(waiting);
+        assert_eq!(monitor.metrics().tcs.waiting, 0);
+
+        let exporter = PrometheusExporter::new();
+        exporter.register("app \"1\"", monitor);
+        let text = exporter.render();
+        assert!(text.contains("# TYPE enclave_usercall_duration_seconds histogram\n"));
+        assert!(text.contains("enclave_usercalls_total{enclave=\"app \\\"1\\\"\",usercall=\"write\"} 2\n"));
+        assert!(text.contains("enclave_usercall_duration_seconds_bucket{enclave=\"app \\\"1\\\"\",usercall=\"write\",le=\"0.00001\"} 1\n"));
+        assert!(text.contains("enclave_usercall_duration_seconds_bucket{enclave=\"app \\\"1\\\"\",usercall=\"write\",le=\"+Inf\"} 2\n"));
+        assert!(text.contains("enclave_tcs{enclave=\"app \\\"1\\\"\",state=\"busy\"} 3\n"));
+        assert!(text.contains("enclave_thread_launches_queued{enclave=\"app \\\"1\\\"\"} 2\n"));
+        assert!(text.contains("enclave_network_denials_total{enclave=\"app \\\"1\\\"\"} 1\n"));
+        assert!(text.contains("enclave_signal_aborts_total{enclave=\"app \\\"1\\\"\"} 1\n"));
+
+        assert!(exporter.unregister("app \"1\"").is_some());
+        assert!(exporter.unregister("app \"1\"").is_none());
+        assert!(!exporter.render().contains("enclave="));
+    }
+}
@@ -9,17 +9,19 @@ extern crate libc;
 #[cfg(all(unix, not(target_abi = "musl")))]
 extern crate nix;
 
-use std::alloc::{GlobalAlloc, Layout, System};
+use std::alloc::Layout;
 use std::cell::RefCell;
 use std::collections::VecDeque;
 use std::io::{self, ErrorKind as IoErrorKind, Read, Result as IoResult};
 use std::result::Result as StdResult;
 use std::str;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::{cmp, fmt};
+use std::ops::Range;
+use std::panic::AssertUnwindSafe;
+use std::{cmp, fmt, mem, ptr, slice};
 use std::pin::Pin;
 
-use std::sync::Arc;
+use std::sync::{Arc, Condvar, Mutex as StdMutex};
 use std::thread;
 use std::time;
 use std::task::{Poll, Context, Waker};
@@ -41,25 +43,85 @@ lazy_static! {
 }
 
 pub(crate) mod abi;
+mod clock;
+mod coredump;
+mod debug;
+mod fifo;
+mod gdbstub;
+mod host_directory;
 mod interface;
-
-use self::abi::dispatch;
+mod metrics;
+mod network_policy;
+mod panic;
+mod replay;
+mod resources;
+mod runtime;
+mod threads;
+mod trace;
+
+use self::abi::{dispatch, UsercallResult};
+pub use self::clock::{AcceleratedClock, Clock, FixedClock, OffsetClock, RecordedClock, SystemClock};
+pub(crate) use self::coredump::CoreDumper;
+pub(crate) use self::gdbstub::GdbServer;
+use self::host_directory::parse_file_addr;
+pub use self::host_directory::{DirectoryAccess, HostDirectory};
+use self::fifo::Fifo;
 use self::interface::{Handler, OutputBuffer};
+pub(crate) use self::metrics::MetricsCounters;
+pub use self::metrics::{
+    EnclaveMetrics, FdMetrics, LatencyHistogram, MetricsMonitor, PrometheusExporter, TcsMetrics, UsercallMetrics,
+};
+use self::network_policy::CheckedAddr;
+pub use self::network_policy::{
+    HostPattern, InvalidHostPattern, NetworkAction, NetworkOperation, NetworkPolicy, NetworkRule,
+    PortRange,
+};
+use self::panic::ThreadContext;
+pub use self::panic::{AbortReason, EnclavePanicReport, ThreadReport};
+pub(crate) use self::replay::{UsercallRecorder, UsercallReplayer, UsercallSession};
+use self::resources::{Resource, ResourceGuard};
+pub(crate) use self::resources::ResourceCounters;
+pub use self::resources::{ResourceLimits, ResourceMonitor, ResourceUsage};
+use self::runtime::{RunGroup, RunQueue};
+pub use self::runtime::{EnclaveRuntime, EnclaveRuntimeBuilder};
+use self::threads::{Launch, ThreadsQueue};
+pub use self::threads::LaunchThreadPolicy;
+use self::trace::UsercallTracer;
+pub use self::trace::{
+    JsonTraceSink, TextTraceSink, UsercallArg, UsercallOutcome, UsercallRecord, UsercallTraceSink,
+};
 #[cfg(all(unix, not(target_abi = "musl")))]
 use self::libc::{c_int, c_void, siginfo_t, ucontext_t};
 #[cfg(all(unix, not(target_abi = "musl")))]
 use self::nix::sys::signal;
+use crate::command::{CommandAborted, CommandPanicked};
+use crate::library::TcsUnavailable;
 use crate::loader::{EnclavePanic, ErasedTcs};
+use crate::symbolize::Symbolizer;
 use crate::tcs;
 use crate::tcs::{CoResult, ThreadResult};
 use std::thread::JoinHandle;
 
 const EV_ABORT: u64 = 0b0000_0000_0000_1000;
 
-type UsercallSendData = (ThreadResult<ErasedTcs>, RunningTcs, RefCell<[u8; 1024]>);
+/// Number of elements in each of the asynchronous usercall queues.
+const ASYNC_QUEUE_LEN: usize = 256;
+
+/// Prefix of the addresses of Unix domain sockets in stream usercalls.
+const UNIX_SOCKET_PREFIX: &str = "unix:";
+
+/// What a worker reports to the usercall loop after running `Work`
+enum UsercallSendData {
+    Thread(ThreadResult<ErasedTcs>, RunningTcs, RefCell<[u8; 1024]>),
+    /// The worker panicked while running enclave code, with the panic
+    /// message. The TCS is lost, so the thread can't continue.
+    Panicked(EnclaveEntry, String),
+}
+/// How an enclave entry ended, reported to the usercall loop
+type ThreadResultData = (StdResult<(u64, u64), EnclaveAbort<EnclavePanic>>, EnclaveEntry, ThreadContext);
 
-struct ReadOnly<R>(Pin<Box<R>>);
-struct WriteOnly<W>(Pin<Box<W>>);
+struct ReadOnly<R: ?Sized>(Pin<Box<R>>);
+struct WriteOnly<W: ?Sized>(Pin<Box<W>>);
 
 macro_rules! forward {
     (fn $n:ident(mut self: Pin<&mut Self> $(, $p:ident : $t:ty)*) -> $ret:ty) => {
@@ -69,17 +131,17 @@ macro_rules! forward {
     }
 }
 
-impl<R: std::marker::Unpin + AsyncRead> AsyncRead for ReadOnly<R> {
+impl<R: ?Sized + AsyncRead> AsyncRead for ReadOnly<R> {
     forward!(fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<tokio::io::Result<usize>>);
 }
 
-impl<T> AsyncRead for WriteOnly<T> {
+impl<T: ?Sized> AsyncRead for WriteOnly<T> {
     fn poll_read(self: Pin<&mut Self>, _cx: &mut Context, _buf: &mut [u8]) -> Poll<tokio::io::Result<usize>> {
         Poll::Ready(Err(IoErrorKind::BrokenPipe.into()))
     }
 }
 
-impl<T> AsyncWrite for ReadOnly<T> {
+impl<T: ?Sized> AsyncWrite for ReadOnly<T> {
     fn poll_write(self: Pin<&mut Self>, _cx: &mut Context, _buf: &[u8]) -> Poll<tokio::io::Result<usize>> {
         Poll::Ready(Err(IoErrorKind::BrokenPipe.into()))
     }
@@ -93,7 +155,7 @@ impl<T> AsyncWrite for ReadOnly<T> {
     }
 }
 
-impl<W: std::marker::Unpin + AsyncWrite> AsyncWrite for WriteOnly<W> {
+impl<W: ?Sized + AsyncWrite> AsyncWrite for WriteOnly<W> {
     forward!(fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<tokio::io::Result<usize>>);
     forward!(fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<tokio::io::Result<()>>);
     forward!(fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<tokio::io::Result<()>>);
@@ -166,6 +228,75 @@ impl AsyncRead for Stdin {
     }
 }
 
+/// A stream that discards writes and is always at end-of-file.
+struct Null;
+
+impl AsyncRead for Null {
+    fn poll_read(self: Pin<&mut Self>, _cx: &mut Context, _buf: &mut [u8]) -> Poll<tokio::io::Result<usize>> {
+        Poll::Ready(Ok(0))
+    }
+}
+
+impl AsyncWrite for Null {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context, buf: &[u8]) -> Poll<tokio::io::Result<usize>> {
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<tokio::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<tokio::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Describes what to connect one of the standard streams of an enclave to.
+///
+/// Standard streams can be configured while [building](../struct.EnclaveBuilder.html#method.stdin)
+/// the enclave. By default, the enclave uses the standard streams of the runner.
+pub struct Stdio(Option<Pin<Box<dyn AsyncStream>>>);
+
+impl Stdio {
+    /// Use the corresponding standard stream of the runner.
+    pub fn inherit() -> Stdio {
+        Stdio(None)
+    }
+
+    /// Reading returns end-of-file and writes are discarded.
+    pub fn null() -> Stdio {
+        Stdio::stream(Null)
+    }
+
+    /// Use `stream` for reading or writing, as appropriate for the standard
+    /// stream being configured.
+    pub fn stream<S: AsyncStream>(stream: S) -> Stdio {
+        Stdio(Some(Box::pin(stream)))
+    }
+}
+
+impl Default for Stdio {
+    fn default() -> Stdio {
+        Stdio::inherit()
+    }
+}
+
+impl fmt::Debug for Stdio {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            None => f.write_str("Stdio::inherit()"),
+            Some(_) => f.write_str("Stdio::stream(..)"),
+        }
+    }
+}
+
+/// Use an open file or pipe.
+impl From<std::fs::File> for Stdio {
+    fn from(file: std::fs::File) -> Stdio {
+        Stdio::stream(tokio::fs::File::from_std(file))
+    }
+}
+
 pub trait AsyncStream: AsyncRead + AsyncWrite + 'static + Send + Sync {
     fn poll_read_alloc(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<Vec<u8>>>
     {
@@ -416,6 +547,44 @@ impl AsyncListener for tokio::net::TcpListener {
     }
 }
 
+#[cfg(unix)]
+fn unix_socket_addr(addr: IoResult<std::os::unix::net::SocketAddr>) -> String {
+    match addr {
+        Ok(addr) => match addr.as_pathname() {
+            Some(path) => format!("{}{}", UNIX_SOCKET_PREFIX, path.display()),
+            None => UNIX_SOCKET_PREFIX.to_owned(),
+        },
+        Err(_) => "error".to_owned(),
+    }
+}
+
+#[cfg(unix)]
+impl AsyncListener for tokio::net::UnixListener {
+    fn poll_accept(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        local_addr: Option<&mut String>,
+        peer_addr: Option<&mut String>,
+    ) -> Poll<tokio::io::Result<Option<Box<dyn AsyncStream>>>> {
+        let mut incoming = self.incoming();
+        let inner = Pin::new(&mut incoming);
+        match inner.poll_next(cx) {
+            Poll::Ready(Some(Ok(stream))) => {
+                if let Some(local_addr) = local_addr {
+                    *local_addr = unix_socket_addr(stream.local_addr());
+                }
+                if let Some(peer_addr) = peer_addr {
+                    *peer_addr = unix_socket_addr(stream.peer_addr());
+                }
+                Poll::Ready(Ok(Some(Box::new(stream))))
+            }
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Err(e)),
+            Poll::Ready(None) => Poll::Ready(Ok(None)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
 enum AsyncFileDesc {
     Stream(AsyncStreamContainer),
     Listener(AsyncListenerContainer),
@@ -447,6 +616,22 @@ impl AsyncFileDesc {
     }
 }
 
+/// An entry in the file descriptor table.
+struct OpenFd {
+    desc: Arc<AsyncFileDesc>,
+    /// Released when the file descriptor is closed
+    _resources: Vec<ResourceGuard>,
+}
+
+impl OpenFd {
+    fn new(desc: AsyncFileDesc, resources: Vec<ResourceGuard>) -> Self {
+        OpenFd {
+            desc: Arc::new(desc),
+            _resources: resources,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub(crate) enum EnclaveAbort<T> {
     Exit {
@@ -457,6 +642,26 @@ pub(crate) enum EnclaveAbort<T> {
     IndefiniteWait,
     InvalidUsercall(u64),
     MainReturned,
+    /// The runner panicked while running the thread
+    RunnerPanicked(String),
+    /// A usercall had no recorded result to replay
+    ReplayDiverged(String),
+}
+
+impl EnclaveAbort<EnclavePanic> {
+    /// The reason to report to the user, or `None` if this thread merely
+    /// exited because another thread stopped the enclave.
+    fn into_reason(self) -> Option<AbortReason> {
+        match self {
+            EnclaveAbort::Exit { panic } => Some(AbortReason::Panic { panic }),
+            EnclaveAbort::Secondary => None,
+            EnclaveAbort::IndefiniteWait => Some(AbortReason::IndefiniteWait),
+            EnclaveAbort::InvalidUsercall(n) => Some(AbortReason::InvalidUsercall { number: n }),
+            EnclaveAbort::MainReturned => Some(AbortReason::MainReturned),
+            EnclaveAbort::RunnerPanicked(message) => Some(AbortReason::RunnerPanicked { message }),
+            EnclaveAbort::ReplayDiverged(message) => Some(AbortReason::ReplayDiverged { message }),
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
@@ -482,10 +687,12 @@ struct StoppedTcs {
 struct IOHandlerInput<'tcs> {
     tcs: &'tcs mut RunningTcs,
     enclave: Arc<EnclaveState>,
-    work_sender: &'tcs crossbeam::crossbeam_channel::Sender<Work>,
+    work_sender: &'tcs WorkSender,
 }
 
 struct RunningTcs {
+    /// `None` for asynchronous usercalls
+    address: Option<TcsAddress>,
     pending_event_set: u8,
     pending_events: VecDeque<u8>,
     event_queue: futures::channel::mpsc::UnboundedReceiver<u8>,
@@ -498,15 +705,97 @@ enum EnclaveKind {
 }
 
 struct PanicReason {
-    primary_panic_reason: Option<EnclaveAbort<EnclavePanic>>,
-    other_reasons: Vec<EnclaveAbort<EnclavePanic>>,
+    primary_panic_reason: Option<(EnclaveAbort<EnclavePanic>, ThreadContext)>,
+    other_reasons: Vec<(EnclaveAbort<EnclavePanic>, ThreadContext)>,
+    /// Where the main thread stopped, if it stopped abnormally
+    main_context: ThreadContext,
 }
 
 struct Command {
     panic_reason: Mutex<PanicReason>,
+    /// Whether the enclave was aborted from outside, see `EnclaveState::abort`
+    aborted: AtomicBool,
+    abort_notify: tokio::sync::Notify,
+}
+
+struct Library {
+    tcs_waiters: StdMutex<TcsWaiters>,
+    tcs_available: Condvar,
+}
+
+/// Callers waiting for a free library TCS, in arrival order.
+#[derive(Default)]
+struct TcsWaiters {
+    queue: VecDeque<usize>,
+    next_id: usize,
+    /// Wakers of the asynchronous callers in the queue, see `TcsWaiter`
+    wakers: FnvHashMap<usize, Waker>,
+}
+
+impl TcsWaiters {
+    /// Add a caller to the end of the queue, returning its id.
+    fn join(&mut self) -> usize {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.queue.push_back(id);
+        id
+    }
+
+    fn leave(&mut self, id: usize) {
+        self.queue.retain(|&waiter| waiter != id);
+        self.wakers.remove(&id);
+    }
+}
+
+/// An asynchronous library call waiting for a TCS, in the same queue as the
+/// callers of `acquire_library_tcs`. Dropping it leaves the queue.
+struct TcsWaiter {
+    enclave: Arc<EnclaveState>,
+    id: Option<usize>,
+}
+
+impl Future for TcsWaiter {
+    type Output = StdResult<StoppedTcs, TcsUnavailable>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let library = this.enclave.kind.as_library().unwrap();
+        let mut waiters = library.tcs_waiters.lock().unwrap();
+        let id = *this.id.get_or_insert_with(|| waiters.join());
+        match this.enclave.try_take_library_tcs(&waiters, id) {
+            Some(result) => {
+                waiters.leave(id);
+                drop(waiters);
+                this.id = None;
+                // The next caller in line may now be able to take a TCS
+                this.enclave.notify_tcs_waiters();
+                Poll::Ready(result)
+            }
+            None => {
+                waiters.wakers.insert(id, cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for TcsWaiter {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            let library = self.enclave.kind.as_library().unwrap();
+            library.tcs_waiters.lock().unwrap().leave(id);
+            self.enclave.notify_tcs_waiters();
+        }
+    }
 }
 
-struct Library {}
+/// How long a library call should wait for a TCS to become available.
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum TcsWait {
+    No,
+    Indefinite,
+    Timeout(time::Duration),
+}
 
 impl EnclaveKind {
     fn as_command(&self) -> Option<&Command> {
@@ -516,7 +805,7 @@ impl EnclaveKind {
         }
     }
 
-    fn _as_library(&self) -> Option<&Library> {
+    fn as_library(&self) -> Option<&Library> {
         match self {
             EnclaveKind::Library(l) => Some(l),
             _ => None,
@@ -524,15 +813,60 @@ impl EnclaveKind {
     }
 }
 
+/// The queues allocated by the `async_queues` usercall.
+struct AsyncQueues {
+    usercall_queue: Fifo<Usercall>,
+    return_queue: Fifo<Return>,
+}
+
+/// Settings from the `EnclaveBuilder` that apply while the enclave runs.
+#[derive(Debug, Default)]
+pub(crate) struct EnclaveConfig {
+    pub usercall_ext: Option<Box<dyn UsercallExtension>>,
+    pub clock: Option<Box<dyn Clock>>,
+    pub forward_panics: bool,
+    pub signal_grace_period: Option<time::Duration>,
+    pub usercall_trace: Option<Box<dyn UsercallTraceSink>>,
+    pub usercall_session: Option<UsercallSession>,
+    pub stdin: Stdio,
+    pub stdout: Stdio,
+    pub stderr: Stdio,
+    pub network_policy: NetworkPolicy,
+    pub symbolizer: Option<Symbolizer>,
+    pub core_dump: Option<CoreDumper>,
+    pub gdbserver: Option<GdbServer>,
+    pub resources: Arc<ResourceCounters>,
+    pub metrics: Arc<MetricsCounters>,
+    pub runtime: Option<EnclaveRuntime>,
+    pub launch_thread_policy: LaunchThreadPolicy,
+}
+
 pub(crate) struct EnclaveState {
     kind: EnclaveKind,
+    /// The enclave's virtual address range
+    enclave_range: Range<usize>,
     event_queues: FnvHashMap<TcsAddress, futures::channel::mpsc::UnboundedSender<u8>>,
-    fds: Mutex<FnvHashMap<Fd, Arc<AsyncFileDesc>>>,
+    fds: Mutex<FnvHashMap<Fd, OpenFd>>,
     last_fd: AtomicUsize,
     exiting: AtomicBool,
     usercall_ext: Box<dyn UsercallExtension>,
-    threads_queue: crossbeam::queue::SegQueue<StoppedTcs>,
+    clock: Box<dyn Clock>,
+    threads_queue: ThreadsQueue<StoppedTcs>,
     forward_panics: bool,
+    signal_grace_period: Option<time::Duration>,
+    usercall_trace: Option<Box<dyn UsercallTraceSink>>,
+    usercall_session: Option<UsercallSession>,
+    network_policy: NetworkPolicy,
+    symbolizer: Option<Symbolizer>,
+    core_dump: Option<CoreDumper>,
+    resources: Arc<ResourceCounters>,
+    metrics: Arc<MetricsCounters>,
+    async_queues: StdMutex<Option<Arc<AsyncQueues>>>,
+    /// Notified whenever the enclave might have submitted asynchronous
+    /// usercalls or consumed returns, i.e. on every synchronous usercall.
+    async_queues_notify: tokio::sync::Notify,
+    /// Where enclave threads run, if the enclave uses an `EnclaveRuntime`
+    run_queue: Option<RunQueue>,
 }
 
 struct Work {
@@ -545,18 +879,69 @@ enum CoEntry {
     Resume(tcs::Usercall<ErasedTcs>, (u64, u64)),
 }
 
-impl Work {
-    fn do_work(self, io_send_queue: &mut tokio::sync::mpsc::UnboundedSender<UsercallSendData>) {
-        let buf = RefCell::new([0u8; 1024]);
-        let usercall_send_data = match self.entry {
-            CoEntry::Initial(erased_tcs, p1, p2, p3, p4, p5) => {
-                let coresult = tcs::coenter(erased_tcs, p1, p2, p3, p4, p5, Some(&buf));
-                (coresult, self.tcs, buf)
-            }
-            CoEntry::Resume(usercall, coresult) => {
-                let coresult = usercall.coreturn(coresult, Some(&buf));
-                (coresult, self.tcs, buf)
+/// Queues enclave threads to run on a worker thread, which is either one of
+/// the threads of an enclave entry or a thread of an `EnclaveRuntime`.
+#[derive(Clone)]
+enum WorkSender {
+    Channel(crossbeam::crossbeam_channel::Sender<Work>),
+    Runtime(Arc<RunGroup>),
+}
+
+impl WorkSender {
+    fn send(&self, work: Work) -> StdResult<(), crossbeam::crossbeam_channel::SendError<Work>> {
+        match self {
+            WorkSender::Channel(sender) => sender.send(work),
+            WorkSender::Runtime(group) => {
+                group.send(work);
+                Ok(())
             }
+        }
+    }
+}
+
+impl Work {
+    /// The initial entry of a launched thread.
+    fn thread(thread: StoppedTcs, mode: EnclaveEntry) -> Self {
+        Work {
+            tcs: RunningTcs {
+                address: Some(thread.tcs.address()),
+                pending_events: Default::default(),
+                pending_event_set: 0,
+                event_queue: thread.event_queue,
+                mode,
+            },
+            entry: CoEntry::Initial(thread.tcs, 0, 0, 0, 0, 0),
+        }
+    }
+
+    /// Take back the TCS of work that hasn't started.
+    fn into_stopped_tcs(self) -> StoppedTcs {
+        match self.entry {
+            CoEntry::Initial(tcs, ..) => StoppedTcs {
+                tcs,
+                event_queue: self.tcs.event_queue,
+            },
+            CoEntry::Resume(..) => unreachable!(),
+        }
+    }
+
+    fn do_work(self, io_send_queue: &tokio::sync::mpsc::UnboundedSender<UsercallSendData>) {
+        let mode = self.tcs.mode.clone();
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            let buf = RefCell::new([0u8; 1024]);
+            let coresult = match self.entry {
+                CoEntry::Initial(erased_tcs, p1, p2, p3, p4, p5) => {
+                    tcs::coenter(erased_tcs, p1, p2, p3, p4, p5, Some(&buf))
+                }
+                CoEntry::Resume(usercall, coresult) => usercall.coreturn(coresult, Some(&buf)),
+            };
+            (coresult, self.tcs, buf)
+        }));
+        // The panic hook has already printed the panic. Nothing else would
+        // report back for this thread, so the usercall loop must be told.
+        let usercall_send_data = match result {
+            Ok((coresult, tcs, buf)) => UsercallSendData::Thread(coresult, tcs, buf),
+            Err(payload) => UsercallSendData::Panicked(mode, CommandPanicked::from_payload(payload).0),
         };
         // if there is an error do nothing, as it means that the main thread has exited
         let _ = io_send_queue.send(usercall_send_data);
@@ -580,75 +965,228 @@ impl EnclaveState {
 
     fn new(
         kind: EnclaveKind,
+        enclave_range: Range<usize>,
         mut event_queues: FnvHashMap<TcsAddress, futures::channel::mpsc::UnboundedSender<u8>>,
-        usercall_ext: Option<Box<dyn UsercallExtension>>,
         threads_vector: Vec<ErasedTcs>,
-        forward_panics: bool,
+        config: EnclaveConfig,
     ) -> Arc<Self> {
         let mut fds = FnvHashMap::default();
 
         fds.insert(
             FD_STDIN,
-            Arc::new(AsyncFileDesc::stream(Box::new(ReadOnly(
-                Box::pin(Stdin)
-            )))),
+            OpenFd::new(AsyncFileDesc::stream(match config.stdin.0 {
+                Some(stream) => Box::new(ReadOnly(stream)),
+                None => Box::new(ReadOnly(Box::pin(Stdin))),
+            }), vec![]),
         );
         fds.insert(
             FD_STDOUT,
-            Arc::new(AsyncFileDesc::stream(Box::new(WriteOnly(
-                Box::pin(tokio::io::stdout()),
-            )))),
+            OpenFd::new(AsyncFileDesc::stream(match config.stdout.0 {
+                Some(stream) => Box::new(WriteOnly(stream)),
+                None => Box::new(WriteOnly(Box::pin(tokio::io::stdout()))),
+            }), vec![]),
         );
         fds.insert(
             FD_STDERR,
-            Arc::new(AsyncFileDesc::stream(Box::new(WriteOnly(
-                Box::pin(tokio::io::stderr()),
-            )))),
+            OpenFd::new(AsyncFileDesc::stream(match config.stderr.0 {
+                Some(stream) => Box::new(WriteOnly(stream)),
+                None => Box::new(WriteOnly(Box::pin(tokio::io::stderr()))),
+            }), vec![]),
         );
         let last_fd = AtomicUsize::new(fds.keys().cloned().max().unwrap() as _);
 
-        let usercall_ext = usercall_ext.unwrap_or_else(|| Box::new(UsercallExtensionDefault));
+        let usercall_ext = config.usercall_ext.unwrap_or_else(|| Box::new(UsercallExtensionDefault));
+        let clock = config.clock.unwrap_or_else(|| Box::new(SystemClock));
 
-        let threads_queue = crossbeam::queue::SegQueue::new();
+        let threads_queue = ThreadsQueue::new(config.launch_thread_policy, config.metrics.clone());
 
         for thread in threads_vector {
             threads_queue.push(Self::event_queue_add_tcs(&mut event_queues, thread));
         }
 
+        config.metrics.set_tcs_total(event_queues.len());
+
+        if let Some(gdbserver) = config.gdbserver {
+            let tcss = event_queues.keys().map(|tcs| tcs.0 as u64).collect();
+            if let Err(e) = gdbserver.start(enclave_range.clone(), tcss, config.metrics.clone()) {
+                eprintln!("Unable to start gdb server: {}", e);
+            }
+        }
+
         Arc::new(EnclaveState {
             kind,
+            enclave_range,
             event_queues,
             fds: Mutex::new(fds),
             last_fd,
             exiting: AtomicBool::new(false),
             usercall_ext,
+            clock,
             threads_queue,
-            forward_panics,
+            forward_panics: config.forward_panics,
+            signal_grace_period: config.signal_grace_period,
+            usercall_trace: config.usercall_trace,
+            usercall_session: config.usercall_session,
+            network_policy: config.network_policy,
+            symbolizer: config.symbolizer,
+            core_dump: config.core_dump,
+            resources: config.resources,
+            metrics: config.metrics,
+            async_queues: StdMutex::new(None),
+            async_queues_notify: tokio::sync::Notify::new(),
+            run_queue: config.runtime.map(|runtime| runtime.queue()),
+        })
+    }
+
+    pub(crate) fn resource_monitor(&self) -> ResourceMonitor {
+        ResourceMonitor(self.resources.clone())
+    }
+
+    pub(crate) fn metrics_monitor(&self) -> MetricsMonitor {
+        MetricsMonitor(self.metrics.clone())
+    }
+
+    /// Print the backtrace of a panic, if an enclave ELF file was provided.
+    /// If the enclave didn't print a backtrace itself, the stack of `tcs` is
+    /// used, if possible.
+    fn print_symbolized_backtrace(&self, tcs: Option<usize>) {
+        if let Some(ref symbolizer) = self.symbolizer {
+            let base = self.enclave_range.start as u64;
+            let frames = tcs.and_then(|tcs| panic::backtrace(&self.enclave_range, tcs).ok());
+            let offsets = frames.map(|frames| frames.iter().map(|frame| frame - base).collect::<Vec<_>>());
+            symbolizer.print_backtrace(offsets.as_deref());
+        }
+    }
+
+    /// Write a core dump of the enclave, if requested. Only the first panic
+    /// is dumped.
+    ///
+    /// The other enclave threads are aborted first, so that they stop
+    /// changing enclave memory once they perform a usercall. Reading the
+    /// enclave memory blocks, so the core file is written on the blocking
+    /// thread pool.
+    async fn write_core_dump(enclave: &Arc<Self>, tcs: Option<usize>) {
+        if enclave.core_dump.is_none() {
+            return;
+        }
+        enclave.abort_all_threads();
+        let enclave = enclave.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            let core_dump = enclave.core_dump.as_ref().unwrap();
+            let tcss = enclave.event_queues.keys().map(|tcs| tcs.0).collect::<Vec<_>>();
+            match core_dump.write(&enclave.enclave_range, tcs, &tcss) {
+                Ok(true) => eprintln!("Enclave core dump written to {}", core_dump.path().display()),
+                Ok(false) => {}
+                Err(e) => eprintln!("Unable to write enclave core dump: {}", e),
+            }
         })
+        .await;
+        if let Err(e) = result {
+            eprintln!("Unable to write enclave core dump: {}", e);
+        }
+    }
+
+    fn recorder(&self) -> Option<&UsercallRecorder> {
+        match self.usercall_session {
+            Some(UsercallSession::Record(ref recorder)) => Some(recorder),
+            _ => None,
+        }
+    }
+
+    fn replayer(&self) -> Option<&UsercallReplayer> {
+        match self.usercall_session {
+            Some(UsercallSession::Replay(ref replayer)) => Some(replayer),
+            _ => None,
+        }
+    }
+
+    /// Abort the enclave if the usercall that returned `result` had no
+    /// recorded result to replay. The replayer methods return as soon as
+    /// they find out, so no other usercall can have diverged in between.
+    fn replay_result<T>(&self, result: UsercallResult<T>) -> UsercallResult<T> {
+        match self.replayer().and_then(UsercallReplayer::take_divergence) {
+            Some(message) => Err(EnclaveAbort::ReplayDiverged(message)),
+            None => result,
+        }
     }
 
     fn syscall_loop(
         enclave: Arc<EnclaveState>,
         io_queue_receive: tokio::sync::mpsc::UnboundedReceiver<UsercallSendData>,
-        work_sender: crossbeam::crossbeam_channel::Sender<Work>,
+        work_sender: WorkSender,
     ) -> StdResult<(u64, u64), EnclaveAbort<EnclavePanic>> {
-        let (tx_return_channel, mut rx_return_channel) = tokio::sync::mpsc::unbounded_channel();
-        let enclave_clone = enclave.clone();
+        if let Some(ref run_queue) = enclave.run_queue {
+            // The runtime's threads drive the I/O and timers
+            let handle = run_queue.tokio_handle();
+            let usercalls = EnclaveState::handle_usercalls(enclave, io_queue_receive, work_sender);
+            return handle.enter(|| futures::executor::block_on(usercalls));
+        }
+
         let mut rt = tokio::runtime::Builder::new()
             .basic_scheduler()
             .enable_all()
             .build()
             .expect("failed to create tokio Runtime");
-        let local_set = tokio::task::LocalSet::new();
+
+        rt.block_on(EnclaveState::handle_usercalls(enclave, io_queue_receive, work_sender))
+    }
+
+    /// Handle usercalls until the enclave entry described by the
+    /// `EnclaveEntry` of the TCSs returns.
+    ///
+    /// The usercall handlers are polled as part of the returned future
+    /// instead of being spawned, so this can be driven by any executor
+    /// running in a tokio context.
+    async fn handle_usercalls(
+        enclave: Arc<EnclaveState>,
+        io_queue_receive: tokio::sync::mpsc::UnboundedReceiver<UsercallSendData>,
+        work_sender: WorkSender,
+    ) -> StdResult<(u64, u64), EnclaveAbort<EnclavePanic>> {
+        let (tx_return_channel, mut rx_return_channel) = tokio::sync::mpsc::unbounded_channel();
+        // Asynchronous usercalls are only supported for commands, which have
+        // a single usercall loop that can own the queues.
+        let async_future = if enclave.kind.as_command().is_some() {
+            EnclaveState::handle_async_usercalls(
+                enclave.clone(),
+                work_sender.clone(),
+                tx_return_channel.clone(),
+            ).boxed_local()
+        } else {
+            futures::future::pending().boxed_local()
+        };
+        let signal_future = match (enclave.kind.as_command(), enclave.signal_grace_period) {
+            (Some(_), Some(grace_period)) => {
+                EnclaveState::forward_signals(enclave.clone(), grace_period).boxed_local()
+            }
+            _ => futures::future::pending().boxed_local(),
+        };
+        // Stop handling usercalls once the enclave is aborted from outside,
+        // even if some threads are blocked in usercalls
+        let enclave_clone = enclave.clone();
+        let abort_future = async move {
+            match enclave_clone.kind.as_command() {
+                Some(cmd) => cmd.abort_notify.notified().await,
+                None => futures::future::pending().await,
+            }
+            Err(EnclaveAbort::Secondary)
+        };
+        let enclave_clone = enclave.clone();
 
         let return_future = async move {
             while let (Some(work), stream) = rx_return_channel.into_future().await {
                 rx_return_channel = stream;
-                let (my_result, mode) = work;
+                let (my_result, mode, context) = work;
                 let res = match (my_result, mode) {
                     (e, EnclaveEntry::Library)
-                    | (e, EnclaveEntry::ExecutableMain)
+                    | (e, EnclaveEntry::LibraryThread)
                     | (e @ Err(EnclaveAbort::Secondary), EnclaveEntry::ExecutableNonMain) => e,
+                    (e, EnclaveEntry::ExecutableMain) => {
+                        if e.is_err() {
+                            let cmd = enclave_clone.kind.as_command().unwrap();
+                            cmd.panic_reason.lock().await.main_context = context;
+                        }
+                        e
+                    }
                     (Ok(_), EnclaveEntry::ExecutableNonMain) => {
                         continue;
                     }
@@ -656,21 +1194,29 @@ impl EnclaveState {
                     | (
                         Err(e @ EnclaveAbort::InvalidUsercall(_)),
                         EnclaveEntry::ExecutableNonMain,
+                    )
+                    | (
+                        Err(e @ EnclaveAbort::RunnerPanicked(_)),
+                        EnclaveEntry::ExecutableNonMain,
+                    )
+                    | (
+                        Err(e @ EnclaveAbort::ReplayDiverged(_)),
+                        EnclaveEntry::ExecutableNonMain,
                     ) => {
                         let cmd = enclave_clone.kind.as_command().unwrap();
                         let mut cmddata = cmd.panic_reason.lock().await;
 
                         if cmddata.primary_panic_reason.is_none() {
-                            cmddata.primary_panic_reason = Some(e)
+                            cmddata.primary_panic_reason = Some((e, context))
                         } else {
-                            cmddata.other_reasons.push(e)
+                            cmddata.other_reasons.push((e, context))
                         }
                         Err(EnclaveAbort::Secondary)
                     }
                     (Err(e), EnclaveEntry::ExecutableNonMain) => {
                         let cmd = enclave_clone.kind.as_command().unwrap();
                         let mut cmddata = cmd.panic_reason.lock().await;
-                        cmddata.other_reasons.push(e);
+                        cmddata.other_reasons.push((e, context));
                         continue;
                     }
                 };
@@ -680,15 +1226,33 @@ impl EnclaveState {
         };
         let enclave_clone = enclave.clone();
         let io_future = async move {
-            let mut recv_queue = io_queue_receive.into_future();
-            while let (Some(work), stream) = recv_queue.await {
+            let mut recv_queue = io_queue_receive.fuse();
+            let mut pending_usercalls = futures::stream::FuturesUnordered::new();
+            loop {
+                let work = futures::select! {
+                    work = recv_queue.next() => match work {
+                        Some(work) => work,
+                        None => break,
+                    },
+                    () = pending_usercalls.select_next_some() => continue,
+                };
                 let work_sender = work_sender.clone();
                 let tx_return_channel = tx_return_channel.clone();
                 let enclave_clone = enclave_clone.clone();
-                recv_queue = stream.into_future();
-                let (coresult, mut state, buf) = work;
+                let (coresult, mut state, buf) = match work {
+                    UsercallSendData::Thread(coresult, state, buf) => (coresult, state, buf),
+                    UsercallSendData::Panicked(mode, message) => {
+                        let _ = tx_return_channel.send((
+                            Err(EnclaveAbort::RunnerPanicked(message)),
+                            mode,
+                            ThreadContext::default(),
+                        ));
+                        continue;
+                    }
+                };
                 match coresult {
                     CoResult::Yield(usercall) => {
+                        enclave_clone.async_queues_notify.notify();
                         let fut = async move {
                             let mut input = IOHandlerInput {
                                 enclave: enclave_clone.clone(),
@@ -698,7 +1262,18 @@ impl EnclaveState {
                             let handler = Handler(&mut input);
                             let (_handler, result) = {
                                 let (p1, p2, p3, p4, p5) = usercall.parameters();
-                                dispatch(handler, p1, p2, p3, p4, p5).await
+                                let tracer = enclave_clone.usercall_trace.as_ref().map(|sink| unsafe {
+                                    let tcs = Some(usercall.tcs_address() as usize);
+                                    UsercallTracer::start(&**sink, tcs, None, (p1, p2, p3, p4, p5))
+                                });
+                                let start = time::Instant::now();
+                                let (handler, result) = dispatch(handler, p1, p2, p3, p4, p5).await;
+                                let result = enclave_clone.replay_result(result);
+                                enclave_clone.metrics.usercall(p1, start.elapsed(), &result);
+                                if let Some(tracer) = tracer {
+                                    tracer.finish(&result);
+                                }
+                                (handler, result)
                             };
                             let ret = match result {
                                 Ok(ret) => {
@@ -715,6 +1290,8 @@ impl EnclaveState {
                                     #[cfg(all(unix, not(target_abi = "musl")))]
                                     trap_attached_debugger(usercall.tcs_address() as _).await;
                                     let panic = EnclavePanic::from(buf.into_inner());
+                                    enclave_clone.print_symbolized_backtrace(Some(usercall.tcs_address() as _));
+                                    EnclaveState::write_core_dump(&enclave_clone, Some(usercall.tcs_address() as _)).await;
                                     if enclave_clone.forward_panics {
                                         panic!("{}", &panic);
                                     }
@@ -730,16 +1307,33 @@ impl EnclaveState {
                                 }
                                 Err(EnclaveAbort::MainReturned) => Err(EnclaveAbort::MainReturned),
                                 Err(EnclaveAbort::Secondary) => Err(EnclaveAbort::Secondary),
+                                Err(EnclaveAbort::RunnerPanicked(message)) => Err(EnclaveAbort::RunnerPanicked(message)),
+                                Err(EnclaveAbort::ReplayDiverged(message)) => Err(EnclaveAbort::ReplayDiverged(message)),
                             };
-                            let _ = tx_return_channel.send((ret, state.mode));
+                            let context = match ret {
+                                Ok(_) | Err(EnclaveAbort::Secondary) => ThreadContext::default(),
+                                Err(_) => {
+                                    let tcs = usercall.tcs_address() as usize;
+                                    ThreadContext {
+                                        tcs: Some(tcs),
+                                        usercall: Some(usercall.parameters().0),
+                                        backtrace: panic::backtrace(&enclave_clone.enclave_range, tcs).ok(),
+                                    }
+                                }
+                            };
+                            let _ = tx_return_channel.send((ret, state.mode, context));
                         };
-                        tokio::task::spawn_local(fut);
+                        pending_usercalls.push(fut.boxed_local());
                     }
                     CoResult::Return((tcs, v1, v2)) => {
                         let fut = async move {
+                            let context = ThreadContext {
+                                tcs: Some(tcs.address().0),
+                                ..Default::default()
+                            };
                             let ret = match state.mode {
-                                EnclaveEntry::Library => {
-                                    enclave_clone.threads_queue.push(StoppedTcs {
+                                EnclaveEntry::Library | EnclaveEntry::LibraryThread => {
+                                    EnclaveState::release_library_tcs(&enclave_clone, StoppedTcs {
                                         tcs,
                                         event_queue: state.event_queue,
                                     });
@@ -755,17 +1349,21 @@ impl EnclaveState {
                                     // If the enclave is in the exit-state, threads are no
                                     // longer able to be launched
                                     if !enclave_clone.exiting.load(Ordering::SeqCst) {
-                                        enclave_clone.threads_queue.push(StoppedTcs {
+                                        let thread = StoppedTcs {
                                             tcs,
                                             event_queue: state.event_queue,
-                                        });
+                                        };
+                                        // Start a queued thread on the TCS
+                                        if let Some(thread) = enclave_clone.threads_queue.release(thread) {
+                                            let _ = EnclaveState::start_thread(&enclave_clone, thread, &work_sender);
+                                        }
                                     }
                                     Ok((0, 0))
                                 }
                             };
-                            let _ = tx_return_channel.send((ret, state.mode));
+                            let _ = tx_return_channel.send((ret, state.mode, context));
                         };
-                        tokio::task::spawn_local(fut);
+                        pending_usercalls.push(fut.boxed_local());
                     }
                 };
             }
@@ -773,9 +1371,17 @@ impl EnclaveState {
         };
 
         // Note that:
-        // - io_future will never return, its job is to spawn new futures that handle I/O.
+        // - io_future will never return, its job is to spawn new futures that handle I/O,
+        //   including asynchronous usercalls.
         // - return_future returns in certain cases (see above) and in such cases we want to
         //   terminate the syscall loop.
+        let io_future = futures::future::join3(io_future, async_future, signal_future);
+        let return_future =
+            futures::future::select(return_future.boxed_local(), abort_future.boxed_local()).map(|either| {
+                match either {
+                    Either::Left((x, _)) | Either::Right((x, _)) => x,
+                }
+            });
         let select_fut =
             futures::future::select(return_future.boxed_local(), io_future.boxed_local()).map( |either| {
                 match either {
@@ -784,7 +1390,166 @@ impl EnclaveState {
                 }
             });
 
-        local_set.block_on(&mut rt, select_fut.unit_error()).unwrap()
+        select_fut.await
+    }
+
+    /// Process usercalls submitted through the asynchronous usercall queues,
+    /// once those have been allocated by the enclave.
+    ///
+    /// The enclave has no way to signal userspace directly, so the queues
+    /// are checked whenever the enclave performs a synchronous usercall, as
+    /// well as when an asynchronous usercall completes.
+    async fn handle_async_usercalls(
+        enclave: Arc<EnclaveState>,
+        work_sender: WorkSender,
+        tx_return_channel: tokio::sync::mpsc::UnboundedSender<ThreadResultData>,
+    ) {
+        let mut pending_usercalls = futures::stream::FuturesUnordered::new();
+        let mut pending_returns = VecDeque::new();
+        loop {
+            futures::select! {
+                () = enclave.async_queues_notify.notified().fuse() => {},
+                ret = pending_usercalls.select_next_some() => pending_returns.extend(ret),
+            }
+
+            let queues = match *enclave.async_queues.lock().unwrap() {
+                Some(ref queues) => queues.clone(),
+                None => continue,
+            };
+
+            while let Some(ret) = pending_returns.pop_front() {
+                match queues.return_queue.try_push(ret) {
+                    Ok(was_empty) => {
+                        if was_empty {
+                            enclave.send_to_all(EV_RETURNQ_NOT_EMPTY as _);
+                        }
+                    }
+                    // Retry once the enclave has made room
+                    Err(ret) => {
+                        pending_returns.push_front(ret);
+                        break;
+                    }
+                }
+            }
+
+            while let Some((usercall, was_full)) = queues.usercall_queue.try_pop() {
+                if was_full {
+                    enclave.send_to_all(EV_USERCALLQ_NOT_FULL as _);
+                }
+                let fut = EnclaveState::handle_async_usercall(
+                    enclave.clone(),
+                    work_sender.clone(),
+                    tx_return_channel.clone(),
+                    usercall,
+                );
+                pending_usercalls.push(fut.boxed_local());
+            }
+        }
+    }
+
+    /// Send `EV_SIGNAL` to all TCSs once the runner receives a termination
+    /// signal, see [`handle_signals`](#method.handle_signals).
+    async fn forward_signals(enclave: Arc<EnclaveState>, grace_period: time::Duration) {
+        match termination_signals() {
+            Ok(signals) => Self::handle_signals(&enclave, signals, grace_period).await,
+            Err(_) => enclave.metrics.signal_forwarding_failed(),
+        }
+    }
+
+    /// Send `EV_SIGNAL` to all TCSs once `signals` yields a signal. If the
+    /// enclave hasn't exited after `grace_period`, or another signal is
+    /// received in the meantime, the enclave is aborted.
+    async fn handle_signals<S: TokioStream<Item = ()> + Unpin>(
+        enclave: &EnclaveState,
+        signals: S,
+        grace_period: time::Duration,
+    ) {
+        let mut signals = signals.fuse();
+        if signals.next().await.is_none() {
+            return;
+        }
+        enclave.send_to_all(EV_SIGNAL as _);
+        futures::select! {
+            () = tokio::time::delay_for(grace_period).fuse() => {},
+            _ = signals.next() => {},
+        }
+        enclave.metrics.signal_abort();
+        enclave.abort();
+    }
+
+    /// Dispatch a single asynchronous usercall, returning the value to be
+    /// posted on the return queue.
+    async fn handle_async_usercall(
+        enclave: Arc<EnclaveState>,
+        work_sender: WorkSender,
+        tx_return_channel: tokio::sync::mpsc::UnboundedSender<ThreadResultData>,
+        usercall: Usercall,
+    ) -> Option<Return> {
+        // Asynchronous usercalls don't belong to a TCS, so they get an event
+        // queue of their own that never receives any events.
+        let (_event_sender, event_queue) = futures::channel::mpsc::unbounded();
+        let mut state = RunningTcs {
+            address: None,
+            event_queue,
+            pending_event_set: 0,
+            pending_events: Default::default(),
+            mode: EnclaveEntry::ExecutableNonMain,
+        };
+        let mut input = IOHandlerInput {
+            enclave: enclave.clone(),
+            tcs: &mut state,
+            work_sender: &work_sender,
+        };
+        let handler = Handler(&mut input);
+        let (_handler, result) = {
+            let (p1, p2, p3, p4, p5) = usercall.args;
+            let tracer = enclave.usercall_trace.as_ref().map(|sink| unsafe {
+                UsercallTracer::start(&**sink, None, Some(usercall.id), (p1, p2, p3, p4, p5))
+            });
+            let start = time::Instant::now();
+            let (handler, result) = dispatch(handler, p1, p2, p3, p4, p5).await;
+            let result = enclave.replay_result(result);
+            enclave.metrics.usercall(p1, start.elapsed(), &result);
+            if let Some(tracer) = tracer {
+                tracer.finish(&result);
+            }
+            (handler, result)
+        };
+        let ret = match result {
+            Ok(value) => return Some(Return { id: usercall.id, value }),
+            Err(EnclaveAbort::Exit { panic: true }) => {
+                // There is no debug buffer for asynchronous usercalls
+                let panic = EnclavePanic::NoDebugBuf;
+                enclave.print_symbolized_backtrace(None);
+                EnclaveState::write_core_dump(&enclave, None).await;
+                if enclave.forward_panics {
+                    panic!("{}", &panic);
+                }
+                Err(EnclaveAbort::Exit { panic })
+            }
+            Err(EnclaveAbort::Exit { panic: false }) | Err(EnclaveAbort::Secondary) => return None,
+            Err(EnclaveAbort::IndefiniteWait) => Err(EnclaveAbort::IndefiniteWait),
+            Err(EnclaveAbort::InvalidUsercall(n)) => Err(EnclaveAbort::InvalidUsercall(n)),
+            Err(EnclaveAbort::MainReturned) => Err(EnclaveAbort::MainReturned),
+            Err(EnclaveAbort::RunnerPanicked(message)) => Err(EnclaveAbort::RunnerPanicked(message)),
+            Err(EnclaveAbort::ReplayDiverged(message)) => Err(EnclaveAbort::ReplayDiverged(message)),
+        };
+        // Report the failure like that of a secondary thread
+        let context = ThreadContext {
+            usercall: Some(usercall.args.0),
+            ..Default::default()
+        };
+        let _ = tx_return_channel.send((ret, EnclaveEntry::ExecutableNonMain, context));
+        None
+    }
+
+    fn worker_loop(
+        work_receiver: crossbeam::crossbeam_channel::Receiver<Work>,
+        io_queue_send: tokio::sync::mpsc::UnboundedSender<UsercallSendData>,
+    ) {
+        while let Ok(work) = work_receiver.recv() {
+            work.do_work(&io_queue_send);
+        }
     }
 
     fn run(
@@ -800,12 +1565,10 @@ impl EnclaveState {
             let mut thread_handles = vec![];
             for _ in 0..num_of_worker_threads {
                 let work_receiver = work_receiver.clone();
-                let mut io_queue_send = io_queue_send.clone();
+                let io_queue_send = io_queue_send.clone();
 
                 thread_handles.push(thread::spawn(move || {
-                    while let Ok(work) = work_receiver.recv() {
-                        work.do_work(&mut io_queue_send);
-                    }
+                    EnclaveState::worker_loop(work_receiver, io_queue_send)
                 }));
             }
             thread_handles
@@ -813,6 +1576,17 @@ impl EnclaveState {
 
         let (io_queue_send, io_queue_receive) = tokio::sync::mpsc::unbounded_channel();
 
+        if let Some(ref run_queue) = enclave.run_queue {
+            let group = Arc::new(run_queue.group(io_queue_send));
+            group.send(start_work);
+            let main_result =
+                EnclaveState::syscall_loop(enclave.clone(), io_queue_receive, WorkSender::Runtime(group.clone()));
+            // Like joining the worker threads, wait for threads that are
+            // still running in the enclave
+            group.wait_idle();
+            return main_result;
+        }
+
         let (work_sender, work_receiver) = crossbeam::crossbeam_channel::unbounded();
         work_sender
             .send(start_work)
@@ -822,7 +1596,7 @@ impl EnclaveState {
             create_worker_threads(num_of_worker_threads, work_receiver, io_queue_send);
         // main syscall polling loop
         let main_result =
-            EnclaveState::syscall_loop(enclave.clone(), io_queue_receive, work_sender);
+            EnclaveState::syscall_loop(enclave.clone(), io_queue_receive, WorkSender::Channel(work_sender));
 
         for handler in join_handlers {
             let _ = handler.join();
@@ -830,41 +1604,128 @@ impl EnclaveState {
         return main_result;
     }
 
+    /// Like `run`, but handles usercalls on the current tokio runtime and
+    /// enters the enclave on a thread of its blocking thread pool, or of the
+    /// enclave's `EnclaveRuntime`.
+    async fn run_async(
+        enclave: Arc<EnclaveState>,
+        start_work: Work,
+    ) -> StdResult<(u64, u64), EnclaveAbort<EnclavePanic>> {
+        let (io_queue_send, io_queue_receive) = tokio::sync::mpsc::unbounded_channel();
+
+        if let Some(ref run_queue) = enclave.run_queue {
+            let group = Arc::new(run_queue.group(io_queue_send));
+            group.send(start_work);
+            return EnclaveState::handle_usercalls(enclave, io_queue_receive, WorkSender::Runtime(group)).await;
+        }
+
+        let (work_sender, work_receiver) = crossbeam::crossbeam_channel::unbounded();
+        work_sender
+            .send(start_work)
+            .expect("Work sender couldn't send data to receiver");
+
+        // The worker exits once the usercall handler has dropped all senders
+        tokio::task::spawn_blocking(move || EnclaveState::worker_loop(work_receiver, io_queue_send));
+
+        EnclaveState::handle_usercalls(enclave, io_queue_receive, WorkSender::Channel(work_sender)).await
+    }
+
+    /// Set up the enclave state for a command. The returned function runs
+    /// the enclave to completion.
     pub(crate) fn main_entry(
         main: ErasedTcs,
         threads: Vec<ErasedTcs>,
-        usercall_ext: Option<Box<dyn UsercallExtension>>,
-        forward_panics: bool,
-    ) -> StdResult<(), failure::Error> {
+        enclave_range: Range<usize>,
+        config: EnclaveConfig,
+        cmd_args: Vec<Vec<u8>>,
+    ) -> (Arc<Self>, impl FnOnce() -> StdResult<(), failure::Error> + Send) {
         let mut event_queues =
             FnvHashMap::with_capacity_and_hasher(threads.len() + 1, Default::default());
         let main = Self::event_queue_add_tcs(&mut event_queues, main);
 
+        let (argv, argc, args_error) = match Self::alloc_args(&config.resources, &cmd_args) {
+            Ok((argv, argc)) => (argv, argc, None),
+            Err(e) => (ptr::null(), 0, Some(e)),
+        };
+
         let main_work = Work {
             tcs: RunningTcs {
+                address: Some(main.tcs.address()),
                 event_queue: main.event_queue,
                 pending_event_set: 0,
                 pending_events: Default::default(),
                 mode: EnclaveEntry::ExecutableMain,
             },
-            entry: CoEntry::Initial(main.tcs, 0, 0, 0, 0, 0),
+            entry: CoEntry::Initial(main.tcs, argv as _, argc as _, 0, 0, 0),
         };
 
-        let num_of_worker_threads = num_cpus::get();
-
         let kind = EnclaveKind::Command(Command {
             panic_reason: Mutex::new(PanicReason {
                 primary_panic_reason: None,
                 other_reasons: vec![],
+                main_context: ThreadContext::default(),
             }),
+            aborted: AtomicBool::new(false),
+            abort_notify: tokio::sync::Notify::new(),
         });
-        let enclave = EnclaveState::new(kind, event_queues, usercall_ext, threads, forward_panics);
+        let enclave = EnclaveState::new(kind, enclave_range, event_queues, threads, config);
+
+        let enclave_clone = enclave.clone();
+        (enclave, move || {
+            if let Some(e) = args_error {
+                bail!("Unable to pass the arguments to the enclave: {}", e);
+            }
+            EnclaveState::command_run(enclave_clone, main_work)
+        })
+    }
+
+    /// Copy the arguments to user memory. The enclave takes ownership of the
+    /// buffers and is expected to deallocate them using the `free` usercall,
+    /// so they count against its memory limit.
+    fn alloc_args(resources: &ResourceCounters, cmd_args: &[Vec<u8>]) -> IoResult<(*const ByteBuffer, usize)> {
+        let mut args = Vec::with_capacity(cmd_args.len());
+        let free_args = |args: &[ByteBuffer]| {
+            for arg in args.iter().filter(|arg| arg.len > 0) {
+                unsafe { resources.free_user(arg.data as _, Layout::from_size_align(arg.len, 1).unwrap()) };
+            }
+        };
+        for a in cmd_args {
+            match resources.alloc_user_bytes(a) {
+                Ok(data) => args.push(ByteBuffer { len: a.len(), data }),
+                Err(e) => {
+                    free_args(&args);
+                    return Err(e);
+                }
+            }
+        }
+        if args.is_empty() {
+            return Ok((ptr::NonNull::dangling().as_ptr(), 0));
+        }
+        let size = mem::size_of::<ByteBuffer>() * args.len();
+        let layout = Layout::from_size_align(size, mem::align_of::<ByteBuffer>()).unwrap();
+        match resources.alloc_user(layout) {
+            Ok(argv) => {
+                let argv = argv as *mut ByteBuffer;
+                unsafe { ptr::copy_nonoverlapping(args.as_ptr(), argv, args.len()) };
+                Ok((argv, args.len()))
+            }
+            Err(e) => {
+                free_args(&args);
+                Err(e)
+            }
+        }
+    }
+
+    fn command_run(enclave: Arc<Self>, main_work: Work) -> StdResult<(), failure::Error> {
+        let num_of_worker_threads = num_cpus::get();
 
         let main_result = EnclaveState::run(enclave.clone(), num_of_worker_threads, main_work);
 
         let main_panicking = match main_result {
             Err(EnclaveAbort::MainReturned)
             | Err(EnclaveAbort::InvalidUsercall(_))
+            | Err(EnclaveAbort::RunnerPanicked(_))
+            | Err(EnclaveAbort::ReplayDiverged(_))
             | Err(EnclaveAbort::Exit { .. }) => true,
             Err(EnclaveAbort::IndefiniteWait) | Err(EnclaveAbort::Secondary) | Ok(_) => false,
         };
@@ -878,46 +1739,196 @@ impl EnclaveState {
         rt.block_on(async move {
             enclave.abort_all_threads();
             //clear the threads_queue
-            while enclave.threads_queue.pop().is_ok() {}
+            enclave.threads_queue.clear();
 
             let cmd = enclave.kind.as_command().unwrap();
             let mut cmddata = cmd.panic_reason.lock().await;
-            let main_result = match (main_panicking, cmddata.primary_panic_reason.take()) {
-                (false, Some(reason)) => Err(reason),
-                // TODO: interpret other_reasons
-                _ => main_result,
-            };
-            match main_result {
-                Err(EnclaveAbort::Exit { panic }) => Err(panic.into()),
-                Err(EnclaveAbort::IndefiniteWait) => {
-                    bail!("All enclave threads are waiting indefinitely without possibility of wakeup")
+            let main_context = mem::take(&mut cmddata.main_context);
+            let mut other_reasons = mem::take(&mut cmddata.other_reasons);
+            let (main_result, context) = match (main_panicking, cmddata.primary_panic_reason.take()) {
+                (false, Some((reason, context))) => (Err(reason), context),
+                (true, Some(reason)) => {
+                    other_reasons.insert(0, reason);
+                    (main_result, main_context)
                 }
-                Err(EnclaveAbort::InvalidUsercall(n)) => {
-                    bail!("The enclave performed an invalid usercall 0x{:x}", n)
-                }
-                Err(EnclaveAbort::MainReturned) => bail!(
-                    "The enclave returned from the main entrypoint in violation of the specification."
-                ),
-                // Should always be able to return the real exit reason
-                Err(EnclaveAbort::Secondary) => unreachable!(),
-                Ok(_) => Ok(()),
-            }
+                (_, None) => (main_result, main_context),
+            };
+            let reason = match main_result {
+                Ok(_) => return Ok(()),
+                Err(e) => match e.into_reason() {
+                    Some(reason) => reason,
+                    None if cmd.aborted.load(Ordering::SeqCst) => return Err(CommandAborted.into()),
+                    // Should always be able to return the real exit reason
+                    None => unreachable!(),
+                },
+            };
+            let secondary = other_reasons
+                .into_iter()
+                .filter_map(|(e, context)| Some(context.report(e.into_reason()?)))
+                .collect();
+            Err(EnclavePanicReport {
+                primary: context.report(reason),
+                secondary,
+                enclave_base: enclave.enclave_range.start as _,
+            }.into())
         }.boxed_local())
     }
 
     pub(crate) fn library(
         threads: Vec<ErasedTcs>,
-        usercall_ext: Option<Box<dyn UsercallExtension>>,
-        forward_panics: bool,
+        enclave_range: Range<usize>,
+        config: EnclaveConfig,
     ) -> Arc<Self> {
         let event_queues = FnvHashMap::with_capacity_and_hasher(threads.len(), Default::default());
 
-        let kind = EnclaveKind::Library(Library {});
+        let kind = EnclaveKind::Library(Library {
+            tcs_waiters: StdMutex::new(TcsWaiters::default()),
+            tcs_available: Condvar::new(),
+        });
 
-        let enclave = EnclaveState::new(kind, event_queues, usercall_ext, threads, forward_panics);
+        let enclave = EnclaveState::new(kind, enclave_range, event_queues, threads, config);
         return enclave;
     }
 
+    /// Take a TCS from the threads queue for a library call.
+    ///
+    /// Callers are served in the order in which they started waiting. A
+    /// caller that isn't first in line doesn't get a TCS, even if one is
+    /// available.
+    fn acquire_library_tcs(&self, wait: TcsWait) -> StdResult<StoppedTcs, TcsUnavailable> {
+        let library = self.kind.as_library().unwrap();
+        let deadline = match wait {
+            TcsWait::Timeout(timeout) => Some(time::Instant::now() + timeout),
+            TcsWait::No | TcsWait::Indefinite => None,
+        };
+
+        let mut waiters = library.tcs_waiters.lock().unwrap();
+        let id = waiters.join();
+
+        let result = loop {
+            if let Some(result) = self.try_take_library_tcs(&waiters, id) {
+                break result;
+            }
+            waiters = match (wait, deadline) {
+                (TcsWait::No, _) => break Err(TcsUnavailable::Busy),
+                (_, Some(deadline)) => {
+                    let now = time::Instant::now();
+                    if now >= deadline {
+                        break Err(TcsUnavailable::TimedOut);
+                    }
+                    library.tcs_available.wait_timeout(waiters, deadline - now).unwrap().0
+                }
+                (_, None) => library.tcs_available.wait(waiters).unwrap(),
+            };
+        };
+
+        waiters.leave(id);
+        drop(waiters);
+        // The next caller in line may now be able to take a TCS
+        self.notify_tcs_waiters();
+        result
+    }
+
+    /// Take a TCS for the waiting caller `id` if it's first in line. Fails
+    /// if the enclave is exiting.
+    fn try_take_library_tcs(&self, waiters: &TcsWaiters, id: usize) -> Option<StdResult<StoppedTcs, TcsUnavailable>> {
+        if self.exiting.load(Ordering::SeqCst) {
+            return Some(Err(TcsUnavailable::Exited));
+        }
+        if waiters.queue.front() == Some(&id) {
+            return self.threads_queue.pop().map(Ok);
+        }
+        None
+    }
+
+    /// Return a TCS to the threads queue after a library call or a thread
+    /// launched by a library returns, and wake up callers waiting for it. If
+    /// a launched thread is queued, it's started on the TCS instead.
+    fn release_library_tcs(enclave: &Arc<Self>, thread: StoppedTcs) {
+        match enclave.threads_queue.release(thread) {
+            Some(thread) => {
+                // The thread was launched successfully as far as the enclave
+                // is concerned, so there's no one left to return this to
+                if let Err(e) = Self::start_library_thread(enclave, thread) {
+                    eprintln!("Unable to start an enclave thread launched by a library: {}", e);
+                }
+            }
+            None => enclave.notify_tcs_waiters(),
+        }
+    }
+
+    /// Start a thread launched by the enclave on `thread`.
+    ///
+    /// The threads of a command run alongside its other threads. A thread
+    /// launched by a library may outlive the library call that launched it,
+    /// so it is run like a library call of its own, with all parameters set
+    /// to zero.
+    ///
+    /// If the thread can't be started, the TCS is returned to the threads
+    /// queue.
+    fn start_thread(enclave: &Arc<Self>, thread: StoppedTcs, work_sender: &WorkSender) -> IoResult<()> {
+        match enclave.kind {
+            EnclaveKind::Command(_) => {
+                let work = Work::thread(thread, EnclaveEntry::ExecutableNonMain);
+                work_sender.send(work).map_err(|e| {
+                    enclave.threads_queue.push(e.0.into_stopped_tcs());
+                    io::Error::new(IoErrorKind::NotConnected, "Work Sender: send error")
+                })
+            }
+            EnclaveKind::Library(_) => Self::start_library_thread(enclave, thread),
+        }
+    }
+
+    /// Run a thread launched by a library on a host thread of its own.
+    ///
+    /// Nothing waits for such a thread, so failures to create the host
+    /// thread and errors returned by the thread are counted in the metrics,
+    /// as `launches_failed` and `threads_failed` respectively.
+    fn start_library_thread(enclave: &Arc<Self>, thread: StoppedTcs) -> IoResult<()> {
+        // The work is handed over after the thread has been created, so that
+        // the TCS isn't lost if that fails
+        let (work_send, work_receive) = std::sync::mpsc::channel::<Work>();
+        let enclave_clone = enclave.clone();
+        let spawned = thread::Builder::new()
+            .name("enclave-library-thread".to_owned())
+            .spawn(move || {
+                if let Ok(work) = work_receive.recv() {
+                    let metrics = enclave_clone.metrics.clone();
+                    let result = EnclaveState::run(enclave_clone, 1, work);
+                    if let Err(e) = Self::library_result(result) {
+                        metrics.thread_failed();
+                        eprintln!("Enclave thread launched by a library failed: {}", e);
+                    }
+                }
+            });
+        let work = Work::thread(thread, EnclaveEntry::LibraryThread);
+        match spawned {
+            Ok(_) => {
+                let _ = work_send.send(work);
+                Ok(())
+            }
+            Err(e) => {
+                enclave.metrics.launch_failed();
+                enclave.threads_queue.push(work.into_stopped_tcs());
+                enclave.notify_tcs_waiters();
+                Err(e)
+            }
+        }
+    }
+
+    fn notify_tcs_waiters(&self) {
+        if let Some(library) = self.kind.as_library() {
+            // Taking the lock ensures a waiter that just found the queue
+            // empty is blocked on the condition variable, or has registered
+            // its waker, before notifying.
+            let wakers = mem::take(&mut library.tcs_waiters.lock().unwrap().wakers);
+            library.tcs_available.notify_all();
+            for (_, waker) in wakers {
+                waker.wake();
+            }
+        }
+    }
+
     pub(crate) fn library_entry(
         enclave: &Arc<Self>,
         p1: u64,
@@ -925,23 +1936,61 @@ impl EnclaveState {
         p3: u64,
         p4: u64,
         p5: u64,
+        wait: TcsWait,
+    ) -> StdResult<(u64, u64), failure::Error> {
+        let thread = enclave.acquire_library_tcs(wait)?;
+        let work = Self::library_work(thread, p1, p2, p3, p4, p5);
+        // Threads launched during the call are run separately, so only the
+        // calling thread needs a worker thread
+        let num_of_worker_threads = 1;
+
+        let library_result = EnclaveState::run(enclave.clone(), num_of_worker_threads, work);
+
+        Self::library_result(library_result)
+    }
+
+    /// Like `library_entry` with `TcsWait::Indefinite`, but waits for a TCS
+    /// asynchronously.
+    ///
+    /// The usercall handlers aren't `Send`, so they're polled on a thread of
+    /// the blocking thread pool, within the current tokio context, instead
+    /// of by the returned future. Once a TCS has been obtained, the call
+    /// runs to completion and returns the TCS even if the future is dropped.
+    pub(crate) async fn library_entry_async(
+        enclave: Arc<Self>,
+        p1: u64,
+        p2: u64,
+        p3: u64,
+        p4: u64,
+        p5: u64,
     ) -> StdResult<(u64, u64), failure::Error> {
-        let thread = enclave.threads_queue.pop().expect("threads queue empty");
-        let work = Work {
+        let thread = TcsWaiter { enclave: enclave.clone(), id: None }.await?;
+        let work = Self::library_work(thread, p1, p2, p3, p4, p5);
+        let handle = tokio::runtime::Handle::current();
+
+        let library_result = tokio::task::spawn_blocking(move || {
+            handle.enter(|| futures::executor::block_on(EnclaveState::run_async(enclave, work)))
+        }).await?;
+
+        Self::library_result(library_result)
+    }
+
+    fn library_work(thread: StoppedTcs, p1: u64, p2: u64, p3: u64, p4: u64, p5: u64) -> Work {
+        Work {
             tcs: RunningTcs {
+                address: Some(thread.tcs.address()),
                 event_queue: thread.event_queue,
                 mode: EnclaveEntry::Library,
                 pending_event_set: 0,
                 pending_events: Default::default(),
             },
             entry: CoEntry::Initial(thread.tcs, p1, p2, p3, p4, p5),
-        };
-        // As currently we are not supporting spawning threads let the number of threads be 2
-        // one for usercall handling the other for actually running
-        let num_of_worker_threads = 1;
-
-        let library_result = EnclaveState::run(enclave.clone(), num_of_worker_threads, work);
+        }
+    }
 
+    fn library_result(
+        library_result: StdResult<(u64, u64), EnclaveAbort<EnclavePanic>>,
+    ) -> StdResult<(u64, u64), failure::Error> {
         match library_result {
             Err(EnclaveAbort::Exit { panic }) => Err(panic.into()),
             Err(EnclaveAbort::IndefiniteWait) => {
@@ -953,25 +2002,65 @@ impl EnclaveState {
             Err(EnclaveAbort::Secondary) => {
                 bail!("This thread exited because another thread aborted")
             }
+            Err(EnclaveAbort::RunnerPanicked(message)) => {
+                bail!("The enclave runner panicked while running the enclave: {}", message)
+            }
+            Err(EnclaveAbort::ReplayDiverged(message)) => {
+                bail!("The usercall replay diverged from the recording: {}", message)
+            }
             Err(EnclaveAbort::MainReturned) => unreachable!(),
             Ok(result) => Ok(result),
         }
     }
 
+    fn send_to_all(&self, event_set: u8) {
+        for queue in self.event_queues.values() {
+            let _ = queue.unbounded_send(event_set);
+        }
+    }
+
+    /// Abort a command from outside the enclave. Threads waiting for events
+    /// are woken up, and no further usercalls are handled.
+    pub(crate) fn abort(&self) {
+        let cmd = self.kind.as_command().expect("only commands can be aborted");
+        cmd.aborted.store(true, Ordering::SeqCst);
+        self.abort_all_threads();
+        cmd.abort_notify.notify();
+    }
+
     fn abort_all_threads(&self) {
         self.exiting.store(true, Ordering::SeqCst);
         // wake other threads
         for queue in self.event_queues.values() {
             let _ = queue.unbounded_send(EV_ABORT as _);
         }
+        self.notify_tcs_waiters();
     }
 }
 
+#[cfg(unix)]
+fn termination_signals() -> IoResult<Pin<Box<dyn TokioStream<Item = ()>>>> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let interrupt = signal(SignalKind::interrupt())?;
+    let terminate = signal(SignalKind::terminate())?;
+    Ok(Box::pin(futures::stream::select(interrupt, terminate)))
+}
+
+#[cfg(not(unix))]
+fn termination_signals() -> IoResult<Pin<Box<dyn TokioStream<Item = ()>>>> {
+    Ok(Box::pin(futures::stream::unfold((), |()| async {
+        tokio::signal::ctrl_c().await.ok().map(|()| ((), ()))
+    })))
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 enum EnclaveEntry {
     ExecutableMain,
     ExecutableNonMain,
     Library,
+    /// A thread launched by a library
+    LibraryThread,
 }
 
 #[repr(C)]
@@ -1081,6 +2170,101 @@ pub trait UsercallExtension: 'static + Send + Sync + std::fmt::Debug {
             Ok(None)
         }.boxed_local()
     }
+
+    /// Handle a usercall with a user-defined number, i.e. a number with the
+    /// [`USERCALL_USER_DEFINED`](../../fortanix_sgx_abi/constant.USERCALL_USER_DEFINED.html)
+    /// bit set. The meaning of the arguments `a1` through `a4` and of the
+    /// returned values is defined by the application. Arguments that point to
+    /// user memory can be accessed using `memory`.
+    ///
+    /// If `user_defined_usercall` returns `None`, the usercall is considered
+    /// invalid and the enclave is aborted. This is the default.
+    #[allow(unused)]
+    fn user_defined_usercall<'future>(
+        &'future self,
+        n: u64,
+        a1: u64,
+        a2: u64,
+        a3: u64,
+        a4: u64,
+        memory: &'future UserMemory,
+    ) -> std::pin::Pin<Box<dyn Future<Output = Option<(u64, u64)>> + 'future>> {
+        async {
+            None
+        }.boxed_local()
+    }
+
+    /// The features this extension provides, which are reported to the
+    /// enclave by the [`capabilities`] usercall. This is a combination of
+    /// [`CAPABILITY_USER_DEFINED_USERCALLS`], if the extension handles
+    /// user-defined usercalls, and [`CAPABILITY_EXTENSION_ADDRESSES`], if it
+    /// handles some addresses in `connect_stream` or `bind_stream`. Other
+    /// bits are ignored. The default is 0.
+    ///
+    /// [`capabilities`]: ../../fortanix_sgx_abi/struct.Usercalls.html#method.capabilities
+    /// [`CAPABILITY_USER_DEFINED_USERCALLS`]: ../../fortanix_sgx_abi/constant.CAPABILITY_USER_DEFINED_USERCALLS.html
+    /// [`CAPABILITY_EXTENSION_ADDRESSES`]: ../../fortanix_sgx_abi/constant.CAPABILITY_EXTENSION_ADDRESSES.html
+    fn capabilities(&self) -> u64 {
+        0
+    }
+}
+
+/// Access to user memory for handlers of [user-defined usercalls](trait.UsercallExtension.html#method.user_defined_usercall).
+///
+/// Pointers to user memory are passed as plain usercall arguments. These
+/// functions check that the memory range is valid and doesn't overlap the
+/// enclave, but otherwise trust the enclave to only pass pointers to user
+/// memory it has allocated, as is the case for the predefined usercalls.
+#[derive(Debug)]
+pub struct UserMemory {
+    enclave_range: Range<usize>,
+    resources: Arc<ResourceCounters>,
+}
+
+impl UserMemory {
+    fn check(&self, ptr: u64, len: usize) -> IoResult<*mut u8> {
+        let start = ptr as usize;
+        let end = start.checked_add(len).ok_or(IoErrorKind::InvalidInput)?;
+        if start == 0 || (start < self.enclave_range.end && end > self.enclave_range.start) {
+            return Err(IoErrorKind::InvalidInput.into());
+        }
+        Ok(start as _)
+    }
+
+    /// Copy `len` bytes starting at `ptr` out of user memory.
+    pub fn read(&self, ptr: u64, len: usize) -> IoResult<Vec<u8>> {
+        if len == 0 {
+            return Ok(vec![]);
+        }
+        let ptr = self.check(ptr, len)?;
+        Ok(unsafe { slice::from_raw_parts(ptr, len).to_vec() })
+    }
+
+    /// Copy `data` into user memory starting at `ptr`.
+    pub fn write(&self, ptr: u64, data: &[u8]) -> IoResult<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let ptr = self.check(ptr, data.len())?;
+        unsafe { ptr::copy_nonoverlapping(data.as_ptr(), ptr, data.len()) };
+        Ok(())
+    }
+
+    /// Allocate a user memory buffer holding `data`, and store the
+    /// [`ByteBuffer`](../../fortanix_sgx_abi/struct.ByteBuffer.html)
+    /// describing it at `ptr`. The enclave must deallocate the buffer, as for
+    /// buffers returned by the `read_alloc` usercall. The buffer counts
+    /// against the enclave's memory limit until then.
+    pub fn write_byte_buffer(&self, ptr: u64, data: Vec<u8>) -> IoResult<()> {
+        let ptr = self.check(ptr, mem::size_of::<ByteBuffer>())?;
+        let buf = if data.is_empty() {
+            ByteBuffer { data: ptr::null(), len: 0 }
+        } else {
+            ByteBuffer { data: self.resources.alloc_user_bytes(&data)?, len: data.len() }
+        };
+        unsafe { ptr::write_unaligned(ptr as *mut ByteBuffer, buf) };
+        Ok(())
+    }
 }
 
 impl<T: UsercallExtension> From<T> for Box<dyn UsercallExtension> {
@@ -1096,21 +2280,25 @@ impl UsercallExtension for UsercallExtensionDefault {}
 impl<'tcs> IOHandlerInput<'tcs> {
     async fn lookup_fd(&self, fd: Fd) -> IoResult<Arc<AsyncFileDesc>> {
         match self.enclave.fds.lock().await.get(&fd) {
-            Some(stream) => Ok(stream.clone()),
+            Some(open_fd) => Ok(open_fd.desc.clone()),
             None => Err(IoErrorKind::BrokenPipe.into()), // FIXME: Rust normally maps Unix EBADF to `Other`
         }
     }
 
-    async fn alloc_fd(&self, stream: AsyncFileDesc) -> Fd {
+    /// Add `stream` to the file descriptor table. `connection` is held until
+    /// the file descriptor is closed.
+    async fn alloc_fd(&self, stream: AsyncFileDesc, connection: Option<ResourceGuard>) -> IoResult<Fd> {
+        let mut resources = vec![self.enclave.resources.acquire_guard(Resource::Fds)?];
+        resources.extend(connection);
         let fd = (self
             .enclave
             .last_fd
             .fetch_add(1, Ordering::Relaxed)
             .checked_add(1)
             .expect("FD overflow")) as Fd;
-        let prev = self.enclave.fds.lock().await.insert(fd, Arc::new(stream));
+        let prev = self.enclave.fds.lock().await.insert(fd, OpenFd::new(stream, resources));
         debug_assert!(prev.is_none());
-        fd
+        Ok(fd)
     }
 
     #[inline(always)]
@@ -1120,167 +2308,380 @@ impl<'tcs> IOHandlerInput<'tcs> {
 
     #[inline(always)]
     async fn read(&self, fd: Fd, buf: &mut [u8]) -> IoResult<usize> {
-        let file_desc = self.lookup_fd(fd).await?;
-        file_desc.as_stream()?.async_read(buf).await
+        if let Some(replayer) = self.enclave.replayer() {
+            return replayer.read(fd, buf);
+        }
+        let ret = async {
+            let file_desc = self.lookup_fd(fd).await?;
+            file_desc.as_stream()?.async_read(buf).await
+        }.await;
+        if let Ok(n) = ret {
+            self.enclave.metrics.read(fd, n);
+        }
+        if let Some(recorder) = self.enclave.recorder() {
+            recorder.read(fd, buf, &ret);
+        }
+        ret
     }
 
     #[inline(always)]
     async fn read_alloc(&self, fd: Fd, buf: &mut OutputBuffer<'tcs>) -> IoResult<()> {
-        let file_desc = self.lookup_fd(fd).await?;
-        let v = file_desc.as_stream()?.async_read_alloc().await?;
-        buf.set(v);
-        Ok(())
+        if let Some(replayer) = self.enclave.replayer() {
+            return replayer.read_alloc(fd, buf);
+        }
+        let ret = async {
+            let file_desc = self.lookup_fd(fd).await?;
+            let v = file_desc.as_stream()?.async_read_alloc().await?;
+            self.enclave.metrics.read(fd, v.len());
+            buf.set(v)?;
+            Ok(())
+        }.await;
+        if let Some(recorder) = self.enclave.recorder() {
+            recorder.read_alloc(fd, buf, &ret);
+        }
+        ret
     }
 
     #[inline(always)]
     async fn write(&self, fd: Fd, buf: &[u8]) -> IoResult<usize> {
-        let file_desc = self.lookup_fd(fd).await?;
-        return file_desc.as_stream()?.async_write(buf).await;
+        if let Some(replayer) = self.enclave.replayer() {
+            let ret = replayer.write(fd, buf);
+            match (fd, &ret) {
+                (FD_STDOUT, Ok(n)) | (FD_STDERR, Ok(n)) => self.write_replayed_output(fd, &buf[..*n]).await,
+                _ => {}
+            }
+            return ret;
+        }
+        let ret = async {
+            let file_desc = self.lookup_fd(fd).await?;
+            file_desc.as_stream()?.async_write(buf).await
+        }.await;
+        if let Ok(n) = ret {
+            self.enclave.metrics.written(fd, n);
+        }
+        if let (FD_STDERR, Some(symbolizer), Ok(n)) = (fd, &self.enclave.symbolizer, &ret) {
+            symbolizer.enclave_output(&buf[..*n]);
+        }
+        if let Some(recorder) = self.enclave.recorder() {
+            recorder.write(fd, &ret);
+        }
+        ret
+    }
+
+    /// Write the output of a replayed `write` to the enclave's standard
+    /// output or error stream, so it ends up where it would when running
+    /// live.
+    async fn write_replayed_output(&self, fd: Fd, mut data: &[u8]) {
+        if let (FD_STDERR, Some(symbolizer)) = (fd, &self.enclave.symbolizer) {
+            symbolizer.enclave_output(data);
+        }
+        let file_desc = match self.lookup_fd(fd).await {
+            Ok(file_desc) => file_desc,
+            Err(_) => return,
+        };
+        let stream = match file_desc.as_stream() {
+            Ok(stream) => stream,
+            Err(_) => return,
+        };
+        while !data.is_empty() {
+            match stream.async_write(data).await {
+                Ok(0) | Err(_) => return,
+                Ok(n) => data = &data[n..],
+            }
+        }
+        let _ = stream.async_flush().await;
     }
 
     #[inline(always)]
     async fn flush(&self, fd: Fd) -> IoResult<()> {
-        let file_desc = self.lookup_fd(fd).await?;
-        file_desc.as_stream()?.async_flush().await
+        if let Some(replayer) = self.enclave.replayer() {
+            return replayer.flush(fd);
+        }
+        let ret = async {
+            let file_desc = self.lookup_fd(fd).await?;
+            file_desc.as_stream()?.async_flush().await
+        }.await;
+        if let Some(recorder) = self.enclave.recorder() {
+            recorder.flush(fd, &ret);
+        }
+        ret
     }
 
     #[inline(always)]
     async fn close(&self, fd: Fd) {
         self.enclave.fds.lock().await.remove(&fd);
+        self.enclave.metrics.closed(fd);
+    }
+
+    /// Check `addr` against the network policy. Files opened by an extension
+    /// such as `HostDirectory` are not network connections, so callers only
+    /// check `file:` addresses if no extension handles them, and check other
+    /// addresses before passing them to an extension.
+    async fn check_network_policy(&self, operation: NetworkOperation, addr: &str) -> IoResult<CheckedAddr> {
+        match self.enclave.network_policy.check(operation, addr).await {
+            (NetworkAction::Allow, checked) => Ok(checked),
+            (NetworkAction::Deny, _) => {
+                self.enclave.metrics.network_denied();
+                Err(IoErrorKind::PermissionDenied.into())
+            }
+        }
     }
 
     #[inline(always)]
     async fn bind_stream(
         &self,
         addr: &[u8],
-        local_addr: Option<&mut OutputBuffer<'tcs>>,
+        mut local_addr: Option<&mut OutputBuffer<'tcs>>,
     ) -> IoResult<Fd> {
-        let addr = str::from_utf8(addr).map_err(|_| IoErrorKind::ConnectionRefused)?;
-        let mut local_addr_str = local_addr.as_ref().map(|_| String::new());
-        if let Some(stream_ext) = self
-            .enclave
-            .usercall_ext
-            .bind_stream(addr, local_addr_str.as_mut()).await?
-        {
-            if let Some(local_addr) = local_addr {
-                local_addr.set(local_addr_str.unwrap().into_bytes());
-            }
-            return Ok(self.alloc_fd(AsyncFileDesc::listener(stream_ext)).await);
+        if let Some(replayer) = self.enclave.replayer() {
+            return replayer.bind_stream(addr, local_addr);
         }
+        let ret = async {
+            let local_addr = local_addr.as_deref_mut();
+            let addr = str::from_utf8(addr).map_err(|_| IoErrorKind::ConnectionRefused)?;
+            let checked = match parse_file_addr(addr) {
+                Some(_) => None,
+                None => Some(self.check_network_policy(NetworkOperation::Bind, addr).await?),
+            };
+            let mut local_addr_str = local_addr.as_ref().map(|_| String::new());
+            if let Some(stream_ext) = self
+                .enclave
+                .usercall_ext
+                .bind_stream(addr, local_addr_str.as_mut()).await?
+            {
+                if let Some(local_addr) = local_addr {
+                    local_addr.set(local_addr_str.unwrap().into_bytes())?;
+                }
+                return self.alloc_fd(AsyncFileDesc::listener(stream_ext), None).await;
+            }
+            let checked = match checked {
+                Some(checked) => checked,
+                None => self.check_network_policy(NetworkOperation::Bind, addr).await?,
+            };
 
-        let socket = tokio::net::TcpListener::bind(addr).await?;
-        if let Some(local_addr) = local_addr {
-            local_addr.set(socket.local_addr()?.to_string().into_bytes());
+            let addrs = match checked {
+                CheckedAddr::Unix => {
+                    return self.bind_unix_socket(&addr[UNIX_SOCKET_PREFIX.len()..], local_addr).await;
+                }
+                CheckedAddr::Resolved(addrs) => addrs,
+                CheckedAddr::Unresolved(e) => return Err(e),
+            };
+
+            let socket = tokio::net::TcpListener::bind(&addrs[..]).await?;
+            if let Some(local_addr) = local_addr {
+                local_addr.set(socket.local_addr()?.to_string().into_bytes())?;
+            }
+            self.alloc_fd(AsyncFileDesc::listener(Box::new(socket)), None).await
+        }.await;
+        if let Some(recorder) = self.enclave.recorder() {
+            recorder.bind_stream(addr, &local_addr, &ret);
         }
-        Ok(self.alloc_fd(AsyncFileDesc::listener(Box::new(socket))).await)
+        ret
     }
 
     #[inline(always)]
     async fn accept_stream(
         &self,
         fd: Fd,
-        local_addr: Option<&mut OutputBuffer<'tcs>>,
-        peer_addr: Option<&mut OutputBuffer<'tcs>>,
+        mut local_addr: Option<&mut OutputBuffer<'tcs>>,
+        mut peer_addr: Option<&mut OutputBuffer<'tcs>>,
     ) -> IoResult<Fd> {
-        let mut local_addr_str = local_addr.as_ref().map(|_| String::new());
-        let mut peer_addr_str = peer_addr.as_ref().map(|_| String::new());
-
-        let file_desc = self.lookup_fd(fd).await?;
-        let stream = file_desc.as_listener()?.async_accept(local_addr_str.as_mut(), peer_addr_str.as_mut()).await?.unwrap();
-
-        if let Some(local_addr) = local_addr {
-            local_addr.set(&local_addr_str.unwrap().into_bytes()[..])
+        if let Some(replayer) = self.enclave.replayer() {
+            return replayer.accept_stream(fd, local_addr, peer_addr);
         }
-        if let Some(peer_addr) = peer_addr {
-            peer_addr.set(&peer_addr_str.unwrap().into_bytes()[..])
+        let ret = async {
+            let local_addr = local_addr.as_deref_mut();
+            let peer_addr = peer_addr.as_deref_mut();
+            let mut local_addr_str = local_addr.as_ref().map(|_| String::new());
+            let mut peer_addr_str = peer_addr.as_ref().map(|_| String::new());
+
+            let file_desc = self.lookup_fd(fd).await?;
+            let listener = file_desc.as_listener()?;
+            let connection = self.enclave.resources.acquire_guard(Resource::Connections)?;
+            let pending = self.enclave.resources.acquire_guard(Resource::PendingAccepts)?;
+            let stream = listener.async_accept(local_addr_str.as_mut(), peer_addr_str.as_mut()).await?.unwrap();
+            drop(pending);
+
+            if let Some(local_addr) = local_addr {
+                local_addr.set(&local_addr_str.unwrap().into_bytes()[..])?
+            }
+            if let Some(peer_addr) = peer_addr {
+                peer_addr.set(&peer_addr_str.unwrap().into_bytes()[..])?
+            }
+            self.alloc_fd(AsyncFileDesc::stream(stream), Some(connection)).await
+        }.await;
+        if let Some(recorder) = self.enclave.recorder() {
+            recorder.accept_stream(fd, &local_addr, &peer_addr, &ret);
         }
-        Ok(self.alloc_fd(AsyncFileDesc::stream(stream)).await)
+        ret
     }
 
     #[inline(always)]
     async fn connect_stream(
         &self,
         addr: &[u8],
-        local_addr: Option<&mut OutputBuffer<'tcs>>,
-        peer_addr: Option<&mut OutputBuffer<'tcs>>,
+        mut local_addr: Option<&mut OutputBuffer<'tcs>>,
+        mut peer_addr: Option<&mut OutputBuffer<'tcs>>,
     ) -> IoResult<Fd> {
-        let addr = str::from_utf8(addr).map_err(|_| IoErrorKind::ConnectionRefused)?;
-        let mut local_addr_str = local_addr.as_ref().map(|_| String::new());
-        let mut peer_addr_str = peer_addr.as_ref().map(|_| String::new());
-        if let Some(stream_ext) = self.enclave.usercall_ext.connect_stream(
-            addr,
-            local_addr_str.as_mut(),
-            peer_addr_str.as_mut(),
-        ).await? {
+        if let Some(replayer) = self.enclave.replayer() {
+            return replayer.connect_stream(addr, local_addr, peer_addr);
+        }
+        let ret = async {
+            let local_addr = local_addr.as_deref_mut();
+            let peer_addr = peer_addr.as_deref_mut();
+            let addr = str::from_utf8(addr).map_err(|_| IoErrorKind::ConnectionRefused)?;
+            let checked = match parse_file_addr(addr) {
+                Some(_) => None,
+                None => Some(self.check_network_policy(NetworkOperation::Connect, addr).await?),
+            };
+            let connection = self.enclave.resources.acquire_guard(Resource::Connections)?;
+            let mut local_addr_str = local_addr.as_ref().map(|_| String::new());
+            let mut peer_addr_str = peer_addr.as_ref().map(|_| String::new());
+            if let Some(stream_ext) = self.enclave.usercall_ext.connect_stream(
+                addr,
+                local_addr_str.as_mut(),
+                peer_addr_str.as_mut(),
+            ).await? {
+                if let Some(local_addr) = local_addr {
+                    local_addr.set(local_addr_str.unwrap().into_bytes())?;
+                }
+                if let Some(peer_addr) = peer_addr {
+                    peer_addr.set(peer_addr_str.unwrap().into_bytes())?;
+                }
+                return self.alloc_fd(AsyncFileDesc::stream(stream_ext), Some(connection)).await;
+            }
+            let checked = match checked {
+                Some(checked) => checked,
+                None => self.check_network_policy(NetworkOperation::Connect, addr).await?,
+            };
+
+            let addrs = match checked {
+                CheckedAddr::Unix => {
+                    let path = &addr[UNIX_SOCKET_PREFIX.len()..];
+                    return self.connect_unix_socket(path, local_addr, peer_addr, connection).await;
+                }
+                CheckedAddr::Resolved(addrs) => addrs,
+                CheckedAddr::Unresolved(e) => return Err(e),
+            };
+
+            let stream = tokio::net::TcpStream::connect(&addrs[..]).await?;
+
             if let Some(local_addr) = local_addr {
-                local_addr.set(local_addr_str.unwrap().into_bytes());
+                match stream.local_addr() {
+                    Ok(local) => local_addr.set(local.to_string().into_bytes())?,
+                    Err(_) => local_addr.set(&b"error"[..])?,
+                }
             }
             if let Some(peer_addr) = peer_addr {
-                peer_addr.set(peer_addr_str.unwrap().into_bytes());
+                match stream.peer_addr() {
+                    Ok(peer) => peer_addr.set(peer.to_string().into_bytes())?,
+                    Err(_) => peer_addr.set(&b"error"[..])?,
+                }
             }
-            return Ok(self.alloc_fd(AsyncFileDesc::stream(stream_ext)).await);
+            self.alloc_fd(AsyncFileDesc::stream(Box::new(stream)), Some(connection)).await
+        }.await;
+        if let Some(recorder) = self.enclave.recorder() {
+            recorder.connect_stream(addr, &local_addr, &peer_addr, &ret);
         }
+        ret
+    }
 
-        let stream = tokio::net::TcpStream::connect(addr).await?;
+    #[cfg(unix)]
+    async fn bind_unix_socket(
+        &self,
+        path: &str,
+        local_addr: Option<&mut OutputBuffer<'tcs>>,
+    ) -> IoResult<Fd> {
+        let socket = tokio::net::UnixListener::bind(path)?;
+        if let Some(local_addr) = local_addr {
+            local_addr.set(unix_socket_addr(socket.local_addr()).into_bytes())?;
+        }
+        self.alloc_fd(AsyncFileDesc::listener(Box::new(socket)), None).await
+    }
 
+    #[cfg(unix)]
+    async fn connect_unix_socket(
+        &self,
+        path: &str,
+        local_addr: Option<&mut OutputBuffer<'tcs>>,
+        peer_addr: Option<&mut OutputBuffer<'tcs>>,
+        connection: ResourceGuard,
+    ) -> IoResult<Fd> {
+        let stream = tokio::net::UnixStream::connect(path).await?;
         if let Some(local_addr) = local_addr {
-            match stream.local_addr() {
-                Ok(local) => local_addr.set(local.to_string().into_bytes()),
-                Err(_) => local_addr.set(&b"error"[..]),
-            }
+            local_addr.set(unix_socket_addr(stream.local_addr()).into_bytes())?;
         }
         if let Some(peer_addr) = peer_addr {
-            match stream.peer_addr() {
-                Ok(peer) => peer_addr.set(peer.to_string().into_bytes()),
-                Err(_) => peer_addr.set(&b"error"[..]),
-            }
+            peer_addr.set(unix_socket_addr(stream.peer_addr()).into_bytes())?;
         }
-        Ok(self.alloc_fd(AsyncFileDesc::stream(Box::new(stream))).await)
+        self.alloc_fd(AsyncFileDesc::stream(Box::new(stream)), Some(connection)).await
+    }
+
+    #[cfg(not(unix))]
+    async fn bind_unix_socket(
+        &self,
+        _path: &str,
+        _local_addr: Option<&mut OutputBuffer<'tcs>>,
+    ) -> IoResult<Fd> {
+        Err(IoErrorKind::InvalidInput.into())
+    }
+
+    #[cfg(not(unix))]
+    async fn connect_unix_socket(
+        &self,
+        _path: &str,
+        _local_addr: Option<&mut OutputBuffer<'tcs>>,
+        _peer_addr: Option<&mut OutputBuffer<'tcs>>,
+        _connection: ResourceGuard,
+    ) -> IoResult<Fd> {
+        Err(IoErrorKind::InvalidInput.into())
     }
 
     #[inline(always)]
     fn launch_thread(&self) -> IoResult<()> {
-        // check if enclave is of type command
-        self.enclave
-            .kind
-            .as_command()
-            .ok_or(IoErrorKind::InvalidInput)?;
-        let new_tcs = match self.enclave.threads_queue.pop() {
-            Ok(tcs) => tcs,
-            Err(_) => {
-                return Err(IoErrorKind::WouldBlock.into());
+        // Once a command is exiting, its TCSs are no longer available
+        if self.enclave.exiting.load(Ordering::SeqCst) {
+            return Err(IoErrorKind::WouldBlock.into());
+        }
+        match self.enclave.threads_queue.launch() {
+            Launch::Start(thread) => EnclaveState::start_thread(&self.enclave, thread, self.work_sender),
+            Launch::Queued => Ok(()),
+            Launch::Unavailable => {
+                if self.enclave.threads_queue.first_launch_failure() {
+                    eprintln!(
+                        "Unable to launch an enclave thread: none of the enclave's {} TCSs is available. \
+                         The enclave needs to be converted with more threads (e.g. using the \
+                         --threads option of ftxsgx-elf2sgxs), or thread launches can be queued \
+                         using EnclaveBuilder::launch_thread_policy.",
+                        self.enclave.event_queues.len()
+                    );
+                }
+                Err(IoErrorKind::WouldBlock.into())
             }
-        };
+        }
+    }
 
-        let ret = self.work_sender.send(Work {
-            tcs: RunningTcs {
-                pending_events: Default::default(),
-                pending_event_set: 0,
-                event_queue: new_tcs.event_queue,
-                mode: EnclaveEntry::ExecutableNonMain,
-            },
-            entry: CoEntry::Initial(new_tcs.tcs, 0, 0, 0, 0, 0),
-        });
-        match ret {
-            Ok(()) => Ok(()),
-            Err(e) => {
-                let event_queue = e.0.tcs.event_queue;
-                let entry = e.0.entry;
-                match entry {
-                    CoEntry::Initial(tcs, _, _ ,_, _, _) => {
-                        self.enclave.threads_queue.push(StoppedTcs {
-                            tcs,
-                            event_queue,
-                        });
-                    },
-                    _ => unreachable!(),
-                };
-                Err(std::io::Error::new(
-                    IoErrorKind::NotConnected,
-                    "Work Sender: send error",
-                ))
-            }
+    #[inline(always)]
+    async fn user_defined_usercall(
+        &self,
+        n: u64,
+        a1: u64,
+        a2: u64,
+        a3: u64,
+        a4: u64,
+    ) -> StdResult<(u64, u64), EnclaveAbort<bool>> {
+        if n & USERCALL_USER_DEFINED == 0 {
+            return Err(EnclaveAbort::InvalidUsercall(n));
         }
+        let memory = UserMemory {
+            enclave_range: self.enclave.enclave_range.clone(),
+            resources: self.enclave.resources.clone(),
+        };
+        self.enclave
+            .usercall_ext
+            .user_defined_usercall(n, a1, a2, a3, a4, &memory)
+            .await
+            .ok_or(EnclaveAbort::InvalidUsercall(n))
     }
 
     #[inline(always)]
@@ -1290,7 +2691,7 @@ impl<'tcs> IOHandlerInput<'tcs> {
     }
 
     fn check_event_set(set: u64) -> IoResult<u8> {
-        const EV_ALL: u64 = EV_USERCALLQ_NOT_FULL | EV_RETURNQ_NOT_EMPTY | EV_UNPARK;
+        const EV_ALL: u64 = EV_USERCALLQ_NOT_FULL | EV_RETURNQ_NOT_EMPTY | EV_UNPARK | EV_SIGNAL;
         if (set & !EV_ALL) != 0 {
             return Err(IoErrorKind::InvalidInput.into());
         }
@@ -1302,60 +2703,80 @@ impl<'tcs> IOHandlerInput<'tcs> {
 
     #[inline(always)]
     async fn wait(&mut self, event_mask: u64, timeout: u64) -> IoResult<u64> {
-        let wait = match timeout {
-            WAIT_NO => false,
-            WAIT_INDEFINITE => true,
-            _ => return Err(IoErrorKind::InvalidInput.into()),
-        };
+        // Replayed per TCS, by offset since the enclave may be loaded at a
+        // different address
+        let tcs_offset = self.tcs.address.map(|tcs| (tcs.0 - self.enclave.enclave_range.start) as u64);
+        if let Some(replayer) = self.enclave.replayer() {
+            return replayer.wait(tcs_offset);
+        }
+        let ret = async {
+            let (wait, mut timeout) = match timeout {
+                WAIT_NO => (false, None),
+                WAIT_INDEFINITE => (true, None),
+                timeout => (true, Some(self.enclave.clock.delay(time::Duration::from_nanos(timeout)).fuse())),
+            };
 
-        let event_mask = Self::check_event_set(event_mask)?;
+            let event_mask = Self::check_event_set(event_mask)?;
 
-        let mut ret = None;
+            let mut ret = None;
 
-        if (self.tcs.pending_event_set & event_mask) != 0 {
-            if let Some(pos) = self
-                .tcs
-                .pending_events
-                .iter()
-                .position(|ev| (ev & event_mask) != 0)
-            {
-                ret = self.tcs.pending_events.remove(pos);
-                self.tcs.pending_event_set = self.tcs.pending_events.iter().fold(0, |m, ev| m | ev);
+            if (self.tcs.pending_event_set & event_mask) != 0 {
+                if let Some(pos) = self
+                    .tcs
+                    .pending_events
+                    .iter()
+                    .position(|ev| (ev & event_mask) != 0)
+                {
+                    ret = self.tcs.pending_events.remove(pos);
+                    self.tcs.pending_event_set = self.tcs.pending_events.iter().fold(0, |m, ev| m | ev);
+                }
             }
-        }
 
-        if ret.is_none() {
-            loop {
-                let ev = if wait {
-                    Ok(self.tcs.event_queue.next().await.unwrap())
-                } else {
-                    match self.tcs.event_queue.try_next() {
-                        Ok(Some(ev)) => Ok(ev),
-                        Err(_) => break,
-                        Ok(None) => Err(()),
+            if ret.is_none() {
+                let _waiting = if wait { Some(self.enclave.metrics.tcs_waiting()) } else { None };
+                loop {
+                    let ev = if let Some(mut timeout) = timeout.as_mut() {
+                        // An event that is already queued takes precedence
+                        // over an expired timeout
+                        futures::select_biased! {
+                            ev = self.tcs.event_queue.next() => Ok(ev.unwrap()),
+                            () = timeout => break,
+                        }
+                    } else if wait {
+                        Ok(self.tcs.event_queue.next().await.unwrap())
+                    } else {
+                        match self.tcs.event_queue.try_next() {
+                            Ok(Some(ev)) => Ok(ev),
+                            Err(_) => break,
+                            Ok(None) => Err(()),
+                        }
+                    }
+                    .expect("TCS event queue disconnected unexpectedly");
+                    if (ev & (EV_ABORT as u8)) != 0 {
+                        // dispatch will make sure this is not returned to enclave
+                        return Err(IoErrorKind::Other.into());
                     }
-                }
-                .expect("TCS event queue disconnected unexpectedly");
-                if (ev & (EV_ABORT as u8)) != 0 {
-                    // dispatch will make sure this is not returned to enclave
-                    return Err(IoErrorKind::Other.into());
-                }
 
-                if (ev & event_mask) != 0 {
-                    ret = Some(ev);
-                    break;
-                } else {
-                    self.tcs.pending_events.push_back(ev);
-                    self.tcs.pending_event_set |= ev;
+                    if (ev & event_mask) != 0 {
+                        ret = Some(ev);
+                        break;
+                    } else {
+                        self.tcs.pending_events.push_back(ev);
+                        self.tcs.pending_event_set |= ev;
+                    }
                 }
             }
-        }
 
-        if let Some(ret) = ret {
-            Ok(ret.into())
-        } else {
-            Err(IoErrorKind::WouldBlock.into())
+            if let Some(ret) = ret {
+                Ok(ret.into())
+            } else {
+                Err(IoErrorKind::WouldBlock.into())
+            }
+        }.await;
+        if let Some(recorder) = self.enclave.recorder() {
+            recorder.wait(tcs_offset, &ret);
         }
+        ret
     }
 
     #[inline(always)]
@@ -1387,27 +2808,24 @@ impl<'tcs> IOHandlerInput<'tcs> {
 
     #[inline(always)]
     fn insecure_time(&mut self) -> u64 {
-        let time = time::SystemTime::now()
+        if let Some(replayer) = self.enclave.replayer() {
+            return replayer.insecure_time();
+        }
+        let time = self.enclave.clock.now()
             .duration_since(time::UNIX_EPOCH)
-            .unwrap();
-        (time.subsec_nanos() as u64) + time.as_secs() * 1_000_000_000
+            .unwrap_or_default();
+        let time = (time.subsec_nanos() as u64) + time.as_secs() * 1_000_000_000;
+        if let Some(recorder) = self.enclave.recorder() {
+            recorder.insecure_time(time);
+        }
+        time
     }
 
     #[inline(always)]
     fn alloc(&self, size: usize, alignment: usize) -> IoResult<*mut u8> {
-        unsafe {
-            let layout =
-                Layout::from_size_align(size, alignment).map_err(|_| IoErrorKind::InvalidInput)?;
-            if layout.size() == 0 {
-                return Err(IoErrorKind::InvalidInput.into());
-            }
-            let ptr = System.alloc(layout);
-            if ptr.is_null() {
-                Err(IoErrorKind::Other.into())
-            } else {
-                Ok(ptr)
-            }
-        }
+        let layout =
+            Layout::from_size_align(size, alignment).map_err(|_| IoErrorKind::InvalidInput)?;
+        self.enclave.resources.alloc_user(layout)
     }
 
     #[inline(always)]
@@ -1418,16 +2836,200 @@ impl<'tcs> IOHandlerInput<'tcs> {
             if size == 0 {
                 return Ok(());
             }
-            Ok(System.dealloc(ptr, layout))
+            Ok(self.enclave.resources.free_user(ptr, layout))
         }
     }
 
     #[inline(always)]
     fn async_queues(
-        &self,
-        _usercall_queue: &mut FifoDescriptor<Usercall>,
-        _return_queue: &mut FifoDescriptor<Return>,
-    ) -> IoResult<()> {
-        Err(IoErrorKind::Other.into())
+        &mut self,
+        usercall_queue: &mut FifoDescriptor<Usercall>,
+        return_queue: &mut FifoDescriptor<Return>,
+    ) -> StdResult<IoResult<()>, EnclaveAbort<bool>> {
+        // Libraries may run multiple usercall loops concurrently, which
+        // can't share a single set of queues.
+        if self.enclave.kind.as_command().is_none() {
+            return Ok(Err(IoErrorKind::Other.into()));
+        }
+
+        let mut async_queues = self.enclave.async_queues.lock().unwrap();
+        if async_queues.is_some() {
+            drop(async_queues);
+            return Err(self.exit(true));
+        }
+
+        let queues = AsyncQueues {
+            usercall_queue: Fifo::new(ASYNC_QUEUE_LEN),
+            return_queue: Fifo::new(ASYNC_QUEUE_LEN),
+        };
+        *usercall_queue = queues.usercall_queue.descriptor();
+        *return_queue = queues.return_queue.descriptor();
+        *async_queues = Some(Arc::new(queues));
+        Ok(Ok(()))
+    }
+
+    #[inline(always)]
+    fn capabilities(&self) -> u64 {
+        let mut capabilities = self.enclave.usercall_ext.capabilities()
+            & (CAPABILITY_USER_DEFINED_USERCALLS | CAPABILITY_EXTENSION_ADDRESSES);
+        capabilities |= CAPABILITY_WAIT_TIMEOUT;
+        // See `async_queues`
+        if self.enclave.kind.as_command().is_some() {
+            capabilities |= CAPABILITY_ASYNC_QUEUES;
+            // Signals are only forwarded to commands
+            if self.enclave.signal_grace_period.is_some() {
+                capabilities |= CAPABILITY_SIGNAL_EVENT;
+            }
+        }
+        capabilities
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_memory() {
+        let mut buf = [0u8; 16];
+        let ptr = buf.as_mut_ptr() as usize;
+        let memory = UserMemory { enclave_range: 0x1000..0x2000, resources: Default::default() };
+        memory.write(ptr as u64 + 4, b"abcd").unwrap();
+        assert_eq!(memory.read(ptr as u64 + 2, 4).unwrap(), b"\0\0ab");
+        assert_eq!(memory.read(0, 0).unwrap(), b"");
+
+        // Null, overflowing and enclave addresses are rejected
+        assert!(memory.read(0, 1).is_err());
+        assert!(memory.read(!0, 2).is_err());
+        assert!(memory.read(0xfff, 2).is_err());
+        assert!(memory.write(0x1fff, b"x").is_err());
+        assert!(memory.check(0x2000, 16).is_ok());
+    }
+
+    #[test]
+    fn output_buffer_memory() {
+        let resources = Arc::new(ResourceCounters::new(ResourceLimits {
+            memory: Some(100),
+            ..Default::default()
+        }));
+        let monitor = ResourceMonitor(resources.clone());
+        let layout = |size| Layout::from_size_align(size, 1).unwrap();
+        resources.alloc_user(layout(60)).unwrap();
+
+        // A buffer returned by `read_alloc` is counted until the enclave frees it
+        let mut buf = ByteBuffer { data: ptr::null(), len: 0 };
+        let mut out = OutputBuffer::new(&mut buf, resources.clone());
+        assert!(out.set(&[1; 41][..]).is_err());
+        out.set(&[1; 30][..]).unwrap();
+        assert_eq!(out.get(), Some(&[1; 30][..]));
+        drop(out);
+        assert_eq!((buf.len, monitor.usage().memory), (30, 90));
+        assert!(resources.alloc_user(layout(11)).is_err());
+        unsafe { resources.free_user(buf.data as _, layout(buf.len)) };
+        assert_eq!(monitor.usage().memory, 60);
+        assert!(resources.alloc_user(layout(41)).is_err());
+
+        // Freeing memory the runner didn't count doesn't raise the budget
+        let other = Box::into_raw(vec![0u8; 20].into_boxed_slice()) as *mut u8;
+        unsafe { resources.free_user(other, layout(20)) };
+        assert_eq!(monitor.usage().memory, 60);
+
+        let memory = UserMemory { enclave_range: 0x1000..0x2000, resources: resources.clone() };
+        let mut buf = ByteBuffer { data: ptr::null(), len: 0 };
+        memory.write_byte_buffer(&mut buf as *mut _ as u64, vec![2; 40]).unwrap();
+        assert_eq!(monitor.usage().memory, 100);
+        assert!(memory.write_byte_buffer(&mut buf as *mut _ as u64, vec![2; 1]).is_err());
+    }
+
+    fn command(event_queues: FnvHashMap<TcsAddress, futures::channel::mpsc::UnboundedSender<u8>>) -> Arc<EnclaveState> {
+        let kind = EnclaveKind::Command(Command {
+            panic_reason: Mutex::new(PanicReason {
+                primary_panic_reason: None,
+                other_reasons: vec![],
+                main_context: ThreadContext::default(),
+            }),
+            aborted: AtomicBool::new(false),
+            abort_notify: tokio::sync::Notify::new(),
+        });
+        EnclaveState::new(kind, 0..0, event_queues, vec![], EnclaveConfig::default())
+    }
+
+    /// Handle usercalls after a worker panicked while running a thread.
+    fn handle_panicked(
+        enclave: Arc<EnclaveState>,
+        mode: EnclaveEntry,
+    ) -> StdResult<(u64, u64), EnclaveAbort<EnclavePanic>> {
+        let (io_queue_send, io_queue_receive) = tokio::sync::mpsc::unbounded_channel();
+        let (work_sender, _work_receiver) = crossbeam::crossbeam_channel::unbounded();
+        assert!(io_queue_send.send(UsercallSendData::Panicked(mode, "oops".to_owned())).is_ok());
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(EnclaveState::handle_usercalls(enclave, io_queue_receive, WorkSender::Channel(work_sender)))
+    }
+
+    #[test]
+    fn worker_panic() {
+        let library = EnclaveState::library(vec![], 0..0, EnclaveConfig::default());
+        let result = handle_panicked(library, EnclaveEntry::Library);
+        match EnclaveState::library_result(result) {
+            Err(e) => assert!(e.to_string().contains("panicked while running the enclave: oops")),
+            Ok(_) => panic!("library call succeeded"),
+        }
+
+        let command = || command(Default::default());
+        match handle_panicked(command(), EnclaveEntry::ExecutableMain) {
+            Err(EnclaveAbort::RunnerPanicked(message)) => assert_eq!(message, "oops"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        // Other threads stop the enclave, with the panic as the reason
+        let enclave = command();
+        match handle_panicked(enclave.clone(), EnclaveEntry::ExecutableNonMain) {
+            Err(EnclaveAbort::Secondary) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let cmd = enclave.kind.as_command().unwrap();
+        let primary = futures::executor::block_on(cmd.panic_reason.lock()).primary_panic_reason.take();
+        match primary {
+            Some((EnclaveAbort::RunnerPanicked(message), _)) => assert_eq!(message, "oops"),
+            other => panic!("unexpected reason: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn signal_grace_period() {
+        let (event_sender, mut event_queue) = futures::channel::mpsc::unbounded();
+        let mut event_queues = FnvHashMap::default();
+        event_queues.insert(TcsAddress(0x1000), event_sender);
+        let enclave = command(event_queues);
+        let aborted = || enclave.kind.as_command().unwrap().aborted.load(Ordering::SeqCst);
+        let one_signal = || futures::stream::iter(vec![()]).chain(futures::stream::pending());
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+
+        // Without a signal, nothing is sent
+        rt.block_on(EnclaveState::handle_signals(&enclave, futures::stream::empty(), time::Duration::from_secs(0)));
+        assert!(event_queue.try_next().is_err());
+
+        // The enclave is signaled, and isn't aborted while the grace period
+        // lasts, i.e. until it exits and signal handling is stopped
+        let handle = EnclaveState::handle_signals(&enclave, one_signal(), time::Duration::from_secs(3600));
+        assert!(rt.block_on(async { tokio::time::timeout(time::Duration::from_millis(50), handle).await }).is_err());
+        assert_eq!(event_queue.try_next().unwrap(), Some(EV_SIGNAL as u8));
+        assert!(event_queue.try_next().is_err());
+        assert!(!aborted());
+        assert_eq!(enclave.metrics_monitor().metrics().signal_aborts, 0);
+
+        // The enclave is aborted once the grace period expires
+        rt.block_on(EnclaveState::handle_signals(&enclave, one_signal(), time::Duration::from_millis(10)));
+        assert_eq!(event_queue.try_next().unwrap(), Some(EV_SIGNAL as u8));
+        assert_eq!(event_queue.try_next().unwrap(), Some(EV_ABORT as u8));
+        assert!(aborted());
+        assert_eq!(enclave.metrics_monitor().metrics().signal_aborts, 1);
+
+        // A second signal aborts the enclave right away
+        let enclave = command(Default::default());
+        let signals = futures::stream::iter(vec![(), ()]).chain(futures::stream::pending());
+        let handle = EnclaveState::handle_signals(&enclave, signals, time::Duration::from_secs(3600));
+        assert!(rt.block_on(async { tokio::time::timeout(time::Duration::from_secs(10), handle).await }).is_ok());
+        assert!(enclave.kind.as_command().unwrap().aborted.load(Ordering::SeqCst));
+        assert_eq!(enclave.metrics_monitor().metrics().signal_aborts, 1);
     }
 }
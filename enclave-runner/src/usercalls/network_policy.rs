@@ -0,0 +1,614 @@
+/* Copyright (c) Fortanix, Inc.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Restricting the network addresses an enclave can connect to or bind to.
+
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+
+use serde::de::{self, Deserialize, Deserializer};
+
+use super::UNIX_SOCKET_PREFIX;
+
+/// Whether a network operation is allowed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NetworkAction {
+    Allow,
+    Deny,
+}
+
+impl Default for NetworkAction {
+    fn default() -> Self {
+        NetworkAction::Allow
+    }
+}
+
+/// The network operations an enclave can perform.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NetworkOperation {
+    /// The `connect_stream` usercall
+    Connect,
+    /// The `bind_stream` usercall
+    Bind,
+}
+
+impl fmt::Display for NetworkOperation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NetworkOperation::Connect => f.write_str("connect"),
+            NetworkOperation::Bind => f.write_str("bind"),
+        }
+    }
+}
+
+/// Matches the host part of a network address.
+///
+/// Parsed from one of the following forms:
+/// * `*`, matching any host,
+/// * `*.example.com`, matching any subdomain of `example.com`,
+/// * an IP address or a network in CIDR notation, such as `10.0.0.0/8`,
+/// * `unix:/path`, matching the Unix domain socket at `/path`, or
+///   `unix:/dir/*`, matching any Unix domain socket path starting with `/dir/`,
+/// * any other string, matching that host name exactly.
+///
+/// Host names are compared case-insensitively with the name the enclave
+/// specified. Network patterns are matched against the IP addresses a host
+/// name resolves to, where IPv4-mapped IPv6 addresses such as
+/// `::ffff:10.0.0.1` are treated as the IPv4 address they map to.
+///
+/// Unix domain socket paths are compared after removing empty and `.`
+/// components, so `unix:/run//./a.sock` matches `unix:/run/a.sock`. Paths
+/// are not resolved in the file system: a pattern for a directory also
+/// matches sockets reached through symbolic links in that directory. Paths
+/// with `..` components are invalid in patterns, and are always denied by
+/// [`NetworkPolicy::check`].
+///
+/// [`NetworkPolicy::check`]: struct.NetworkPolicy.html#method.check
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HostPattern {
+    Any,
+    Subdomain(String),
+    Network(IpAddr, u8),
+    UnixSocket(String),
+    UnixSocketPrefix(String),
+    Name(String),
+}
+
+#[derive(Debug, Fail)]
+#[fail(display = "invalid host pattern: {:?}", _0)]
+pub struct InvalidHostPattern(String);
+
+impl FromStr for HostPattern {
+    type Err = InvalidHostPattern;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidHostPattern(s.to_owned());
+        if s == "*" {
+            return Ok(HostPattern::Any);
+        }
+        if s.starts_with("*.") {
+            return Ok(HostPattern::Subdomain(s[1..].to_ascii_lowercase()));
+        }
+        if s.starts_with(UNIX_SOCKET_PREFIX) {
+            return Ok(if s.ends_with('*') {
+                HostPattern::UnixSocketPrefix(normalize_unix_addr(&s[..s.len() - 1]).ok_or_else(invalid)?)
+            } else {
+                HostPattern::UnixSocket(normalize_unix_addr(s).ok_or_else(invalid)?)
+            });
+        }
+        let (ip, prefix) = match s.find('/') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        };
+        match (ip.parse::<IpAddr>(), prefix) {
+            (Ok(ip), prefix) => {
+                let max = if ip.is_ipv4() { 32 } else { 128 };
+                let prefix = match prefix {
+                    Some(prefix) => prefix.parse().ok().filter(|&p| p <= max).ok_or_else(invalid)?,
+                    None => max,
+                };
+                Ok(HostPattern::Network(ip, prefix))
+            }
+            (Err(_), Some(_)) => Err(invalid()),
+            (Err(_), None) if s.is_empty() || s.contains('*') => Err(invalid()),
+            (Err(_), None) => Ok(HostPattern::Name(s.to_ascii_lowercase())),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for HostPattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+    }
+}
+
+/// Lexically normalize a Unix domain socket address, removing empty and `.`
+/// path components but keeping a trailing `/`. Returns `None` if the path
+/// has a `..` component, since where that leads depends on the file system.
+fn normalize_unix_addr(addr: &str) -> Option<String> {
+    let path = &addr[UNIX_SOCKET_PREFIX.len()..];
+    let mut normalized = String::from(UNIX_SOCKET_PREFIX);
+    let mut empty = true;
+    if path.starts_with('/') {
+        normalized.push('/');
+    }
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => return None,
+            component => {
+                if !empty {
+                    normalized.push('/');
+                }
+                normalized.push_str(component);
+                empty = false;
+            }
+        }
+    }
+    if path.ends_with('/') && !empty {
+        normalized.push('/');
+    }
+    Some(normalized)
+}
+
+fn in_network(ip: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    fn mask(addr: u128, bits: u32, prefix: u8) -> u128 {
+        let shift = bits - prefix as u32;
+        if shift >= 128 { 0 } else { addr >> shift }
+    }
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => {
+            mask(u32::from(ip).into(), 32, prefix) == mask(u32::from(net).into(), 32, prefix)
+        }
+        (IpAddr::V6(ip), IpAddr::V6(net)) => {
+            mask(u128::from(ip), 128, prefix) == mask(u128::from(net), 128, prefix)
+        }
+        _ => false,
+    }
+}
+
+/// Convert an IPv4-mapped IPv6 address to the IPv4 address it maps to.
+fn unmap(ip: IpAddr) -> IpAddr {
+    if let IpAddr::V6(ip) = ip {
+        if let [0, 0, 0, 0, 0, 0xffff, hi, lo] = ip.segments() {
+            return IpAddr::V4(Ipv4Addr::new((hi >> 8) as u8, hi as u8, (lo >> 8) as u8, lo as u8));
+        }
+    }
+    ip
+}
+
+/// The host part of an address, as matched against host patterns.
+#[derive(Copy, Clone, Debug)]
+enum Host<'a> {
+    /// A normalized Unix domain socket address
+    Unix(&'a str),
+    /// A host name that couldn't be resolved
+    Name(&'a str),
+    /// A resolved IP address, and the host name it was resolved from if the
+    /// enclave didn't specify an IP address
+    Ip(IpAddr, Option<&'a str>),
+}
+
+impl HostPattern {
+    fn matches(&self, host: Host) -> bool {
+        match (self, host) {
+            (HostPattern::Any, _) => true,
+            (HostPattern::Subdomain(suffix), Host::Name(name))
+            | (HostPattern::Subdomain(suffix), Host::Ip(_, Some(name))) => {
+                name.len() > suffix.len() && name.to_ascii_lowercase().ends_with(suffix.as_str())
+            }
+            (HostPattern::Network(network, prefix), Host::Ip(ip, _)) => in_network(unmap(ip), *network, *prefix),
+            (HostPattern::UnixSocket(addr), Host::Unix(unix)) => unix == addr,
+            (HostPattern::UnixSocketPrefix(prefix), Host::Unix(unix)) => unix.starts_with(prefix.as_str()),
+            (HostPattern::Name(pattern), Host::Name(name))
+            | (HostPattern::Name(pattern), Host::Ip(_, Some(name))) => name.eq_ignore_ascii_case(pattern),
+            _ => false,
+        }
+    }
+}
+
+/// An inclusive range of port numbers.
+///
+/// Deserialized from a port number or a string such as `"8000-8999"`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    fn contains(&self, port: u16) -> bool {
+        self.start <= port && port <= self.end
+    }
+}
+
+impl<'de> Deserialize<'de> for PortRange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Ports {
+            Single(u16),
+            Range(String),
+        }
+
+        match Ports::deserialize(deserializer)? {
+            Ports::Single(port) => Ok(PortRange { start: port, end: port }),
+            Ports::Range(s) => {
+                let mut parts = s.splitn(2, '-').map(|p| p.trim().parse::<u16>());
+                match (parts.next(), parts.next()) {
+                    (Some(Ok(port)), None) => Ok(PortRange { start: port, end: port }),
+                    (Some(Ok(start)), Some(Ok(end))) if start <= end => Ok(PortRange { start, end }),
+                    _ => Err(de::Error::custom(format!("invalid port range: {:?}", s))),
+                }
+            }
+        }
+    }
+}
+
+/// A network policy rule. A rule matches an operation if all of its
+/// conditions match.
+#[derive(Clone, Debug, Deserialize)]
+pub struct NetworkRule {
+    pub action: NetworkAction,
+    /// Only match this operation, or any operation if `None`
+    #[serde(default)]
+    pub operation: Option<NetworkOperation>,
+    /// Only match hosts matching this pattern, or any host if `None`
+    #[serde(default)]
+    pub host: Option<HostPattern>,
+    /// Only match these ports, or any port if `None`
+    #[serde(default)]
+    pub ports: Option<PortRange>,
+}
+
+/// Restricts the addresses passed to the `connect_stream` and `bind_stream`
+/// usercalls.
+///
+/// The policy is checked before the addresses are passed to a
+/// [`UsercallExtension`](trait.UsercallExtension.html), except for the
+/// `file:` addresses of a [`HostDirectory`](struct.HostDirectory.html),
+/// which are only checked if no extension handles them. Host names are
+/// resolved first, and an operation is only allowed if it's allowed on every
+/// address the name resolves to. The runner then only connects or binds to
+/// those addresses. Names that can't be resolved are checked by name, and
+/// can still be handled by an extension. The first matching rule determines
+/// whether an operation is allowed on an address. If no rule matches,
+/// `default_action` applies. Denied operations fail with `PermissionDenied`,
+/// and are counted in
+/// [`EnclaveMetrics::network_denials`](struct.EnclaveMetrics.html#structfield.network_denials).
+///
+/// Unix domain socket addresses are normalized before they are matched, as
+/// described for [`HostPattern`]. Addresses with a `..` path component are
+/// always denied, since they could reach sockets outside the directories
+/// named in the rules.
+///
+/// The default policy allows everything, except Unix domain socket
+/// addresses with a `..` path component. A policy can be deserialized, for
+/// example from TOML:
+///
+/// ```toml
+/// default_action = "deny"
+///
+/// [[rules]]
+/// action = "allow"
+/// operation = "connect"
+/// host = "*.example.com"
+/// ports = 443
+///
+/// [[rules]]
+/// action = "allow"
+/// host = "10.0.0.0/8"
+/// ports = "8000-8999"
+/// ```
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct NetworkPolicy {
+    #[serde(default)]
+    pub default_action: NetworkAction,
+    /// Deny any operation on an address other than a Unix domain socket or
+    /// one that resolves to loopback IP addresses only, regardless of the
+    /// rules
+    #[serde(default)]
+    pub loopback_only: bool,
+    #[serde(default)]
+    pub rules: Vec<NetworkRule>,
+}
+
+/// Split an address of the form `host:port` into its parts. Unix domain
+/// socket addresses don't have a port.
+fn split_addr(addr: &str) -> (&str, Option<u16>) {
+    if addr.starts_with(UNIX_SOCKET_PREFIX) {
+        return (addr, None);
+    }
+    if let Some(i) = addr.rfind(':') {
+        let (host, port) = (&addr[..i], &addr[i + 1..]);
+        let host = if host.starts_with('[') && host.ends_with(']') {
+            &host[1..host.len() - 1]
+        } else if host.contains(':') {
+            // An IPv6 address without a port
+            return (addr, None);
+        } else {
+            host
+        };
+        if let Ok(port) = port.parse() {
+            return (host, Some(port));
+        }
+    }
+    (addr, None)
+}
+
+/// An address passed to `connect_stream` or `bind_stream`, as checked
+/// against a network policy.
+#[derive(Debug)]
+pub(super) enum CheckedAddr {
+    /// A Unix domain socket address
+    Unix,
+    /// The addresses a `host:port` address resolved to
+    Resolved(Vec<SocketAddr>),
+    /// An address that couldn't be resolved. Unless an extension handles the
+    /// address, the operation fails with this error.
+    Unresolved(IoError),
+}
+
+impl NetworkPolicy {
+    fn check_host(&self, operation: NetworkOperation, host: Host, port: Option<u16>) -> NetworkAction {
+        if self.loopback_only {
+            let loopback = match host {
+                Host::Unix(_) => true,
+                Host::Name(name) => name.eq_ignore_ascii_case("localhost"),
+                Host::Ip(ip, _) => unmap(ip).is_loopback(),
+            };
+            if !loopback {
+                return NetworkAction::Deny;
+            }
+        }
+        self.rules
+            .iter()
+            .find(|rule| {
+                rule.operation.map_or(true, |op| op == operation)
+                    && rule.host.as_ref().map_or(true, |pattern| pattern.matches(host))
+                    && rule.ports.map_or(true, |ports| port.map_or(false, |port| ports.contains(port)))
+            })
+            .map_or(self.default_action, |rule| rule.action)
+    }
+
+    /// Resolve `addr` and determine whether `operation` is allowed on it.
+    pub(super) async fn check(&self, operation: NetworkOperation, addr: &str) -> (NetworkAction, CheckedAddr) {
+        if addr.starts_with(UNIX_SOCKET_PREFIX) {
+            let action = match normalize_unix_addr(addr) {
+                Some(addr) => self.check_host(operation, Host::Unix(&addr), None),
+                None => NetworkAction::Deny,
+            };
+            return (action, CheckedAddr::Unix);
+        }
+        let (host, port) = split_addr(addr);
+        let ip = host.parse::<IpAddr>().ok();
+        let unresolved = |err| {
+            let host = match ip {
+                Some(ip) => Host::Ip(ip, None),
+                None => Host::Name(host),
+            };
+            (self.check_host(operation, host, port), CheckedAddr::Unresolved(err))
+        };
+        let addrs = match tokio::net::lookup_host(addr).await {
+            Ok(addrs) => addrs.collect::<Vec<_>>(),
+            Err(e) => return unresolved(e),
+        };
+        if addrs.is_empty() {
+            return unresolved(IoError::new(IoErrorKind::InvalidInput, "could not resolve to any address"));
+        }
+        let name = if ip.is_some() { None } else { Some(host) };
+        let allowed = addrs.iter().all(|addr| {
+            self.check_host(operation, Host::Ip(addr.ip(), name), Some(addr.port())) == NetworkAction::Allow
+        });
+        let action = if allowed { NetworkAction::Allow } else { NetworkAction::Deny };
+        (action, CheckedAddr::Resolved(addrs))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(policy: &NetworkPolicy, operation: NetworkOperation, addr: &str) -> NetworkAction {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(policy.check(operation, addr)).0
+    }
+
+    fn ip_addr(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn host_patterns() {
+        let pattern = |s: &str| s.parse::<HostPattern>().unwrap();
+        let ip = |s: &str| Host::Ip(s.parse().unwrap(), None);
+        assert!(pattern("*").matches(Host::Name("anything")));
+        assert!(pattern("*.example.com").matches(Host::Name("api.EXAMPLE.com")));
+        assert!(pattern("*.example.com").matches(Host::Ip(ip_addr("10.1.2.3"), Some("api.example.com"))));
+        assert!(!pattern("*.example.com").matches(Host::Name("example.com")));
+        assert!(!pattern("*.example.com").matches(Host::Name("badexample.com")));
+        assert!(pattern("10.0.0.0/8").matches(ip("10.1.2.3")));
+        assert!(pattern("10.0.0.0/8").matches(Host::Ip(ip_addr("10.1.2.3"), Some("internal"))));
+        assert!(pattern("10.0.0.0/8").matches(ip("::ffff:10.1.2.3")));
+        assert!(!pattern("10.0.0.0/8").matches(ip("::10.1.2.3")));
+        assert!(!pattern("10.0.0.0/8").matches(ip("11.0.0.1")));
+        assert!(!pattern("10.0.0.0/8").matches(Host::Name("10.example.com")));
+        assert!(pattern("0.0.0.0/0").matches(ip("1.2.3.4")));
+        assert!(pattern("fd00::/8").matches(ip("fd12::1")));
+        assert!(!pattern("fd00::/8").matches(ip("10.1.2.3")));
+        assert!(pattern("Localhost").matches(Host::Name("localhost")));
+        assert!(pattern("Localhost").matches(Host::Ip(ip_addr("127.0.0.1"), Some("localhost"))));
+        assert!(pattern("unix:/run/Sidecar.sock").matches(Host::Unix("unix:/run/Sidecar.sock")));
+        assert!(!pattern("unix:/run/Sidecar.sock").matches(Host::Unix("unix:/run/sidecar.sock")));
+        assert!(!pattern("unix:/run/Sidecar.sock").matches(Host::Name("unix:/run/Sidecar.sock")));
+        assert!(pattern("unix:/run/*").matches(Host::Unix("unix:/run/a.sock")));
+        assert!(!pattern("unix:/run/*").matches(Host::Unix("unix:/tmp/a.sock")));
+        assert!(!pattern("unix:/run/*").matches(Host::Unix("unix:/runner.sock")));
+        assert!(pattern("unix:/run/a*").matches(Host::Unix("unix:/run/a/b.sock")));
+        assert_eq!(pattern("unix://run/./*"), HostPattern::UnixSocketPrefix("unix:/run/".to_owned()));
+        assert_eq!(pattern("unix:run/a.sock/"), HostPattern::UnixSocket("unix:run/a.sock/".to_owned()));
+        assert!("unix:/run/../var/*".parse::<HostPattern>().is_err());
+        assert!("unix:/run/..".parse::<HostPattern>().is_err());
+        assert!("10.0.0.0/33".parse::<HostPattern>().is_err());
+        assert!("host/8".parse::<HostPattern>().is_err());
+        assert!("a*b".parse::<HostPattern>().is_err());
+    }
+
+    #[test]
+    fn unix_addrs() {
+        assert_eq!(normalize_unix_addr("unix:/run/a.sock").unwrap(), "unix:/run/a.sock");
+        assert_eq!(normalize_unix_addr("unix:///run//./a.sock").unwrap(), "unix:/run/a.sock");
+        assert_eq!(normalize_unix_addr("unix:./a.sock").unwrap(), "unix:a.sock");
+        assert_eq!(normalize_unix_addr("unix:/run/").unwrap(), "unix:/run/");
+        assert_eq!(normalize_unix_addr("unix:/").unwrap(), "unix:/");
+        assert_eq!(normalize_unix_addr("unix:").unwrap(), "unix:");
+        assert_eq!(normalize_unix_addr("unix:/run/../var/run/docker.sock"), None);
+        assert_eq!(normalize_unix_addr("unix:../a.sock"), None);
+        assert_eq!(normalize_unix_addr("unix:/run/a..sock").unwrap(), "unix:/run/a..sock");
+
+        let policy = NetworkPolicy {
+            default_action: NetworkAction::Deny,
+            loopback_only: false,
+            rules: vec![
+                NetworkRule {
+                    action: NetworkAction::Deny,
+                    operation: None,
+                    host: Some("unix:/run/secret.sock".parse().unwrap()),
+                    ports: None,
+                },
+                NetworkRule {
+                    action: NetworkAction::Allow,
+                    operation: None,
+                    host: Some("unix:/run/*".parse().unwrap()),
+                    ports: None,
+                },
+            ],
+        };
+        assert_eq!(check(&policy, NetworkOperation::Connect, "unix:/run/a.sock"), NetworkAction::Allow);
+        assert_eq!(check(&policy, NetworkOperation::Connect, "unix:/run/./a.sock"), NetworkAction::Allow);
+        assert_eq!(check(&policy, NetworkOperation::Connect, "unix:/run/secret.sock"), NetworkAction::Deny);
+        assert_eq!(check(&policy, NetworkOperation::Connect, "unix:/run//secret.sock"), NetworkAction::Deny);
+        assert_eq!(check(&policy, NetworkOperation::Connect, "unix:/run/../var/run/docker.sock"), NetworkAction::Deny);
+        assert_eq!(check(&policy, NetworkOperation::Bind, "unix:/run/sub/../../etc/a.sock"), NetworkAction::Deny);
+        assert_eq!(check(&policy, NetworkOperation::Connect, "unix:/tmp/a.sock"), NetworkAction::Deny);
+
+        let policy = NetworkPolicy::default();
+        assert_eq!(check(&policy, NetworkOperation::Connect, "unix:/tmp/a.sock"), NetworkAction::Allow);
+        assert_eq!(check(&policy, NetworkOperation::Connect, "unix:/tmp/../a.sock"), NetworkAction::Deny);
+    }
+
+    #[test]
+    fn split() {
+        assert_eq!(split_addr("example.com:443"), ("example.com", Some(443)));
+        assert_eq!(split_addr("[::1]:80"), ("::1", Some(80)));
+        assert_eq!(split_addr("::1"), ("::1", None));
+        assert_eq!(split_addr("service"), ("service", None));
+        assert_eq!(split_addr("unix:/tmp/a:80"), ("unix:/tmp/a:80", None));
+    }
+
+    #[test]
+    fn policy() {
+        let policy = NetworkPolicy {
+            default_action: NetworkAction::Deny,
+            loopback_only: false,
+            rules: vec![
+                NetworkRule {
+                    action: NetworkAction::Deny,
+                    operation: None,
+                    host: Some("10.0.0.1".parse().unwrap()),
+                    ports: None,
+                },
+                NetworkRule {
+                    action: NetworkAction::Allow,
+                    operation: Some(NetworkOperation::Connect),
+                    host: Some("10.0.0.0/8".parse().unwrap()),
+                    ports: Some(PortRange { start: 8000, end: 8999 }),
+                },
+            ],
+        };
+        assert_eq!(check(&policy, NetworkOperation::Connect, "10.2.0.1:8080"), NetworkAction::Allow);
+        assert_eq!(check(&policy, NetworkOperation::Connect, "10.0.0.1:8080"), NetworkAction::Deny);
+        assert_eq!(check(&policy, NetworkOperation::Connect, "10.2.0.1:80"), NetworkAction::Deny);
+        assert_eq!(check(&policy, NetworkOperation::Bind, "10.2.0.1:8080"), NetworkAction::Deny);
+
+        let policy = NetworkPolicy { loopback_only: true, ..NetworkPolicy::default() };
+        assert_eq!(check(&policy, NetworkOperation::Bind, "127.0.0.1:80"), NetworkAction::Allow);
+        assert_eq!(check(&policy, NetworkOperation::Bind, "localhost:80"), NetworkAction::Allow);
+        assert_eq!(check(&policy, NetworkOperation::Bind, "[::1]:80"), NetworkAction::Allow);
+        assert_eq!(check(&policy, NetworkOperation::Bind, "unix:/tmp/a.sock"), NetworkAction::Allow);
+        assert_eq!(check(&policy, NetworkOperation::Bind, "0.0.0.0:80"), NetworkAction::Deny);
+    }
+
+    #[test]
+    fn resolved() {
+        let deny = |host: &str| NetworkRule {
+            action: NetworkAction::Deny,
+            operation: None,
+            host: Some(host.parse().unwrap()),
+            ports: None,
+        };
+        let policy = NetworkPolicy {
+            default_action: NetworkAction::Allow,
+            loopback_only: false,
+            rules: vec![deny("127.0.0.0/8"), deny("::1"), deny("10.0.0.0/8"), deny("8.0.0.0/8"), deny("service")],
+        };
+        // A host name is checked against the addresses it resolves to
+        assert_eq!(check(&policy, NetworkOperation::Connect, "localhost:80"), NetworkAction::Deny);
+        // IPv4-mapped IPv6 addresses are checked as IPv4 addresses
+        assert_eq!(check(&policy, NetworkOperation::Connect, "[::ffff:10.0.0.1]:80"), NetworkAction::Deny);
+        assert_eq!(check(&policy, NetworkOperation::Bind, "[::ffff:a00:1]:80"), NetworkAction::Deny);
+        // Other numeric forms are checked as the address they resolve to.
+        // Depending on the platform, `010.0.0.1` is 10.0.0.1 or 8.0.0.1.
+        assert_eq!(check(&policy, NetworkOperation::Connect, "167772161:80"), NetworkAction::Deny);
+        assert_eq!(check(&policy, NetworkOperation::Connect, "010.0.0.1:80"), NetworkAction::Deny);
+        assert_eq!(check(&policy, NetworkOperation::Connect, "[::ffff:11.0.0.1]:80"), NetworkAction::Allow);
+        // Names that can't be resolved are checked by name
+        assert_eq!(check(&policy, NetworkOperation::Connect, "service"), NetworkAction::Deny);
+        assert_eq!(check(&policy, NetworkOperation::Connect, "other"), NetworkAction::Allow);
+
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let policy = NetworkPolicy::default();
+        match rt.block_on(policy.check(NetworkOperation::Connect, "[::ffff:10.0.0.1]:80")) {
+            (NetworkAction::Allow, CheckedAddr::Resolved(addrs)) => {
+                assert_eq!(addrs, ["[::ffff:10.0.0.1]:80".parse::<SocketAddr>().unwrap()])
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match rt.block_on(policy.check(NetworkOperation::Connect, "localhost:80")) {
+            (NetworkAction::Allow, CheckedAddr::Resolved(addrs)) => assert!(addrs.iter().all(|addr| addr.ip().is_loopback())),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match rt.block_on(policy.check(NetworkOperation::Connect, "service")) {
+            (NetworkAction::Allow, CheckedAddr::Unresolved(_)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match rt.block_on(policy.check(NetworkOperation::Connect, "unix:/tmp/a.sock")) {
+            (NetworkAction::Allow, CheckedAddr::Unix) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn deserialize() {
+        let policy: NetworkPolicy = serde_json::from_str(r#"{
+            "default_action": "deny",
+            "rules": [
+                { "action": "allow", "operation": "connect", "host": "*.example.com", "ports": 443 },
+                { "action": "allow", "ports": "8000-8999" }
+            ]
+        }"#).unwrap();
+        assert!(!policy.loopback_only);
+        assert_eq!(policy.rules[0].host, Some(HostPattern::Subdomain(".example.com".to_owned())));
+        assert_eq!(policy.rules[0].ports, Some(PortRange { start: 443, end: 443 }));
+        assert_eq!(policy.rules[1].ports, Some(PortRange { start: 8000, end: 8999 }));
+        assert_eq!(check(&policy, NetworkOperation::Bind, "0.0.0.0:8443"), NetworkAction::Allow);
+
+        assert!(serde_json::from_str::<NetworkRule>(r#"{ "action": "allow", "ports": "9-1" }"#).is_err());
+    }
+}
@@ -0,0 +1,221 @@
+/* Copyright (c) Fortanix, Inc.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Structured reports of abnormal enclave exits.
+
+use std::fmt;
+use std::io::Result as IoResult;
+use std::ops::Range;
+
+use failure::Fail;
+
+use super::abi::usercall_info;
+use super::debug::{DebugMemory, UsercallFrame};
+use crate::loader::EnclavePanic;
+
+/// Why an enclave thread stopped the enclave.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum AbortReason {
+    /// The thread called `exit` with `panic` set.
+    Panic { panic: EnclavePanic },
+    /// All enclave threads were waiting for events that could never occur.
+    IndefiniteWait,
+    /// The thread performed a usercall with an unknown number.
+    InvalidUsercall { number: u64 },
+    /// The main entrypoint returned, which it must not.
+    MainReturned,
+    /// The runner panicked while running the thread, for example while
+    /// entering the enclave.
+    RunnerPanicked { message: String },
+    /// The thread performed a usercall for which no result was recorded,
+    /// while replaying a recording.
+    ReplayDiverged { message: String },
+}
+
+impl fmt::Display for AbortReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AbortReason::Panic { panic } => panic.fmt(f),
+            AbortReason::IndefiniteWait => {
+                f.write_str("All enclave threads are waiting indefinitely without possibility of wakeup")
+            }
+            AbortReason::InvalidUsercall { number } => {
+                write!(f, "The enclave performed an invalid usercall 0x{:x}", number)
+            }
+            AbortReason::MainReturned => {
+                f.write_str("The enclave returned from the main entrypoint in violation of the specification.")
+            }
+            AbortReason::RunnerPanicked { message } => {
+                write!(f, "The enclave runner panicked while running an enclave thread: {}", message)
+            }
+            AbortReason::ReplayDiverged { message } => {
+                write!(f, "The usercall replay diverged from the recording: {}", message)
+            }
+        }
+    }
+}
+
+/// The reason an enclave thread stopped, and where.
+#[derive(Clone, Debug, Serialize)]
+pub struct ThreadReport {
+    #[serde(flatten)]
+    pub reason: AbortReason,
+    /// The address of the TCS of the thread, or `None` for asynchronous
+    /// usercalls, which don't belong to a thread.
+    pub tcs: Option<u64>,
+    /// The name of the usercall the thread was performing, if any.
+    pub usercall: Option<String>,
+    /// The return addresses on the stack of the thread, innermost first.
+    /// Only available for debug enclaves.
+    pub backtrace: Option<Vec<u64>>,
+}
+
+impl fmt::Display for ThreadReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.tcs {
+            Some(tcs) => write!(f, "TCS {:#x}: ", tcs)?,
+            None => f.write_str("Asynchronous usercall: ")?,
+        }
+        self.reason.fmt(f)?;
+        if let Some(ref usercall) = self.usercall {
+            write!(f, " (in usercall {})", usercall)?;
+        }
+        Ok(())
+    }
+}
+
+/// A report of why an enclave exited abnormally.
+///
+/// This is returned (as a [`failure::Error`] that can be downcast to this
+/// type) by [`Command::run`]. If the primary reason is a panic, the
+/// [`EnclavePanic`] is the cause of this error, and is also returned by
+/// [`panic`].
+///
+/// **NOTE:** Before version 0.4.0, [`Command::run`] returned the
+/// [`EnclavePanic`] itself if the enclave panicked, and a plain error
+/// message for other reasons. Code that downcasts the error to
+/// [`EnclavePanic`] should downcast it to this type and call [`panic`]
+/// instead.
+///
+/// [`failure::Error`]: https://docs.rs/failure/0.1/failure/struct.Error.html
+/// [`Command::run`]: ../struct.Command.html#method.run
+/// [`EnclavePanic`]: ../enum.EnclavePanic.html
+/// [`panic`]: #method.panic
+#[derive(Clone, Debug, Serialize)]
+pub struct EnclavePanicReport {
+    /// The reason the enclave exited.
+    pub primary: ThreadReport,
+    /// Other threads that stopped abnormally before the enclave exited.
+    pub secondary: Vec<ThreadReport>,
+    /// The base address of the enclave, to interpret backtraces.
+    pub enclave_base: u64,
+}
+
+impl fmt::Display for EnclavePanicReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.primary.reason.fmt(f)?;
+        for thread in &self.secondary {
+            write!(f, "\nAlso: {}", thread)?;
+        }
+        Ok(())
+    }
+}
+
+impl EnclavePanicReport {
+    /// The panic, if the primary reason the enclave exited is a panic.
+    pub fn panic(&self) -> Option<&EnclavePanic> {
+        match self.primary.reason {
+            AbortReason::Panic { ref panic } => Some(panic),
+            _ => None,
+        }
+    }
+}
+
+impl Fail for EnclavePanicReport {
+    fn cause(&self) -> Option<&dyn Fail> {
+        self.panic().map(|panic| panic as &dyn Fail)
+    }
+}
+
+/// Where an enclave thread stopped, recorded in the usercall loop.
+#[derive(Clone, Debug, Default)]
+pub(super) struct ThreadContext {
+    pub tcs: Option<usize>,
+    pub usercall: Option<u64>,
+    pub backtrace: Option<Vec<u64>>,
+}
+
+impl ThreadContext {
+    pub(super) fn report(self, reason: AbortReason) -> ThreadReport {
+        ThreadReport {
+            reason,
+            tcs: self.tcs.map(|tcs| tcs as u64),
+            usercall: self.usercall.and_then(usercall_info).map(|info| info.name.to_owned()),
+            backtrace: self.backtrace,
+        }
+    }
+}
+
+const MAX_FRAMES: usize = 128;
+
+/// The return addresses on the stack of a thread that is performing a
+/// usercall, found by following the frame pointers.
+///
+/// This relies on the usercall stack layout of the Rust standard library and
+/// on the enclave being built with frame pointers.
+pub(super) fn backtrace(enclave_range: &Range<usize>, tcs: usize) -> IoResult<Vec<u64>> {
+    let mem = DebugMemory::open()?;
+    let contains = |addr: u64| enclave_range.start as u64 <= addr && addr < enclave_range.end as u64;
+    let frame = UsercallFrame::read(&mem, enclave_range, tcs)?;
+
+    let mut frames = vec![frame.rip];
+    let mut rbp = frame.rbp;
+    while frames.len() < MAX_FRAMES && contains(rbp) {
+        let return_address = mem.read_u64(rbp as usize + 8)?;
+        if !contains(return_address) {
+            break;
+        }
+        frames.push(return_address);
+        let next = mem.read_u64(rbp as usize)?;
+        // The stack grows down, so callers' frames are at higher addresses
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display() {
+        let exit = (0..64).find(|&n| usercall_info(n).map_or(false, |info| info.name == "exit"));
+        let report = EnclavePanicReport {
+            primary: ThreadContext {
+                tcs: Some(0x1000),
+                usercall: exit,
+                backtrace: None,
+            }.report(AbortReason::Panic { panic: EnclavePanic::DebugStr("oops".to_owned()) }),
+            secondary: vec![ThreadContext::default().report(AbortReason::InvalidUsercall { number: 0x42 })],
+            enclave_base: 0,
+        };
+        assert_eq!(
+            report.to_string(),
+            "Enclave panicked: oops\nAlso: Asynchronous usercall: The enclave performed an invalid usercall 0x42"
+        );
+        assert_eq!(report.primary.to_string(), "TCS 0x1000: Enclave panicked: oops (in usercall exit)");
+        assert!(report.cause().is_some());
+        assert_eq!(report.panic().unwrap().to_string(), "Enclave panicked: oops");
+
+        let json = serde_json::to_value(&report.primary).unwrap();
+        assert_eq!(json["reason"], "panic");
+        assert_eq!(json["panic"]["debug_str"], "oops");
+        assert_eq!(json["usercall"], "exit");
+    }
+}
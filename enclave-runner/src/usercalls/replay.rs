@@ -0,0 +1,502 @@
+/* Copyright (c) Fortanix, Inc.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Recording and replaying the results of non-deterministic usercalls.
+//!
+//! A recording is a file with one JSON object per line, each describing the
+//! result of a usercall that depends on the outside world: stream I/O,
+//! network connections, time and events. When replaying, these results are
+//! returned to the enclave without accessing any streams, sockets or clocks.
+//!
+//! Usercalls are matched with recorded results per file descriptor (or
+//! address, for `bind_stream` and `connect_stream`, or TCS, for `wait`), in
+//! order. A replay is therefore faithful as long as the enclave performs the
+//! same usercalls on each file descriptor and thread in the same order as
+//! during the recording, even if usercalls from different threads are
+//! interleaved differently. Threads are identified by the offset of their TCS
+//! in the enclave, so they need to be started in the same order as well.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, ErrorKind as IoErrorKind, Result as IoResult, Write};
+use std::path::Path;
+use std::result::Result as StdResult;
+use std::sync::Mutex;
+
+use fnv::FnvHashMap;
+use fortanix_sgx_abi::{Fd, Result as SgxResult};
+
+use super::interface::{result_from_io_error, OutputBuffer};
+
+/// The recorded result of a usercall: a value or an ABI error code.
+type Outcome<T> = StdResult<T, SgxResult>;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Stream {
+    fd: Fd,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    local_addr: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    peer_addr: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "usercall", rename_all = "snake_case")]
+enum Event {
+    Read { fd: Fd, result: Outcome<Vec<u8>> },
+    ReadAlloc { fd: Fd, result: Outcome<Vec<u8>> },
+    Write { fd: Fd, result: Outcome<usize> },
+    Flush { fd: Fd, result: Outcome<()> },
+    BindStream { addr: String, result: Outcome<Stream> },
+    AcceptStream { fd: Fd, result: Outcome<Stream> },
+    ConnectStream { addr: String, result: Outcome<Stream> },
+    Wait {
+        /// The offset of the TCS in the enclave, `None` for asynchronous
+        /// usercalls
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        tcs: Option<u64>,
+        result: Outcome<u64>,
+    },
+    InsecureTime { time: u64 },
+}
+
+/// Identifies the sequence of events a recorded event belongs to.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Key {
+    Fd(&'static str, Fd),
+    Addr(&'static str, String),
+    Tcs(&'static str, Option<u64>),
+    Call(&'static str),
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Key::Fd(call, fd) => write!(f, "`{}` on fd {}", call, fd),
+            Key::Addr(call, ref addr) => write!(f, "`{}` to {:?}", call, addr),
+            Key::Tcs(call, Some(tcs)) => write!(f, "`{}` on the TCS at offset {:#x}", call, tcs),
+            Key::Tcs(call, None) => write!(f, "asynchronous `{}`", call),
+            Key::Call(call) => write!(f, "`{}`", call),
+        }
+    }
+}
+
+impl Event {
+    fn key(&self) -> Key {
+        match *self {
+            Event::Read { fd, .. } => Key::Fd("read", fd),
+            Event::ReadAlloc { fd, .. } => Key::Fd("read_alloc", fd),
+            Event::Write { fd, .. } => Key::Fd("write", fd),
+            Event::Flush { fd, .. } => Key::Fd("flush", fd),
+            Event::BindStream { ref addr, .. } => Key::Addr("bind_stream", addr.clone()),
+            Event::AcceptStream { fd, .. } => Key::Fd("accept_stream", fd),
+            Event::ConnectStream { ref addr, .. } => Key::Addr("connect_stream", addr.clone()),
+            Event::Wait { tcs, .. } => Key::Tcs("wait", tcs),
+            Event::InsecureTime { .. } => Key::Call("insecure_time"),
+        }
+    }
+}
+
+fn outcome<T, U, F: FnOnce(&T) -> U>(result: &IoResult<T>, f: F) -> Outcome<U> {
+    match *result {
+        Ok(ref v) => Ok(f(v)),
+        Err(ref e) => Err(result_from_io_error(e.kind().into())),
+    }
+}
+
+fn io_result<T>(outcome: Outcome<T>) -> IoResult<T> {
+    const KINDS: &[IoErrorKind] = &[
+        IoErrorKind::NotFound,
+        IoErrorKind::PermissionDenied,
+        IoErrorKind::ConnectionRefused,
+        IoErrorKind::ConnectionReset,
+        IoErrorKind::ConnectionAborted,
+        IoErrorKind::NotConnected,
+        IoErrorKind::AddrInUse,
+        IoErrorKind::AddrNotAvailable,
+        IoErrorKind::BrokenPipe,
+        IoErrorKind::AlreadyExists,
+        IoErrorKind::WouldBlock,
+        IoErrorKind::InvalidInput,
+        IoErrorKind::InvalidData,
+        IoErrorKind::TimedOut,
+        IoErrorKind::WriteZero,
+        IoErrorKind::Interrupted,
+        IoErrorKind::UnexpectedEof,
+    ];
+    outcome.map_err(|code| {
+        KINDS.iter()
+            .cloned()
+            .find(|&kind| result_from_io_error(kind.into()) == code)
+            .unwrap_or(IoErrorKind::Other)
+            .into()
+    })
+}
+
+fn addr_string(buf: &Option<&mut OutputBuffer>) -> Option<String> {
+    buf.as_ref().map(|buf| String::from_utf8_lossy(buf.get().unwrap_or(&[])).into_owned())
+}
+
+fn set_addr(buf: Option<&mut OutputBuffer>, addr: Option<String>) -> IoResult<()> {
+    match (buf, addr) {
+        (Some(buf), Some(addr)) => buf.set(addr),
+        _ => Ok(()),
+    }
+}
+
+/// Records the results of usercalls to a file.
+///
+/// If writing the recording fails, the error is printed and recording stops.
+/// The enclave keeps running.
+pub(crate) struct UsercallRecorder {
+    /// `None` once writing the recording has failed
+    writer: Mutex<Option<BufWriter<Box<dyn Write + Send>>>>,
+}
+
+impl UsercallRecorder {
+    pub(crate) fn create<P: AsRef<Path>>(path: P) -> IoResult<Self> {
+        Ok(Self::new(Box::new(File::create(path)?)))
+    }
+
+    fn new(writer: Box<dyn Write + Send>) -> Self {
+        UsercallRecorder {
+            writer: Mutex::new(Some(BufWriter::new(writer))),
+        }
+    }
+
+    fn record(&self, event: Event) {
+        let mut guard = self.writer.lock().unwrap();
+        let writer = match *guard {
+            Some(ref mut writer) => writer,
+            None => return,
+        };
+        // Flush every event so the recording is usable if the runner crashes
+        let result = serde_json::to_writer(&mut *writer, &event)
+            .map_err(io::Error::from)
+            .and_then(|()| writer.write_all(b"\n"))
+            .and_then(|()| writer.flush());
+        if let Err(e) = result {
+            eprintln!("Unable to write usercall recording, recording stopped: {}", e);
+            *guard = None;
+        }
+    }
+
+    pub(super) fn read(&self, fd: Fd, buf: &[u8], result: &IoResult<usize>) {
+        let result = outcome(result, |&n| buf[..n].to_vec());
+        self.record(Event::Read { fd, result })
+    }
+
+    pub(super) fn read_alloc(&self, fd: Fd, buf: &OutputBuffer, result: &IoResult<()>) {
+        let result = outcome(result, |()| buf.get().unwrap_or(&[]).to_vec());
+        self.record(Event::ReadAlloc { fd, result })
+    }
+
+    pub(super) fn write(&self, fd: Fd, result: &IoResult<usize>) {
+        let result = outcome(result, |&n| n);
+        self.record(Event::Write { fd, result })
+    }
+
+    pub(super) fn flush(&self, fd: Fd, result: &IoResult<()>) {
+        let result = outcome(result, |&()| ());
+        self.record(Event::Flush { fd, result })
+    }
+
+    pub(super) fn bind_stream(&self, addr: &[u8], local_addr: &Option<&mut OutputBuffer>, result: &IoResult<Fd>) {
+        let result = outcome(result, |&fd| Stream {
+            fd,
+            local_addr: addr_string(local_addr),
+            peer_addr: None,
+        });
+        let addr = String::from_utf8_lossy(addr).into_owned();
+        self.record(Event::BindStream { addr, result })
+    }
+
+    pub(super) fn accept_stream(
+        &self,
+        fd: Fd,
+        local_addr: &Option<&mut OutputBuffer>,
+        peer_addr: &Option<&mut OutputBuffer>,
+        result: &IoResult<Fd>,
+    ) {
+        let result = outcome(result, |&fd| Stream {
+            fd,
+            local_addr: addr_string(local_addr),
+            peer_addr: addr_string(peer_addr),
+        });
+        self.record(Event::AcceptStream { fd, result })
+    }
+
+    pub(super) fn connect_stream(
+        &self,
+        addr: &[u8],
+        local_addr: &Option<&mut OutputBuffer>,
+        peer_addr: &Option<&mut OutputBuffer>,
+        result: &IoResult<Fd>,
+    ) {
+        let result = outcome(result, |&fd| Stream {
+            fd,
+            local_addr: addr_string(local_addr),
+            peer_addr: addr_string(peer_addr),
+        });
+        let addr = String::from_utf8_lossy(addr).into_owned();
+        self.record(Event::ConnectStream { addr, result })
+    }
+
+    pub(super) fn wait(&self, tcs: Option<u64>, result: &IoResult<u64>) {
+        let result = outcome(result, |&ev| ev);
+        self.record(Event::Wait { tcs, result })
+    }
+
+    pub(super) fn insecure_time(&self, time: u64) {
+        self.record(Event::InsecureTime { time })
+    }
+}
+
+/// Returns previously recorded results of usercalls.
+///
+/// If the enclave makes a usercall for which no result was recorded, the
+/// replay can't continue faithfully. The usercall then fails, and the
+/// usercall loop aborts the enclave with the reason returned by
+/// `take_divergence`.
+pub(crate) struct UsercallReplayer {
+    events: Mutex<FnvHashMap<Key, VecDeque<Event>>>,
+    /// Why the replay diverged from the recording, until it's taken
+    divergence: Mutex<Option<String>>,
+}
+
+impl UsercallReplayer {
+    pub(crate) fn open<P: AsRef<Path>>(path: P) -> IoResult<Self> {
+        let mut events = FnvHashMap::<_, VecDeque<_>>::default();
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let event: Event = serde_json::from_str(&line)?;
+            events.entry(event.key()).or_default().push_back(event);
+        }
+        Ok(UsercallReplayer {
+            events: Mutex::new(events),
+            divergence: Mutex::new(None),
+        })
+    }
+
+    fn diverged<T>(&self, reason: String) -> IoResult<T> {
+        *self.divergence.lock().unwrap() = Some(reason);
+        Err(IoErrorKind::Other.into())
+    }
+
+    /// Why the replay diverged from the recording, if it did since this was
+    /// last called.
+    pub(super) fn take_divergence(&self) -> Option<String> {
+        self.divergence.lock().unwrap().take()
+    }
+
+    fn next(&self, key: Key) -> IoResult<Event> {
+        let event = self.events.lock().unwrap().get_mut(&key).and_then(VecDeque::pop_front);
+        match event {
+            Some(event) => Ok(event),
+            None => self.diverged(format!("no recorded result left for {}", key)),
+        }
+    }
+
+    pub(super) fn read(&self, fd: Fd, buf: &mut [u8]) -> IoResult<usize> {
+        match self.next(Key::Fd("read", fd))? {
+            Event::Read { result, .. } => io_result(result).and_then(|data| {
+                if data.len() > buf.len() {
+                    return self.diverged(format!("`read` on fd {} with a smaller buffer", fd));
+                }
+                buf[..data.len()].copy_from_slice(&data);
+                Ok(data.len())
+            }),
+            _ => unreachable!(),
+        }
+    }
+
+    pub(super) fn read_alloc(&self, fd: Fd, buf: &mut OutputBuffer) -> IoResult<()> {
+        match self.next(Key::Fd("read_alloc", fd))? {
+            Event::ReadAlloc { result, .. } => io_result(result).and_then(|data| buf.set(data)),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Returns the recorded number of bytes written, at most `buf.len()`.
+    pub(super) fn write(&self, fd: Fd, buf: &[u8]) -> IoResult<usize> {
+        match self.next(Key::Fd("write", fd))? {
+            Event::Write { result, .. } => io_result(result).map(|n| n.min(buf.len())),
+            _ => unreachable!(),
+        }
+    }
+
+    pub(super) fn flush(&self, fd: Fd) -> IoResult<()> {
+        match self.next(Key::Fd("flush", fd))? {
+            Event::Flush { result, .. } => io_result(result),
+            _ => unreachable!(),
+        }
+    }
+
+    pub(super) fn bind_stream(&self, addr: &[u8], local_addr: Option<&mut OutputBuffer>) -> IoResult<Fd> {
+        let addr = String::from_utf8_lossy(addr).into_owned();
+        match self.next(Key::Addr("bind_stream", addr))? {
+            Event::BindStream { result, .. } => io_result(result).and_then(|stream| {
+                set_addr(local_addr, stream.local_addr)?;
+                Ok(stream.fd)
+            }),
+            _ => unreachable!(),
+        }
+    }
+
+    pub(super) fn accept_stream(
+        &self,
+        fd: Fd,
+        local_addr: Option<&mut OutputBuffer>,
+        peer_addr: Option<&mut OutputBuffer>,
+    ) -> IoResult<Fd> {
+        match self.next(Key::Fd("accept_stream", fd))? {
+            Event::AcceptStream { result, .. } => io_result(result).and_then(|stream| {
+                set_addr(local_addr, stream.local_addr)?;
+                set_addr(peer_addr, stream.peer_addr)?;
+                Ok(stream.fd)
+            }),
+            _ => unreachable!(),
+        }
+    }
+
+    pub(super) fn connect_stream(
+        &self,
+        addr: &[u8],
+        local_addr: Option<&mut OutputBuffer>,
+        peer_addr: Option<&mut OutputBuffer>,
+    ) -> IoResult<Fd> {
+        let addr = String::from_utf8_lossy(addr).into_owned();
+        match self.next(Key::Addr("connect_stream", addr))? {
+            Event::ConnectStream { result, .. } => io_result(result).and_then(|stream| {
+                set_addr(local_addr, stream.local_addr)?;
+                set_addr(peer_addr, stream.peer_addr)?;
+                Ok(stream.fd)
+            }),
+            _ => unreachable!(),
+        }
+    }
+
+    pub(super) fn wait(&self, tcs: Option<u64>) -> IoResult<u64> {
+        match self.next(Key::Tcs("wait", tcs))? {
+            Event::Wait { result, .. } => io_result(result),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Returns 0 if no time was recorded, in which case the replay diverged.
+    pub(super) fn insecure_time(&self) -> u64 {
+        match self.next(Key::Call("insecure_time")) {
+            Ok(Event::InsecureTime { time }) => time,
+            Ok(_) => unreachable!(),
+            Err(_) => 0,
+        }
+    }
+}
+
+/// Whether usercall results are being recorded or replayed.
+pub(crate) enum UsercallSession {
+    Record(UsercallRecorder),
+    Replay(UsercallReplayer),
+}
+
+impl fmt::Debug for UsercallSession {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UsercallSession::Record(_) => f.write_str("Record"),
+            UsercallSession::Replay(_) => f.write_str("Replay"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outcome_roundtrip() {
+        let events = vec![
+            Event::Read { fd: 3, result: Ok(b"hello".to_vec()) },
+            Event::Write { fd: 3, result: outcome(&Err(IoErrorKind::BrokenPipe.into()), |&n: &usize| n) },
+            Event::InsecureTime { time: 1234 },
+        ];
+        let lines = events.iter().map(|e| serde_json::to_string(e).unwrap()).collect::<Vec<_>>();
+        assert_eq!(lines[2], r#"{"usercall":"insecure_time","time":1234}"#);
+
+        let replayer = UsercallReplayer {
+            events: Mutex::new(FnvHashMap::default()),
+            divergence: Mutex::new(None),
+        };
+        for line in &lines {
+            let event: Event = serde_json::from_str(line).unwrap();
+            replayer.events.lock().unwrap().entry(event.key()).or_default().push_back(event);
+        }
+        assert_eq!(replayer.insecure_time(), 1234);
+        assert_eq!(replayer.write(3, b"data").unwrap_err().kind(), IoErrorKind::BrokenPipe);
+        let mut buf = [0; 16];
+        assert_eq!(replayer.read(3, &mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"hello");
+    }
+
+    #[test]
+    fn wait_per_tcs() {
+        let replayer = UsercallReplayer {
+            events: Mutex::new(FnvHashMap::default()),
+            divergence: Mutex::new(None),
+        };
+        for event in vec![
+            Event::Wait { tcs: Some(0x1000), result: Ok(1) },
+            Event::Wait { tcs: Some(0x2000), result: Ok(2) },
+            Event::Wait { tcs: Some(0x1000), result: Ok(3) },
+        ] {
+            replayer.events.lock().unwrap().entry(event.key()).or_default().push_back(event);
+        }
+        assert_eq!(replayer.wait(Some(0x2000)).unwrap(), 2);
+        assert_eq!(replayer.wait(Some(0x1000)).unwrap(), 1);
+        assert_eq!(replayer.wait(Some(0x1000)).unwrap(), 3);
+    }
+
+    struct FailingWriter;
+
+    impl Write for FailingWriter {
+        fn write(&mut self, _buf: &[u8]) -> IoResult<usize> {
+            Err(IoErrorKind::Other.into())
+        }
+
+        fn flush(&mut self) -> IoResult<()> {
+            Err(IoErrorKind::Other.into())
+        }
+    }
+
+    #[test]
+    fn record_error() {
+        let recorder = UsercallRecorder::new(Box::new(FailingWriter));
+        recorder.insecure_time(1234);
+        assert!(recorder.writer.lock().unwrap().is_none());
+        recorder.insecure_time(1234);
+    }
+
+    #[test]
+    fn diverged() {
+        let replayer = UsercallReplayer {
+            events: Mutex::new(FnvHashMap::default()),
+            divergence: Mutex::new(None),
+        };
+        assert_eq!(replayer.take_divergence(), None);
+        assert!(replayer.read(4, &mut [0; 16]).is_err());
+        assert_eq!(replayer.take_divergence().unwrap(), "no recorded result left for `read` on fd 4");
+        assert_eq!(replayer.take_divergence(), None);
+
+        let event = Event::Read { fd: 4, result: Ok(b"hello".to_vec()) };
+        replayer.events.lock().unwrap().entry(event.key()).or_default().push_back(event);
+        assert!(replayer.read(4, &mut [0; 4]).is_err());
+        assert_eq!(replayer.take_divergence().unwrap(), "`read` on fd 4 with a smaller buffer");
+        assert_eq!(replayer.insecure_time(), 0);
+        assert_eq!(replayer.take_divergence().unwrap(), "no recorded result left for `insecure_time`");
+    }
+}
@@ -0,0 +1,251 @@
+/* Copyright (c) Fortanix, Inc.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Limits on the host resources an enclave can use through usercalls.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult};
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use fnv::FnvHashMap;
+
+/// Limits on the host resources an enclave can use. `None` means unlimited,
+/// which is the default for all resources.
+///
+/// When a limit is reached, the usercall that would exceed it returns an
+/// error to the enclave.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ResourceLimits {
+    /// Total number of bytes of user memory allocated with the `alloc`
+    /// usercall, or by the runner to return data to the enclave, such as the
+    /// buffers of `read_alloc`.
+    pub memory: Option<u64>,
+    /// Number of open file descriptors, not counting the standard streams.
+    pub fds: Option<u64>,
+    /// Number of open streams created by `connect_stream` or
+    /// `accept_stream`.
+    pub connections: Option<u64>,
+    /// Number of `accept_stream` usercalls waiting for an incoming
+    /// connection at the same time.
+    pub pending_accepts: Option<u64>,
+}
+
+/// A snapshot of the host resources in use by an enclave.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ResourceUsage {
+    pub memory: u64,
+    pub fds: u64,
+    pub connections: u64,
+    pub pending_accepts: u64,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(super) enum Resource {
+    Memory,
+    Fds,
+    Connections,
+    PendingAccepts,
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Resource::Memory => "user memory",
+            Resource::Fds => "open file descriptors",
+            Resource::Connections => "connections",
+            Resource::PendingAccepts => "pending accepts",
+        })
+    }
+}
+
+/// Usage counters of an enclave, checked against its limits.
+#[derive(Debug, Default)]
+pub(crate) struct ResourceCounters {
+    limits: ResourceLimits,
+    memory: AtomicU64,
+    fds: AtomicU64,
+    connections: AtomicU64,
+    pending_accepts: AtomicU64,
+    /// Sizes of the user memory allocations counted in `memory`, by address
+    allocations: Mutex<FnvHashMap<usize, u64>>,
+}
+
+impl ResourceCounters {
+    pub(crate) fn new(limits: ResourceLimits) -> Self {
+        ResourceCounters {
+            limits,
+            ..Default::default()
+        }
+    }
+
+    fn counter(&self, resource: Resource) -> (&AtomicU64, Option<u64>) {
+        match resource {
+            Resource::Memory => (&self.memory, self.limits.memory),
+            Resource::Fds => (&self.fds, self.limits.fds),
+            Resource::Connections => (&self.connections, self.limits.connections),
+            Resource::PendingAccepts => (&self.pending_accepts, self.limits.pending_accepts),
+        }
+    }
+
+    /// Account for `amount` more of `resource`, unless that would exceed the
+    /// limit.
+    pub(super) fn acquire(&self, resource: Resource, amount: u64) -> IoResult<()> {
+        let (counter, limit) = self.counter(resource);
+        let limit = limit.unwrap_or(u64::max_value());
+        let mut current = counter.load(Ordering::Relaxed);
+        loop {
+            let new = match current.checked_add(amount) {
+                Some(new) if new <= limit => new,
+                _ => {
+                    return Err(IoError::new(
+                        IoErrorKind::Other,
+                        format!("enclave resource limit reached: {}", resource),
+                    ))
+                }
+            };
+            match counter.compare_exchange_weak(current, new, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => return Ok(()),
+                Err(prev) => current = prev,
+            }
+        }
+    }
+
+    pub(super) fn release(&self, resource: Resource, amount: u64) {
+        let (counter, _) = self.counter(resource);
+        counter.fetch_sub(amount, Ordering::Relaxed);
+    }
+
+    /// Allocate user memory that the enclave deallocates with the `free`
+    /// usercall, unless that would exceed the memory limit.
+    pub(super) fn alloc_user(&self, layout: Layout) -> IoResult<*mut u8> {
+        if layout.size() == 0 {
+            return Err(IoErrorKind::InvalidInput.into());
+        }
+        let size = layout.size() as u64;
+        self.acquire(Resource::Memory, size)?;
+        let ptr = unsafe { System.alloc(layout) };
+        if ptr.is_null() {
+            self.release(Resource::Memory, size);
+            return Err(IoErrorKind::Other.into());
+        }
+        self.allocations.lock().unwrap().insert(ptr as usize, size);
+        Ok(ptr)
+    }
+
+    /// Copy `data` to user memory allocated with `alloc_user`, laid out like
+    /// a `[u8]`, as the enclave expects when deallocating a
+    /// [`ByteBuffer`](../../fortanix_sgx_abi/struct.ByteBuffer.html).
+    /// Empty data is not allocated.
+    pub(super) fn alloc_user_bytes(&self, data: &[u8]) -> IoResult<*mut u8> {
+        if data.is_empty() {
+            return Ok(ptr::NonNull::dangling().as_ptr());
+        }
+        let ptr = self.alloc_user(Layout::from_size_align(data.len(), 1).unwrap())?;
+        unsafe { ptr::copy_nonoverlapping(data.as_ptr(), ptr, data.len()) };
+        Ok(ptr)
+    }
+
+    /// Deallocate user memory on behalf of the enclave. Only allocations
+    /// made with `alloc_user` are released from the memory usage, with the
+    /// size they were allocated with.
+    pub(super) unsafe fn free_user(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(size) = self.allocations.lock().unwrap().remove(&(ptr as usize)) {
+            self.release(Resource::Memory, size);
+        }
+        System.dealloc(ptr, layout)
+    }
+
+    /// Like `acquire`, but the resource is released again when the returned
+    /// guard is dropped.
+    pub(super) fn acquire_guard(self: &Arc<Self>, resource: Resource) -> IoResult<ResourceGuard> {
+        self.acquire(resource, 1)?;
+        Ok(ResourceGuard {
+            counters: self.clone(),
+            resource,
+        })
+    }
+
+    fn usage(&self) -> ResourceUsage {
+        ResourceUsage {
+            memory: self.memory.load(Ordering::Relaxed),
+            fds: self.fds.load(Ordering::Relaxed),
+            connections: self.connections.load(Ordering::Relaxed),
+            pending_accepts: self.pending_accepts.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// One unit of a resource, held until dropped.
+#[derive(Debug)]
+pub(super) struct ResourceGuard {
+    counters: Arc<ResourceCounters>,
+    resource: Resource,
+}
+
+impl Drop for ResourceGuard {
+    fn drop(&mut self) {
+        self.counters.release(self.resource, 1)
+    }
+}
+
+/// A handle to observe the host resources in use by an enclave.
+///
+/// Obtained from [`Command::resource_monitor`] or
+/// [`Library::resource_monitor`]. The handle remains valid after the enclave
+/// exits.
+///
+/// [`Command::resource_monitor`]: ../struct.Command.html#method.resource_monitor
+/// [`Library::resource_monitor`]: ../struct.Library.html#method.resource_monitor
+#[derive(Clone, Debug)]
+pub struct ResourceMonitor(pub(crate) Arc<ResourceCounters>);
+
+impl ResourceMonitor {
+    /// The current resource usage of the enclave.
+    pub fn usage(&self) -> ResourceUsage {
+        self.0.usage()
+    }
+
+    /// The limits the enclave is running with.
+    pub fn limits(&self) -> ResourceLimits {
+        self.0.limits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits() {
+        let counters = Arc::new(ResourceCounters::new(ResourceLimits {
+            memory: Some(100),
+            fds: Some(2),
+            ..Default::default()
+        }));
+        let monitor = ResourceMonitor(counters.clone());
+
+        let layout = |size| Layout::from_size_align(size, 8).unwrap();
+        let a = counters.alloc_user(layout(60)).unwrap();
+        assert!(counters.alloc_user(layout(41)).is_err());
+        let b = counters.alloc_user(layout(40)).unwrap();
+        unsafe { counters.free_user(b, layout(40)) };
+        assert_eq!(monitor.usage().memory, 60);
+        unsafe { counters.free_user(a, layout(60)) };
+        assert_eq!(monitor.usage().memory, 0);
+
+        let a = counters.acquire_guard(Resource::Fds).unwrap();
+        let _b = counters.acquire_guard(Resource::Fds).unwrap();
+        assert!(counters.acquire_guard(Resource::Fds).is_err());
+        drop(a);
+        assert_eq!(monitor.usage().fds, 1);
+        let _c = counters.acquire_guard(Resource::Fds).unwrap();
+
+        counters.acquire(Resource::Connections, u64::max_value()).unwrap();
+        assert!(counters.acquire(Resource::Connections, 1).is_err());
+    }
+}
@@ -0,0 +1,333 @@
+/* Copyright (c) Fortanix, Inc.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Threads shared by multiple enclaves.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::Result as IoResult;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use fnv::FnvHashMap;
+
+use super::{UsercallSendData, Work};
+
+/// Threads for running enclaves, which can be shared by many [`Command`]s
+/// and [`Library`]s.
+///
+/// By default, each enclave entry creates its own threads: running a
+/// `Command` starts a thread per CPU to run enclave threads on, and every
+/// `Library::call` starts one. Enclaves that are [built] with an
+/// `EnclaveRuntime` instead run on its threads:
+/// * a fixed number of *enclave threads* enter the enclaves. Whenever an
+///   enclave thread can continue, for example because its usercall has
+///   returned, it is queued to run on one of them. The enclaves with queued
+///   threads take turns, so a busy enclave can't delay the others by more
+///   than one run of each of its threads.
+/// * a tokio runtime with a fixed number of *usercall threads* handles
+///   asynchronous I/O and timers for usercalls. The usercalls of an entry
+///   are still processed on the thread that runs the `Command` or calls
+///   into the `Library`.
+///
+/// An enclave thread keeps its runtime thread busy until it exits the
+/// enclave, so enclave code that waits for another thread without
+/// performing a usercall, e.g. using a spin lock, might wait forever when
+/// the enclaves have more threads running than the runtime has enclave
+/// threads.
+///
+/// The threads exit once the runtime and all enclaves using it have been
+/// dropped.
+///
+/// [`Command`]: ../struct.Command.html
+/// [`Library`]: ../struct.Library.html
+/// [built]: ../struct.EnclaveBuilder.html#method.runtime
+#[derive(Clone)]
+pub struct EnclaveRuntime(Arc<Shared>);
+
+struct Shared {
+    scheduler: Arc<Scheduler>,
+    tokio: tokio::runtime::Runtime,
+    next_enclave: AtomicUsize,
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        self.scheduler.queues.lock().unwrap().shutdown = true;
+        self.scheduler.work_available.notify_all();
+    }
+}
+
+impl fmt::Debug for EnclaveRuntime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EnclaveRuntime")
+            .field("enclave_threads", &self.0.scheduler.threads)
+            .finish()
+    }
+}
+
+impl EnclaveRuntime {
+    /// A runtime with an enclave thread and a usercall thread per CPU.
+    pub fn new() -> IoResult<Self> {
+        EnclaveRuntimeBuilder::new().build()
+    }
+
+    /// Queue enclave threads for one enclave.
+    pub(super) fn queue(&self) -> RunQueue {
+        RunQueue {
+            runtime: self.clone(),
+            enclave: self.0.next_enclave.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+}
+
+/// Configures an [`EnclaveRuntime`].
+///
+/// [`EnclaveRuntime`]: struct.EnclaveRuntime.html
+#[derive(Clone, Debug)]
+pub struct EnclaveRuntimeBuilder {
+    enclave_threads: usize,
+    usercall_threads: usize,
+}
+
+impl Default for EnclaveRuntimeBuilder {
+    fn default() -> Self {
+        EnclaveRuntimeBuilder {
+            enclave_threads: num_cpus::get(),
+            usercall_threads: num_cpus::get(),
+        }
+    }
+}
+
+impl EnclaveRuntimeBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of threads that enter enclaves, i.e. the maximum number
+    /// of enclave threads running at the same time. The default is the
+    /// number of CPUs.
+    ///
+    /// # Panics
+    /// Panics if `threads` is 0.
+    pub fn enclave_threads(&mut self, threads: usize) -> &mut Self {
+        assert!(threads > 0, "a runtime needs at least one enclave thread");
+        self.enclave_threads = threads;
+        self
+    }
+
+    /// The number of threads that handle I/O and timers for usercalls. The
+    /// default is the number of CPUs.
+    ///
+    /// # Panics
+    /// Panics if `threads` is 0.
+    pub fn usercall_threads(&mut self, threads: usize) -> &mut Self {
+        assert!(threads > 0, "a runtime needs at least one usercall thread");
+        self.usercall_threads = threads;
+        self
+    }
+
+    pub fn build(&self) -> IoResult<EnclaveRuntime> {
+        let tokio = tokio::runtime::Builder::new()
+            .threaded_scheduler()
+            .core_threads(self.usercall_threads)
+            .thread_name("enclave-usercalls")
+            .enable_all()
+            .build()?;
+
+        let scheduler = Arc::new(Scheduler {
+            queues: Mutex::new(Queues::default()),
+            work_available: Condvar::new(),
+            threads: self.enclave_threads,
+        });
+        for i in 0..self.enclave_threads {
+            let scheduler = scheduler.clone();
+            thread::Builder::new()
+                .name(format!("enclave-{}", i))
+                .spawn(move || scheduler.worker_loop())?;
+        }
+
+        Ok(EnclaveRuntime(Arc::new(Shared {
+            scheduler,
+            tokio,
+            next_enclave: AtomicUsize::new(0),
+        })))
+    }
+}
+
+struct Scheduler {
+    queues: Mutex<Queues<(Work, Arc<RunGroup>)>>,
+    work_available: Condvar,
+    threads: usize,
+}
+
+struct Queues<T> {
+    /// Enclaves with queued work, in the order in which they take turns
+    ready: VecDeque<usize>,
+    work: FnvHashMap<usize, VecDeque<T>>,
+    shutdown: bool,
+}
+
+impl<T> Default for Queues<T> {
+    fn default() -> Self {
+        Queues {
+            ready: VecDeque::new(),
+            work: FnvHashMap::default(),
+            shutdown: false,
+        }
+    }
+}
+
+impl<T> Queues<T> {
+    fn push(&mut self, enclave: usize, work: T) {
+        let queue = self.work.entry(enclave).or_default();
+        if queue.is_empty() {
+            self.ready.push_back(enclave);
+        }
+        queue.push_back(work);
+    }
+
+    /// Take work of the enclave whose turn it is.
+    fn pop(&mut self) -> Option<T> {
+        let enclave = self.ready.pop_front()?;
+        let queue = self.work.get_mut(&enclave).unwrap();
+        let work = queue.pop_front().unwrap();
+        if queue.is_empty() {
+            self.work.remove(&enclave);
+        } else {
+            self.ready.push_back(enclave);
+        }
+        Some(work)
+    }
+}
+
+impl Scheduler {
+    fn worker_loop(&self) {
+        loop {
+            let (work, group) = {
+                let mut queues = self.queues.lock().unwrap();
+                loop {
+                    if let Some(work) = queues.pop() {
+                        break work;
+                    }
+                    // Finish queued work first, so that it isn't dropped
+                    // while the enclave is waiting for it
+                    if queues.shutdown {
+                        return;
+                    }
+                    queues = self.work_available.wait(queues).unwrap();
+                }
+            };
+            let _finished = Finished(&group);
+            // Panics while running enclave code are reported to the usercall
+            // loop by `do_work`. Keep the thread for the work of other
+            // enclaves should anything else panic.
+            let _ = panic::catch_unwind(AssertUnwindSafe(|| work.do_work(&group.io_queue_send)));
+        }
+    }
+}
+
+/// Marks work of a group as finished when dropped, even when unwinding.
+struct Finished<'a>(&'a RunGroup);
+
+impl<'a> Drop for Finished<'a> {
+    fn drop(&mut self) {
+        self.0.finished()
+    }
+}
+
+/// The queue for the enclave threads of one enclave.
+#[derive(Clone)]
+pub(super) struct RunQueue {
+    runtime: EnclaveRuntime,
+    enclave: usize,
+}
+
+impl RunQueue {
+    pub(super) fn tokio_handle(&self) -> tokio::runtime::Handle {
+        self.runtime.0.tokio.handle().clone()
+    }
+
+    /// Start a group of work for one enclave entry, reporting the results
+    /// to `io_queue_send`.
+    pub(super) fn group(&self, io_queue_send: tokio::sync::mpsc::UnboundedSender<UsercallSendData>) -> RunGroup {
+        RunGroup {
+            queue: self.clone(),
+            io_queue_send,
+            outstanding: Mutex::new(0),
+            idle: Condvar::new(),
+        }
+    }
+}
+
+/// The work for one enclave entry, which includes the threads it launches.
+pub(super) struct RunGroup {
+    queue: RunQueue,
+    io_queue_send: tokio::sync::mpsc::UnboundedSender<UsercallSendData>,
+    /// The amount of queued and running work
+    outstanding: Mutex<usize>,
+    idle: Condvar,
+}
+
+impl RunGroup {
+    pub(super) fn send(self: &Arc<Self>, work: Work) {
+        *self.outstanding.lock().unwrap() += 1;
+        let scheduler = &self.queue.runtime.0.scheduler;
+        scheduler.queues.lock().unwrap().push(self.queue.enclave, (work, self.clone()));
+        scheduler.work_available.notify_one();
+    }
+
+    fn finished(&self) {
+        let mut outstanding = self.outstanding.lock().unwrap();
+        *outstanding -= 1;
+        if *outstanding == 0 {
+            self.idle.notify_all();
+        }
+    }
+
+    /// Wait until no work of this group is queued or running.
+    pub(super) fn wait_idle(&self) {
+        let mut outstanding = self.outstanding.lock().unwrap();
+        while *outstanding > 0 {
+            outstanding = self.idle.wait(outstanding).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_robin() {
+        let mut queues = Queues::default();
+        for &(enclave, work) in &[(0, "a1"), (0, "a2"), (0, "a3"), (1, "b1"), (2, "c1"), (2, "c2")] {
+            queues.push(enclave, work);
+        }
+        let mut order = vec![queues.pop().unwrap()];
+        queues.push(1, "b2");
+        while let Some(work) = queues.pop() {
+            order.push(work);
+        }
+        assert_eq!(order, ["a1", "b1", "c1", "a2", "b2", "c2", "a3"]);
+        assert!(queues.work.is_empty());
+    }
+
+    #[test]
+    fn finished_on_panic() {
+        let runtime = EnclaveRuntimeBuilder::new().enclave_threads(1).usercall_threads(1).build().unwrap();
+        let group = runtime.queue().group(tokio::sync::mpsc::unbounded_channel().0);
+        *group.outstanding.lock().unwrap() += 1;
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _finished = Finished(&group);
+            panic!("work panicked");
+        }));
+        assert!(result.is_err());
+        assert_eq!(*group.outstanding.lock().unwrap(), 0);
+        group.wait_idle();
+    }
+}
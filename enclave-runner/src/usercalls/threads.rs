@@ -0,0 +1,204 @@
+/* Copyright (c) Fortanix, Inc.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! The TCSs of an enclave that aren't in use, and launching threads on them.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crossbeam::queue::SegQueue;
+
+use super::metrics::MetricsCounters;
+
+/// What the `launch_thread` usercall does if the enclave has no TCS
+/// available to run the new thread on.
+///
+/// An enclave has a fixed number of TCSs, chosen when it is converted to
+/// SGXS format (see the `--threads` option of `ftxsgx-elf2sgxs`). Each
+/// running thread, as well as each library call, needs one of them.
+///
+/// Set with [`EnclaveBuilder::launch_thread_policy`].
+///
+/// [`EnclaveBuilder::launch_thread_policy`]: ../struct.EnclaveBuilder.html#method.launch_thread_policy
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LaunchThreadPolicy {
+    /// Fail the usercall, which the enclave usually reports as a failure to
+    /// spawn a thread. This is the default.
+    FailFast,
+    /// Let the usercall succeed, and start the thread as soon as another
+    /// thread exits or, in libraries, a library call returns. Queued
+    /// threads are started before waiting library calls get a TCS.
+    ///
+    /// A queued thread can only start if a running thread exits. If the
+    /// running threads are waiting for the queued thread instead, the
+    /// enclave will wait forever.
+    Queue,
+    /// Like `FailFast`, but also fail if launching the thread would leave
+    /// fewer than the given number of TCSs available for library calls.
+    /// Only supported for libraries.
+    ReserveForLibrary(usize),
+}
+
+impl Default for LaunchThreadPolicy {
+    fn default() -> Self {
+        LaunchThreadPolicy::FailFast
+    }
+}
+
+/// The outcome of a thread launch.
+pub(super) enum Launch<T> {
+    /// The thread should be started on this TCS
+    Start(T),
+    /// The thread will be started once a TCS is released
+    Queued,
+    /// No TCS is available
+    Unavailable,
+}
+
+/// The TCSs that aren't in use, counted in the enclave's metrics.
+pub(super) struct ThreadsQueue<T> {
+    queue: SegQueue<T>,
+    policy: LaunchThreadPolicy,
+    /// The number of launched threads waiting for a TCS
+    launches_queued: Mutex<usize>,
+    /// Whether a launch has failed before
+    launch_failed: AtomicBool,
+    metrics: Arc<MetricsCounters>,
+}
+
+impl<T> ThreadsQueue<T> {
+    pub(super) fn new(policy: LaunchThreadPolicy, metrics: Arc<MetricsCounters>) -> Self {
+        ThreadsQueue {
+            queue: SegQueue::new(),
+            policy,
+            launches_queued: Mutex::new(0),
+            launch_failed: AtomicBool::new(false),
+            metrics,
+        }
+    }
+
+    /// Add an unused TCS, without starting queued threads on it.
+    pub(super) fn push(&self, tcs: T) {
+        self.queue.push(tcs);
+        self.metrics.tcs_stopped();
+    }
+
+    pub(super) fn pop(&self) -> Option<T> {
+        let tcs = self.queue.pop().ok()?;
+        self.metrics.tcs_started();
+        Some(tcs)
+    }
+
+    /// Take a TCS for a thread launched by the enclave, according to the
+    /// launch policy.
+    pub(super) fn launch(&self) -> Launch<T> {
+        let mut queued = self.launches_queued.lock().unwrap();
+        let reserved = match self.policy {
+            LaunchThreadPolicy::ReserveForLibrary(n) => n,
+            LaunchThreadPolicy::FailFast | LaunchThreadPolicy::Queue => 0,
+        };
+        // Earlier launches go first
+        if *queued == 0 && self.queue.len() > reserved {
+            if let Some(tcs) = self.pop() {
+                return Launch::Start(tcs);
+            }
+        }
+        match self.policy {
+            LaunchThreadPolicy::Queue => {
+                *queued += 1;
+                self.metrics.set_launches_queued(*queued);
+                Launch::Queued
+            }
+            LaunchThreadPolicy::FailFast | LaunchThreadPolicy::ReserveForLibrary(_) => {
+                self.metrics.launch_failed();
+                Launch::Unavailable
+            }
+        }
+    }
+
+    /// Return a TCS that is no longer in use. If a launched thread is
+    /// waiting for a TCS, the TCS is returned so that the thread can be
+    /// started on it.
+    pub(super) fn release(&self, tcs: T) -> Option<T> {
+        let mut queued = self.launches_queued.lock().unwrap();
+        if *queued > 0 {
+            *queued -= 1;
+            self.metrics.set_launches_queued(*queued);
+            return Some(tcs);
+        }
+        self.push(tcs);
+        None
+    }
+
+    /// Remove all TCSs and queued launches, so no more threads are started.
+    pub(super) fn clear(&self) {
+        let mut queued = self.launches_queued.lock().unwrap();
+        *queued = 0;
+        self.metrics.set_launches_queued(0);
+        while self.pop().is_some() {}
+    }
+
+    /// Returns `true` the first time it's called, to report the first
+    /// failed launch only.
+    pub(super) fn first_launch_failure(&self) -> bool {
+        !self.launch_failed.swap(true, Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usercalls::MetricsMonitor;
+
+    fn queue(policy: LaunchThreadPolicy, tcss: &[&'static str]) -> ThreadsQueue<&'static str> {
+        let queue = ThreadsQueue::new(policy, Arc::new(MetricsCounters::default()));
+        for &tcs in tcss {
+            queue.push(tcs);
+        }
+        queue
+    }
+
+    fn outcome<T>(launch: Launch<T>) -> Result<T, &'static str> {
+        match launch {
+            Launch::Start(tcs) => Ok(tcs),
+            Launch::Queued => Err("queued"),
+            Launch::Unavailable => Err("unavailable"),
+        }
+    }
+
+    #[test]
+    fn fail_fast() {
+        let queue = queue(LaunchThreadPolicy::FailFast, &["a"]);
+        assert_eq!(outcome(queue.launch()), Ok("a"));
+        assert_eq!(outcome(queue.launch()), Err("unavailable"));
+        assert_eq!(queue.release("a"), None);
+        assert_eq!(outcome(queue.launch()), Ok("a"));
+    }
+
+    #[test]
+    fn queue_launches() {
+        let queue = queue(LaunchThreadPolicy::Queue, &["a"]);
+        let monitor = MetricsMonitor(queue.metrics.clone());
+        assert_eq!(outcome(queue.launch()), Ok("a"));
+        assert_eq!(outcome(queue.launch()), Err("queued"));
+        assert_eq!(outcome(queue.launch()), Err("queued"));
+        assert_eq!(monitor.metrics().tcs.launches_queued, 2);
+        assert_eq!(queue.release("a"), Some("a"));
+        assert_eq!(queue.release("a"), Some("a"));
+        assert_eq!(queue.release("a"), None);
+        assert_eq!(monitor.metrics().tcs.launches_queued, 0);
+        assert_eq!(queue.pop(), Some("a"));
+    }
+
+    #[test]
+    fn reserve_for_library() {
+        let queue = queue(LaunchThreadPolicy::ReserveForLibrary(1), &["a", "b"]);
+        assert_eq!(outcome(queue.launch()), Ok("a"));
+        assert_eq!(outcome(queue.launch()), Err("unavailable"));
+        // Library calls can use the reserved TCS
+        assert_eq!(queue.pop(), Some("b"));
+        assert_eq!(MetricsMonitor(queue.metrics.clone()).metrics().tcs.launches_failed, 1);
+    }
+}
@@ -0,0 +1,399 @@
+/* Copyright (c) Fortanix, Inc.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Tracing of the usercalls made by an enclave, similar to `strace`.
+//!
+//! Tracing is enabled by registering a [`UsercallTraceSink`] while
+//! [building](../struct.EnclaveBuilder.html#method.usercall_trace) the
+//! enclave. Every usercall is then reported to the sink once it completes.
+//!
+//! [`UsercallTraceSink`]: trait.UsercallTraceSink.html
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use fortanix_sgx_abi::description::ERRORS;
+use fortanix_sgx_abi::Error;
+use serde::Serializer;
+
+use super::abi::{usercall_info, UsercallResult};
+use super::interface::from_raw_parts_nonnull;
+use super::EnclaveAbort;
+
+/// Addresses longer than this are truncated in trace records.
+const MAX_ADDR_LEN: usize = 256;
+
+/// A usercall argument.
+#[derive(Clone, Debug, Serialize)]
+pub struct UsercallArg {
+    /// The parameter name, as specified in the ABI
+    pub name: &'static str,
+    /// The parameter type, as specified in the ABI
+    #[serde(rename = "type")]
+    pub ty: &'static str,
+    /// The raw register value
+    pub value: u64,
+    /// The contents of the memory pointed to by the argument, for arguments
+    /// that are network addresses
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub string: Option<String>,
+}
+
+impl UsercallArg {
+    fn is_pointer(&self) -> bool {
+        self.ty.starts_with('*') || self.ty.starts_with("Option<")
+    }
+}
+
+/// How a traced usercall completed.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UsercallOutcome {
+    /// The usercall returned to the enclave.
+    Return {
+        /// The values returned in registers
+        values: (u64, u64),
+        /// The name of the error, for usercalls that return a `Result` and
+        /// didn't succeed
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    /// The usercall didn't return because the enclave is exiting.
+    Exit {
+        /// Whether the enclave is exiting due to a panic
+        panic: bool,
+    },
+    /// The usercall didn't return because the enclave is being aborted.
+    Abort {
+        /// The reason for aborting the enclave
+        reason: String,
+    },
+}
+
+/// A completed usercall.
+#[derive(Clone, Debug, Serialize)]
+pub struct UsercallRecord {
+    /// The address of the TCS that made the usercall, or `None` for
+    /// asynchronous usercalls
+    pub tcs: Option<usize>,
+    /// The identifier of asynchronous usercalls
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub async_id: Option<u64>,
+    /// The usercall number
+    pub number: u64,
+    /// The usercall name, or `None` for usercalls not defined by the ABI
+    pub name: Option<&'static str>,
+    pub args: Vec<UsercallArg>,
+    /// The return type, as specified in the ABI
+    pub return_type: &'static str,
+    pub outcome: UsercallOutcome,
+    /// The time taken by the usercall, serialized in nanoseconds
+    #[serde(serialize_with = "serialize_nanos")]
+    pub duration: Duration,
+}
+
+fn serialize_nanos<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(duration.as_nanos() as u64)
+}
+
+fn error_name(code: i32) -> String {
+    if code >= Error::UserRangeStart as i32 && code <= Error::UserRangeEnd as i32 {
+        format!("UserDefined({:#x})", code)
+    } else if let Some(error) = ERRORS.iter().find(|error| error.value == code) {
+        error.name.to_owned()
+    } else {
+        "Other".to_owned()
+    }
+}
+
+impl fmt::Display for UsercallRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.tcs, self.async_id) {
+            (Some(tcs), _) => write!(f, "[{:#x}] ", tcs)?,
+            (None, Some(id)) => write!(f, "[async {}] ", id)?,
+            (None, None) => write!(f, "[async] ")?,
+        }
+        match self.name {
+            Some(name) => write!(f, "{}(", name)?,
+            None => write!(f, "usercall_{:#x}(", self.number)?,
+        }
+        for (i, arg) in self.args.iter().enumerate() {
+            if i != 0 {
+                f.write_str(", ")?;
+            }
+            if arg.is_pointer() {
+                write!(f, "{}={:#x}", arg.name, arg.value)?;
+            } else {
+                write!(f, "{}={}", arg.name, arg.value)?;
+            }
+            if let Some(ref s) = arg.string {
+                write!(f, " {:?}", s)?;
+            }
+        }
+        f.write_str(")")?;
+        match self.outcome {
+            UsercallOutcome::Return { error: Some(ref error), .. } => write!(f, " = Err({})", error)?,
+            UsercallOutcome::Return { values: (v1, v2), error: None } => match self.return_type {
+                "" => {}
+                "Result" => f.write_str(" = Ok")?,
+                "(Result, *mut u8)" => write!(f, " = Ok({:#x})", v2)?,
+                ty if ty.starts_with("(Result,") => write!(f, " = Ok({})", v2)?,
+                ty if ty.starts_with('*') => write!(f, " = {:#x}", v1)?,
+                ty if ty.starts_with('(') => write!(f, " = ({}, {})", v1, v2)?,
+                _ => write!(f, " = {}", v1)?,
+            },
+            UsercallOutcome::Exit { panic: false } => f.write_str(" = <exit>")?,
+            UsercallOutcome::Exit { panic: true } => f.write_str(" = <panic>")?,
+            UsercallOutcome::Abort { ref reason } => write!(f, " = <abort: {}>", reason)?,
+        }
+        write!(f, " <{:?}>", self.duration)
+    }
+}
+
+/// Receives the records of traced usercalls.
+///
+/// Implementations are called from the usercall handling loop and should
+/// not block for long periods of time.
+pub trait UsercallTraceSink: 'static + Send + Sync + fmt::Debug {
+    fn record(&self, record: &UsercallRecord);
+}
+
+impl<T: UsercallTraceSink> From<T> for Box<dyn UsercallTraceSink> {
+    fn from(value: T) -> Box<dyn UsercallTraceSink> {
+        Box::new(value)
+    }
+}
+
+/// Writes usercall records in a human-readable format, one per line.
+pub struct TextTraceSink {
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl TextTraceSink {
+    pub fn new<W: Write + Send + 'static>(writer: W) -> Self {
+        TextTraceSink {
+            writer: Mutex::new(Box::new(writer)),
+        }
+    }
+
+    /// Writes usercall records to the standard error of the runner.
+    pub fn stderr() -> Self {
+        Self::new(io::stderr())
+    }
+}
+
+impl fmt::Debug for TextTraceSink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TextTraceSink").finish()
+    }
+}
+
+impl UsercallTraceSink for TextTraceSink {
+    fn record(&self, record: &UsercallRecord) {
+        let mut writer = self.writer.lock().unwrap();
+        // Tracing must not interfere with the enclave, so errors are ignored
+        let _ = writeln!(writer, "{}", record).and_then(|()| writer.flush());
+    }
+}
+
+/// Writes usercall records as JSON objects, one per line.
+pub struct JsonTraceSink {
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl JsonTraceSink {
+    pub fn new<W: Write + Send + 'static>(writer: W) -> Self {
+        JsonTraceSink {
+            writer: Mutex::new(Box::new(writer)),
+        }
+    }
+
+    /// Writes usercall records to the file at `path`, which is created or
+    /// truncated.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl fmt::Debug for JsonTraceSink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("JsonTraceSink").finish()
+    }
+}
+
+impl UsercallTraceSink for JsonTraceSink {
+    fn record(&self, record: &UsercallRecord) {
+        let mut writer = self.writer.lock().unwrap();
+        // Tracing must not interfere with the enclave, so errors are ignored
+        let _ = serde_json::to_writer(&mut *writer, record)
+            .map_err(io::Error::from)
+            .and_then(|()| writer.write_all(b"\n"))
+            .and_then(|()| writer.flush());
+    }
+}
+
+/// A usercall that is being traced.
+pub(super) struct UsercallTracer<'a> {
+    sink: &'a dyn UsercallTraceSink,
+    record: UsercallRecord,
+    start: Instant,
+}
+
+impl<'a> UsercallTracer<'a> {
+    /// Starts tracing a usercall with the given register values.
+    ///
+    /// # Safety
+    /// Address arguments are read from user memory, the same way the usercall
+    /// handler will read them.
+    pub(super) unsafe fn start(
+        sink: &'a dyn UsercallTraceSink,
+        tcs: Option<usize>,
+        async_id: Option<u64>,
+        (n, a1, a2, a3, a4): (u64, u64, u64, u64, u64),
+    ) -> Self {
+        let values = [a1, a2, a3, a4];
+        let (name, return_type, mut args) = match usercall_info(n) {
+            Some(info) => {
+                let args = info.params.iter().zip(&values)
+                    .map(|(&(name, ty), &value)| UsercallArg { name, ty, value, string: None })
+                    .collect::<Vec<_>>();
+                (Some(info.name), info.returns, args)
+            }
+            None => {
+                let args = ["arg1", "arg2", "arg3", "arg4"].iter().zip(&values)
+                    .map(|(&name, &value)| UsercallArg { name, ty: "u64", value, string: None })
+                    .collect();
+                (None, "", args)
+            }
+        };
+
+        // Network addresses are passed as an `addr`, `len` pair
+        let len = args.iter().find(|arg| arg.name == "len").map(|arg| arg.value as usize);
+        if let (Some(arg), Some(len)) = (args.iter_mut().find(|arg| arg.name == "addr"), len) {
+            if let Ok(addr) = from_raw_parts_nonnull(arg.value as *const u8, len.min(MAX_ADDR_LEN)) {
+                arg.string = Some(String::from_utf8_lossy(addr).into_owned());
+            }
+        }
+
+        UsercallTracer {
+            sink,
+            record: UsercallRecord {
+                tcs,
+                async_id,
+                number: n,
+                name,
+                args,
+                return_type,
+                outcome: UsercallOutcome::Exit { panic: false },
+                duration: Duration::default(),
+            },
+            start: Instant::now(),
+        }
+    }
+
+    /// Reports the completed usercall to the sink.
+    pub(super) fn finish(mut self, result: &UsercallResult<(u64, u64)>) {
+        self.record.duration = self.start.elapsed();
+        self.record.outcome = match *result {
+            Ok(values) => {
+                let returns_result = usercall_info(self.record.number).map_or(false, |info| info.returns_result);
+                let error = if returns_result && values.0 != 0 {
+                    Some(error_name(values.0 as i32))
+                } else {
+                    None
+                };
+                UsercallOutcome::Return { values, error }
+            }
+            Err(EnclaveAbort::Exit { panic }) => UsercallOutcome::Exit { panic },
+            Err(EnclaveAbort::IndefiniteWait) => UsercallOutcome::Abort {
+                reason: "all enclave threads are waiting indefinitely".to_owned(),
+            },
+            Err(EnclaveAbort::InvalidUsercall(n)) => UsercallOutcome::Abort {
+                reason: format!("invalid usercall {}", n),
+            },
+            Err(EnclaveAbort::MainReturned) => UsercallOutcome::Abort {
+                reason: "main thread returned".to_owned(),
+            },
+            Err(EnclaveAbort::Secondary) => UsercallOutcome::Abort {
+                reason: "another enclave thread exited".to_owned(),
+            },
+            Err(EnclaveAbort::RunnerPanicked(ref message)) => UsercallOutcome::Abort {
+                reason: format!("the runner panicked: {}", message),
+            },
+            Err(EnclaveAbort::ReplayDiverged(ref message)) => UsercallOutcome::Abort {
+                reason: format!("the replay diverged: {}", message),
+            },
+        };
+        self.sink.record(&self.record);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct Collect(Mutex<Vec<String>>);
+
+    impl UsercallTraceSink for Collect {
+        fn record(&self, record: &UsercallRecord) {
+            self.0.lock().unwrap().push(record.to_string());
+        }
+    }
+
+    fn number(name: &str) -> u64 {
+        (1..64).find(|&n| usercall_info(n).map(|info| info.name) == Some(name)).unwrap()
+    }
+
+    #[test]
+    fn connect_stream() {
+        let sink = Collect::default();
+        let addr = b"example.com:443";
+        let tracer = unsafe {
+            UsercallTracer::start(
+                &sink,
+                Some(0x1000),
+                None,
+                (number("connect_stream"), addr.as_ptr() as u64, addr.len() as u64, 0, 0),
+            )
+        };
+        tracer.finish(&Ok((Error::ConnectionRefused as u64, 0)));
+        let line = sink.0.lock().unwrap().pop().unwrap();
+        assert!(line.starts_with(&format!(
+            "[0x1000] connect_stream(addr={:#x} \"example.com:443\", len=15, local_addr=0x0, peer_addr=0x0) = Err(ConnectionRefused) <",
+            addr.as_ptr() as usize
+        )), "{}", line);
+    }
+
+    #[test]
+    fn error_names() {
+        assert_eq!(error_name(Error::NotFound as i32), "NotFound");
+        assert_eq!(error_name(Error::Other as i32), "Other");
+        assert_eq!(error_name(0x1234), "Other");
+        assert_eq!(error_name(Error::UserRangeStart as i32 + 1), "UserDefined(0x40000001)");
+    }
+
+    #[test]
+    fn json() {
+        let record = UsercallRecord {
+            tcs: None,
+            async_id: Some(7),
+            number: 0x8000_0001,
+            name: None,
+            args: vec![],
+            return_type: "",
+            outcome: UsercallOutcome::Abort { reason: "invalid usercall 2147483649".to_owned() },
+            duration: Duration::from_micros(3),
+        };
+        assert_eq!(
+            serde_json::to_string(&record).unwrap(),
+            r#"{"tcs":null,"async_id":7,"number":2147483649,"name":null,"args":[],"return_type":"","outcome":{"abort":{"reason":"invalid usercall 2147483649"}},"duration":3000}"#
+        );
+    }
+}
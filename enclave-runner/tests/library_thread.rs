@@ -0,0 +1,145 @@
+/* Copyright (c) Fortanix, Inc.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use aesm_client::AesmClient;
+use enclave_runner::{EnclaveBuilder, Library, TcsUnavailable};
+#[cfg(unix)]
+use sgxs_loaders::isgx::Device as IsgxDevice;
+#[cfg(windows)]
+use sgxs_loaders::enclaveapi::Sgx as IsgxDevice;
+
+/// Library call that launches a thread, see `launch-thread/main.S`
+const LAUNCH: u64 = 1;
+/// Library call that returns the number of launched threads that ran
+const THREADS: u64 = 2;
+/// Library call that waits for the number of nanoseconds in `p2`
+const WAIT: u64 = 3;
+
+#[test]
+fn library_launches_thread() {
+    let mut device = IsgxDevice::new()
+        .unwrap()
+        .einittoken_provider(AesmClient::new())
+        .build();
+
+    let library = EnclaveBuilder::new_from_memory(include_bytes!("launch-thread/launch-thread.sgxs"))
+        .build_library(&mut device)
+        .unwrap();
+
+    unsafe {
+        assert_eq!(library.call(LAUNCH, 0, 0, 0, 0).unwrap(), (0, 0));
+
+        // The launched thread runs on its own host thread, and may still be
+        // running when the launching call returns
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while library.call(THREADS, 0, 0, 0, 0).unwrap().0 == 0 {
+            assert!(Instant::now() < deadline, "launched thread didn't run");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(library.call(THREADS, 0, 0, 0, 0).unwrap(), (1, 0));
+    }
+
+    let tcs = library.metrics().metrics().tcs;
+    assert_eq!((tcs.launches_failed, tcs.threads_failed), (0, 0));
+}
+
+#[test]
+fn library_call_async() {
+    let mut device = IsgxDevice::new()
+        .unwrap()
+        .einittoken_provider(AesmClient::new())
+        .build();
+
+    let library = EnclaveBuilder::new_from_memory(include_bytes!("launch-thread/launch-thread.sgxs"))
+        .build_library(&mut device)
+        .unwrap();
+
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        // The returned future is `Send` and doesn't borrow the library
+        let call = tokio::spawn(unsafe { library.call_async(THREADS, 0, 0, 0, 0) });
+        assert_eq!(call.await.unwrap().unwrap(), (0, 0));
+
+        // More calls than there are TCSs wait for one in turn
+        let calls = (0..8).map(|_| tokio::spawn(unsafe { library.call_async(THREADS, 0, 0, 0, 0) }));
+        for call in futures::future::join_all(calls).await {
+            assert_eq!(call.unwrap().unwrap(), (0, 0));
+        }
+
+        // Dropping calls, whether they have entered the enclave or are
+        // still waiting for a TCS, doesn't leave any TCS in use
+        let mut calls = (0..4).map(|_| Box::pin(unsafe { library.call_async(THREADS, 0, 0, 0, 0) })).collect::<Vec<_>>();
+        for call in &mut calls {
+            let _ = futures::poll!(call);
+        }
+        drop(calls);
+    });
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let tcs = library.metrics().metrics().tcs;
+        if tcs.stopped == tcs.total {
+            break;
+        }
+        assert!(Instant::now() < deadline, "TCSs of dropped calls weren't returned");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Waits until `n` TCSs are blocked in the `wait` usercall
+fn wait_for_waiting(library: &Library, n: u64) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while library.metrics().metrics().tcs.waiting < n {
+        assert!(Instant::now() < deadline, "calls didn't start waiting");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn library_tcs_unavailable() {
+    let mut device = IsgxDevice::new()
+        .unwrap()
+        .einittoken_provider(AesmClient::new())
+        .build();
+
+    let library = EnclaveBuilder::new_from_memory(include_bytes!("launch-thread/launch-thread.sgxs"))
+        .build_library(&mut device)
+        .unwrap();
+
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        // Keep both TCSs of the enclave busy
+        let wait = Duration::from_secs(2).as_nanos() as u64;
+        let calls = (0..2).map(|_| tokio::spawn(unsafe { library.call_async(WAIT, wait, 0, 0, 0) })).collect::<Vec<_>>();
+        wait_for_waiting(&library, 2);
+
+        let err = unsafe { library.try_call(THREADS, 0, 0, 0, 0) }.unwrap_err();
+        match err.downcast_ref::<TcsUnavailable>() {
+            Some(TcsUnavailable::Busy) => {}
+            _ => panic!("unexpected error: {}", err),
+        }
+
+        let start = Instant::now();
+        let err = unsafe { library.call_timeout(THREADS, 0, 0, 0, 0, Duration::from_millis(100)) }.unwrap_err();
+        match err.downcast_ref::<TcsUnavailable>() {
+            Some(TcsUnavailable::TimedOut) => {}
+            _ => panic!("unexpected error: {}", err),
+        }
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        for call in futures::future::join_all(calls).await {
+            call.unwrap().unwrap();
+        }
+    });
+
+    // The TCSs are available again once the calls have returned
+    unsafe {
+        assert_eq!(library.try_call(THREADS, 0, 0, 0, 0).unwrap(), (0, 0));
+    }
+}
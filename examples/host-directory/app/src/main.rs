@@ -0,0 +1,14 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+fn main() -> std::io::Result<()> {
+    // Read a file from the directory served by the runner
+    let mut name = String::new();
+    TcpStream::connect("file:name.txt")?.read_to_string(&mut name)?;
+
+    // Create a file next to it
+    let mut greeting = TcpStream::connect("file+write:greeting.txt")?;
+    writeln!(greeting, "Hello, {}!", name.trim())?;
+
+    Ok(())
+}
@@ -0,0 +1,55 @@
+/* Copyright (c) Fortanix, Inc.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+extern crate aesm_client;
+extern crate enclave_runner;
+extern crate sgxs_loaders;
+
+use aesm_client::AesmClient;
+use enclave_runner::usercalls::{DirectoryAccess, HostDirectory};
+use enclave_runner::EnclaveBuilder;
+use sgxs_loaders::isgx::Device as IsgxDevice;
+
+/// This example demonstrates the `HostDirectory` usercall extension, which
+/// lets the enclave read and write the files in a directory on the host by
+/// connecting to `file:` addresses.
+
+fn usage(name: String) {
+    println!("Usage:\n{} <path_to_sgxs_file> <directory>", name);
+}
+
+fn parse_args() -> Result<(String, String), ()> {
+    let args: Vec<String> = std::env::args().collect();
+    match args.len() {
+        3 => Ok((args[1].to_owned(), args[2].to_owned())),
+        _ => {
+            usage(args[0].to_owned());
+            Err(())
+        }
+    }
+}
+
+fn main() {
+    let (file, directory) = parse_args().unwrap();
+
+    let mut device = IsgxDevice::new()
+        .unwrap()
+        .einittoken_provider(AesmClient::new())
+        .build();
+
+    let mut enclave_builder = EnclaveBuilder::new(file.as_ref());
+    enclave_builder.dummy_signature();
+    enclave_builder.usercall_extension(HostDirectory::new(directory, DirectoryAccess::ReadWrite).unwrap());
+    let enclave = enclave_builder.build(&mut device).unwrap();
+
+    enclave
+        .run()
+        .map_err(|e| {
+            println!("Error while executing SGX enclave.\n{}", e);
+            std::process::exit(1)
+        })
+        .unwrap();
+}
@@ -0,0 +1,14 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+
+fn main() -> std::io::Result<()> {
+    let mut stream = BufReader::new(TcpStream::connect("echo")?);
+
+    stream.get_mut().write_all(b"Hello, world!\n")?;
+
+    let mut echo = String::new();
+    stream.read_line(&mut echo)?;
+    print!("{}", echo);
+
+    Ok(())
+}
@@ -0,0 +1,67 @@
+/* Copyright (c) Fortanix, Inc.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! An `ftxsgx-runner` plugin that handles connections to the address "echo"
+//! by sending back everything the enclave writes, prefixed with the
+//! plugin configuration.
+
+use std::ffi::CStr;
+use std::io::{BufRead, BufReader, Write};
+use std::os::raw::{c_char, c_int, c_void};
+use std::os::unix::io::IntoRawFd;
+use std::os::unix::net::UnixStream;
+use std::thread;
+
+struct Echo {
+    prefix: String,
+}
+
+#[no_mangle]
+pub extern "C" fn ftxsgx_plugin_abi_version() -> u32 {
+    1
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ftxsgx_plugin_new(config: *const c_char) -> *mut c_void {
+    let prefix = CStr::from_ptr(config).to_string_lossy().into_owned();
+    Box::into_raw(Box::new(Echo { prefix })) as *mut c_void
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ftxsgx_plugin_destroy(plugin: *mut c_void) {
+    drop(Box::from_raw(plugin as *mut Echo));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ftxsgx_plugin_connect_stream(plugin: *mut c_void, addr: *const c_char, fd: *mut c_int) -> c_int {
+    let echo = &*(plugin as *const Echo);
+    if CStr::from_ptr(addr).to_bytes() != b"echo" {
+        return 1;
+    }
+    let (enclave, service) = match UnixStream::pair() {
+        Ok(pair) => pair,
+        // EIO
+        Err(e) => return -e.raw_os_error().unwrap_or(5),
+    };
+    let prefix = echo.prefix.clone();
+    thread::spawn(move || {
+        let mut writer = match service.try_clone() {
+            Ok(writer) => writer,
+            Err(_) => return,
+        };
+        for line in BufReader::new(service).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => return,
+            };
+            if writeln!(writer, "{}{}", prefix, line).is_err() {
+                return;
+            }
+        }
+    });
+    *fd = enclave.into_raw_fd();
+    0
+}
@@ -0,0 +1,22 @@
+set -e
+
+# Build the plugin
+cd plugin
+cargo build
+cd -
+
+# Build APP
+cd app
+cargo +nightly build --target=x86_64-fortanix-unknown-sgx
+cd -
+
+# Convert the APP
+ftxsgx-elf2sgxs app/target/x86_64-fortanix-unknown-sgx/debug/app --heap-size 0x20000 --stack-size 0x20000 --threads 1
+
+# Execute with the plugin, configured with a prefix
+cat > extensions.toml <<TOML
+[[plugin]]
+path = "plugin/target/debug/libecho_plugin.so"
+config = "echo: "
+TOML
+ftxsgx-runner --signature dummy --extensions extensions.toml app/target/x86_64-fortanix-unknown-sgx/debug/app.sgxs
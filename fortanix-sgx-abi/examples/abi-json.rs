@@ -0,0 +1,17 @@
+/* Copyright (c) Fortanix, Inc.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Print the machine-readable description of the usercalls as JSON.
+
+extern crate fortanix_sgx_abi;
+
+use fortanix_sgx_abi::description::write_json;
+
+fn main() {
+    let mut json = String::new();
+    write_json(&mut json).unwrap();
+    print!("{}", json);
+}
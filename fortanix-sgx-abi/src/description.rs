@@ -0,0 +1,417 @@
+/* Copyright (c) Fortanix, Inc.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A machine-readable description of the usercalls.
+//!
+//! The tables in this module are generated from the same definitions as the
+//! rest of this crate, so they always match the [`Usercalls`] documentation
+//! and the [`invoke_with_usercalls`] macro. They are meant for generating
+//! code for, or checking, usercall implementations that can't use this crate
+//! directly, such as runners written in other languages. [`write_json`]
+//! serializes all tables as a JSON document.
+//!
+//! [`Usercalls`]: ../struct.Usercalls.html
+//! [`invoke_with_usercalls`]: ../macro.invoke_with_usercalls.html
+//! [`write_json`]: fn.write_json.html
+
+use core::fmt;
+
+use super::*;
+
+/// A register used to pass usercall numbers, arguments or return values, as
+/// specified in the calling convention.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Register {
+    Rdi,
+    Rsi,
+    Rdx,
+    R8,
+    R9,
+}
+
+impl Register {
+    /// The lowercase register name, e.g. `"rdi"`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Register::Rdi => "rdi",
+            Register::Rsi => "rsi",
+            Register::Rdx => "rdx",
+            Register::R8 => "r8",
+            Register::R9 => "r9",
+        }
+    }
+}
+
+/// The register the usercall number is passed in.
+pub const NUMBER_REGISTER: Register = Register::Rdi;
+
+/// The registers arguments are passed in, in order.
+pub const ARGUMENT_REGISTERS: [Register; 4] = [Register::Rsi, Register::Rdx, Register::R8, Register::R9];
+
+/// The registers values are returned in, in order.
+pub const RETURN_REGISTERS: [Register; 2] = [Register::Rsi, Register::Rdx];
+
+/// A usercall.
+#[derive(Copy, Clone, Debug)]
+pub struct UsercallDescription {
+    pub number: u64,
+    pub name: &'static str,
+    pub params: &'static [ValueDescription],
+    /// The return values, empty if the usercall doesn't return a value
+    pub returns: &'static [ValueDescription],
+    /// Whether the usercall never returns to the enclave (`-> !`)
+    pub diverges: bool,
+    /// Whether the first return value is a [`Result`], i.e.
+    /// [`RESULT_SUCCESS`] or one of the [`ERRORS`]. Other return values are
+    /// only meaningful if the usercall was successful.
+    ///
+    /// [`Result`]: ../type.Result.html
+    /// [`RESULT_SUCCESS`]: ../constant.RESULT_SUCCESS.html
+    /// [`ERRORS`]: constant.ERRORS.html
+    pub fallible: bool,
+    /// The documentation, one line of Markdown per element
+    pub docs: &'static [&'static str],
+}
+
+/// A usercall argument or return value.
+#[derive(Copy, Clone, Debug)]
+pub struct ValueDescription {
+    /// The argument name, or the empty string for return values
+    pub name: &'static str,
+    /// The Rust type, as formatted by `stringify!`, e.g. `* mut u8`
+    pub ty: &'static str,
+    pub register: Register,
+}
+
+/// An error code of the [`Error`] type.
+///
+/// [`Error`]: ../enum.Error.html
+#[derive(Copy, Clone, Debug)]
+pub struct ErrorDescription {
+    pub name: &'static str,
+    pub value: i32,
+    pub docs: &'static [&'static str],
+}
+
+// Describe arguments, counting the argument index in `$i`.
+macro_rules! describe_params {
+    ([$($done:expr),*] $i:expr; ) => { &[$($done),*] };
+    ([$($done:expr),*] $i:expr; $n:ident: $t:ty $(, $rn:ident: $rt:ty)*) => {
+        describe_params!([$($done,)* ValueDescription {
+            name: stringify!($n),
+            ty: stringify!($t),
+            register: ARGUMENT_REGISTERS[$i],
+        }] $i + 1; $($rn: $rt),*)
+    };
+}
+
+// Describe return values, which are written as `$r:tt` like in
+// `invoke_with_usercalls`.
+macro_rules! describe_returns {
+    (@ $i:tt $r:ty) => {
+        ValueDescription {
+            name: "",
+            ty: stringify!($r),
+            register: RETURN_REGISTERS[$i],
+        }
+    };
+    // (returns, diverges, fallible)
+    () => { (&[], false, false) };
+    (!) => { (&[], true, false) };
+    ((Result, $r:ty)) => { (&[describe_returns!(@ 0 Result), describe_returns!(@ 1 $r)], false, true) };
+    (($r1:ty, $r2:ty)) => { (&[describe_returns!(@ 0 $r1), describe_returns!(@ 1 $r2)], false, false) };
+    (Result) => { (&[describe_returns!(@ 0 Result)], false, true) };
+    ($r:ty) => { (&[describe_returns!(@ 0 $r)], false, false) };
+}
+
+// Walk the ABI specification like `define_invoke_with_usercalls`, collecting
+// usercalls and the variants of `Error`, then define the tables.
+macro_rules! define_descriptions {
+    // collect all usercalls
+    (@ [$($usercalls:tt)*] $errors:tt $(#[$meta1:meta])* impl Usercalls { $($(#[doc = $doc:literal])* pub fn $f:ident($($n:ident: $t:ty),*) $(-> $r:tt)* { unimplemented!() } )* } $($remainder:tt)* ) =>
+        { define_descriptions!(@ [$($usercalls)* $(($f ($($n: $t),*) ($($r)*) [$($doc),*]))*] $errors $($remainder)*); };
+    // collect the error codes
+    (@ $usercalls:tt [] $(#[$meta:meta])* pub enum Error { $($(#[doc = $doc:literal])* $variant:ident = $value:expr),* $(,)* } $($remainder:tt)* ) =>
+        { define_descriptions!(@ $usercalls [$(($variant [$($doc),*]))*] $($remainder)*); };
+    // visit modules
+    (@ $usercalls:tt $errors:tt $(#[$meta:meta])* pub mod $modname:ident { $($contents:tt)* } $($remainder:tt)*) =>
+        { define_descriptions!(@ $usercalls $errors $($contents)* $($remainder)*); };
+    // ignore all other items
+    (@ $usercalls:tt $errors:tt $item:item $($remainder:tt)*) =>
+        { define_descriptions!(@ $usercalls $errors $($remainder)*); };
+    // define the tables
+    (@ [$(($f:ident ($($n:ident: $t:ty),*) ($($r:tt)*) [$($doc:expr),*]))*] [$(($variant:ident [$($vdoc:expr),*]))*]) => {
+        #[allow(non_camel_case_types)]
+        enum UsercallNumber {
+            __enclave_usercalls_invalid,
+            $($f,)*
+        }
+
+        /// All usercalls, in order of their numbers.
+        pub const USERCALLS: &[UsercallDescription] = &[$(
+            {
+                const RETURNS: (&[ValueDescription], bool, bool) = describe_returns!($($r)*);
+                UsercallDescription {
+                    number: UsercallNumber::$f as u64,
+                    name: stringify!($f),
+                    params: describe_params!([] 0; $($n: $t),*),
+                    returns: RETURNS.0,
+                    diverges: RETURNS.1,
+                    fallible: RETURNS.2,
+                    docs: &[$($doc),*],
+                }
+            },
+        )*];
+
+        /// All error codes, in order of their values.
+        pub const ERRORS: &[ErrorDescription] = &[$(
+            ErrorDescription {
+                name: stringify!($variant),
+                value: Error::$variant as i32,
+                docs: &[$($vdoc),*],
+            },
+        )*];
+    };
+    // start with empty lists
+    ($($tt:tt)*) => {
+        define_descriptions!(@ [] [] $($tt)*);
+    }
+}
+
+invoke_with_abi_spec!(define_descriptions);
+
+impl UsercallDescription {
+    /// The documentation as Markdown.
+    pub fn docs(&self) -> Docs {
+        Docs(self.docs)
+    }
+}
+
+impl ErrorDescription {
+    /// The documentation as Markdown.
+    pub fn docs(&self) -> Docs {
+        Docs(self.docs)
+    }
+}
+
+/// Documentation, formatted as Markdown by its `Display` implementation.
+#[derive(Copy, Clone, Debug)]
+pub struct Docs(&'static [&'static str]);
+
+impl fmt::Display for Docs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, line) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str("\n")?;
+            }
+            // Doc comments start with a space after `///`
+            f.write_str(if line.starts_with(' ') { &line[1..] } else { line })?;
+        }
+        Ok(())
+    }
+}
+
+/// Formats a value as a JSON string.
+struct JsonString<T>(T);
+
+impl<T: fmt::Display> fmt::Display for JsonString<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        struct Escape<'a, 'b>(&'a mut fmt::Formatter<'b>);
+
+        impl<'a, 'b> fmt::Write for Escape<'a, 'b> {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                for c in s.chars() {
+                    match c {
+                        '"' => self.0.write_str("\\\""),
+                        '\\' => self.0.write_str("\\\\"),
+                        '\n' => self.0.write_str("\\n"),
+                        c if (c as u32) < 0x20 => write!(self.0, "\\u{:04x}", c as u32),
+                        c => fmt::Write::write_char(self.0, c),
+                    }?;
+                }
+                Ok(())
+            }
+        }
+
+        f.write_str("\"")?;
+        fmt::write(&mut Escape(f), format_args!("{}", self.0))?;
+        f.write_str("\"")
+    }
+}
+
+/// Formats a type without the spaces added by `stringify!`, e.g. `*mut u8`.
+struct TypeName(&'static str);
+
+impl fmt::Display for TypeName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut prev = ' ';
+        let mut chars = self.0.chars().peekable();
+        while let Some(c) = chars.next() {
+            let next = chars.peek().cloned().unwrap_or(' ');
+            if c == ' ' && ("*&<(".contains(prev) || "<>),".contains(next)) {
+                continue;
+            }
+            fmt::Write::write_char(f, c)?;
+            prev = c;
+        }
+        Ok(())
+    }
+}
+
+fn write_values<W: fmt::Write>(out: &mut W, values: &[ValueDescription]) -> fmt::Result {
+    out.write_str("[")?;
+    for (i, value) in values.iter().enumerate() {
+        if i > 0 {
+            out.write_str(", ")?;
+        }
+        write!(out, "{{\"type\": {}, \"register\": {}", JsonString(TypeName(value.ty)), JsonString(value.register.name()))?;
+        if !value.name.is_empty() {
+            write!(out, ", \"name\": {}", JsonString(value.name))?;
+        }
+        out.write_str("}")?;
+    }
+    out.write_str("]")
+}
+
+/// Write the description of the usercalls as a JSON document.
+///
+/// The document has the following structure. Types are written as in this
+/// crate, e.g. `*mut u8` or `Fd`, and documentation is Markdown.
+///
+/// ```json
+/// {
+///   "number_register": "rdi",
+///   "argument_registers": ["rsi", "rdx", "r8", "r9"],
+///   "return_registers": ["rsi", "rdx"],
+///   "usercalls": [
+///     {
+///       "number": 1,
+///       "name": "read",
+///       "params": [{"type": "Fd", "register": "rsi", "name": "fd"}, ...],
+///       "returns": [{"type": "Result", "register": "rsi"}, ...],
+///       "diverges": false,
+///       "fallible": true,
+///       "docs": "Read up to `len` bytes from stream `fd`..."
+///     },
+///     ...
+///   ],
+///   "errors": [{"name": "PermissionDenied", "value": 1, "docs": ""}, ...]
+/// }
+/// ```
+pub fn write_json<W: fmt::Write>(out: &mut W) -> fmt::Result {
+    let registers = |out: &mut W, registers: &[Register]| {
+        out.write_str("[")?;
+        for (i, register) in registers.iter().enumerate() {
+            if i > 0 {
+                out.write_str(", ")?;
+            }
+            write!(out, "{}", JsonString(register.name()))?;
+        }
+        out.write_str("]")
+    };
+
+    write!(out, "{{\n  \"number_register\": {},\n", JsonString(NUMBER_REGISTER.name()))?;
+    out.write_str("  \"argument_registers\": ")?;
+    registers(out, &ARGUMENT_REGISTERS)?;
+    out.write_str(",\n  \"return_registers\": ")?;
+    registers(out, &RETURN_REGISTERS)?;
+
+    out.write_str(",\n  \"usercalls\": [")?;
+    for (i, usercall) in USERCALLS.iter().enumerate() {
+        if i > 0 {
+            out.write_str(",")?;
+        }
+        write!(out, "\n    {{\n      \"number\": {},\n      \"name\": {},\n      \"params\": ", usercall.number, JsonString(usercall.name))?;
+        write_values(out, usercall.params)?;
+        out.write_str(",\n      \"returns\": ")?;
+        write_values(out, usercall.returns)?;
+        write!(
+            out,
+            ",\n      \"diverges\": {},\n      \"fallible\": {},\n      \"docs\": {}\n    }}",
+            usercall.diverges,
+            usercall.fallible,
+            JsonString(usercall.docs())
+        )?;
+    }
+
+    out.write_str("\n  ],\n  \"errors\": [")?;
+    for (i, error) in ERRORS.iter().enumerate() {
+        if i > 0 {
+            out.write_str(",")?;
+        }
+        write!(
+            out,
+            "\n    {{\"name\": {}, \"value\": {}, \"docs\": {}}}",
+            JsonString(error.name),
+            error.value,
+            JsonString(error.docs())
+        )?;
+    }
+    out.write_str("\n  ]\n}\n")
+}
+
+#[cfg(all(test, not(feature = "docs")))]
+mod tests {
+    extern crate std;
+
+    use self::std::string::String;
+    use self::std::vec::Vec;
+    use super::*;
+
+    // `stringify!` output depends on how the tokens were captured, so types
+    // are compared without whitespace
+    fn normalize(s: &str) -> String {
+        s.chars().filter(|c| !c.is_whitespace()).collect()
+    }
+
+    fn returns(usercall: &UsercallDescription) -> String {
+        let types = usercall.returns.iter().map(|value| value.ty).collect::<Vec<_>>();
+        match (usercall.diverges, &types[..]) {
+            (true, _) => normalize("!"),
+            (false, &[]) => String::new(),
+            (false, &[ty]) => normalize(ty),
+            (false, types) => normalize(&std::format!("({})", types.join(","))),
+        }
+    }
+
+    macro_rules! check_usercalls {
+        ($(fn $f:ident($($n:ident: $t:ty),*) $(-> $r:tt)*; )*) => {{
+            let mut usercalls = USERCALLS.iter();
+            let mut number = 0;
+            $(
+                number += 1;
+                let usercall = usercalls.next().expect(concat!("missing description of ", stringify!($f)));
+                assert_eq!((usercall.number, usercall.name), (number, stringify!($f)));
+                let params = usercall.params.iter().map(|param| (param.name, normalize(param.ty))).collect::<Vec<_>>();
+                assert_eq!(params, [$((stringify!($n), normalize(stringify!($t)))),*], "{}", usercall.name);
+                for (param, register) in usercall.params.iter().zip(ARGUMENT_REGISTERS.iter()) {
+                    assert_eq!(param.register, *register, "{}", usercall.name);
+                }
+                let return_type = returns(usercall);
+                assert_eq!(return_type, normalize(stringify!($($r)*)), "{}", usercall.name);
+                let fallible = return_type == "Result" || return_type.starts_with("(Result,");
+                assert_eq!(usercall.fallible, fallible, "{}", usercall.name);
+            )*
+            assert!(usercalls.next().is_none(), "description of unknown usercall");
+        }};
+    }
+
+    #[test]
+    fn matches_usercalls() {
+        invoke_with_usercalls!(check_usercalls);
+    }
+
+    #[test]
+    fn json() {
+        let mut json = String::new();
+        write_json(&mut json).unwrap();
+        for usercall in USERCALLS {
+            assert!(json.contains(&std::format!("\"name\": \"{}\"", usercall.name)));
+        }
+        assert!(json.contains("{\"type\": \"*mut u8\", \"register\": \"rdx\", \"name\": \"buf\"}"));
+        assert_eq!(json.matches('{').count(), json.matches('}').count());
+    }
+}
@@ -453,6 +453,18 @@ pub const EV_RETURNQ_NOT_EMPTY: u64 = 0b0000_0000_0000_0010;
 /// An event that enclaves can use for synchronization.
 #[cfg_attr(feature = "rustc-dep-of-std", unstable(feature = "sgx_platform", issue = "56975"))]
 pub const EV_UNPARK: u64 = 0b0000_0000_0000_0100;
+/// An event that userspace sends to all TCSes when it has been asked to
+/// terminate the enclave, for example because it received a termination
+/// signal. The enclave should exit soon after, as userspace may stop it
+/// if it doesn't.
+///
+/// Userspace only generates this event if it reports the
+/// [`CAPABILITY_SIGNAL_EVENT`] capability. This event was added in version
+/// 0.3.4.
+///
+/// [`CAPABILITY_SIGNAL_EVENT`]: constant.CAPABILITY_SIGNAL_EVENT.html
+#[cfg_attr(feature = "rustc-dep-of-std", unstable(feature = "sgx_platform", issue = "56975"))]
+pub const EV_SIGNAL: u64 = 0b0000_0000_0001_0000;
 
 #[cfg_attr(feature = "rustc-dep-of-std", unstable(feature = "sgx_platform", issue = "56975"))]
 pub const WAIT_NO: u64 = 0;
@@ -503,14 +515,19 @@ impl Usercalls {
 
     /// Wait for an event to occur, or check if an event is currently pending.
     ///
-    /// `timeout` must be [`WAIT_NO`] or [`WAIT_INDEFINITE`]. If it is another
-    /// value, userspace will return an error.
+    /// `timeout` must be [`WAIT_NO`], [`WAIT_INDEFINITE`] or, if userspace
+    /// reports the [`CAPABILITY_WAIT_TIMEOUT`] capability, a number of
+    /// nanoseconds. Otherwise, userspace will return an error for other
+    /// values. Timeouts other than [`WAIT_NO`] and [`WAIT_INDEFINITE`] were
+    /// added in version 0.3.4.
     ///
     /// If `timeout` is [`WAIT_INDEFINITE`], this call will block and return
     /// once a matching event is queued on this TCS. If `timeout` is
     /// [`WAIT_NO`], this call will return immediately, and the return value
     /// will indicate if an event was pending. If it was, it has been dequeued.
-    /// If not, the [`WouldBlock`] error value will be returned.
+    /// If not, the [`WouldBlock`] error value will be returned. For other
+    /// values, this call will block until a matching event is queued or
+    /// `timeout` has expired, in which case [`WouldBlock`] is returned.
     ///
     /// A matching event is one whose bits are equal to or a subset of
     /// `event_mask`. If `event_mask` is `0`, this call will never return due
@@ -751,6 +768,60 @@ pub mod async {
     }
 }
 
+/// The version of this specification, as `major << 32 | minor << 16 | patch`.
+#[cfg_attr(feature = "rustc-dep-of-std", unstable(feature = "sgx_platform", issue = "56975"))]
+pub const ABI_VERSION: u64 = 0x0000_0003_0004;
+
+/// Capability: the [`async_queues`] usercall is supported.
+///
+/// [`async_queues`]: struct.Usercalls.html#method.async_queues
+#[cfg_attr(feature = "rustc-dep-of-std", unstable(feature = "sgx_platform", issue = "56975"))]
+pub const CAPABILITY_ASYNC_QUEUES: u64 = 0x01;
+/// Capability: userspace handles some usercalls with a number that has the
+/// [`USERCALL_USER_DEFINED`] bit set. Which ones is application-defined.
+///
+/// [`USERCALL_USER_DEFINED`]: constant.USERCALL_USER_DEFINED.html
+#[cfg_attr(feature = "rustc-dep-of-std", unstable(feature = "sgx_platform", issue = "56975"))]
+pub const CAPABILITY_USER_DEFINED_USERCALLS: u64 = 0x02;
+/// Capability: userspace assigns an application-defined meaning to some
+/// addresses passed to [`bind_stream`] or [`connect_stream`], for example
+/// by connecting to a local service when the enclave connects to a
+/// particular name.
+///
+/// [`bind_stream`]: struct.Usercalls.html#method.bind_stream
+/// [`connect_stream`]: struct.Usercalls.html#method.connect_stream
+#[cfg_attr(feature = "rustc-dep-of-std", unstable(feature = "sgx_platform", issue = "56975"))]
+pub const CAPABILITY_EXTENSION_ADDRESSES: u64 = 0x04;
+/// Capability: userspace sends the [`EV_SIGNAL`] event when it has been
+/// asked to terminate the enclave.
+///
+/// [`EV_SIGNAL`]: constant.EV_SIGNAL.html
+#[cfg_attr(feature = "rustc-dep-of-std", unstable(feature = "sgx_platform", issue = "56975"))]
+pub const CAPABILITY_SIGNAL_EVENT: u64 = 0x08;
+/// Capability: the [`wait`] usercall accepts a timeout in nanoseconds, in
+/// addition to [`WAIT_NO`] and [`WAIT_INDEFINITE`].
+///
+/// [`wait`]: struct.Usercalls.html#method.wait
+/// [`WAIT_NO`]: constant.WAIT_NO.html
+/// [`WAIT_INDEFINITE`]: constant.WAIT_INDEFINITE.html
+#[cfg_attr(feature = "rustc-dep-of-std", unstable(feature = "sgx_platform", issue = "56975"))]
+pub const CAPABILITY_WAIT_TIMEOUT: u64 = 0x10;
+
+/// # Capabilities
+impl Usercalls {
+    /// Returns the version of this specification that userspace implements,
+    /// in the format of [`ABI_VERSION`], and the optional features that
+    /// userspace supports, as a combination of the `CAPABILITY_*` constants.
+    /// Undefined bits must be 0 and should be ignored by the enclave.
+    ///
+    /// This usercall was added in version 0.3.4. Userspace implementing an
+    /// earlier version treats it like any other undefined usercall. Enclaves
+    /// that need to run with such userspace must not use this usercall.
+    ///
+    /// [`ABI_VERSION`]: constant.ABI_VERSION.html
+    pub fn capabilities() -> (u64, u64) { unimplemented!() }
+}
+
 ]; ] ];
 
 // docs: Just render the docs verbatim
@@ -823,3 +894,7 @@ macro_rules! define_invoke_with_usercalls {
 
 #[cfg(not(feature = "docs"))]
 invoke_with_abi_spec!(define_invoke_with_usercalls);
+
+// Not part of `std`, which only needs the definitions above
+#[cfg(not(feature = "rustc-dep-of-std"))]
+pub mod description;
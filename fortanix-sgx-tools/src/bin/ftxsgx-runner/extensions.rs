@@ -0,0 +1,239 @@
+/* Copyright (c) Fortanix, Inc.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Usercall extensions configured in a TOML file.
+//!
+//! ```toml
+//! # Connections by the enclave to "database" go to db.internal:5432
+//! [[connect]]
+//! address = "database"
+//! target = "db.internal:5432"
+//!
+//! # Targets can be Unix sockets
+//! [[connect]]
+//! address = "agent"
+//! target = "unix:/run/agent.sock"
+//!
+//! # The enclave listens on 0.0.0.0:8080 when it binds to "http"
+//! [[bind]]
+//! address = "http"
+//! target = "0.0.0.0:8080"
+//!
+//! # Serve files through `file:` addresses, see `HostDirectory`
+//! [directory]
+//! path = "/srv/enclave"
+//! writable = false
+//!
+//! # Extensions in dynamic libraries, see the `plugin` module
+//! [[plugin]]
+//! path = "/usr/lib/libmy_extension.so"
+//! config = "verbose=1"
+//! ```
+//!
+//! Extensions are tried in the order `connect`/`bind`, `directory`,
+//! `plugin`, and plugins in the order in which they are listed. Addresses
+//! that aren't handled by any extension are handled as usual, and the
+//! network policy applies to the addresses used by the enclave.
+
+use std::future::Future;
+use std::io::Result as IoResult;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+
+use enclave_runner::usercalls::{AsyncListener, AsyncStream, DirectoryAccess, HostDirectory, UsercallExtension};
+use failure::{Error, ResultExt};
+use fortanix_sgx_abi::CAPABILITY_EXTENSION_ADDRESSES;
+use futures::FutureExt;
+
+#[cfg(unix)]
+use crate::plugin::Plugin;
+
+const UNIX_SOCKET_PREFIX: &str = "unix:";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExtensionsConfig {
+    #[serde(default)]
+    connect: Vec<Redirect>,
+    #[serde(default)]
+    bind: Vec<Redirect>,
+    directory: Option<DirectoryConfig>,
+    #[serde(default)]
+    plugin: Vec<PluginConfig>,
+}
+
+/// Use `target` when the enclave uses `address`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Redirect {
+    address: String,
+    target: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DirectoryConfig {
+    path: PathBuf,
+    #[serde(default)]
+    writable: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PluginConfig {
+    path: PathBuf,
+    #[serde(default)]
+    config: String,
+}
+
+/// The usercall extensions of `ftxsgx-runner`.
+#[derive(Debug, Default)]
+pub struct Extensions {
+    connect: Vec<Redirect>,
+    bind: Vec<Redirect>,
+    directory: Option<HostDirectory>,
+    #[cfg(unix)]
+    plugins: Vec<Plugin>,
+}
+
+impl Extensions {
+    pub fn new(config: ExtensionsConfig) -> Result<Self, Error> {
+        let directory = match config.directory {
+            Some(directory) => {
+                let access = if directory.writable { DirectoryAccess::ReadWrite } else { DirectoryAccess::ReadOnly };
+                let directory = HostDirectory::new(&directory.path, access)
+                    .with_context(|_| format!("While opening directory {}", directory.path.display()))?;
+                Some(directory)
+            }
+            None => None,
+        };
+        let mut extensions = Extensions {
+            connect: config.connect,
+            bind: config.bind,
+            directory,
+            ..Default::default()
+        };
+        for plugin in config.plugin {
+            extensions.add_plugin(&plugin.path, &plugin.config)?;
+        }
+        Ok(extensions)
+    }
+
+    #[cfg(unix)]
+    pub fn add_plugin(&mut self, path: &Path, config: &str) -> Result<(), Error> {
+        let plugin = Plugin::load(path, config).with_context(|_| format!("While loading plugin {}", path.display()))?;
+        self.plugins.push(plugin);
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn add_plugin(&mut self, _path: &Path, _config: &str) -> Result<(), Error> {
+        bail!("Plugins are only supported on Unix")
+    }
+
+    fn redirect<'a>(redirects: &'a [Redirect], addr: &str) -> Option<&'a str> {
+        redirects.iter().find(|r| r.address == addr).map(|r| &r.target[..])
+    }
+
+    fn extensions(&self) -> Vec<&dyn UsercallExtension> {
+        let mut extensions = vec![];
+        if let Some(ref directory) = self.directory {
+            extensions.push(directory as &dyn UsercallExtension);
+        }
+        #[cfg(unix)]
+        extensions.extend(self.plugins.iter().map(|p| p as &dyn UsercallExtension));
+        extensions
+    }
+}
+
+async fn connect(target: &str, local_addr: Option<&mut String>, peer_addr: Option<&mut String>) -> IoResult<Box<dyn AsyncStream>> {
+    #[cfg(unix)]
+    {
+        if target.starts_with(UNIX_SOCKET_PREFIX) {
+            let stream = tokio::net::UnixStream::connect(&target[UNIX_SOCKET_PREFIX.len()..]).await?;
+            if let Some(peer_addr) = peer_addr {
+                *peer_addr = target.to_owned();
+            }
+            return Ok(Box::new(stream));
+        }
+    }
+    let stream = tokio::net::TcpStream::connect(target).await?;
+    if let (Some(local_addr), Ok(local)) = (local_addr, stream.local_addr()) {
+        *local_addr = local.to_string();
+    }
+    if let (Some(peer_addr), Ok(peer)) = (peer_addr, stream.peer_addr()) {
+        *peer_addr = peer.to_string();
+    }
+    Ok(Box::new(stream))
+}
+
+async fn bind(target: &str, local_addr: Option<&mut String>) -> IoResult<Box<dyn AsyncListener>> {
+    #[cfg(unix)]
+    {
+        if target.starts_with(UNIX_SOCKET_PREFIX) {
+            let listener = tokio::net::UnixListener::bind(&target[UNIX_SOCKET_PREFIX.len()..])?;
+            if let Some(local_addr) = local_addr {
+                *local_addr = target.to_owned();
+            }
+            return Ok(Box::new(listener));
+        }
+    }
+    let listener = tokio::net::TcpListener::bind(target).await?;
+    if let (Some(local_addr), Ok(local)) = (local_addr, listener.local_addr()) {
+        *local_addr = local.to_string();
+    }
+    Ok(Box::new(listener))
+}
+
+impl UsercallExtension for Extensions {
+    fn connect_stream<'future>(
+        &'future self,
+        addr: &'future str,
+        mut local_addr: Option<&'future mut String>,
+        mut peer_addr: Option<&'future mut String>,
+    ) -> Pin<Box<dyn Future<Output = IoResult<Option<Box<dyn AsyncStream>>>> + 'future>> {
+        async move {
+            if let Some(target) = Self::redirect(&self.connect, addr) {
+                return connect(target, local_addr, peer_addr).await.map(Some);
+            }
+            for extension in self.extensions() {
+                let stream = extension
+                    .connect_stream(addr, local_addr.as_deref_mut(), peer_addr.as_deref_mut())
+                    .await?;
+                if stream.is_some() {
+                    return Ok(stream);
+                }
+            }
+            Ok(None)
+        }
+        .boxed_local()
+    }
+
+    fn bind_stream<'future>(
+        &'future self,
+        addr: &'future str,
+        mut local_addr: Option<&'future mut String>,
+    ) -> Pin<Box<dyn Future<Output = IoResult<Option<Box<dyn AsyncListener>>>> + 'future>> {
+        async move {
+            if let Some(target) = Self::redirect(&self.bind, addr) {
+                return bind(target, local_addr).await.map(Some);
+            }
+            for extension in self.extensions() {
+                let listener = extension.bind_stream(addr, local_addr.as_deref_mut()).await?;
+                if listener.is_some() {
+                    return Ok(listener);
+                }
+            }
+            Ok(None)
+        }
+        .boxed_local()
+    }
+
+    fn capabilities(&self) -> u64 {
+        let redirects = if self.connect.is_empty() && self.bind.is_empty() { 0 } else { CAPABILITY_EXTENSION_ADDRESSES };
+        self.extensions().iter().fold(redirects, |capabilities, extension| capabilities | extension.capabilities())
+    }
+}
@@ -0,0 +1,205 @@
+/* Copyright (c) Fortanix, Inc.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+extern crate aesm_client;
+extern crate enclave_runner;
+extern crate fortanix_sgx_abi;
+extern crate sgxs_loaders;
+#[macro_use]
+extern crate failure;
+#[macro_use]
+extern crate clap;
+extern crate futures;
+extern crate libloading;
+#[macro_use]
+extern crate serde_derive;
+extern crate tokio;
+extern crate toml;
+
+mod extensions;
+#[cfg(unix)]
+mod plugin;
+
+use aesm_client::AesmClient;
+use enclave_runner::EnclaveBuilder;
+use enclave_runner::usercalls::{JsonTraceSink, NetworkPolicy, TextTraceSink};
+use failure::{Error, ResultExt};
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+#[cfg(unix)]
+use sgxs_loaders::isgx::Device as IsgxDevice;
+#[cfg(windows)]
+use sgxs_loaders::enclaveapi::Sgx as IsgxDevice;
+
+use clap::{App, AppSettings, Arg};
+
+use crate::extensions::{Extensions, ExtensionsConfig};
+
+arg_enum!{
+    #[derive(PartialEq, Debug)]
+    #[allow(non_camel_case_types)]
+    pub enum Signature {
+        coresident,
+        dummy
+    }
+}
+
+fn main() -> Result<(), Error> {
+    let args = App::new("ftxsgx-runner")
+        .setting(AppSettings::TrailingVarArg)
+        .arg(
+            Arg::with_name("file")
+                .required(true)
+        )
+        .arg(Arg::with_name("signature")
+            .short("s")
+            .long("signature")
+            .required(false)
+            .takes_value(true)
+            .possible_values(&Signature::variants()))
+        .arg(Arg::with_name("trace")
+            .long("trace")
+            .help("Print every usercall made by the enclave to stderr"))
+        .arg(Arg::with_name("trace-json")
+            .long("trace-json")
+            .takes_value(true)
+            .value_name("FILE")
+            .conflicts_with("trace")
+            .help("Write every usercall made by the enclave to FILE as JSON lines"))
+        .arg(Arg::with_name("record")
+            .long("record")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Record the results of non-deterministic usercalls to FILE"))
+        .arg(Arg::with_name("replay")
+            .long("replay")
+            .takes_value(true)
+            .value_name("FILE")
+            .conflicts_with("record")
+            .help("Replay the usercall results recorded in FILE"))
+        .arg(Arg::with_name("network-policy")
+            .long("network-policy")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Restrict the network addresses the enclave can use to the policy in the TOML file FILE"))
+        .arg(Arg::with_name("elf")
+            .long("elf")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Symbolize backtraces of enclave panics using the debug information in the enclave ELF file FILE"))
+        .arg(Arg::with_name("core-dump")
+            .long("core-dump")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Write an ELF core file to FILE when a debug enclave panics"))
+        .arg(Arg::with_name("gdbserver")
+            .long("gdbserver")
+            .takes_value(true)
+            .value_name("[ADDR:]PORT")
+            .help("Serve the gdb remote protocol for a debug enclave on PORT, on localhost unless ADDR is given"))
+        .arg(Arg::with_name("extensions")
+            .long("extensions")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Load the usercall extensions configured in the TOML file FILE"))
+        .arg(Arg::with_name("plugin")
+            .long("plugin")
+            .takes_value(true)
+            .value_name("LIBRARY")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Load a usercall extension plugin from the dynamic library LIBRARY"))
+        .arg(Arg::with_name("forward-signals")
+            .long("forward-signals")
+            .takes_value(true)
+            .value_name("SECONDS")
+            .validator(|v| v.parse::<u64>().map(|_| ()).map_err(|e| e.to_string()))
+            .help("Notify the enclave of SIGINT and SIGTERM, and abort it if it hasn't exited after SECONDS"))
+        .arg(Arg::with_name("enclave-args")
+            .long_help("Arguments passed to the enclave. \
+                Note that this is not an appropriate channel for passing \
+                secrets or security configurations to the enclave.")
+            .multiple(true))
+        .get_matches();
+
+    let file = args.value_of("file").unwrap();
+
+    let mut device = IsgxDevice::new()
+        .context("While opening SGX device")?
+        .einittoken_provider(AesmClient::new())
+        .build();
+
+    let mut enclave_builder = EnclaveBuilder::new(file.as_ref());
+
+    match args.value_of("signature").map(|v| v.parse().expect("validated")) {
+        Some(Signature::coresident) => { enclave_builder.coresident_signature().context("While loading coresident signature")?; }
+        Some(Signature::dummy) => { enclave_builder.dummy_signature(); },
+        None => (),
+    }
+
+    if args.is_present("trace") {
+        enclave_builder.usercall_trace(TextTraceSink::stderr());
+    } else if let Some(path) = args.value_of("trace-json") {
+        let sink = JsonTraceSink::create(path).context("While creating trace file")?;
+        enclave_builder.usercall_trace(sink);
+    }
+
+    if let Some(path) = args.value_of("record") {
+        enclave_builder.record_usercalls(path).context("While creating usercall recording")?;
+    } else if let Some(path) = args.value_of("replay") {
+        enclave_builder.replay_usercalls(path).context("While loading usercall recording")?;
+    }
+
+    if let Some(path) = args.value_of("network-policy") {
+        let policy = fs::read_to_string(path).context("While reading network policy")?;
+        let policy: NetworkPolicy = toml::from_str(&policy).context("While parsing network policy")?;
+        enclave_builder.network_policy(policy);
+    }
+
+    if let Some(elf) = args.value_of("elf") {
+        enclave_builder.enclave_elf(elf);
+    }
+
+    if let Some(path) = args.value_of("core-dump") {
+        enclave_builder.core_dump(path);
+    }
+
+    if let Some(addr) = args.value_of("gdbserver") {
+        enclave_builder.gdbserver(addr);
+    }
+
+    if args.is_present("extensions") || args.is_present("plugin") {
+        let config = match args.value_of("extensions") {
+            Some(path) => {
+                let config = fs::read_to_string(path).context("While reading extensions configuration")?;
+                toml::from_str(&config).context("While parsing extensions configuration")?
+            }
+            None => ExtensionsConfig::default(),
+        };
+        let mut extensions = Extensions::new(config)?;
+        for plugin in args.values_of("plugin").into_iter().flatten() {
+            extensions.add_plugin(Path::new(plugin), "")?;
+        }
+        enclave_builder.usercall_extension(extensions);
+    }
+
+    if let Some(grace_period) = args.value_of("forward-signals") {
+        let grace_period = Duration::from_secs(grace_period.parse().expect("validated"));
+        enclave_builder.forward_signals(grace_period);
+    }
+
+    if let Some(enclave_args) = args.values_of("enclave-args") {
+        enclave_builder.args(enclave_args);
+    }
+
+    let enclave = enclave_builder.build(&mut device).context("While loading SGX enclave")?;
+
+    enclave.run().map_err(|e| {
+        eprintln!("Error while executing SGX enclave.\n{}", e);
+        std::process::exit(-1)
+    })
+}
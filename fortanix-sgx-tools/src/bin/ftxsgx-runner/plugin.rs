@@ -0,0 +1,231 @@
+/* Copyright (c) Fortanix, Inc.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Usercall extensions in dynamic libraries.
+//!
+//! A plugin is a dynamic library exporting the following C functions:
+//!
+//! ```c
+//! /* Required. Returns the plugin ABI version, which must be 1. */
+//! uint32_t ftxsgx_plugin_abi_version(void);
+//!
+//! /* Required. Creates an instance of the plugin, configured by the
+//!  * NUL-terminated string `config`, which is empty if no configuration was
+//!  * given. Returns NULL on failure. */
+//! void *ftxsgx_plugin_new(const char *config);
+//!
+//! /* Optional. Destroys an instance created by `ftxsgx_plugin_new`, once the
+//!  * runner no longer uses it. */
+//! void ftxsgx_plugin_destroy(void *plugin);
+//!
+//! /* Optional. Handles a connection by the enclave to the NUL-terminated
+//!  * address `addr`. */
+//! int ftxsgx_plugin_connect_stream(void *plugin, const char *addr, int *fd);
+//!
+//! /* Optional. Handles a bind to `addr` by the enclave. */
+//! int ftxsgx_plugin_bind_stream(void *plugin, const char *addr, int *fd);
+//! ```
+//!
+//! `ftxsgx_plugin_connect_stream` and `ftxsgx_plugin_bind_stream` return 0
+//! after storing a connected or listening stream socket (TCP or Unix) in
+//! `*fd`, which the runner then owns. They return 1 if the plugin doesn't
+//! handle `addr`, and a negated `errno` value to fail the usercall. They may
+//! block, and may be called from multiple threads at the same time.
+
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::future::Future;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult};
+use std::os::raw::{c_char, c_int, c_void};
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+
+use enclave_runner::usercalls::{AsyncListener, AsyncStream, UsercallExtension};
+use failure::{Error, ResultExt};
+use fortanix_sgx_abi::CAPABILITY_EXTENSION_ADDRESSES;
+use futures::FutureExt;
+use libloading::Library;
+
+const ABI_VERSION: u32 = 1;
+
+type AbiVersionFn = unsafe extern "C" fn() -> u32;
+type NewFn = unsafe extern "C" fn(config: *const c_char) -> *mut c_void;
+type DestroyFn = unsafe extern "C" fn(plugin: *mut c_void);
+type StreamFn = unsafe extern "C" fn(plugin: *mut c_void, addr: *const c_char, fd: *mut c_int) -> c_int;
+
+struct Instance {
+    connect_stream: Option<StreamFn>,
+    bind_stream: Option<StreamFn>,
+    destroy: Option<DestroyFn>,
+    plugin: *mut c_void,
+    // Must outlive the function pointers
+    _library: Library,
+}
+
+impl Drop for Instance {
+    fn drop(&mut self) {
+        if let Some(destroy) = self.destroy {
+            unsafe { destroy(self.plugin) }
+        }
+    }
+}
+
+// Plugins must be thread-safe
+unsafe impl Send for Instance {}
+unsafe impl Sync for Instance {}
+
+impl Instance {
+    /// Call `f`, returning the file descriptor, or `None` if the plugin
+    /// doesn't handle `addr`.
+    fn call(&self, f: StreamFn, addr: &CStr) -> IoResult<Option<RawFd>> {
+        let mut fd = -1;
+        match unsafe { f(self.plugin, addr.as_ptr(), &mut fd) } {
+            0 if fd >= 0 => Ok(Some(fd)),
+            0 => Err(IoError::new(IoErrorKind::Other, "plugin returned an invalid file descriptor")),
+            1 => Ok(None),
+            errno if errno < 0 => Err(IoError::from_raw_os_error(-errno)),
+            _ => Err(IoError::new(IoErrorKind::Other, "plugin returned an invalid status")),
+        }
+    }
+}
+
+/// A usercall extension loaded from a dynamic library.
+#[derive(Clone)]
+pub struct Plugin {
+    path: PathBuf,
+    instance: Arc<Instance>,
+}
+
+impl fmt::Debug for Plugin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Plugin").field("path", &self.path).finish()
+    }
+}
+
+impl Plugin {
+    pub fn load(path: &Path, config: &str) -> Result<Self, Error> {
+        let config = CString::new(config).map_err(|_| format_err!("Plugin configuration contains a NUL byte"))?;
+        let library = Library::new(path).context("While loading plugin library")?;
+        unsafe {
+            let abi_version = library
+                .get::<AbiVersionFn>(b"ftxsgx_plugin_abi_version\0")
+                .context("While looking up ftxsgx_plugin_abi_version")?;
+            let version = abi_version();
+            if version != ABI_VERSION {
+                bail!("Unsupported plugin ABI version {}, expected {}", version, ABI_VERSION);
+            }
+            let new = *library
+                .get::<NewFn>(b"ftxsgx_plugin_new\0")
+                .context("While looking up ftxsgx_plugin_new")?;
+            let connect_stream = library.get::<StreamFn>(b"ftxsgx_plugin_connect_stream\0").ok().map(|f| *f);
+            let bind_stream = library.get::<StreamFn>(b"ftxsgx_plugin_bind_stream\0").ok().map(|f| *f);
+            let destroy = library.get::<DestroyFn>(b"ftxsgx_plugin_destroy\0").ok().map(|f| *f);
+
+            let plugin = new(config.as_ptr());
+            if plugin.is_null() {
+                bail!("Plugin initialization failed");
+            }
+            Ok(Plugin {
+                path: path.to_owned(),
+                instance: Arc::new(Instance {
+                    connect_stream,
+                    bind_stream,
+                    destroy,
+                    plugin,
+                    _library: library,
+                }),
+            })
+        }
+    }
+
+    /// Call `f` on the blocking thread pool.
+    async fn call(&self, f: StreamFn, addr: &str) -> IoResult<Option<RawFd>> {
+        let addr = CString::new(addr).map_err(|_| IoError::from(IoErrorKind::InvalidInput))?;
+        let instance = self.instance.clone();
+        tokio::task::spawn_blocking(move || instance.call(f, &addr))
+            .await
+            .map_err(|e| IoError::new(IoErrorKind::Other, e))?
+    }
+}
+
+/// Wrap a stream socket returned by a plugin.
+fn stream(fd: RawFd, local_addr: Option<&mut String>, peer_addr: Option<&mut String>) -> IoResult<Box<dyn AsyncStream>> {
+    let tcp = unsafe { std::net::TcpStream::from_raw_fd(fd) };
+    // Only succeeds for IP sockets
+    if let Ok(local) = tcp.local_addr() {
+        if let Some(local_addr) = local_addr {
+            *local_addr = local.to_string();
+        }
+        if let (Some(peer_addr), Ok(peer)) = (peer_addr, tcp.peer_addr()) {
+            *peer_addr = peer.to_string();
+        }
+        return Ok(Box::new(tokio::net::TcpStream::from_std(tcp)?));
+    }
+    let unix = unsafe { std::os::unix::net::UnixStream::from_raw_fd(tcp.into_raw_fd()) };
+    Ok(Box::new(tokio::net::UnixStream::from_std(unix)?))
+}
+
+/// Wrap a listening socket returned by a plugin.
+fn listener(fd: RawFd, local_addr: Option<&mut String>) -> IoResult<Box<dyn AsyncListener>> {
+    let tcp = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    if let Ok(local) = tcp.local_addr() {
+        if let Some(local_addr) = local_addr {
+            *local_addr = local.to_string();
+        }
+        return Ok(Box::new(tokio::net::TcpListener::from_std(tcp)?));
+    }
+    let unix = unsafe { std::os::unix::net::UnixListener::from_raw_fd(tcp.into_raw_fd()) };
+    Ok(Box::new(tokio::net::UnixListener::from_std(unix)?))
+}
+
+impl UsercallExtension for Plugin {
+    fn connect_stream<'future>(
+        &'future self,
+        addr: &'future str,
+        local_addr: Option<&'future mut String>,
+        peer_addr: Option<&'future mut String>,
+    ) -> Pin<Box<dyn Future<Output = IoResult<Option<Box<dyn AsyncStream>>>> + 'future>> {
+        async move {
+            let f = match self.instance.connect_stream {
+                Some(f) => f,
+                None => return Ok(None),
+            };
+            match self.call(f, addr).await? {
+                Some(fd) => stream(fd, local_addr, peer_addr).map(Some),
+                None => Ok(None),
+            }
+        }
+        .boxed_local()
+    }
+
+    fn bind_stream<'future>(
+        &'future self,
+        addr: &'future str,
+        local_addr: Option<&'future mut String>,
+    ) -> Pin<Box<dyn Future<Output = IoResult<Option<Box<dyn AsyncListener>>>> + 'future>> {
+        async move {
+            let f = match self.instance.bind_stream {
+                Some(f) => f,
+                None => return Ok(None),
+            };
+            match self.call(f, addr).await? {
+                Some(fd) => listener(fd, local_addr).map(Some),
+                None => Ok(None),
+            }
+        }
+        .boxed_local()
+    }
+
+    fn capabilities(&self) -> u64 {
+        if self.instance.connect_stream.is_some() || self.instance.bind_stream.is_some() {
+            CAPABILITY_EXTENSION_ADDRESSES
+        } else {
+            0
+        }
+    }
+}